@@ -0,0 +1,45 @@
+//! Measures the cost of `ServiceAccountAccess::token()` with and without a cached token.
+//!
+//! Run using `cargo bench --bench service_account`. Requesting tokens for new scopes requires
+//! signing an RS256 assertion, while requesting cached tokens must not sign anything.
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use yup_oauth2::{service_account_key_from_file, GetToken, ServiceAccountAccess};
+
+const ITERATIONS: u32 = 200;
+
+fn per_call(elapsed: Duration) -> String {
+    format!(
+        "{:>8.1} µs/call",
+        elapsed.as_micros() as f64 / f64::from(ITERATIONS)
+    )
+}
+
+fn main() {
+    let _m = mockito::mock("POST", "/token")
+        .with_body(r#"{"access_token":"ya29.bench","token_type":"Bearer","expires_in":3600}"#)
+        .create();
+    let mut key = service_account_key_from_file("examples/Sanguine-69411a0c0eea.json").unwrap();
+    key.token_uri = Some(format!("{}/token", mockito::server_url()));
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let client = hyper::Client::builder()
+        .executor(rt.executor())
+        .build::<_, hyper::Body>(hyper_rustls::HttpsConnector::new(1));
+    let acc = ServiceAccountAccess::new(key).hyper_client(client).build();
+
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        rt.block_on(acc.token(vec![format!("scope{}", i)])).unwrap();
+    }
+    println!("new scopes (sign + request): {}", per_call(start.elapsed()));
+
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        rt.block_on(acc.token(vec![format!("scope{}", i)])).unwrap();
+    }
+    println!("cached tokens:               {}", per_call(start.elapsed()));
+
+    rt.shutdown_now().wait().unwrap();
+}
@@ -0,0 +1,118 @@
+//! Shared setup of the runnable examples: command line options, the HTTP client, and running
+//! futures to completion.
+//!
+//! Each example is a binary target of this crate, e.g. `cargo run --example device -- --help`.
+#![allow(dead_code)]
+
+use std::env;
+use std::fmt::Debug;
+use std::process;
+
+use futures::prelude::*;
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use yup_oauth2::Token;
+
+pub type Client = hyper::Client<HttpsConnector<HttpConnector>>;
+
+/// The options common to all examples.
+pub struct Options {
+    /// The file containing the client secret or service account key.
+    pub secret: String,
+    /// The file caching tokens between runs.
+    pub tokens: String,
+    pub scopes: Vec<String>,
+    /// Positional arguments.
+    pub free: Vec<String>,
+}
+
+/// Parses the command line, using `secret` and `scopes` unless overridden, and sets up logging
+/// (use `RUST_LOG=yup_oauth2=debug` to see the requests made).
+pub fn options(usage: &str, secret: &str, scopes: &[&str]) -> Options {
+    env_logger::init();
+    let mut opts = getopts::Options::new();
+    opts.optopt("", "secret", "file containing the credentials", "FILE")
+        .optopt("", "tokens", "file caching the tokens", "FILE")
+        .optmulti("", "scope", "scope to request, repeatable", "SCOPE")
+        .optflag("h", "help", "print this help");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => fail(e),
+    };
+    if matches.opt_present("help") {
+        print!("{}", opts.usage(usage));
+        process::exit(0);
+    }
+    let mut requested = matches.opt_strs("scope");
+    if requested.is_empty() {
+        requested = scopes.iter().map(|s| s.to_string()).collect();
+    }
+    Options {
+        secret: matches
+            .opt_str("secret")
+            .unwrap_or_else(|| secret.to_string()),
+        tokens: matches
+            .opt_str("tokens")
+            .unwrap_or_else(|| "tokencache.json".to_string()),
+        scopes: requested,
+        free: matches.free,
+    }
+}
+
+/// Prints `error` and exits.
+pub fn fail<E: Debug>(error: E) -> ! {
+    eprintln!("error: {:?}", error);
+    process::exit(1)
+}
+
+/// An HTTPS client, not keeping connections alive, so the runtime exits once done.
+pub fn client() -> Client {
+    hyper::Client::builder()
+        .keep_alive(false)
+        .build(HttpsConnector::new(1))
+}
+
+/// Runs `fut` to completion, exiting if it fails.
+pub fn run<F>(fut: F) -> F::Item
+where
+    F: Future + Send + 'static,
+    F::Item: Send + 'static,
+    F::Error: Debug + Send + 'static,
+{
+    let mut rt = tokio::runtime::Runtime::new().unwrap_or_else(|e| fail(e));
+    rt.block_on(fut).unwrap_or_else(|e| fail(e))
+}
+
+/// Prints the parts of `token` which are safe to show.
+pub fn print_token(token: &Token) {
+    let shown: String = token.access_token.chars().take(8).collect();
+    println!("access token: {}... ({})", shown, token.token_type);
+    match token.expires_at() {
+        Some(at) => println!("expires at:   {:?}", at),
+        None => println!("expires at:   never"),
+    }
+    println!("refreshable:  {}", token.refresh_token.is_some());
+}
+
+/// Calls `url` using the bearer token `token`, returning the status and body.
+pub fn get(
+    client: &Client,
+    url: &str,
+    token: &Token,
+) -> impl Future<Item = (hyper::StatusCode, String), Error = hyper::Error> {
+    let request = hyper::Request::get(url)
+        .header(
+            hyper::header::AUTHORIZATION,
+            format!("Bearer {}", token.access_token),
+        )
+        .body(hyper::Body::empty())
+        .unwrap_or_else(|e| fail(e));
+    client.request(request).and_then(|response| {
+        let status = response.status();
+        response
+            .into_body()
+            .concat2()
+            .map(move |body| (status, String::from_utf8_lossy(&body).into_owned()))
+    })
+}
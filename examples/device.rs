@@ -0,0 +1,24 @@
+//! Obtains a token using the device flow, for devices without a browser.
+//!
+//! The user code is printed, to be entered on another device; tokens are cached in
+//! `tokencache.json`, so later runs only refresh them.
+mod common;
+
+use yup_oauth2::{Authenticator, DeviceFlow, GetToken};
+
+fn main() {
+    let opts = common::options(
+        "Usage: device [--secret clientsecret.json] [--scope SCOPE]...",
+        "clientsecret.json",
+        &["https://www.googleapis.com/auth/youtube.readonly"],
+    );
+    let secret =
+        yup_oauth2::read_application_secret(&opts.secret).unwrap_or_else(|e| common::fail(e));
+    let auth = Authenticator::new(DeviceFlow::new(secret))
+        .persist_tokens_to_disk(&opts.tokens)
+        .build()
+        .unwrap_or_else(|e| common::fail(e));
+
+    let token = common::run(auth.token(opts.scopes.clone()));
+    common::print_token(&token);
+}
@@ -0,0 +1,57 @@
+//! Obtains a token using the installed flow, receiving the authorization code on a loopback
+//! listener at an ephemeral port.
+//!
+//! The authorization URL is opened in the browser; tokens are cached in `tokencache.json`.
+mod common;
+
+use std::error::Error;
+use std::fmt;
+
+use futures::prelude::*;
+use yup_oauth2::{
+    Authenticator, DefaultFlowDelegate, FlowDelegate, GetToken, InstalledFlow,
+    InstalledFlowReturnMethod,
+};
+
+/// Opens the authorization URL in the browser instead of only printing it.
+#[derive(Clone)]
+struct BrowserDelegate;
+
+impl FlowDelegate for BrowserDelegate {
+    fn present_user_url<S: AsRef<str> + fmt::Display>(
+        &mut self,
+        url: S,
+        need_code: bool,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Box<dyn Error + Send>> + Send> {
+        if need_code {
+            return DefaultFlowDelegate.present_user_url(url, need_code);
+        }
+        println!("Opening {}", url);
+        if let Err(e) = open::that(url.as_ref()) {
+            println!(
+                "Could not open the browser ({}); please open the URL yourself.",
+                e
+            );
+        }
+        Box::new(futures::future::ok(None))
+    }
+}
+
+fn main() {
+    let opts = common::options(
+        "Usage: installed [--secret clientsecret.json] [--scope SCOPE]...",
+        "clientsecret.json",
+        &["https://www.googleapis.com/auth/drive.file"],
+    );
+    let secret =
+        yup_oauth2::read_application_secret(&opts.secret).unwrap_or_else(|e| common::fail(e));
+    let flow = InstalledFlow::new(secret, InstalledFlowReturnMethod::HTTPRedirect(0))
+        .delegate(BrowserDelegate);
+    let auth = Authenticator::new(flow)
+        .persist_tokens_to_disk(&opts.tokens)
+        .build()
+        .unwrap_or_else(|e| common::fail(e));
+
+    let token = common::run(auth.token(opts.scopes.clone()));
+    common::print_token(&token);
+}
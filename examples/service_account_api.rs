@@ -0,0 +1,44 @@
+//! Obtains a token for a service account and uses it to list the Pub/Sub topics of the
+//! account's project.
+//!
+//! The key is downloaded from the Cloud Console; the service account needs the
+//! `roles/pubsub.viewer` role.
+mod common;
+
+use futures::prelude::*;
+use yup_oauth2::{GetToken, ServiceAccountAccess};
+
+fn main() {
+    let opts = common::options(
+        "Usage: service_account_api [--secret serviceaccount.json] [PROJECT]",
+        "serviceaccount.json",
+        &["https://www.googleapis.com/auth/pubsub"],
+    );
+    let key =
+        yup_oauth2::service_account_key_from_file(&opts.secret).unwrap_or_else(|e| common::fail(e));
+    let project = match opts
+        .free
+        .first()
+        .cloned()
+        .or_else(|| key.project_id.clone())
+    {
+        Some(project) => project,
+        None => common::fail("the key contains no project_id; pass the project instead"),
+    };
+    let access = ServiceAccountAccess::new(key).build();
+
+    let client = common::client();
+    let url = format!(
+        "https://pubsub.googleapis.com/v1/projects/{}/topics",
+        project
+    );
+    let fut = access
+        .token(opts.scopes.clone())
+        .map_err(|e| e.to_string())
+        .and_then(move |token| {
+            common::print_token(&token);
+            common::get(&client, &url, &token).map_err(|e| e.to_string())
+        });
+    let (status, body) = common::run(fut);
+    println!("{}\n{}", status, body);
+}
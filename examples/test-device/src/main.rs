@@ -7,7 +7,7 @@ use tokio;
 fn main() {
     let creds = yup_oauth2::read_application_secret(path::Path::new("clientsecret.json"))
         .expect("clientsecret");
-    let mut auth = Authenticator::new(DeviceFlow::new(creds))
+    let auth = Authenticator::new(DeviceFlow::new(creds))
         .persist_tokens_to_disk("tokenstorage.json")
         .build()
         .expect("authenticator");
@@ -16,7 +16,7 @@ fn main() {
     let secret = yup_oauth2::read_application_secret(Path::new("clientsecret.json"))
         .expect("clientsecret.json");
 
-    let mut auth = Authenticator::new(InstalledFlow::new(
+    let auth = Authenticator::new(InstalledFlow::new(
         secret,
         yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect(8081),
     ))
@@ -10,7 +10,7 @@ use std::path;
 fn main() {
     let creds =
         yup_oauth2::service_account_key_from_file(path::Path::new("serviceaccount.json")).unwrap();
-    let mut sa = yup_oauth2::ServiceAccountAccess::new(creds).build();
+    let sa = yup_oauth2::ServiceAccountAccess::new(creds).build();
 
     let fut = sa
         .token(vec!["https://www.googleapis.com/auth/pubsub"])
@@ -0,0 +1,12 @@
+//! Error responses of the token, device authorization and revocation endpoints. Run with
+//! `cargo fuzz run json_error`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use yup_oauth2::{JsonError, RequestError};
+
+fuzz_target!(|data: &[u8]| {
+    let body = String::from_utf8_lossy(data);
+    if let Some(error) = JsonError::from_response(&body) {
+        let _ = RequestError::from(error).to_string();
+    }
+});
@@ -0,0 +1,24 @@
+//! Key sets and the JWTs validated against them. The input is the key set, a NUL byte, and the
+//! token. Run with `cargo fuzz run jwt`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use yup_oauth2::{validate_access_token_claims, DiscoveryDocument, Jwks, TokenValidation};
+
+fuzz_target!(|data: &[u8]| {
+    let mut parts = data.splitn(2, |&b| b == 0);
+    let (keys, token) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    let _ = serde_json::from_slice::<DiscoveryDocument>(keys);
+    let jwks = match serde_json::from_slice::<Jwks>(keys) {
+        Ok(jwks) => jwks,
+        Err(_) => Jwks { keys: Vec::new() },
+    };
+    let validation = TokenValidation::new("https://accounts.google.com", "client");
+    if let Ok(claims) =
+        validate_access_token_claims(&String::from_utf8_lossy(token), &jwks, &validation)
+    {
+        let _ = claims.expires_at();
+    }
+});
@@ -0,0 +1,15 @@
+//! Redirect URIs of the authorization code flow, which any local process or web page can send.
+//! Run with `cargo fuzz run redirect`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+use yup_oauth2::{AuthorizationResponse, Scheme};
+
+fuzz_target!(|data: &[u8]| {
+    let uri = String::from_utf8_lossy(data);
+    let _ = AuthorizationResponse::from_uri(&uri).into_code();
+    let _ = AuthorizationResponse::from_query(&uri).into_code();
+    if let Ok(scheme) = Scheme::from_str(&uri) {
+        let _ = scheme.header_value();
+    }
+});
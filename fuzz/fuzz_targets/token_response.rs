@@ -0,0 +1,25 @@
+//! Token endpoint responses, as parsed by the flows. Run with `cargo fuzz run token_response`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use yup_oauth2::{
+    DefaultTokenResponseParser, StrictTokenResponseParser, Token, TokenResponseParser,
+};
+
+fn use_token(token: &Token) {
+    let _ = token.expired();
+    let _ = token.expires_in();
+    let _ = token.expires_at();
+}
+
+fuzz_target!(|data: &[u8]| {
+    let body = String::from_utf8_lossy(data);
+    if let Ok(token) = DefaultTokenResponseParser.parse_token_response(&body) {
+        use_token(&token);
+    }
+    if let Ok(token) = StrictTokenResponseParser.parse_token_response(&body) {
+        use_token(&token);
+    }
+    if let Ok(token) = serde_json::from_slice::<Token>(data) {
+        use_token(&token);
+    }
+});
@@ -0,0 +1,112 @@
+//! An audit trail of what an authenticator does with credentials.
+//!
+//! Set an `AuditSink` using `Authenticator::audit_sink()` to receive an `AuditEvent` whenever a
+//! flow is started, a token is obtained, refreshed or invalidated, or the token storage is
+//! accessed. Events never contain tokens, so they may be shipped to a log collector or SIEM.
+use std::sync::Arc;
+
+use ::log::{info, log};
+
+use crate::storage::RefreshFailureKind;
+use crate::time::{self, Timestamp};
+
+/// Something an authenticator did with a credential.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEvent {
+    /// When it happened.
+    pub time: Timestamp,
+    /// The client ID of the authenticator's application secret; empty for flows without one.
+    pub client_id: String,
+    /// The scopes of the token concerned, sorted. Empty for `TokenInvalidated`, as tokens are
+    /// invalidated by value.
+    pub scopes: Vec<String>,
+    pub kind: AuditEventKind,
+}
+
+/// What happened in an `AuditEvent`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditEventKind {
+    /// No usable token was stored, so the flow was asked for a new one, possibly involving the
+    /// user.
+    FlowStarted,
+    /// The flow obtained a new token, e.g. after the user granted consent.
+    ConsentGranted,
+    /// The flow failed to obtain a token; contains the error message.
+    FlowFailed(String),
+    /// An expired token was refreshed.
+    TokenRefreshed,
+    /// Refreshing a token failed.
+    RefreshFailed(RefreshFailureKind),
+    /// The scopes were refused by the authenticator's `ScopePolicy`; contains the error
+    /// message.
+    PolicyViolation(String),
+    /// A token was invalidated using `GetToken::invalidate()`, e.g. because a resource server
+    /// rejected it.
+    TokenInvalidated,
+    /// The token storage was read; `found` is whether it held a token for the scopes.
+    StorageRead { found: bool },
+    /// A token was written to the token storage.
+    StorageWritten,
+    /// Reading from (`write: false`) or writing to the token storage failed; contains the
+    /// error message.
+    StorageFailed { write: bool, error: String },
+}
+
+/// Receives the `AuditEvent`s of an authenticator.
+///
+/// Events are delivered synchronously while tokens are requested, so implementations that ship
+/// them over the network should buffer them. Closures taking an `&AuditEvent` are sinks, too.
+pub trait AuditSink {
+    fn record(&self, event: &AuditEvent);
+}
+
+impl<F: Fn(&AuditEvent)> AuditSink for F {
+    fn record(&self, event: &AuditEvent) {
+        self(event)
+    }
+}
+
+/// An `AuditSink` writing every event to the `log` crate, at level info with the target
+/// `yup_oauth2::audit`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogAuditSink;
+
+impl AuditSink for LogAuditSink {
+    fn record(&self, event: &AuditEvent) {
+        info!(
+            target: "yup_oauth2::audit",
+            "{} client_id={} scopes={} {:?}",
+            time::display(&event.time),
+            event.client_id,
+            event.scopes.join(" "),
+            event.kind
+        );
+    }
+}
+
+/// Creates `AuditEvent`s for an authenticator and passes them to its sink, if any.
+#[derive(Clone, Default)]
+pub(crate) struct Auditor {
+    sink: Option<Arc<dyn AuditSink + Send + Sync>>,
+    client_id: String,
+}
+
+impl Auditor {
+    pub(crate) fn new(
+        sink: Option<Arc<dyn AuditSink + Send + Sync>>,
+        client_id: String,
+    ) -> Auditor {
+        Auditor { sink, client_id }
+    }
+
+    pub(crate) fn record(&self, scopes: &[String], kind: AuditEventKind) {
+        if let Some(ref sink) = self.sink {
+            sink.record(&AuditEvent {
+                time: time::from_secs(time::now()),
+                client_id: self.client_id.clone(),
+                scopes: scopes.to_vec(),
+                kind,
+            });
+        }
+    }
+}
@@ -1,15 +1,38 @@
+use crate::audit::{AuditEventKind, AuditSink, Auditor};
 use crate::authenticator_delegate::{AuthenticatorDelegate, DefaultAuthenticatorDelegate, Retry};
+use crate::backoff::BackoffPolicy;
+use crate::config_check::ConfigReport;
+use crate::consent::ConsentCheck;
 use crate::refresh::RefreshFlow;
-use crate::storage::{hash_scopes, DiskTokenStorage, MemoryStorage, TokenStorage};
-use crate::types::{ApplicationSecret, GetToken, RefreshResult, RequestError, Token};
+use crate::retry_budget::RetryBudget;
+use crate::scope::ScopePolicy;
+use crate::secret_storage::{load_secret_from, LoadSecret, SecretStorage};
+use crate::stats::{AuthenticatorStats, SignInOutcome, StatsRecorder, TokenInfo, TokenSource};
+#[cfg(feature = "disk-storage")]
+use crate::storage::DiskTokenStorage;
+use crate::storage::{
+    MemoryStorage, RefreshFailure, RefreshFailureKind, RefreshStart, TokenKey, TokenStorage,
+};
+use crate::storage_combinators::ProfileStorage;
+use crate::time;
+use crate::types::{
+    check_valid_for, ApplicationSecret, DefaultTokenResponseParser, GetToken, RefreshResult,
+    RequestError, Token, TokenResponseParser, DEFAULT_EXPIRY_MARGIN,
+};
 
+use ::log::{error, log, warn};
+use futures::sync::oneshot;
 use futures::{future, prelude::*};
+use tokio::executor::Executor;
 use tokio_timer;
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::io;
+#[cfg(feature = "disk-storage")]
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// Authenticator abstracts different `GetToken` implementations behind one type and handles
 /// caching received tokens. It's important to use it (instead of the flows directly) because
@@ -21,6 +44,8 @@ use std::sync::{Arc, Mutex};
 /// NOTE: It is recommended to use a client constructed like this in order to prevent functions
 /// like `hyper::run()` from hanging: `let client = hyper::Client::builder().keep_alive(false);`.
 /// Due to token requests being rare, this should not result in a too bad performance problem.
+/// Where they are not rare, use `Authenticator::keep_alive()`; the client is shared by the flow
+/// and the refresh flow.
 struct AuthenticatorImpl<
     T: GetToken,
     S: TokenStorage,
@@ -29,10 +54,25 @@ struct AuthenticatorImpl<
 > {
     client: hyper::Client<C>,
     inner: Arc<Mutex<T>>,
-    store: Arc<Mutex<S>>,
-    delegate: AD,
+    store: SharedStorage<S::Error>,
+    delegate: Mutex<AD>,
+    parser: Arc<dyn TokenResponseParser + Send + Sync>,
+    stats: Arc<StatsRecorder>,
+    audit: Arc<Auditor>,
+    policy: ScopePolicy,
+    expiry_margin: Duration,
+    retry_budget: Option<RetryBudget>,
+    secrets: Option<LoadSecret>,
+    refresh_latency_budget: Option<Duration>,
+    consent_check: Option<Arc<ConsentCheck>>,
+    refresh_retries: Option<(u32, Arc<dyn BackoffPolicy>)>,
+    in_flight: Arc<InFlight>,
 }
 
+/// The storage of an authenticator: `S` itself, or a `ProfileStorage` wrapping it for a profile
+/// other than the default one.
+type SharedStorage<E> = Arc<Mutex<dyn TokenStorage<Error = E> + Send>>;
+
 /// A trait implemented for any hyper::Client as well as teh DefaultHyperClient.
 pub trait HyperClientBuilder {
     type Connector: hyper::client::connect::Connect;
@@ -41,14 +81,42 @@ pub trait HyperClientBuilder {
 }
 
 /// The builder value used when the default hyper client should be used.
-pub struct DefaultHyperClient;
+///
+/// By default, connections are not kept alive, as idle connections would keep runtimes like the
+/// one started by `tokio::run()` from shutting down. Enable keep-alive using
+/// `Authenticator::keep_alive()` when tokens are requested or refreshed frequently.
+///
+/// Servers are verified using the Mozilla root certificates of `webpki-roots`, plus those added
+/// using `Authenticator::root_certificates()`.
+#[derive(Clone, Default)]
+pub struct DefaultHyperClient {
+    pub(crate) keep_alive: bool,
+    pub(crate) root_certificates: Vec<rustls::Certificate>,
+}
+
 impl HyperClientBuilder for DefaultHyperClient {
     type Connector = hyper_rustls::HttpsConnector<hyper::client::connect::HttpConnector>;
 
     fn build_hyper_client(self) -> hyper::Client<Self::Connector> {
+        let connector = if self.root_certificates.is_empty() {
+            hyper_rustls::HttpsConnector::new(1)
+        } else {
+            let mut http = hyper::client::connect::HttpConnector::new(1);
+            http.enforce_http(false);
+            let mut config = rustls::ClientConfig::new();
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            for cert in &self.root_certificates {
+                if let Err(e) = config.root_store.add(cert) {
+                    error!("Ignoring invalid root certificate: {:?}", e);
+                }
+            }
+            hyper_rustls::HttpsConnector::from((http, config))
+        };
         hyper::Client::builder()
-            .keep_alive(false)
-            .build::<_, hyper::Body>(hyper_rustls::HttpsConnector::new(1))
+            .keep_alive(self.keep_alive)
+            .build::<_, hyper::Body>(connector)
     }
 }
 
@@ -73,6 +141,12 @@ pub trait AuthFlow<C> {
 /// An authenticator can be used with `InstalledFlow`'s or `DeviceFlow`'s and
 /// will refresh tokens as they expire as well as optionally persist tokens to
 /// disk.
+///
+/// Tokens may be requested from many threads and tasks at once. Concurrent requests for a token
+/// which has to be refreshed, or obtained from the flow, wait for the first of them to store
+/// its token, so that the token is refreshed once and the user is asked once. If that request
+/// fails or is dropped, the next waiting one tries again. Tokens for other scopes are obtained
+/// independently. Processes sharing a `DiskTokenStorage` don't overwrite each other's tokens.
 pub struct Authenticator<
     T: AuthFlow<C::Connector>,
     S: TokenStorage,
@@ -83,6 +157,16 @@ pub struct Authenticator<
     token_getter: T,
     store: io::Result<S>,
     delegate: AD,
+    parser: Arc<dyn TokenResponseParser + Send + Sync>,
+    audit: Option<Arc<dyn AuditSink + Send + Sync>>,
+    policy: ScopePolicy,
+    expiry_margin: Duration,
+    retry_budget: Option<RetryBudget>,
+    secrets: Option<LoadSecret>,
+    refresh_latency_budget: Option<Duration>,
+    consent_check: Option<Arc<ConsentCheck>>,
+    refresh_retries: Option<(u32, Arc<dyn BackoffPolicy>)>,
+    profile: String,
 }
 
 impl<T> Authenticator<T, MemoryStorage, DefaultAuthenticatorDelegate, DefaultHyperClient>
@@ -96,23 +180,69 @@ where
     ///
     /// Examples
     /// ```
+    /// # #[cfg(feature = "device")]
+    /// # {
     /// use std::path::Path;
     /// use yup_oauth2::{ApplicationSecret, Authenticator, DeviceFlow};
     /// let creds = ApplicationSecret::default();
     /// let auth = Authenticator::new(DeviceFlow::new(creds)).build().unwrap();
+    /// # }
     /// ```
     pub fn new(
         flow: T,
     ) -> Authenticator<T, MemoryStorage, DefaultAuthenticatorDelegate, DefaultHyperClient> {
         Authenticator {
-            client: DefaultHyperClient,
+            client: DefaultHyperClient::default(),
             token_getter: flow,
             store: Ok(MemoryStorage::new()),
             delegate: DefaultAuthenticatorDelegate,
+            parser: Arc::new(DefaultTokenResponseParser),
+            audit: None,
+            policy: ScopePolicy::new(),
+            expiry_margin: DEFAULT_EXPIRY_MARGIN,
+            retry_budget: None,
+            secrets: None,
+            refresh_latency_budget: None,
+            consent_check: None,
+            refresh_retries: None,
+            profile: String::new(),
         }
     }
 }
 
+impl<T, S, AD> Authenticator<T, S, AD, DefaultHyperClient>
+where
+    T: AuthFlow<<DefaultHyperClient as HyperClientBuilder>::Connector>,
+    S: TokenStorage,
+    AD: AuthenticatorDelegate,
+{
+    /// Keep connections of the default hyper client alive between requests. The client is
+    /// shared by the flow and all token refreshes, so this avoids a new TLS handshake for every
+    /// request made to the provider.
+    pub fn keep_alive(self, keep_alive: bool) -> Self {
+        Authenticator {
+            client: DefaultHyperClient {
+                keep_alive,
+                ..self.client
+            },
+            ..self
+        }
+    }
+
+    /// Trust servers whose certificates are issued by `certificates`, in addition to the
+    /// default root certificates, e.g. for a corporate proxy intercepting TLS connections or a
+    /// provider using a private certificate authority. The certificates only apply to this
+    /// authenticator's client. Read them using `root_certificates_from_pem()`.
+    pub fn root_certificates<I>(self, certificates: I) -> Self
+    where
+        I: IntoIterator<Item = rustls::Certificate>,
+    {
+        let mut client = self.client;
+        client.root_certificates.extend(certificates);
+        Authenticator { client, ..self }
+    }
+}
+
 impl<T, S, AD, C> Authenticator<T, S, AD, C>
 where
     T: AuthFlow<C::Connector>,
@@ -134,10 +264,21 @@ where
             token_getter: self.token_getter,
             store: self.store,
             delegate: self.delegate,
+            parser: self.parser,
+            audit: self.audit,
+            policy: self.policy,
+            expiry_margin: self.expiry_margin,
+            retry_budget: self.retry_budget,
+            secrets: self.secrets,
+            refresh_latency_budget: self.refresh_latency_budget,
+            consent_check: self.consent_check,
+            refresh_retries: self.refresh_retries,
+            profile: self.profile,
         }
     }
 
     /// Persist tokens to disk in the provided filename.
+    #[cfg(feature = "disk-storage")]
     pub fn persist_tokens_to_disk<P: AsRef<Path>>(
         self,
         path: P,
@@ -148,6 +289,16 @@ where
             token_getter: self.token_getter,
             store: disk_storage,
             delegate: self.delegate,
+            parser: self.parser,
+            audit: self.audit,
+            policy: self.policy,
+            expiry_margin: self.expiry_margin,
+            retry_budget: self.retry_budget,
+            secrets: self.secrets,
+            refresh_latency_budget: self.refresh_latency_budget,
+            consent_check: self.consent_check,
+            refresh_retries: self.refresh_retries,
+            profile: self.profile,
         }
     }
 
@@ -161,28 +312,178 @@ where
             token_getter: self.token_getter,
             store: self.store,
             delegate: delegate,
+            parser: self.parser,
+            audit: self.audit,
+            policy: self.policy,
+            expiry_margin: self.expiry_margin,
+            retry_budget: self.retry_budget,
+            secrets: self.secrets,
+            refresh_latency_budget: self.refresh_latency_budget,
+            consent_check: self.consent_check,
+            refresh_retries: self.refresh_retries,
+            profile: self.profile,
+        }
+    }
+
+    /// Use the provided parser for token responses received while refreshing tokens. By
+    /// default, responses are expected to follow RFC 6749.
+    pub fn token_response_parser<P>(self, parser: P) -> Authenticator<T, S, AD, C>
+    where
+        P: 'static + TokenResponseParser + Send + Sync,
+    {
+        Authenticator {
+            parser: Arc::new(parser),
+            ..self
+        }
+    }
+
+    /// Send an `AuditEvent` to `sink` whenever a flow is started, a token is obtained, refreshed
+    /// or invalidated, or the token storage is accessed.
+    pub fn audit_sink<A>(self, sink: A) -> Authenticator<T, S, AD, C>
+    where
+        A: 'static + AuditSink + Send + Sync,
+    {
+        Authenticator {
+            audit: Some(Arc::new(sink)),
+            ..self
+        }
+    }
+
+    /// Refuse requests for scopes violating `policy` with `RequestError::PolicyViolation`,
+    /// before looking for a stored token or contacting the provider. By default, all scopes are
+    /// permitted.
+    pub fn scope_policy(self, policy: ScopePolicy) -> Authenticator<T, S, AD, C> {
+        Authenticator { policy, ..self }
+    }
+
+    /// Refresh stored tokens once they expire within `margin`, e.g. to leave more time for
+    /// long uploads. (default: `DEFAULT_EXPIRY_MARGIN`, one minute)
+    pub fn expiry_margin(self, margin: Duration) -> Authenticator<T, S, AD, C> {
+        Authenticator {
+            expiry_margin: margin,
+            ..self
+        }
+    }
+
+    /// Stop contacting the provider once refreshes or flows failed repeatedly, as determined by
+    /// `budget`, failing with `RequestError::CircuitOpen` instead. Clones of `budget` may be
+    /// used by several authenticators. (default: no limit)
+    pub fn retry_budget(self, budget: RetryBudget) -> Authenticator<T, S, AD, C> {
+        Authenticator {
+            retry_budget: Some(budget),
+            ..self
+        }
+    }
+
+    /// Retry refreshes failing with network errors, like timeouts or refused connections, up to
+    /// `retries` times, waiting for the delays of `backoff` in between, before failing with the
+    /// last error. Concurrent requests for the token wait for the retries. Refresh tokens refused
+    /// by the provider aren't retried. (default: no retries)
+    pub fn refresh_retries<P: 'static + BackoffPolicy>(
+        self,
+        retries: u32,
+        backoff: P,
+    ) -> Authenticator<T, S, AD, C> {
+        Authenticator {
+            refresh_retries: Some((retries, Arc::new(backoff))),
+            ..self
         }
     }
 
-    /// Create the authenticator.
-    pub fn build(self) -> io::Result<impl GetToken>
+    /// Keep the tokens in the storage apart from those of other profiles, like the profiles of
+    /// the AWS CLI, e.g. to sign in with a `work` and a `personal` account on one machine. Each
+    /// profile has its own tokens for the same client and scopes, see `ProfileStorage`.
+    /// (default: the profile with the empty name, whose tokens are stored as by previous
+    /// versions of this crate)
+    pub fn profile<P: Into<String>>(self, name: P) -> Authenticator<T, S, AD, C> {
+        Authenticator {
+            profile: name.into(),
+            ..self
+        }
+    }
+
+    /// Return the stored token right away if refreshing it takes longer than `budget`, provided
+    /// it remains valid for `DEFAULT_EXPIRY_MARGIN`, and let the refresh complete in the
+    /// background, so that a slow token endpoint doesn't hold up requests. Use it with an
+    /// `expiry_margin()` leaving enough time to refresh. The background refresh runs on the
+    /// tokio executor of the caller; without one, the refresh is waited for. (default: none)
+    pub fn refresh_latency_budget(self, budget: Duration) -> Authenticator<T, S, AD, C> {
+        Authenticator {
+            refresh_latency_budget: Some(budget),
+            ..self
+        }
+    }
+
+    /// Check stored tokens using `check`, to find out that the user revoked their consent before
+    /// refreshing fails, e.g. to ask them to sign in again at a convenient time. (default: none)
+    ///
+    /// ```
+    /// # #[cfg(feature = "device")]
+    /// # {
+    /// # use std::time::Duration;
+    /// # use yup_oauth2::*;
+    /// # let flow = DeviceFlow::new(ApplicationSecret::default());
+    /// let endpoint = ConsentEndpoint::TokenInfo(GOOGLE_TOKENINFO_URL.to_string());
+    /// let auth = Authenticator::new(flow)
+    ///     .consent_check(ConsentCheck::new(endpoint, Duration::from_secs(3600)))
+    ///     .build();
+    /// # }
+    /// ```
+    pub fn consent_check(self, check: ConsentCheck) -> Authenticator<T, S, AD, C> {
+        Authenticator {
+            consent_check: Some(Arc::new(check)),
+            ..self
+        }
+    }
+
+    /// Load the application secret from `storage` whenever a token is requested, rather than
+    /// using the one of the flow, so that the client secret used to refresh tokens can be kept
+    /// in a vault and rotated. A flow obtaining new tokens still needs the secret; construct it
+    /// from `SecretStorage::load()` when the storage holds no tokens yet.
+    pub fn secret_storage<SS>(self, storage: SS) -> Authenticator<T, S, AD, C>
+    where
+        SS: 'static + SecretStorage + Send + Sync,
+    {
+        Authenticator {
+            secrets: Some(load_secret_from(storage)),
+            ..self
+        }
+    }
+
+    /// Create the authenticator. The returned token source can be shared between threads.
+    pub fn build(self) -> io::Result<impl GetToken + Send + Sync>
     where
         T::TokenGetter: 'static + GetToken + Send,
         S: 'static + Send,
         AD: 'static + Send,
-        C::Connector: 'static + Clone + Send,
+        C::Connector: 'static + Clone + Send + Sync,
     {
         let client = self.client.build_hyper_client();
-        let store = Arc::new(Mutex::new(self.store?));
-        let inner = Arc::new(Mutex::new(
-            self.token_getter.build_token_getter(client.clone()),
-        ));
+        let store: SharedStorage<S::Error> = if self.profile.is_empty() {
+            Arc::new(Mutex::new(self.store?))
+        } else {
+            Arc::new(Mutex::new(ProfileStorage::new(self.store?, self.profile)))
+        };
+        let inner = self.token_getter.build_token_getter(client.clone());
+        let audit = Auditor::new(self.audit, inner.application_secret().client_id);
+        let inner = Arc::new(Mutex::new(inner));
 
-        Ok(AuthenticatorImpl {
+        Ok(AuthenticatorImpl::<_, S, _, _> {
             client,
             inner,
             store,
-            delegate: self.delegate,
+            delegate: Mutex::new(self.delegate),
+            parser: self.parser,
+            stats: Arc::new(StatsRecorder::default()),
+            audit: Arc::new(audit),
+            policy: self.policy,
+            expiry_margin: self.expiry_margin,
+            retry_budget: self.retry_budget,
+            secrets: self.secrets,
+            refresh_latency_budget: self.refresh_latency_budget,
+            consent_check: self.consent_check,
+            refresh_retries: self.refresh_retries,
+            in_flight: Arc::new(InFlight::default()),
         })
     }
 }
@@ -191,74 +492,272 @@ impl<
         GT: 'static + GetToken + Send,
         S: 'static + TokenStorage + Send,
         AD: 'static + AuthenticatorDelegate + Send,
-        C: 'static + hyper::client::connect::Connect + Clone + Send,
-    > GetToken for AuthenticatorImpl<GT, S, AD, C>
+        C: 'static + hyper::client::connect::Connect + Clone + Send + Sync,
+    > AuthenticatorImpl<GT, S, AD, C>
 {
-    /// Returns the API Key of the inner flow.
-    fn api_key(&mut self) -> Option<String> {
-        self.inner.lock().unwrap().api_key()
+    /// Returns the application secret of the secret storage, if any, or else of the flow.
+    fn current_secret(&self) -> Result<ApplicationSecret, RequestError> {
+        match self.secrets {
+            Some(ref load) => load(),
+            None => Ok(self.inner.lock().unwrap().application_secret()),
+        }
     }
-    /// Returns the application secret of the inner flow.
-    fn application_secret(&self) -> ApplicationSecret {
-        self.inner.lock().unwrap().application_secret()
+
+    /// Returns the storage key of the application's tokens for `scopes`, and the sorted scopes.
+    fn token_key<I, T>(&self, appsecret: &ApplicationSecret, scopes: I) -> (u64, Vec<String>)
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let key = TokenKey::new(appsecret.client_id.as_str(), scopes);
+        (key.scope_hash(), key.scopes().to_vec())
     }
 
-    fn token<I, T>(
-        &mut self,
+    /// Returns a cached token for `scopes`, or refreshes it if it expires within `margin` or
+    /// `force` is set, or obtains a new one from the flow. Expired tokens without a refresh
+    /// token are only replaced when `force` is set.
+    fn get_token<I, T>(
+        &self,
         scopes: I,
-    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        force: bool,
+        margin: Duration,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
     where
         T: Into<String>,
         I: IntoIterator<Item = T>,
     {
-        let (scope_key, scopes) = hash_scopes(scopes);
+        let appsecret = match self.current_secret() {
+            Ok(appsecret) => appsecret,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let (scope_key, scopes) = self.token_key(&appsecret, scopes);
+        if let Err(e) = self.policy.check(&scopes) {
+            self.audit
+                .record(&scopes, AuditEventKind::PolicyViolation(e.to_string()));
+            return Box::new(future::err(e));
+        }
         let store = self.store.clone();
-        let mut delegate = self.delegate.clone();
+        let mut delegate = self.delegate.lock().unwrap().clone();
         let client = self.client.clone();
-        let appsecret = self.inner.lock().unwrap().application_secret();
         let gettoken = self.inner.clone();
+        let parser = self.parser.clone();
+        let stats = self.stats.clone();
+        let audit = self.audit.clone();
+        let budget = self.retry_budget.clone();
+        let latency_budget = self.refresh_latency_budget;
+        let consent_check = self.consent_check.clone();
+        let in_flight = self.in_flight.clone();
+        let refresh_retries = self.refresh_retries.clone();
+        let location = self.store.lock().unwrap().location();
+        let info = move |token, obtained_via, obtained_at| TokenInfo {
+            token,
+            obtained_via,
+            obtained_at,
+            storage: location.clone(),
+        };
         let loopfn = move |()| -> Box<
-            dyn Future<Item = future::Loop<Token, ()>, Error = RequestError> + Send,
+            dyn Future<Item = future::Loop<TokenInfo, ()>, Error = RequestError> + Send,
         > {
             // How well does this work with tokio?
-            match store.lock().unwrap().get(
-                scope_key.clone(),
-                &scopes.iter().map(|s| s.as_str()).collect(),
-            ) {
+            let stored = store
+                .lock()
+                .unwrap()
+                .get(scope_key, &scopes.iter().map(|s| s.as_str()).collect());
+            audit.record(
+                &scopes,
+                match stored {
+                    Ok(ref t) => AuditEventKind::StorageRead { found: t.is_some() },
+                    Err(ref e) => AuditEventKind::StorageFailed {
+                        write: false,
+                        error: e.to_string(),
+                    },
+                },
+            );
+            let stored = match stored {
+                // Without a refresh token, a new token can only be obtained from the flow.
+                Ok(Some(ref t)) if force && t.refresh_token.is_none() => Ok(None),
+                stored => stored,
+            };
+            match stored {
                 Ok(Some(t)) => {
-                    if !t.expired() {
-                        return Box::new(Ok(future::Loop::Break(t)).into_future());
+                    if !t.expires_within(margin) && !force {
+                        let check = consent_check.clone().map(|check| {
+                            let (store, scopes) = (store.clone(), scopes.clone());
+                            let (client, appsecret) = (client.clone(), appsecret.clone());
+                            let mut delegate = delegate.clone();
+                            let access_token = t.access_token.clone();
+                            move || {
+                                let mut executor = tokio::executor::DefaultExecutor::current();
+                                if executor.status().is_err() || !check.start(scope_key) {
+                                    return;
+                                }
+                                let checked = check
+                                    .revoked(client, &appsecret, &access_token)
+                                    .then(move |revoked| {
+                                        match revoked {
+                                            Ok(true) => {
+                                                warn!("The token for {:?} was revoked", scopes);
+                                                let _ =
+                                                    store.lock().unwrap().invalidate(&access_token);
+                                                delegate.reauth_required(&scopes);
+                                            }
+                                            Ok(false) => {}
+                                            Err(e) => warn!(
+                                                "Failed to check the token for {:?}: {}",
+                                                scopes, e
+                                            ),
+                                        }
+                                        Ok(())
+                                    });
+                                let _ = executor.spawn(Box::new(checked));
+                            }
+                        });
+                        let info = info(t, TokenSource::Storage, stats.last_refresh(scope_key));
+                        return Box::new(future::lazy(move || {
+                            // Checked once polled, on the executor of the caller.
+                            if let Some(check) = check {
+                                check();
+                            }
+                            Ok(future::Loop::Break(info))
+                        }));
+                    }
+                    // Rather than running the flow, which may involve the user, the caller
+                    // decides whether to ask for a new authorization.
+                    if t.refresh_token.is_none() {
+                        return Box::new(Err(RequestError::NoRefreshTokenAvailable).into_future());
+                    }
+                    let stale = latency_budget.filter(|_| !force && !t.expired()).map(|b| {
+                        let last_refresh = stats.last_refresh(scope_key);
+                        (b, info(t.clone(), TokenSource::Storage, last_refresh))
+                    });
+                    // Another request is refreshing the token already; use its token once it is
+                    // stored, or the stored one meanwhile if it is within the latency budget.
+                    let refreshing = match in_flight.enter(scope_key) {
+                        Ok(refreshing) => refreshing,
+                        Err(refreshed) => match stale {
+                            Some((_, stale)) => {
+                                return Box::new(Ok(future::Loop::Break(stale)).into_future())
+                            }
+                            None => {
+                                return Box::new(refreshed.then(|_| Ok(future::Loop::Continue(()))))
+                            }
+                        },
+                    };
+                    // Another process sharing the storage may have refreshed the token already,
+                    // or be refreshing it now. If the storage can't tell, the token is refreshed
+                    // regardless.
+                    let mut finished = None;
+                    if !force {
+                        let started = store.lock().unwrap().refresh_started(
+                            scope_key,
+                            &scopes.iter().map(|s| s.as_str()).collect(),
+                        );
+                        match started {
+                            Ok(RefreshStart::Busy) => {
+                                return Box::new(
+                                    tokio_timer::sleep(REFRESH_LOCK_POLL_INTERVAL)
+                                        .then(|_| Ok(future::Loop::Continue(()))),
+                                )
+                            }
+                            Ok(RefreshStart::Ready(current)) => {
+                                let guard = RefreshGuard {
+                                    store: store.clone(),
+                                    scope_key,
+                                    scopes: scopes.clone(),
+                                };
+                                if let Some(current) = current {
+                                    if !current.expires_within(margin) {
+                                        // Refreshed by another process.
+                                        let info = info(current, TokenSource::Storage, None);
+                                        return Box::new(
+                                            Ok(future::Loop::Break(info)).into_future(),
+                                        );
+                                    }
+                                }
+                                finished = Some(guard);
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                    if let Some(Err(e)) = budget.as_ref().map(RetryBudget::admit) {
+                        return Box::new(Err(e).into_future());
                     }
                     // Implement refresh flow.
                     let refresh_token = t.refresh_token.clone();
                     let mut delegate = delegate.clone();
                     let store = store.clone();
                     let scopes = scopes.clone();
-                    let refresh_fut = RefreshFlow::refresh_token(
-                        client.clone(),
-                        appsecret.clone(),
-                        refresh_token.unwrap(),
-                    )
-                        .and_then(move |rr| -> Box<dyn Future<Item=future::Loop<Token, ()>, Error=RequestError> + Send> {
-                            match rr {
-                                RefreshResult::Error(ref e) => {
-                                    delegate.token_refresh_failed(
-                                        format!("{}", e.description().to_string()),
-                                        &Some("the request has likely timed out".to_string()),
-                                        );
-                                    Box::new(Err(RequestError::Refresh(rr)).into_future())
-                                }
-                                RefreshResult::RefreshError(ref s, ref ss) => {
-                                    delegate.token_refresh_failed(
-                                        format!("{} {}", s, ss.clone().map(|s| format!("({})", s)).unwrap_or("".to_string())),
-                                        &Some("the refresh token is likely invalid and your authorization has been revoked".to_string()),
-                                        );
-                                    Box::new(Err(RequestError::Refresh(rr)).into_future())
+                    let stats = stats.clone();
+                    let audit = audit.clone();
+                    let info = info.clone();
+                    let (failed_budget, budget) = (budget.clone(), budget.clone());
+                    let (client, appsecret, parser) =
+                        (client.clone(), appsecret.clone(), parser.clone());
+                    let (refresh_retries, retry_budget) = (refresh_retries.clone(), budget.clone());
+                    let refresh_token = refresh_token.unwrap();
+                    // Retried within the refresh, so that the requests waiting for it keep
+                    // waiting rather than refreshing the token as well.
+                    let refresh_fut = future::loop_fn(
+                        (0, Duration::from_secs(0)),
+                        move |(retried, previous)| {
+                            let (refresh_retries, budget) = (refresh_retries.clone(), retry_budget.clone());
+                            RefreshFlow::refresh_token_with_parser(
+                                client.clone(),
+                                appsecret.clone(),
+                                refresh_token.clone(),
+                                parser.clone(),
+                            )
+                            .and_then(move |rr| -> Box<dyn Future<Item = future::Loop<RefreshResult, (u32, Duration)>, Error = RequestError> + Send> {
+                                if let (RefreshResult::Error(ref e), Some((retries, ref backoff))) = (&rr, &refresh_retries) {
+                                    if retried < *retries {
+                                        let delay = backoff.delay(retried + 1, previous);
+                                        warn!("Refreshing the token failed, retrying in {:?}: {}", delay, e);
+                                        if let Some(ref budget) = budget {
+                                            budget.failed();
+                                        }
+                                        return Box::new(tokio_timer::sleep(delay).then(move |_| {
+                                            Ok(future::Loop::Continue((retried + 1, delay)))
+                                        }));
+                                    }
                                 }
+                                Box::new(Ok(future::Loop::Break(rr)).into_future())
+                            })
+                        },
+                    )
+                        .map_err(move |e| {
+                            // E.g. an error page instead of a token response.
+                            if let Some(ref budget) = failed_budget {
+                                budget.failed();
+                            }
+                            e
+                        })
+                        .and_then(move |rr| -> Box<dyn Future<Item=future::Loop<TokenInfo, ()>, Error=RequestError> + Send> {
+                            let (kind, message, hint) = match rr {
+                                RefreshResult::Error(ref e) => (
+                                    RefreshFailureKind::Transport,
+                                    format!("{}", e.description().to_string()),
+                                    "the request has likely timed out",
+                                ),
+                                RefreshResult::RefreshError(ref e) => (
+                                    RefreshFailureKind::Rejected,
+                                    format!("{} {}", e.error, e.error_description.clone().map(|s| format!("({})", s)).unwrap_or("".to_string())),
+                                    "the refresh token is likely invalid and your authorization has been revoked",
+                                ),
+                                RefreshResult::ReauthRequired(ref uri) => (
+                                    RefreshFailureKind::ReauthRequired,
+                                    format!("reauthentication required{}", uri.clone().map(|u| format!(" (see {})", u)).unwrap_or("".to_string())),
+                                    "the provider requires you to sign in again before issuing new tokens",
+                                ),
                                 RefreshResult::Success(t) => {
-                                    if let Err(e) = store.lock().unwrap().set(scope_key, &scopes.iter().map(|s| s.as_str()).collect(), Some(t.clone())) {
+                                    if let Some(ref budget) = budget {
+                                        budget.succeeded();
+                                    }
+                                    stats.refreshed(scope_key, &scopes);
+                                    audit.record(&scopes, AuditEventKind::TokenRefreshed);
+                                    return if let Err(e) = store.lock().unwrap().set(scope_key, &scopes.iter().map(|s| s.as_str()).collect(), Some(t.clone())) {
+                                        audit.record(&scopes, AuditEventKind::StorageFailed { write: true, error: e.to_string() });
                                         match delegate.token_storage_failure(true, &e) {
-                                            Retry::Skip => Box::new(Ok(future::Loop::Break(t)).into_future()),
+                                            Retry::Skip => Box::new(Ok(future::Loop::Break(info(t, TokenSource::Refresh, Some(time::now())))).into_future()),
                                             Retry::Abort => Box::new(Err(RequestError::Cache(Box::new(e))).into_future()),
                                             Retry::After(d) => Box::new(
                                                 tokio_timer::sleep(d)
@@ -266,36 +765,111 @@ impl<
                                                 )
                                                 as Box<
                                                 dyn Future<
-                                                Item = future::Loop<Token, ()>,
+                                                Item = future::Loop<TokenInfo, ()>,
                                                 Error = RequestError> + Send>,
                                         }
                                     } else {
-                                        Box::new(Ok(future::Loop::Break(t)).into_future())
+                                        audit.record(&scopes, AuditEventKind::StorageWritten);
+                                        Box::new(Ok(future::Loop::Break(info(t, TokenSource::Refresh, Some(time::now())))).into_future())
                                     }
-                                },
+                                }
+                            };
+                            if let Some(ref budget) = budget {
+                                budget.failed();
+                            }
+                            delegate.token_refresh_failed(&message, &Some(hint.to_string()));
+                            if kind != RefreshFailureKind::Transport {
+                                delegate.reauth_required(&scopes);
                             }
+                            let failure = RefreshFailure::new(kind, message);
+                            stats.failed(scope_key, &scopes, &failure);
+                            audit.record(&scopes, AuditEventKind::RefreshFailed(kind));
+                            // The refresh error is more relevant to the caller than a failure to
+                            // record it.
+                            let _ = store.lock().unwrap().record_refresh_failure(
+                                scope_key,
+                                &scopes.iter().map(|s| s.as_str()).collect(),
+                                failure,
+                            );
+                            Box::new(Err(RequestError::Refresh(rr)).into_future())
+                        })
+                        .then(move |r| {
+                            drop(finished);
+                            drop(refreshing);
+                            r
                         });
-                    Box::new(refresh_fut)
+                    match stale {
+                        Some((latency_budget, stale)) => {
+                            Box::new(within_latency_budget(refresh_fut, latency_budget, stale))
+                        }
+                        None => Box::new(refresh_fut),
+                    }
                 }
                 Ok(None) => {
+                    // Another request is obtaining the token already.
+                    let obtaining = match in_flight.enter(scope_key) {
+                        Ok(obtaining) => obtaining,
+                        Err(obtained) => {
+                            return Box::new(obtained.then(|_| Ok(future::Loop::Continue(()))))
+                        }
+                    };
+                    if let Some(Err(e)) = budget.as_ref().map(RetryBudget::admit) {
+                        return Box::new(Err(e).into_future());
+                    }
+                    let (failed_budget, budget) = (budget.clone(), budget.clone());
                     let store = store.clone();
                     let scopes = scopes.clone();
                     let mut delegate = delegate.clone();
+                    let stats = stats.clone();
+                    let audit = audit.clone();
+                    let info = info.clone();
+                    audit.record(&scopes, AuditEventKind::FlowStarted);
+                    let failed_audit = audit.clone();
+                    let failed_scopes = scopes.clone();
                     Box::new(
                         gettoken
                             .lock()
                             .unwrap()
                             .token(scopes.clone())
+                            .map_err(move |e| {
+                                if let Some(ref budget) = failed_budget {
+                                    budget.failed();
+                                }
+                                failed_audit.record(
+                                    &failed_scopes,
+                                    AuditEventKind::FlowFailed(e.to_string()),
+                                );
+                                e
+                            })
                             .and_then(move |t| {
+                                if let Some(ref budget) = budget {
+                                    budget.succeeded();
+                                }
+                                stats.obtained(scope_key, &scopes);
+                                let location = store.lock().unwrap().location();
+                                stats.signed_in(SignInOutcome::new(&t, &scopes, location));
+                                audit.record(&scopes, AuditEventKind::ConsentGranted);
                                 if let Err(e) = store.lock().unwrap().set(
                                     scope_key,
                                     &scopes.iter().map(|s| s.as_str()).collect(),
                                     Some(t.clone()),
                                 ) {
+                                    audit.record(
+                                        &scopes,
+                                        AuditEventKind::StorageFailed {
+                                            write: true,
+                                            error: e.to_string(),
+                                        },
+                                    );
                                     match delegate.token_storage_failure(true, &e) {
-                                        Retry::Skip => {
-                                            Box::new(Ok(future::Loop::Break(t)).into_future())
-                                        }
+                                        Retry::Skip => Box::new(
+                                            Ok(future::Loop::Break(info(
+                                                t,
+                                                TokenSource::Flow,
+                                                Some(time::now()),
+                                            )))
+                                            .into_future(),
+                                        ),
                                         Retry::Abort => Box::new(
                                             Err(RequestError::Cache(Box::new(e))).into_future(),
                                         ),
@@ -305,14 +879,26 @@ impl<
                                         )
                                             as Box<
                                                 dyn Future<
-                                                        Item = future::Loop<Token, ()>,
+                                                        Item = future::Loop<TokenInfo, ()>,
                                                         Error = RequestError,
                                                     > + Send,
                                             >,
                                     }
                                 } else {
-                                    Box::new(Ok(future::Loop::Break(t)).into_future())
+                                    audit.record(&scopes, AuditEventKind::StorageWritten);
+                                    Box::new(
+                                        Ok(future::Loop::Break(info(
+                                            t,
+                                            TokenSource::Flow,
+                                            Some(time::now()),
+                                        )))
+                                        .into_future(),
+                                    )
                                 }
+                            })
+                            .then(move |r| {
+                                drop(obtaining);
+                                r
                             }),
                     )
                 }
@@ -331,3 +917,1106 @@ impl<
         Box::new(future::loop_fn((), loopfn))
     }
 }
+
+/// How often a request asks the storage again whether it may refresh a token another process is
+/// refreshing.
+const REFRESH_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Calls `TokenStorage::refresh_finished()` when dropped, once the refresh is over however it
+/// ends, including when the request is abandoned.
+struct RefreshGuard<E: 'static + Error + Send + Sync> {
+    store: SharedStorage<E>,
+    scope_key: u64,
+    scopes: Vec<String>,
+}
+
+impl<E: 'static + Error + Send + Sync> Drop for RefreshGuard<E> {
+    fn drop(&mut self) {
+        if let Ok(mut store) = self.store.lock() {
+            let scopes = self.scopes.iter().map(|s| s.as_str()).collect();
+            let _ = store.refresh_finished(self.scope_key, &scopes);
+        }
+    }
+}
+
+/// The tokens being refreshed or obtained from the flow, by scope hash, so that concurrent
+/// requests for a token wait for the one obtaining it rather than obtaining it once more.
+#[derive(Default)]
+struct InFlight(Mutex<HashMap<u64, future::Shared<oneshot::Receiver<()>>>>);
+
+impl InFlight {
+    /// Returns a guard to hold while obtaining the token stored under `scope_key`, or if
+    /// another request holds it, a future resolving once that request finished.
+    fn enter(
+        self: &Arc<Self>,
+        scope_key: u64,
+    ) -> Result<InFlightGuard, future::Shared<oneshot::Receiver<()>>> {
+        let mut in_flight = self.0.lock().unwrap();
+        if let Some(finished) = in_flight.get(&scope_key) {
+            return Err(finished.clone());
+        }
+        let (done, finished) = oneshot::channel();
+        in_flight.insert(scope_key, finished.shared());
+        Ok(InFlightGuard {
+            in_flight: self.clone(),
+            scope_key,
+            _done: done,
+        })
+    }
+}
+
+/// Wakes the requests waiting in `InFlight::enter()` when dropped, also if the request holding
+/// it is dropped before it finished.
+struct InFlightGuard {
+    in_flight: Arc<InFlight>,
+    scope_key: u64,
+    _done: oneshot::Sender<()>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.0.lock().unwrap().remove(&self.scope_key);
+    }
+}
+
+/// Resolves to the result of `refresh` if it finishes within `budget`, or else to the `stale` token,
+/// leaving the refresh to complete on the current executor. Without an executor, the refresh is
+/// waited for.
+fn within_latency_budget<F>(
+    refresh: F,
+    budget: Duration,
+    stale: TokenInfo,
+) -> impl Future<Item = future::Loop<TokenInfo, ()>, Error = RequestError> + Send
+where
+    F: 'static + Future<Item = future::Loop<TokenInfo, ()>, Error = RequestError> + Send,
+{
+    let timer = tokio_timer::Delay::new(Instant::now() + budget);
+    refresh.select2(timer).then(
+        move |r| -> Box<
+            dyn Future<Item = future::Loop<TokenInfo, ()>, Error = RequestError> + Send,
+        > {
+            let refresh = match r {
+                Ok(future::Either::A((done, _))) => return Box::new(future::ok(done)),
+                Err(future::Either::A((e, _))) => return Box::new(future::err(e)),
+                Ok(future::Either::B((_, refresh))) | Err(future::Either::B((_, refresh))) => {
+                    refresh
+                }
+            };
+            let mut executor = tokio::executor::DefaultExecutor::current();
+            if executor.status().is_err() {
+                return Box::new(refresh);
+            }
+            warn!(
+                "Refreshing the token takes longer than {:?}; using the stored token meanwhile",
+                budget
+            );
+            // The outcome of the refresh is recorded along the way.
+            let _ = executor.spawn(Box::new(refresh.then(|_| Ok(()))));
+            Box::new(future::ok(future::Loop::Break(stale)))
+        },
+    )
+}
+
+impl<
+        GT: 'static + GetToken + Send,
+        S: 'static + TokenStorage + Send,
+        AD: 'static + AuthenticatorDelegate + Send,
+        C: 'static + hyper::client::connect::Connect + Clone + Send + Sync,
+    > GetToken for AuthenticatorImpl<GT, S, AD, C>
+{
+    /// Returns the API Key of the inner flow.
+    fn api_key(&self) -> Option<String> {
+        self.inner.lock().unwrap().api_key()
+    }
+    /// Returns the application secret of the inner flow.
+    fn application_secret(&self) -> ApplicationSecret {
+        self.inner.lock().unwrap().application_secret()
+    }
+
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        Box::new(
+            self.get_token(scopes, false, self.expiry_margin)
+                .map(|info| info.token),
+        )
+    }
+
+    fn token_info<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.get_token(scopes, false, self.expiry_margin)
+    }
+
+    /// Refreshes the token for `scopes` even if it hasn't expired. If there is no refresh token,
+    /// a new token is obtained from the flow.
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        Box::new(
+            self.get_token(scopes, true, self.expiry_margin)
+                .map(|info| info.token),
+        )
+    }
+
+    /// Refreshes the stored token for `scopes` if it expires within `duration`.
+    fn token_valid_for<I, T>(
+        &self,
+        duration: Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let margin = std::cmp::max(duration, self.expiry_margin);
+        Box::new(
+            self.get_token(scopes, false, margin)
+                .and_then(move |info| check_valid_for(info.token, duration)),
+        )
+    }
+
+    fn refresh_failures<I, T>(&self, scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let appsecret = self.current_secret()?;
+        let (scope_key, scopes) = self.token_key(&appsecret, scopes);
+        self.store
+            .lock()
+            .unwrap()
+            .refresh_failures(scope_key, &scopes.iter().map(|s| s.as_str()).collect())
+            .map_err(|e| RequestError::Cache(Box::new(e)))
+    }
+
+    /// Checks the flow, and that the secret storage, if any, yields a secret.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let mut report = ConfigReport::default();
+        if let Some(ref load) = self.secrets {
+            let loaded = load().map(|_| ()).map_err(|e| e.to_string());
+            report.check("application secret storage", loaded);
+        }
+        Box::new(self.inner.lock().unwrap().validate().map(move |flow| {
+            report.extend(flow);
+            report
+        }))
+    }
+
+    /// Returns what happened to the tokens handed out since the authenticator was built.
+    fn stats(&self) -> AuthenticatorStats {
+        self.stats.snapshot()
+    }
+
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        self.stats.last_sign_in()
+    }
+
+    /// Expires the stored token with `access_token`, which is then refreshed on the next call
+    /// to `token()`. Stored tokens without a refresh token are removed instead.
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        let invalidated = self
+            .store
+            .lock()
+            .unwrap()
+            .invalidate(access_token)
+            .map_err(|e| RequestError::Cache(Box::new(e)))?;
+        if invalidated {
+            self.audit.record(&[], AuditEventKind::TokenInvalidated);
+        }
+        Ok(invalidated)
+    }
+}
+
+/// A token source restricted to a subset of scopes, as returned by `GetToken::scoped()`.
+///
+/// Requests for scopes outside of the subset fail with `RequestError::InvalidScope`, without
+/// contacting the provider.
+#[derive(Clone, Debug)]
+pub struct ScopedAuthenticator<G> {
+    inner: G,
+    scopes: Vec<String>,
+}
+
+impl<G: GetToken> ScopedAuthenticator<G> {
+    pub fn new<I, T>(inner: G, scopes: I) -> ScopedAuthenticator<G>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        ScopedAuthenticator {
+            inner,
+            scopes: scopes.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// The scopes tokens may be requested for.
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    fn check_scopes<I, T>(&self, scopes: I) -> Result<Vec<String>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        match scopes.iter().find(|s| !self.scopes.contains(s)) {
+            Some(scope) => Err(RequestError::InvalidScope(format!(
+                "scope {} is not available to this token source",
+                scope
+            ))),
+            None => Ok(scopes),
+        }
+    }
+
+    /// Returns a token for all of this handle's scopes.
+    pub fn token_for_all_scopes(
+        &self,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.inner.token(self.scopes.iter().cloned())
+    }
+}
+
+impl<G: GetToken> GetToken for ScopedAuthenticator<G> {
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        match self.check_scopes(scopes) {
+            Ok(scopes) => self.inner.token(scopes),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn token_info<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        match self.check_scopes(scopes) {
+            Ok(scopes) => self.inner.token_info(scopes),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        match self.check_scopes(scopes) {
+            Ok(scopes) => self.inner.force_refresh(scopes),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn token_valid_for<I, T>(
+        &self,
+        duration: Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        match self.check_scopes(scopes) {
+            Ok(scopes) => self.inner.token_valid_for(duration, scopes),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn api_key(&self) -> Option<String> {
+        self.inner.api_key()
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        self.inner.application_secret()
+    }
+
+    /// ID tokens aren't restricted to the scopes.
+    fn id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.inner.id_token(audience)
+    }
+
+    fn refresh_failures<I, T>(&self, scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.inner.refresh_failures(scopes)
+    }
+
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        self.inner.validate()
+    }
+
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.inner.invalidate(access_token)
+    }
+
+    fn stats(&self) -> AuthenticatorStats {
+        self.inner.stats()
+    }
+
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        self.inner.last_sign_in()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditEvent;
+    use crate::consent::ConsentEndpoint;
+    #[cfg(feature = "device")]
+    use crate::device::DeviceFlow;
+    use crate::helper::parse_application_secret;
+    use crate::types::tests::SECRET;
+    use crate::types::TokenProvider;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[cfg(feature = "device")]
+    #[test]
+    fn test_scoped_authenticator() {
+        let secret = parse_application_secret(SECRET).unwrap();
+        let auth = Arc::new(Authenticator::new(DeviceFlow::new(secret)).build().unwrap());
+        let drive = auth.clone().scoped(vec!["drive", "drive.file"]);
+        let mail = (&*auth).scoped(vec!["gmail"]);
+        assert_send_sync(&drive);
+        assert_eq!(&["gmail".to_string()], mail.scopes());
+        assert_eq!(
+            auth.application_secret().client_id,
+            drive.application_secret().client_id
+        );
+
+        match drive.token(vec!["drive.file", "gmail"]).wait() {
+            Err(RequestError::InvalidScope(msg)) => assert!(msg.contains("gmail")),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    /// Returns a new token with `refresh_token`, expiring after `expires_in` seconds, on every
+    /// call.
+    #[derive(Clone)]
+    struct FixedFlow {
+        secret: ApplicationSecret,
+        calls: Arc<AtomicUsize>,
+        refresh_token: Option<String>,
+        expires_in: i64,
+    }
+
+    impl<C> AuthFlow<C> for FixedFlow {
+        type TokenGetter = FixedFlow;
+
+        fn build_token_getter(self, _: hyper::Client<C>) -> FixedFlow {
+            self
+        }
+    }
+
+    impl GetToken for FixedFlow {
+        fn token<I, T>(&self, _: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        where
+            T: Into<String>,
+            I: IntoIterator<Item = T>,
+        {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let token = Token::new(
+                "flow-token".to_string(),
+                "Bearer".to_string(),
+                self.refresh_token.clone(),
+                Some(self.expires_in),
+            );
+            Box::new(future::ok(token))
+        }
+
+        fn api_key(&self) -> Option<String> {
+            None
+        }
+
+        fn application_secret(&self) -> ApplicationSecret {
+            self.secret.clone()
+        }
+    }
+
+    #[cfg(feature = "device")]
+    #[test]
+    fn test_scope_policy() {
+        let secret = parse_application_secret(SECRET).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let auth = Authenticator::new(DeviceFlow::new(secret))
+            .scope_policy(ScopePolicy::new().deny(vec!["https://www.googleapis.com/auth/cloud*"]))
+            .audit_sink(move |e: &AuditEvent| recorded.lock().unwrap().push(e.kind.clone()))
+            .build()
+            .unwrap();
+
+        // Refused without contacting the device authorization endpoint.
+        match auth
+            .token(vec![
+                "openid",
+                "https://www.googleapis.com/auth/cloud-platform",
+            ])
+            .wait()
+        {
+            Err(RequestError::PolicyViolation(msg)) => assert!(msg.contains("cloud-platform")),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let events = events.lock().unwrap();
+        match &events[..] {
+            [AuditEventKind::PolicyViolation(_)] => {}
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+
+    #[test]
+    fn test_token_info() {
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        secret.token_uri = format!("{}/token_info/token", mockito::server_url());
+        let auth = Authenticator::new(FixedFlow {
+            secret,
+            calls: Arc::new(AtomicUsize::new(0)),
+            refresh_token: Some("refresh-token".to_string()),
+            expires_in: 0,
+        })
+        .build()
+        .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let _m = mockito::mock("POST", "/token_info/token")
+            .with_body(r#"{"access_token": "refreshed-token", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+
+        let info = rt.block_on(auth.token_info(vec!["drive"])).unwrap();
+        assert_eq!(
+            ("flow-token", TokenSource::Flow, None),
+            (
+                info.token.access_token.as_str(),
+                info.obtained_via,
+                info.storage.clone()
+            )
+        );
+        assert!(info.age().unwrap() < Duration::from_secs(5));
+        // The token expired right away.
+        let info = rt.block_on(auth.token_info(vec!["drive"])).unwrap();
+        assert_eq!(TokenSource::Refresh, info.obtained_via);
+        let obtained_at = info.obtained_at();
+        let info = rt.block_on(auth.token_info(vec!["drive"])).unwrap();
+        assert_eq!("refreshed-token", info.token.access_token);
+        assert_eq!(
+            (TokenSource::Storage, obtained_at),
+            (info.obtained_via, info.obtained_at())
+        );
+        // References, `Arc`s and `Box`es of the authenticator know as well.
+        let shared = Arc::new(auth);
+        let provider: Arc<dyn TokenProvider> = shared.clone();
+        let forwarded = vec![
+            <&_ as GetToken>::token_info(&&*shared, vec!["drive"]),
+            <Arc<_> as GetToken>::token_info(&shared, vec!["drive"]),
+            <Box<_> as GetToken>::token_info(&Box::new(shared.clone()), vec!["drive"]),
+            <dyn TokenProvider as GetToken>::token_info(&*provider, vec!["drive"]),
+        ];
+        for info in forwarded {
+            assert_eq!(
+                TokenSource::Storage,
+                rt.block_on(info).unwrap().obtained_via
+            );
+        }
+        let provider: &dyn TokenProvider = &*provider;
+        assert!(GetToken::last_sign_in(provider).is_some());
+        assert!(GetToken::refresh_failures(provider, vec!["drive"])
+            .unwrap()
+            .is_empty());
+        _m.assert();
+    }
+
+    #[test]
+    fn test_force_refresh() {
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        secret.token_uri = format!("{}/force_refresh/token", mockito::server_url());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let auth = Authenticator::new(FixedFlow {
+            secret,
+            calls: calls.clone(),
+            refresh_token: Some("refresh-token".to_string()),
+            expires_in: 3600,
+        })
+        .audit_sink(move |e: &AuditEvent| recorded.lock().unwrap().push(e.clone()))
+        .build()
+        .unwrap();
+        let mut rt = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .panic_handler(|e| std::panic::resume_unwind(e))
+            .build()
+            .unwrap();
+
+        let _m = mockito::mock("POST", "/force_refresh/token")
+            .match_body(mockito::Matcher::Regex(
+                "refresh_token=refresh-token&grant_type=refresh_token".to_string(),
+            ))
+            .with_body(r#"{"access_token": "refreshed-token", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(2)
+            .create();
+
+        assert_eq!(None, auth.last_sign_in());
+        let token = rt.block_on(auth.token(vec!["drive"])).unwrap();
+        assert_eq!("flow-token", token.access_token);
+        let sign_in = auth.last_sign_in().unwrap();
+        assert_eq!(token.expires_at(), sign_in.expires_at());
+        assert_eq!(
+            (vec!["drive".to_string()], None),
+            (sign_in.scopes, sign_in.storage)
+        );
+        let token = rt.block_on(auth.token(vec!["drive"])).unwrap();
+        assert_eq!("flow-token", token.access_token);
+        let token = rt.block_on(auth.force_refresh(vec!["drive"])).unwrap();
+        assert_eq!("refreshed-token", token.access_token);
+        // The refreshed token replaces the cached one.
+        let token = rt.block_on(auth.token(vec!["drive"])).unwrap();
+        assert_eq!("refreshed-token", token.access_token);
+        // An invalidated token is refreshed on the next request.
+        assert!(!auth.invalidate("flow-token").unwrap());
+        assert!(auth.invalidate("refreshed-token").unwrap());
+        rt.block_on(auth.token(vec!["drive"])).unwrap();
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        let stats = auth.stats();
+        assert_eq!(1, stats.credentials.len());
+        let drive = &stats.credentials[0];
+        assert_eq!(
+            (1, 2, 0),
+            (
+                drive.obtained_count,
+                drive.refresh_count,
+                drive.failed_refresh_count
+            )
+        );
+        _m.assert();
+
+        let events = events.lock().unwrap();
+        assert_eq!(vec!["drive".to_string()], events[0].scopes);
+        assert!(events[0].client_id.ends_with(".apps.googleusercontent.com"));
+        let read = |found| AuditEventKind::StorageRead { found };
+        assert_eq!(
+            vec![
+                read(false),
+                AuditEventKind::FlowStarted,
+                AuditEventKind::ConsentGranted,
+                AuditEventKind::StorageWritten,
+                read(true),
+                read(true),
+                AuditEventKind::TokenRefreshed,
+                AuditEventKind::StorageWritten,
+                read(true),
+                AuditEventKind::TokenInvalidated,
+                read(true),
+                AuditEventKind::TokenRefreshed,
+                AuditEventKind::StorageWritten,
+            ],
+            events.iter().map(|e| e.kind.clone()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_no_refresh_token_available() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let auth = Authenticator::new(FixedFlow {
+            secret: parse_application_secret(SECRET).unwrap(),
+            calls: calls.clone(),
+            refresh_token: None,
+            expires_in: 0,
+        })
+        .build()
+        .unwrap();
+
+        assert_eq!(
+            "flow-token",
+            auth.token(vec!["drive"]).wait().unwrap().access_token
+        );
+        // The token expired, and no refresh is attempted.
+        match auth.token(vec!["drive"]).wait() {
+            Err(RequestError::NoRefreshTokenAvailable) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        // A forced refresh asks the flow again.
+        auth.force_refresh(vec!["drive"]).wait().unwrap();
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        secret.token_uri = format!("{}/retry_budget/token", mockito::server_url());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let budget = RetryBudget::new(2, Duration::from_secs(3600));
+        let auth = Authenticator::new(FixedFlow {
+            secret,
+            calls: calls.clone(),
+            refresh_token: Some("revoked".to_string()),
+            expires_in: 0,
+        })
+        .retry_budget(budget.clone())
+        .build()
+        .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(auth.token(vec!["drive"])).unwrap();
+
+        let _m = mockito::mock("POST", "/retry_budget/token")
+            .with_status(400)
+            .with_body(r#"{"error": "invalid_grant", "error_description": "Token has been expired or revoked."}"#)
+            .expect(2)
+            .create();
+        for _ in 0..2 {
+            match rt.block_on(auth.token(vec!["drive"])) {
+                Err(RequestError::Refresh(RefreshResult::RefreshError(_))) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+        // The provider isn't contacted anymore, neither to refresh nor to run the flow.
+        assert!(budget.is_open());
+        match rt.block_on(auth.token(vec!["drive"])) {
+            Err(RequestError::CircuitOpen(d)) => assert!(d > Duration::from_secs(3500)),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match rt.block_on(auth.token(vec!["gmail"])) {
+            Err(RequestError::CircuitOpen(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        _m.assert();
+    }
+
+    #[test]
+    fn test_refresh_retries() {
+        use crate::backoff::FixedBackoff;
+        use crate::transport::tests::{FakeConnector, FakeReply};
+
+        let connector = FakeConnector::new(vec![
+            FakeReply::Refused,
+            FakeReply::Refused,
+            FakeReply::Json(
+                200,
+                r#"{"access_token": "refreshed", "token_type": "Bearer"}"#,
+            ),
+            FakeReply::Refused,
+            FakeReply::Refused,
+            FakeReply::Refused,
+        ]);
+        let auth = Authenticator::new(FixedFlow {
+            secret: parse_application_secret(SECRET).unwrap(),
+            calls: Arc::new(AtomicUsize::new(0)),
+            refresh_token: Some("refresh".to_string()),
+            expires_in: 0,
+        })
+        .hyper_client(connector.client())
+        .refresh_retries(2, FixedBackoff(Duration::from_millis(10)))
+        .build()
+        .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(auth.token(vec!["drive"])).unwrap();
+
+        let token = rt.block_on(auth.token(vec!["drive"])).unwrap();
+        assert_eq!("refreshed", token.access_token);
+        // Each request retries twice before failing.
+        auth.invalidate("refreshed").unwrap();
+        match rt.block_on(auth.token(vec!["drive"])) {
+            Err(RequestError::Refresh(RefreshResult::Error(_))) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(0, connector.remaining());
+    }
+
+    /// A vault whose secret can be rotated or become unavailable.
+    struct Vault(Arc<Mutex<Option<ApplicationSecret>>>);
+
+    impl SecretStorage for Vault {
+        type Error = io::Error;
+
+        fn load(&self) -> io::Result<ApplicationSecret> {
+            self.0
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "vault sealed"))
+        }
+    }
+
+    #[test]
+    fn test_secret_storage() {
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        secret.token_uri = format!("{}/secret_storage/token", mockito::server_url());
+        let vault = Arc::new(Mutex::new(Some(ApplicationSecret {
+            client_secret: "vault-secret".to_string(),
+            ..secret.clone()
+        })));
+        secret.client_secret = String::new();
+        let auth = Authenticator::new(FixedFlow {
+            secret,
+            calls: Arc::new(AtomicUsize::new(0)),
+            refresh_token: Some("refresh-token".to_string()),
+            expires_in: 0,
+        })
+        .secret_storage(Vault(vault.clone()))
+        .build()
+        .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(auth.token(vec!["drive"])).unwrap();
+
+        let _m = mockito::mock("POST", "/secret_storage/token")
+            .match_body(mockito::Matcher::Regex(
+                "client_secret=vault-secret&refresh_token=refresh-token".to_string(),
+            ))
+            .with_body(
+                r#"{"access_token": "refreshed-token", "token_type": "Bearer", "expires_in": 0}"#,
+            )
+            .expect(1)
+            .create();
+        let token = rt.block_on(auth.token(vec!["drive"])).unwrap();
+        assert_eq!("refreshed-token", token.access_token);
+        _m.assert();
+
+        *vault.lock().unwrap() = None;
+        match rt.block_on(auth.token(vec!["drive"])) {
+            Err(RequestError::SecretStorage(e)) => assert_eq!("vault sealed", e.to_string()),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "disk-storage")]
+    #[test]
+    fn test_profiles() {
+        let path =
+            std::env::temp_dir().join(format!("yup-oauth2-profiles-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let authenticator = |profile: &str| {
+            Authenticator::new(FixedFlow {
+                secret: parse_application_secret(SECRET).unwrap(),
+                calls: calls.clone(),
+                refresh_token: Some("refresh-token".to_string()),
+                expires_in: 3600,
+            })
+            .persist_tokens_to_disk(&path)
+            .profile(profile)
+            .build()
+            .unwrap()
+        };
+
+        // Each profile signs in once, and finds its own token afterwards.
+        for profile in &["work", "personal", "work", ""] {
+            authenticator(profile).token(vec!["scope"]).wait().unwrap();
+        }
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+        let info = authenticator("personal")
+            .token_info(vec!["scope"])
+            .wait()
+            .unwrap();
+        assert_eq!(TokenSource::Storage, info.obtained_via);
+        assert!(info.storage.unwrap().ends_with("(profile personal)"));
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "disk-storage")]
+    #[test]
+    fn test_profile_shared_between_processes() {
+        let path = std::env::temp_dir().join(format!(
+            "yup-oauth2-shared-profile-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        secret.token_uri = format!("{}/shared_profile/token", mockito::server_url());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let authenticator = || {
+            Authenticator::new(FixedFlow {
+                secret: secret.clone(),
+                calls: calls.clone(),
+                refresh_token: Some("refresh-token".to_string()),
+                expires_in: 0,
+            })
+            .persist_tokens_to_disk(&path)
+            .profile("work")
+            .build()
+            .unwrap()
+        };
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _m = mockito::mock("POST", "/shared_profile/token")
+            .with_body(r#"{"access_token": "refreshed-token", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+        let first = authenticator();
+        rt.block_on(first.token(vec!["scope"])).unwrap();
+        // The second process reads the expired token, which the first one then refreshes.
+        let second = authenticator();
+        let token = rt.block_on(first.token(vec!["scope"])).unwrap();
+        assert_eq!("refreshed-token", token.access_token);
+        let info = rt.block_on(second.token_info(vec!["scope"])).unwrap();
+        assert_eq!("refreshed-token", info.token.access_token);
+        assert_eq!(TokenSource::Storage, info.obtained_via);
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        _m.assert();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "disk-storage")]
+    #[test]
+    fn test_refresh_lock() {
+        let dir = std::env::temp_dir();
+        let authenticator = |path: &Path, token_uri: String| {
+            let _ = std::fs::remove_file(path);
+            let mut secret = parse_application_secret(SECRET).unwrap();
+            secret.token_uri = token_uri;
+            let auth = Authenticator::new(FixedFlow {
+                secret,
+                calls: Arc::new(AtomicUsize::new(0)),
+                refresh_token: Some("refresh-token".to_string()),
+                expires_in: 0,
+            })
+            .persist_tokens_to_disk(path)
+            .build()
+            .unwrap();
+            auth.token(vec!["scope"]).wait().unwrap();
+            auth
+        };
+        let lock_file = |path: &Path| std::path::PathBuf::from(format!("{}.lock", path.display()));
+        let locked = |path: &Path| {
+            let file = std::fs::File::create(lock_file(path)).unwrap();
+            fs2::FileExt::try_lock_exclusive(&file).is_err()
+        };
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        // While another process refreshes the token, the executor keeps running other tasks.
+        let path = dir.join(format!(
+            "yup-oauth2-refresh-lock-{}.json",
+            std::process::id()
+        ));
+        let auth = authenticator(
+            &path,
+            format!("{}/refresh_lock/token", mockito::server_url()),
+        );
+        let _m = mockito::mock("POST", "/refresh_lock/token")
+            .with_body(r#"{"access_token": "refreshed-token", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+        let held = std::fs::File::create(lock_file(&path)).unwrap();
+        fs2::FileExt::lock_exclusive(&held).unwrap();
+        let other = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs2::FileExt::unlock(&held).unwrap();
+        });
+        let slept = tokio_timer::sleep(Duration::from_millis(20))
+            .map(|()| Instant::now())
+            .map_err(|e| RequestError::UserError(e.to_string()));
+        let refreshed = auth
+            .token(vec!["scope"])
+            .map(|token| (token, Instant::now()));
+        let (slept_at, (token, refreshed_at)) = rt.block_on(slept.join(refreshed)).unwrap();
+        other.join().unwrap();
+        assert_eq!("refreshed-token", token.access_token);
+        assert!(slept_at < refreshed_at);
+        assert!(!locked(&path));
+        _m.assert();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(lock_file(&path)).unwrap();
+
+        // An abandoned refresh releases the lock.
+        let path = dir.join(format!("yup-oauth2-abandoned-{}.json", std::process::id()));
+        let unresponsive = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let auth = authenticator(
+            &path,
+            format!("http://{}/token", unresponsive.local_addr().unwrap()),
+        );
+        let abandoned = auth
+            .token(vec!["scope"])
+            .map(|_| ())
+            .select2(tokio_timer::sleep(Duration::from_millis(50)).map_err(|_| ()))
+            .map(|_| ());
+        assert!(rt.block_on(abandoned).is_ok());
+        assert!(!locked(&path));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(lock_file(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_expiry_margin() {
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        secret.token_uri = format!("{}/expiry_margin/token", mockito::server_url());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let flow = FixedFlow {
+            secret,
+            calls: calls.clone(),
+            refresh_token: Some("refresh-token".to_string()),
+            expires_in: 600,
+        };
+        let default = Authenticator::new(flow.clone()).build().unwrap();
+        let cautious = Authenticator::new(flow)
+            .expiry_margin(Duration::from_secs(900))
+            .build()
+            .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _m = mockito::mock("POST", "/expiry_margin/token")
+            .with_body(r#"{"access_token": "refreshed-token", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+        rt.block_on(default.token(vec!["drive"])).unwrap();
+        let token = rt.block_on(default.token(vec!["drive"])).unwrap();
+        assert_eq!("flow-token", token.access_token);
+        // The token expires within the margin, so it is refreshed.
+        rt.block_on(cautious.token(vec!["drive"])).unwrap();
+        let token = rt.block_on(cautious.token(vec!["drive"])).unwrap();
+        assert_eq!("refreshed-token", token.access_token);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        _m.assert();
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let in_flight = Arc::new(InFlight::default());
+        let refreshing = in_flight.enter(1).ok().unwrap();
+        let refreshed = in_flight.enter(1).err().unwrap();
+        let other = in_flight.enter(2).ok().unwrap();
+        // Dropping the guard, e.g. with the request holding it, wakes the waiting requests.
+        drop(refreshing);
+        assert!(refreshed.wait().is_err());
+        assert!(in_flight.enter(1).is_ok());
+        assert!(in_flight.enter(2).is_err());
+        drop(other);
+        assert!(in_flight.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_consent_check() {
+        #[derive(Clone)]
+        struct Reauth(Arc<Mutex<Vec<Vec<String>>>>);
+
+        impl AuthenticatorDelegate for Reauth {
+            fn reauth_required(&mut self, scopes: &[String]) {
+                self.0.lock().unwrap().push(scopes.to_vec());
+            }
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let reauths = Arc::new(Mutex::new(Vec::new()));
+        let endpoint = ConsentEndpoint::TokenInfo(format!(
+            "{}/consent_check/tokeninfo",
+            mockito::server_url()
+        ));
+        let auth = Authenticator::new(FixedFlow {
+            secret: parse_application_secret(SECRET).unwrap(),
+            calls: calls.clone(),
+            refresh_token: None,
+            expires_in: 3600,
+        })
+        .delegate(Reauth(reauths.clone()))
+        .consent_check(ConsentCheck::new(endpoint, Duration::from_secs(3600)))
+        .build()
+        .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let _m = mockito::mock("POST", "/consent_check/tokeninfo")
+            .match_body("access_token=flow-token")
+            .with_status(400)
+            .with_body(r#"{"error": "invalid_token"}"#)
+            .expect(1)
+            .create();
+
+        // Tokens are checked once returned from storage, in the background.
+        rt.block_on(auth.token(vec!["drive"])).unwrap();
+        rt.block_on(auth.token(vec!["drive"])).unwrap();
+        let started = std::time::Instant::now();
+        while reauths.lock().unwrap().is_empty() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(vec![vec!["drive".to_string()]], *reauths.lock().unwrap());
+        // The revoked token was dropped, as it can't be refreshed.
+        rt.block_on(auth.token(vec!["drive"])).unwrap();
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        _m.assert();
+    }
+
+    #[test]
+    fn test_token_valid_for() {
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        secret.token_uri = format!("{}/token_valid_for/token", mockito::server_url());
+        let auth = Authenticator::new(FixedFlow {
+            secret,
+            calls: Arc::new(AtomicUsize::new(0)),
+            refresh_token: Some("refresh-token".to_string()),
+            expires_in: 600,
+        })
+        .build()
+        .unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let _m = mockito::mock("POST", "/token_valid_for/token")
+            .with_body(r#"{"access_token": "refreshed-token", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(2)
+            .create();
+
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        let token = rt.block_on(auth.token_valid_for(minutes(5), vec!["drive"]));
+        assert_eq!("flow-token", token.unwrap().access_token);
+        // The stored token expires too soon, so it is refreshed.
+        let token = rt.block_on(auth.token_valid_for(minutes(30), vec!["drive"]));
+        assert_eq!("refreshed-token", token.unwrap().access_token);
+        // Even a refreshed token doesn't last two hours.
+        match rt.block_on(auth.token_valid_for(minutes(120), vec!["drive"])) {
+            Err(RequestError::UserError(msg)) => assert!(msg.contains("7200s"), "{}", msg),
+            r => panic!("unexpected result {:?}", r),
+        }
+        _m.assert();
+    }
+
+    #[cfg(feature = "device")]
+    #[test]
+    fn test_authenticator_shared_between_threads() {
+        let secret = parse_application_secret(SECRET).unwrap();
+        let auth = Arc::new(Authenticator::new(DeviceFlow::new(secret)).build().unwrap());
+        assert_send_sync(&auth);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let auth = auth.clone();
+                thread::spawn(move || {
+                    assert_eq!(None, auth.api_key());
+                    auth.application_secret().client_id
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(
+                "14070749909-vgip2f1okm7bkvajhi9jugan6126io9v.apps.googleusercontent.com",
+                t.join().unwrap()
+            );
+        }
+    }
+}
@@ -1,12 +1,10 @@
-use hyper;
-
 use std::error::Error;
 use std::fmt;
 use std::io;
 
-use crate::types::{PollError, RequestError};
+use crate::time::{self, Timestamp};
+use crate::types::{PollError, RequestError, TransportError};
 
-use chrono::{DateTime, Local, Utc};
 use std::time::Duration;
 
 use futures::{future, prelude::*};
@@ -30,10 +28,13 @@ pub struct PollInformation {
     pub user_code: String,
     /// ... at the verification URL
     pub verification_url: String,
+    /// The verification URL including the `user_code`, if provided by the server. It may be
+    /// shown in addition to the `verification_url`, e.g. as a QR code.
+    pub verification_url_complete: Option<String>,
 
     /// The `user_code` expires at the given time
     /// It's the time the user has left to authenticate your application
-    pub expires_at: DateTime<Utc>,
+    pub expires_at: Timestamp,
     /// The interval in which we may poll for a status change
     /// The server responds with errors of we poll too fast.
     pub interval: Duration,
@@ -41,7 +42,11 @@ pub struct PollInformation {
 
 impl fmt::Display for PollInformation {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        writeln!(f, "Proceed with polling until {}", self.expires_at)
+        writeln!(
+            f,
+            "Proceed with polling until {}",
+            time::display(&self.expires_at)
+        )
     }
 }
 
@@ -49,7 +54,9 @@ impl fmt::Display for PollError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
             PollError::HttpError(ref err) => err.fmt(f),
-            PollError::Expired(ref date) => writeln!(f, "Authentication expired at {}", date),
+            PollError::Expired(ref date) => {
+                writeln!(f, "Authentication expired at {}", time::display(date))
+            }
             PollError::AccessDenied => "Access denied by user".fmt(f),
             PollError::TimedOut => "Timed out waiting for token".fmt(f),
             PollError::Other(ref s) => format!("Unknown server error: {}", s).fmt(f),
@@ -74,7 +81,7 @@ pub trait AuthenticatorDelegate: Clone {
     /// Called whenever there is an client, usually if there are network problems.
     ///
     /// Return retry information.
-    fn client_error(&mut self, _: &hyper::Error) -> Retry {
+    fn client_error(&mut self, _: &TransportError) -> Retry {
         Retry::Abort
     }
 
@@ -91,6 +98,12 @@ pub trait AuthenticatorDelegate: Clone {
     /// The server denied the attempt to obtain a request code
     fn request_failure(&mut self, _: RequestError) {}
 
+    /// Called if the user has to sign in again to obtain tokens for `scopes`: if refreshing
+    /// failed as the refresh token was refused or the provider requires reauthentication, or
+    /// earlier, if the authenticator's `ConsentCheck` found the token revoked, which invalidated
+    /// the stored token. Applications may ask the user to sign in at a convenient time.
+    fn reauth_required(&mut self, _scopes: &[String]) {}
+
     /// Called if we could not acquire a refresh token for a reason possibly specified
     /// by the server.
     /// This call is made for the delegate's information only.
@@ -112,9 +125,10 @@ pub trait AuthenticatorDelegate: Clone {
 /// certain cases.
 pub trait FlowDelegate: Clone {
     /// Called if the request code is expired. You will have to start over in this case.
-    /// This will be the last call the delegate receives.
-    /// Given `DateTime` is the expiration date
-    fn expired(&mut self, _: &DateTime<Utc>) {}
+    /// This will be the last call the delegate receives, unless the `DeviceFlow` renews
+    /// expired codes, see `user_code_renewed()`.
+    /// Given `Timestamp` is the expiration date
+    fn expired(&mut self, _: &Timestamp) {}
 
     /// Called if the user denied access. You would have to start over.
     /// This will be the last call the delegate receives.
@@ -146,9 +160,24 @@ pub trait FlowDelegate: Clone {
             pi.user_code, pi.verification_url
         );
         println!("Do not close this application until you either denied or granted access.");
+        println!("You have time until {}.", time::display(&pi.expires_at));
+    }
+
+    /// The `DeviceFlow` requested a new `user_code` after the previous one expired, see
+    /// `DeviceFlow::renew_expired_codes()`. The new code replaces the one presented before.
+    /// By default, it is presented using `present_user_code()`.
+    fn user_code_renewed(&mut self, pi: &PollInformation) {
+        self.present_user_code(pi)
+    }
+
+    /// Called by the InstalledFlow if the redirect listener serves HTTPS using a self-signed
+    /// certificate, before `present_user_url()`. The user may compare the SHA-256 `fingerprint`
+    /// to the one shown by the browser before accepting the certificate.
+    fn present_certificate_fingerprint(&mut self, fingerprint: &str) {
         println!(
-            "You have time until {}.",
-            pi.expires_at.with_timezone(&Local)
+            "The browser will warn about the certificate of this application's local server. \
+             Please verify its SHA-256 fingerprint before proceeding: {}",
+            fingerprint
         );
     }
 
@@ -164,7 +193,7 @@ pub trait FlowDelegate: Clone {
         if need_code {
             println!(
                 "Please direct your browser to {}, follow the instructions and enter the \
-                 code displayed here: ",
+                 code displayed, or the address you were redirected to, here: ",
                 url
             );
 
@@ -0,0 +1,249 @@
+//! Support for the Azure Active Directory (Microsoft identity platform) v2.0 endpoints.
+//!
+//! Azure AD endpoints are parameterized by a tenant, which is either a tenant ID, a domain name
+//! like `contoso.onmicrosoft.com`, or one of `common`, `organizations` and `consumers`.
+//!
+//! ```no_run
+//! # #[cfg(feature = "device")]
+//! # {
+//! use yup_oauth2::{AzureAd, Authenticator, GetToken};
+//!
+//! let azure = AzureAd::new("contoso.onmicrosoft.com");
+//! let auth = Authenticator::new(azure.device_flow("my-client-id"))
+//!     .token_response_parser(yup_oauth2::AzureTokenResponseParser::default())
+//!     .build()
+//!     .unwrap();
+//! let tok = auth.token(AzureAd::scopes(vec!["User.Read"]));
+//! # }
+//! ```
+use serde::de::{self, Deserialize, Deserializer};
+
+#[cfg(feature = "device")]
+use crate::authenticator_delegate::DefaultFlowDelegate;
+#[cfg(feature = "device")]
+use crate::device::{DeviceFlow, DeviceFlowProtocol};
+use crate::types::{ApplicationSecret, RequestError, ScopeSeparator, Token, TokenResponseParser};
+
+pub const AZURE_AUTH_URI_TEMPLATE: &str =
+    "https://login.microsoftonline.com/{tenant}/oauth2/v2.0/authorize";
+pub const AZURE_TOKEN_URI_TEMPLATE: &str =
+    "https://login.microsoftonline.com/{tenant}/oauth2/v2.0/token";
+pub const AZURE_DEVICE_CODE_URI_TEMPLATE: &str =
+    "https://login.microsoftonline.com/{tenant}/oauth2/v2.0/devicecode";
+
+/// The scope which has to be requested for Azure AD to issue a refresh token.
+pub const AZURE_OFFLINE_ACCESS_SCOPE: &str = "offline_access";
+
+/// Endpoints of an Azure AD tenant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AzureAd {
+    tenant: String,
+}
+
+impl AzureAd {
+    /// Use the endpoints of the given tenant.
+    pub fn new<S: Into<String>>(tenant: S) -> AzureAd {
+        AzureAd {
+            tenant: tenant.into(),
+        }
+    }
+
+    /// The multi-tenant endpoints, accepting both work and personal Microsoft accounts.
+    pub fn common() -> AzureAd {
+        AzureAd::new("common")
+    }
+
+    /// The multi-tenant endpoints, accepting only work and school accounts.
+    pub fn organizations() -> AzureAd {
+        AzureAd::new("organizations")
+    }
+
+    /// The endpoints accepting only personal Microsoft accounts.
+    pub fn consumers() -> AzureAd {
+        AzureAd::new("consumers")
+    }
+
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    /// Replaces `{tenant}` in `template` by this tenant.
+    pub fn expand(&self, template: &str) -> String {
+        template.replace("{tenant}", &self.tenant)
+    }
+
+    pub fn auth_uri(&self) -> String {
+        self.expand(AZURE_AUTH_URI_TEMPLATE)
+    }
+
+    pub fn token_uri(&self) -> String {
+        self.expand(AZURE_TOKEN_URI_TEMPLATE)
+    }
+
+    pub fn device_code_uri(&self) -> String {
+        self.expand(AZURE_DEVICE_CODE_URI_TEMPLATE)
+    }
+
+    /// Returns an `ApplicationSecret` for an application registered in this tenant. Public
+    /// clients (e.g. for the device flow) pass an empty `client_secret`.
+    pub fn application_secret<S: Into<String>, T: Into<String>>(
+        &self,
+        client_id: S,
+        client_secret: T,
+        redirect_uris: Vec<String>,
+    ) -> ApplicationSecret {
+        ApplicationSecret {
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            token_uri: self.token_uri(),
+            auth_uri: self.auth_uri(),
+            redirect_uris,
+            // The Microsoft identity platform takes space-separated scopes, like RFC 6749.
+            scope_separator: ScopeSeparator::Space,
+            ..Default::default()
+        }
+    }
+
+    /// Returns a `DeviceFlow` for the public client `client_id` using this tenant's endpoints.
+    #[cfg(feature = "device")]
+    pub fn device_flow<S: Into<String>>(&self, client_id: S) -> DeviceFlow<DefaultFlowDelegate> {
+        DeviceFlow::new(self.application_secret(client_id, "", vec![]))
+            .device_code_url(self.device_code_uri())
+            .protocol(DeviceFlowProtocol::Rfc8628)
+    }
+
+    /// Returns `scopes` with `offline_access` appended, unless it is already contained. Without
+    /// it, Azure AD does not return a refresh token.
+    pub fn scopes<I, T>(scopes: I) -> Vec<String>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let mut scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        if !scopes.iter().any(|s| s == AZURE_OFFLINE_ACCESS_SCOPE) {
+            scopes.push(AZURE_OFFLINE_ACCESS_SCOPE.to_string());
+        }
+        scopes
+    }
+}
+
+/// A `TokenResponseParser` for Azure AD token responses.
+///
+/// In addition to `expires_in`, Azure AD returns `ext_expires_in`, the extended lifetime during
+/// which the token is accepted by resources in case of an Azure AD outage. The v1.0 endpoints also
+/// return both as strings instead of numbers. Both encodings are accepted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AzureTokenResponseParser {
+    prefer_extended_expiry: bool,
+}
+
+impl AzureTokenResponseParser {
+    /// If set, the token's expiry is based on `ext_expires_in` instead of `expires_in`.
+    /// (default: false)
+    pub fn prefer_extended_expiry(self, prefer: bool) -> Self {
+        AzureTokenResponseParser {
+            prefer_extended_expiry: prefer,
+        }
+    }
+}
+
+impl TokenResponseParser for AzureTokenResponseParser {
+    fn parse_token_response(&self, body: &str) -> Result<Token, RequestError> {
+        #[derive(Deserialize)]
+        struct JsonToken {
+            access_token: String,
+            token_type: String,
+            refresh_token: Option<String>,
+            #[serde(default, deserialize_with = "int_or_string")]
+            expires_in: Option<i64>,
+            #[serde(default, deserialize_with = "int_or_string")]
+            ext_expires_in: Option<i64>,
+        }
+
+        let t: JsonToken = serde_json::from_str(body).map_err(RequestError::JSONError)?;
+        let expires_in = if self.prefer_extended_expiry {
+            t.ext_expires_in.or(t.expires_in)
+        } else {
+            t.expires_in.or(t.ext_expires_in)
+        };
+        Ok(Token::new(
+            t.access_token,
+            t.token_type,
+            t.refresh_token,
+            expires_in,
+        ))
+    }
+}
+
+fn int_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IntOrString {
+        Int(i64),
+        String(String),
+    }
+
+    match Option::<IntOrString>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(IntOrString::Int(i)) => Ok(Some(i)),
+        Some(IntOrString::String(s)) => s.parse().map(Some).map_err(de::Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_azure_endpoints() {
+        let azure = AzureAd::new("contoso.onmicrosoft.com");
+        assert_eq!(
+            "https://login.microsoftonline.com/contoso.onmicrosoft.com/oauth2/v2.0/token",
+            azure.token_uri()
+        );
+        let secret = AzureAd::common().application_secret("id", "", vec![]);
+        assert_eq!(
+            "https://login.microsoftonline.com/common/oauth2/v2.0/authorize",
+            secret.auth_uri
+        );
+        assert!(secret.client_secret.is_empty());
+        assert_eq!(ScopeSeparator::Space, secret.scope_separator);
+    }
+
+    #[test]
+    fn test_azure_scopes() {
+        assert_eq!(
+            vec!["User.Read", "offline_access"],
+            AzureAd::scopes(vec!["User.Read"])
+        );
+        assert_eq!(
+            vec!["offline_access", "User.Read"],
+            AzureAd::scopes(vec!["offline_access", "User.Read"])
+        );
+    }
+
+    #[test]
+    fn test_azure_token_response() {
+        let v2 = r#"{"token_type":"Bearer","scope":"User.Read","expires_in":3599,"ext_expires_in":7199,"access_token":"at","refresh_token":"rt"}"#;
+        let token = AzureTokenResponseParser::default()
+            .parse_token_response(v2)
+            .unwrap();
+        assert_eq!("at", token.access_token);
+        assert_eq!(Some("rt".to_string()), token.refresh_token);
+        assert!(token.expires_in().unwrap() <= Duration::from_secs(3599));
+        assert!(token.expires_in().unwrap() > Duration::from_secs(3500));
+
+        let v1 = r#"{"token_type":"Bearer","expires_in":"3599","ext_expires_in":"7199","access_token":"at"}"#;
+        let token = AzureTokenResponseParser::default()
+            .prefer_extended_expiry(true)
+            .parse_token_response(v1)
+            .unwrap();
+        assert!(token.expires_in().unwrap() > Duration::from_secs(7100));
+
+        let invalid = r#"{"token_type":"Bearer","expires_in":"soon","access_token":"at"}"#;
+        assert!(AzureTokenResponseParser::default()
+            .parse_token_response(invalid)
+            .is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Delays between the attempts of retried operations: refreshes failing with network errors,
+//! see `Authenticator::refresh_retries()`, device flow polls, see `DeviceFlow::poll_backoff()`,
+//! and the cooldowns of a `RetryBudget`, see `RetryBudget::with_cooldown()`.
+use std::cmp;
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Chooses how long to wait before an attempt of a retried operation. Implement it to tune the
+/// retries for a provider, e.g. for a client ID close to its rate limit.
+pub trait BackoffPolicy: Send + Sync {
+    /// The delay before the `attempt`th retry, counting from one, after waiting `previous`
+    /// before the previous one (zero before the first retry).
+    fn delay(&self, attempt: u32, previous: Duration) -> Duration;
+}
+
+/// The same delay before every retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedBackoff(pub Duration);
+
+impl BackoffPolicy for FixedBackoff {
+    fn delay(&self, _attempt: u32, _previous: Duration) -> Duration {
+        self.0
+    }
+}
+
+/// Delays multiplied by a factor on every retry, up to a maximum: `initial`, `initial * factor`,
+/// `initial * factor²`, ...
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max: Duration,
+    factor: u32,
+}
+
+impl ExponentialBackoff {
+    /// Wait `initial` before the first retry, doubling the delay up to `max`.
+    pub fn new(initial: Duration, max: Duration) -> ExponentialBackoff {
+        ExponentialBackoff {
+            initial,
+            max,
+            factor: 2,
+        }
+    }
+
+    /// Multiply the delay by `factor`, at least one, on every retry. (default: 2)
+    pub fn factor(self, factor: u32) -> ExponentialBackoff {
+        ExponentialBackoff {
+            factor: factor.max(1),
+            ..self
+        }
+    }
+}
+
+impl BackoffPolicy for ExponentialBackoff {
+    fn delay(&self, attempt: u32, _previous: Duration) -> Duration {
+        let growth = self
+            .factor
+            .checked_pow(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        cmp::min(
+            self.initial.checked_mul(growth).unwrap_or(self.max),
+            self.max,
+        )
+    }
+}
+
+/// Random delays between `base` and three times the previous delay, up to a maximum, as
+/// described in https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/.
+/// Clients retrying at the same time, e.g. after the provider failed for all of them, spread
+/// their retries rather than retrying in lockstep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecorrelatedJitter {
+    base: Duration,
+    max: Duration,
+}
+
+impl DecorrelatedJitter {
+    /// Wait at least `base`, and at most `max`.
+    pub fn new(base: Duration, max: Duration) -> DecorrelatedJitter {
+        DecorrelatedJitter { base, max }
+    }
+}
+
+impl BackoffPolicy for DecorrelatedJitter {
+    fn delay(&self, _attempt: u32, previous: Duration) -> Duration {
+        let low = self.base.as_millis() as u64;
+        let high = cmp::max(low, previous.as_millis() as u64 * 3);
+        let mut bytes = [0u8; 8];
+        // Without randomness, the delays only lose their jitter.
+        let _ = SystemRandom::new().fill(&mut bytes);
+        let jitter = u64::from_le_bytes(bytes) % (high - low + 1);
+        cmp::min(Duration::from_millis(low + jitter), self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let secs = Duration::from_secs;
+        let fixed = FixedBackoff(secs(5));
+        assert_eq!(secs(5), fixed.delay(1, Duration::from_secs(0)));
+        assert_eq!(secs(5), fixed.delay(10, secs(5)));
+
+        let exponential = ExponentialBackoff::new(secs(1), secs(60));
+        let delays: Vec<_> = (1..=8).map(|a| exponential.delay(a, secs(0))).collect();
+        let expected = vec![1, 2, 4, 8, 16, 32, 60, 60];
+        assert_eq!(expected.into_iter().map(secs).collect::<Vec<_>>(), delays);
+        assert_eq!(secs(60), exponential.delay(u32::MAX, secs(0)));
+        assert_eq!(secs(9), exponential.factor(3).delay(3, secs(0)));
+
+        let jitter = DecorrelatedJitter::new(secs(1), secs(30));
+        let mut previous = secs(0);
+        for attempt in 1..50 {
+            let delay = jitter.delay(attempt, previous);
+            assert!(delay >= secs(1) && delay <= cmp::max(secs(1), previous * 3));
+            assert!(delay <= secs(30));
+            previous = delay;
+        }
+    }
+}
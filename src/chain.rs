@@ -0,0 +1,395 @@
+//! A token source (`GetToken`) trying several token sources in order, like the credential
+//! provider chains of the AWS SDKs: e.g. a static token from the environment for local
+//! development, then the metadata server when running on Google Cloud, then a service account
+//! key file, and finally an interactive sign-in.
+//!
+//! The first source which hands out a token is remembered, and asked first from then on, so
+//! that e.g. the metadata server isn't tried again on every request of a developer's machine.
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ::log::{debug, log};
+use futures::{future, prelude::*};
+
+use crate::config_check::ConfigReport;
+use crate::stats::{AuthenticatorStats, SignInOutcome, TokenInfo};
+use crate::storage::RefreshFailure;
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token, TokenProvider};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = RequestError> + Send>;
+
+/// A named source of the chain, or why it couldn't be set up.
+#[derive(Clone)]
+struct Link {
+    name: String,
+    source: Result<Arc<dyn TokenProvider>, String>,
+}
+
+/// Tries its sources in the order they were added, handing out the token of the first one
+/// which succeeds. Requests only fail if all sources fail, with a `RequestError::UserError`
+/// listing the failure of every source.
+#[derive(Clone, Default)]
+pub struct ChainedTokenSource {
+    links: Arc<Vec<Link>>,
+    /// The index of the source which handed out the last token.
+    selected: Arc<Mutex<Option<usize>>>,
+}
+
+impl ChainedTokenSource {
+    /// An empty chain; add sources with `source()` and `try_source()`.
+    pub fn new() -> ChainedTokenSource {
+        ChainedTokenSource::default()
+    }
+
+    /// Try `source` after the sources added so far. `name`, like `metadata server`, identifies
+    /// the source in errors and in `selected()`.
+    pub fn source<S, G>(self, name: S, source: G) -> ChainedTokenSource
+    where
+        S: Into<String>,
+        G: 'static + GetToken + Send + Sync,
+    {
+        self.link(name.into(), Ok(Arc::new(source)))
+    }
+
+    /// Like `source()`, for a source whose setup may have failed, e.g. because the environment
+    /// variable of a `StaticTokenSource::from_env()` isn't set, or a key file doesn't exist.
+    /// Sources which failed to set up are skipped, and listed by `unavailable()`.
+    pub fn try_source<S, G, E>(self, name: S, source: Result<G, E>) -> ChainedTokenSource
+    where
+        S: Into<String>,
+        G: 'static + GetToken + Send + Sync,
+        E: fmt::Display,
+    {
+        let source = source
+            .map(|source| Arc::new(source) as Arc<dyn TokenProvider>)
+            .map_err(|e| e.to_string());
+        self.link(name.into(), source)
+    }
+
+    fn link(
+        self,
+        name: String,
+        source: Result<Arc<dyn TokenProvider>, String>,
+    ) -> ChainedTokenSource {
+        let mut links = (*self.links).clone();
+        links.push(Link { name, source });
+        ChainedTokenSource {
+            links: Arc::new(links),
+            selected: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The names of all sources, in the order they are tried.
+    pub fn names(&self) -> Vec<&str> {
+        self.links.iter().map(|l| l.name.as_str()).collect()
+    }
+
+    /// The sources which failed to set up, with the reason.
+    pub fn unavailable(&self) -> Vec<(&str, &str)> {
+        self.links
+            .iter()
+            .filter_map(|l| match l.source {
+                Err(ref e) => Some((l.name.as_str(), e.as_str())),
+                Ok(_) => None,
+            })
+            .collect()
+    }
+
+    /// The name of the source which handed out the last token, if any.
+    pub fn selected(&self) -> Option<&str> {
+        let selected = *self.selected.lock().unwrap();
+        selected.map(|i| self.links[i].name.as_str())
+    }
+
+    /// Forgets the selected source, so that the next request tries all sources in order again,
+    /// e.g. after the environment changed.
+    pub fn reset(&self) {
+        *self.selected.lock().unwrap() = None;
+    }
+
+    /// The indices of the sources to try: the selected one first, then the others in order.
+    fn order(&self) -> Vec<usize> {
+        let selected = *self.selected.lock().unwrap();
+        let available = (0..self.links.len()).filter(|&i| self.links[i].source.is_ok());
+        selected
+            .into_iter()
+            .chain(available.filter(|&i| Some(i) != selected))
+            .collect()
+    }
+
+    /// The source consulted for everything but tokens: the selected one, or else the first
+    /// available one.
+    fn primary(&self) -> Option<(&str, &Arc<dyn TokenProvider>)> {
+        self.order().first().and_then(|&i| {
+            let link = &self.links[i];
+            link.source.as_ref().ok().map(|s| (link.name.as_str(), s))
+        })
+    }
+
+    /// The source which handed out the last token, if any.
+    fn selected_source(&self) -> Option<&Arc<dyn TokenProvider>> {
+        let selected = *self.selected.lock().unwrap();
+        selected.and_then(|i| self.links[i].source.as_ref().ok())
+    }
+
+    /// Returns the result of the first source for which `attempt` succeeds, and selects it.
+    fn first_token<F, R>(&self, attempt: F) -> BoxFuture<R>
+    where
+        F: 'static + Fn(&dyn TokenProvider) -> BoxFuture<R> + Send,
+        R: 'static + Send,
+    {
+        let sources: Vec<_> = self
+            .order()
+            .into_iter()
+            .filter_map(|i| {
+                let link = &self.links[i];
+                link.source.clone().ok().map(|s| (i, link.name.clone(), s))
+            })
+            .collect();
+        let selected = self.selected.clone();
+        let failures: Vec<String> = self
+            .unavailable()
+            .into_iter()
+            .map(|(name, e)| format!("{}: {}", name, e))
+            .collect();
+        Box::new(future::loop_fn(
+            (sources.into_iter(), failures),
+            move |(mut rest, mut failures)| {
+                let (i, name, source) = match rest.next() {
+                    Some(next) => next,
+                    None => {
+                        return future::Either::A(future::err(RequestError::UserError(format!(
+                            "No token source of the chain succeeded: {}",
+                            failures.join("; ")
+                        ))))
+                    }
+                };
+                let selected = selected.clone();
+                future::Either::B(attempt(&*source).then(move |result| match result {
+                    Ok(token) => {
+                        *selected.lock().unwrap() = Some(i);
+                        Ok(future::Loop::Break(token))
+                    }
+                    Err(e) => {
+                        debug!("The token source {} failed: {}", name, e);
+                        failures.push(format!("{}: {}", name, e));
+                        Ok(future::Loop::Continue((rest, failures)))
+                    }
+                }))
+            },
+        ))
+    }
+}
+
+impl fmt::Debug for ChainedTokenSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChainedTokenSource")
+            .field("sources", &self.names())
+            .field("unavailable", &self.unavailable())
+            .field("selected", &self.selected())
+            .finish()
+    }
+}
+
+impl GetToken for ChainedTokenSource {
+    fn token<I, T>(&self, scopes: I) -> BoxFuture<Token>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        self.first_token(move |source| source.get_token(scopes.clone()))
+    }
+
+    fn force_refresh<I, T>(&self, scopes: I) -> BoxFuture<Token>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        self.first_token(move |source| source.get_fresh_token(scopes.clone()))
+    }
+
+    fn id_token(&self, audience: &str) -> BoxFuture<Token> {
+        let audience = audience.to_string();
+        self.first_token(move |source| source.get_id_token(&audience))
+    }
+
+    fn token_valid_for<I, T>(&self, duration: Duration, scopes: I) -> BoxFuture<Token>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        self.first_token(move |source| source.get_token_valid_for(duration, scopes.clone()))
+    }
+
+    fn token_info<I, T>(&self, scopes: I) -> BoxFuture<TokenInfo>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        self.first_token(move |source| source.get_token_info(scopes.clone()))
+    }
+
+    /// Forwards to the selected source, which handed out the token.
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        match self.selected_source() {
+            Some(source) => source.invalidate_token(access_token),
+            None => Ok(false),
+        }
+    }
+
+    /// Forwards to the selected source, which handed out the token.
+    fn refresh_failures<I, T>(&self, scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        match self.selected_source() {
+            Some(source) => {
+                source.get_refresh_failures(scopes.into_iter().map(Into::into).collect())
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Forwards to the selected source, which handed out the token.
+    fn stats(&self) -> AuthenticatorStats {
+        self.selected_source()
+            .map(|source| source.get_stats())
+            .unwrap_or_default()
+    }
+
+    /// Forwards to the selected source, which handed out the token.
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        self.selected_source()
+            .and_then(|source| source.get_last_sign_in())
+    }
+
+    /// Checks the selected source, or else the first available one, naming it in the subjects
+    /// of the checks.
+    fn validate(&self) -> BoxFuture<ConfigReport> {
+        let (name, source) = match self.primary() {
+            Some((name, source)) => (name.to_string(), source.clone()),
+            None => {
+                let mut report = ConfigReport::default();
+                let unavailable: Vec<String> = self
+                    .unavailable()
+                    .into_iter()
+                    .map(|(name, e)| format!("{}: {}", name, e))
+                    .collect();
+                report.check(
+                    "token source chain",
+                    Err(format!(
+                        "no source is available: {}",
+                        unavailable.join("; ")
+                    )),
+                );
+                return Box::new(future::ok(report));
+            }
+        };
+        Box::new(source.validate_config().map(move |mut report| {
+            for check in &mut report.checks {
+                check.subject = format!("{}: {}", name, check.subject);
+            }
+            report
+        }))
+    }
+
+    fn api_key(&self) -> Option<String> {
+        self.primary().and_then(|(_, source)| source.get_api_key())
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        self.primary()
+            .map(|(_, source)| source.get_application_secret())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_token::StaticTokenSource;
+    use crate::time;
+
+    #[test]
+    fn test_chained_token_source() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut expired = Token::new_bearer("ya29.expired", None).unwrap();
+        expired.set_expires_at(Some(time::from_secs(time::now() - 60)));
+        let chain = ChainedTokenSource::new()
+            .try_source(
+                "environment",
+                StaticTokenSource::from_env("YUP_OAUTH2_CHAIN_TEST_UNSET"),
+            )
+            .source("sidecar", StaticTokenSource::new(expired))
+            .source(
+                "pre-provisioned",
+                StaticTokenSource::bearer("ya29.provisioned").unwrap(),
+            );
+        assert_eq!(
+            vec!["environment", "sidecar", "pre-provisioned"],
+            chain.names()
+        );
+        assert_eq!("environment", chain.unavailable()[0].0);
+        assert_eq!(None, chain.selected());
+
+        let token = rt.block_on(chain.token(vec!["scope"])).unwrap();
+        assert_eq!("ya29.provisioned", token.access_token);
+        assert_eq!(Some("pre-provisioned"), chain.selected());
+        assert_eq!(vec![2, 1], chain.order());
+        let report = rt.block_on(chain.validate()).unwrap();
+        assert_eq!("pre-provisioned: static token", report.checks[0].subject);
+        chain.reset();
+        assert_eq!(vec![1, 2], chain.order());
+
+        let chain = ChainedTokenSource::new()
+            .source(
+                "sidecar",
+                StaticTokenSource::new(Token::new_bearer("x", None).unwrap()),
+            )
+            .source("none", StaticTokenSource::bearer("ya29.none").unwrap());
+        match rt.block_on(chain.id_token("https://example.com")) {
+            Err(RequestError::UserError(e)) => {
+                assert!(e.starts_with("No token source of the chain succeeded: sidecar: "));
+                assert!(e.contains("; none: "), "{}", e);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(None, chain.selected());
+
+        let empty = ChainedTokenSource::new().try_source(
+            "environment",
+            StaticTokenSource::from_env("YUP_OAUTH2_CHAIN_TEST_UNSET"),
+        );
+        assert!(!rt.block_on(empty.validate()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_chain_forwards_to_sources() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let short = Token::new_bearer_expiring_in("ya29.short", Duration::from_secs(600)).unwrap();
+        let long = Token::new_bearer_expiring_in("ya29.long", Duration::from_secs(7200)).unwrap();
+        let chain = ChainedTokenSource::new()
+            .source("short", StaticTokenSource::new(short))
+            .source("long", StaticTokenSource::new(long));
+        assert!(chain.refresh_failures(vec!["scope"]).unwrap().is_empty());
+
+        // The first source is asked for a token lasting an hour, not for any token.
+        let hour = Duration::from_secs(3600);
+        let token = rt
+            .block_on(chain.token_valid_for(hour, vec!["scope"]))
+            .unwrap();
+        assert_eq!("ya29.long", token.access_token);
+        assert_eq!(Some("long"), chain.selected());
+
+        let info = rt.block_on(chain.token_info(vec!["scope"])).unwrap();
+        assert_eq!("ya29.long", info.token.access_token);
+        assert_eq!(None, chain.last_sign_in());
+        assert_eq!(AuthenticatorStats::default(), chain.stats());
+    }
+}
@@ -0,0 +1,294 @@
+//! Ready-made `login`, `logout` and `status` subcommands for command line tools, keeping the
+//! user's tokens in a file which the tool's other commands use too.
+//!
+//! ```no_run
+//! # use futures::Future;
+//! # use yup_oauth2::{Authenticator, Cli, GetToken, InstalledFlow, InstalledFlowReturnMethod};
+//! # fn run(command: &str, secret: yup_oauth2::ApplicationSecret) {
+//! let cli = Cli::new(secret.clone(), "tokens.json");
+//! match command {
+//!     "login" => drop(cli.login(vec!["email"]).wait().unwrap()),
+//!     "logout" => drop(cli.logout().wait().unwrap()),
+//!     "status" => {
+//!         for status in cli.status().unwrap() {
+//!             println!("{}", status);
+//!         }
+//!     }
+//!     _ => {
+//!         // Other commands use the stored tokens, and sign in if needed.
+//!         let flow = InstalledFlow::new(secret, InstalledFlowReturnMethod::HTTPRedirectEphemeral);
+//!         let auth = Authenticator::new(flow)
+//!             .persist_tokens_to_disk("tokens.json")
+//!             .build()
+//!             .unwrap();
+//!         let token = auth.token(vec!["email"]).wait().unwrap();
+//!     }
+//! }
+//! # }
+//! ```
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use futures::{future, prelude::*};
+
+use crate::authenticator::Authenticator;
+use crate::authenticator_delegate::{DefaultFlowDelegate, FlowDelegate};
+use crate::device::{DeviceFlow, GOOGLE_DEVICE_CODE_URL};
+use crate::ephemeral::{self, GOOGLE_REVOCATION_URL};
+use crate::installed::{InstalledFlow, InstalledFlowReturnMethod};
+use crate::storage::{DiskTokenStorage, TokenKey, TokenStorage};
+use crate::time::{self, Timestamp};
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+
+/// How `Cli::login()` signs the user in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoginMethod {
+    /// The installed flow, receiving the authorization on a redirect listener. (default)
+    Browser,
+    /// The device flow, for hosts without a browser, like ones the user is logged in to via
+    /// SSH.
+    Device,
+}
+
+/// A stored token, as listed by `Cli::status()`.
+#[derive(Clone, Debug)]
+pub struct LoginStatus {
+    /// The scopes the token was obtained for.
+    pub scopes: Vec<String>,
+    /// When the access token expires, if it does.
+    pub expires_at: Option<Timestamp>,
+    /// Whether the access token expired, or expires within `DEFAULT_EXPIRY_MARGIN`.
+    pub expired: bool,
+    /// Whether there is a refresh token, to obtain access tokens without signing in again.
+    pub refreshable: bool,
+}
+
+impl LoginStatus {
+    fn new(scopes: &[String], token: &Token) -> LoginStatus {
+        LoginStatus {
+            scopes: scopes.to_vec(),
+            expires_at: token.expires_at(),
+            expired: token.expired(),
+            refreshable: token.refresh_token.is_some(),
+        }
+    }
+
+    /// Whether the token is usable, or can be refreshed.
+    pub fn signed_in(&self) -> bool {
+        self.refreshable || !self.expired
+    }
+}
+
+impl fmt::Display for LoginStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.scopes.join(" "))?;
+        match self.expires_at {
+            _ if !self.signed_in() => f.write_str("signed out, the access token expired"),
+            _ if self.expired => f.write_str("signed in, the access token will be refreshed"),
+            Some(ref at) => write!(
+                f,
+                "signed in, the access token expires at {}",
+                time::display(at)
+            ),
+            None => f.write_str("signed in, the access token doesn't expire"),
+        }
+    }
+}
+
+/// Signs the user of a command line tool in and out, keeping their tokens in a file, see the
+/// module documentation.
+pub struct Cli<FD = DefaultFlowDelegate> {
+    secret: ApplicationSecret,
+    token_cache: PathBuf,
+    method: LoginMethod,
+    delegate: FD,
+    device_code_url: String,
+    revocation_url: String,
+}
+
+impl Cli<DefaultFlowDelegate> {
+    /// Sign in to the application with `secret`, keeping the tokens in the file at
+    /// `token_cache`, as in `Authenticator::persist_tokens_to_disk()`.
+    pub fn new<P: Into<PathBuf>>(secret: ApplicationSecret, token_cache: P) -> Cli {
+        Cli {
+            secret,
+            token_cache: token_cache.into(),
+            method: LoginMethod::Browser,
+            delegate: DefaultFlowDelegate,
+            device_code_url: GOOGLE_DEVICE_CODE_URL.to_string(),
+            revocation_url: GOOGLE_REVOCATION_URL.to_string(),
+        }
+    }
+}
+
+impl<FD> Cli<FD>
+where
+    FD: 'static + FlowDelegate + Clone + Send,
+{
+    /// Sign in using `method`. (default: `LoginMethod::Browser`)
+    pub fn method(self, method: LoginMethod) -> Cli<FD> {
+        Cli { method, ..self }
+    }
+
+    /// Interact with the user through `delegate` while signing in.
+    /// (default: `DefaultFlowDelegate`)
+    pub fn delegate<NewFD>(self, delegate: NewFD) -> Cli<NewFD> {
+        Cli {
+            secret: self.secret,
+            token_cache: self.token_cache,
+            method: self.method,
+            delegate,
+            device_code_url: self.device_code_url,
+            revocation_url: self.revocation_url,
+        }
+    }
+
+    /// Start the device flow at `url`, e.g. `GITHUB_DEVICE_CODE_URL`.
+    /// (default: `GOOGLE_DEVICE_CODE_URL`)
+    pub fn device_code_url<S: Into<String>>(self, url: S) -> Cli<FD> {
+        Cli {
+            device_code_url: url.into(),
+            ..self
+        }
+    }
+
+    /// Revoke tokens at `url` when logging out. (default: `GOOGLE_REVOCATION_URL`)
+    pub fn revocation_url<S: Into<String>>(self, url: S) -> Cli<FD> {
+        Cli {
+            revocation_url: url.into(),
+            ..self
+        }
+    }
+
+    /// Signs the user in for `scopes`, and stores the token. The user signs in even if a token
+    /// for `scopes` was stored, e.g. to switch accounts; the stored token is replaced.
+    pub fn login<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let key = TokenKey::new(self.secret.client_id.as_str(), scopes);
+        let scopes = key.scopes().to_vec();
+        let forgotten = self.storage().and_then(|mut storage| {
+            storage.set(
+                key.scope_hash(),
+                &scopes.iter().map(String::as_str).collect(),
+                None,
+            )
+        });
+        let secret = self.secret.clone();
+        let delegate = self.delegate.clone();
+        let token = forgotten.and_then(|()| match self.method {
+            LoginMethod::Browser => {
+                let flow =
+                    InstalledFlow::new(secret, InstalledFlowReturnMethod::HTTPRedirectEphemeral);
+                Authenticator::new(flow.delegate(delegate))
+                    .persist_tokens_to_disk(&self.token_cache)
+                    .build()
+                    .map(|auth| auth.token(scopes))
+            }
+            LoginMethod::Device => {
+                let flow = DeviceFlow::new(secret).device_code_url(self.device_code_url.clone());
+                Authenticator::new(flow.delegate(delegate))
+                    .persist_tokens_to_disk(&self.token_cache)
+                    .build()
+                    .map(|auth| auth.token(scopes))
+            }
+        });
+        match token {
+            Ok(token) => token,
+            Err(e) => Box::new(future::err(RequestError::LowLevelError(e))),
+        }
+    }
+
+    /// Lists the stored tokens.
+    pub fn status(&self) -> io::Result<Vec<LoginStatus>> {
+        Ok(self
+            .storage()?
+            .tokens()
+            .map(|(scopes, token)| LoginStatus::new(scopes, token))
+            .collect())
+    }
+
+    /// Revokes the stored tokens and removes the token file. Resolves to whether any tokens were
+    /// stored. The file is removed even if revoking fails, so that the user is signed out
+    /// locally at least; the first failure is returned then.
+    pub fn logout(&self) -> Box<dyn Future<Item = bool, Error = RequestError> + Send> {
+        let storage = match self.storage() {
+            Ok(storage) => storage,
+            Err(e) => return Box::new(future::err(RequestError::LowLevelError(e))),
+        };
+        let secret = ApplicationSecret {
+            token_uri: self.revocation_url.clone(),
+            token_uri_fallbacks: Vec::new(),
+            ..self.secret.clone()
+        };
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(hyper_rustls::HttpsConnector::new(1));
+        let revocations: Vec<_> = storage
+            .tokens()
+            .map(|(_, token)| {
+                let revocable = token.refresh_token.as_ref().unwrap_or(&token.access_token);
+                ephemeral::revoke(client.clone(), &secret, revocable).then(Ok::<_, RequestError>)
+            })
+            .collect();
+        let token_cache = self.token_cache.clone();
+        Box::new(future::join_all(revocations).and_then(move |results| {
+            match fs::remove_file(&token_cache) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(RequestError::LowLevelError(e)),
+                Ok(()) => {}
+            }
+            let signed_in = !results.is_empty();
+            results
+                .into_iter()
+                .collect::<Result<Vec<()>, _>>()
+                .map(|_| signed_in)
+        }))
+    }
+
+    fn storage(&self) -> io::Result<DiskTokenStorage> {
+        DiskTokenStorage::new(self.token_cache.to_string_lossy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_status() {
+        let scopes = vec!["email".to_string(), "profile".to_string()];
+        let mut token = Token::new("at".to_string(), "Bearer".to_string(), None, None);
+        assert_eq!(
+            "email profile: signed in, the access token doesn't expire",
+            LoginStatus::new(&scopes, &token).to_string()
+        );
+        token.set_expires_at(Some(time::from_secs(0)));
+        let status = LoginStatus::new(&scopes, &token);
+        assert!(!status.signed_in());
+        assert_eq!(
+            "email profile: signed out, the access token expired",
+            status.to_string()
+        );
+        token.refresh_token = Some("rt".to_string());
+        assert_eq!(
+            "email profile: signed in, the access token will be refreshed",
+            LoginStatus::new(&scopes, &token).to_string()
+        );
+        let status = LoginStatus::new(
+            &scopes,
+            &Token::new("at".into(), "Bearer".into(), None, Some(3600)),
+        );
+        assert!(status.signed_in());
+        assert!(status
+            .to_string()
+            .starts_with("email profile: signed in, the access token expires at "));
+    }
+}
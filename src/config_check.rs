@@ -0,0 +1,157 @@
+//! Checks of the configuration of a token source, run at startup so that a deployment with a
+//! wrong secret or an unreachable endpoint fails right away, rather than on the first request.
+//! See `GetToken::validate()`.
+use std::fmt;
+use std::time::Duration;
+
+use futures::{future, prelude::*};
+
+use crate::helper::validate_application_secret;
+use crate::types::{ApplicationSecret, RequestError};
+
+/// How long `validate()` waits for an endpoint to respond before reporting it unreachable.
+pub const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The outcome of one check of a `ConfigReport`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigCheck {
+    /// What was checked, e.g. `token endpoint https://oauth2.googleapis.com/token`.
+    pub subject: String,
+    /// What is wrong with it, or `None` if the check passed.
+    pub problem: Option<String>,
+}
+
+/// The checks run by `GetToken::validate()`, with the problems found. Implementations of
+/// `validate()` for other token sources can build their reports with the checks used here.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigReport {
+    pub checks: Vec<ConfigCheck>,
+}
+
+impl ConfigReport {
+    /// Checks `secret` with `validate_application_secret()`.
+    pub fn for_secret(secret: &ApplicationSecret) -> ConfigReport {
+        let mut report = ConfigReport::default();
+        let valid = validate_application_secret(secret).map_err(|e| e.to_string());
+        report.check("application secret", valid);
+        report
+    }
+
+    /// Records the outcome of checking `subject`.
+    pub fn check<S: Into<String>>(&mut self, subject: S, outcome: Result<(), String>) {
+        self.checks.push(ConfigCheck {
+            subject: subject.into(),
+            problem: outcome.err(),
+        });
+    }
+
+    /// Appends the checks of `other`.
+    pub fn extend(&mut self, other: ConfigReport) {
+        self.checks.extend(other.checks);
+    }
+
+    /// Checks that the named `endpoints` respond within `REACHABILITY_TIMEOUT`, all at once. Any
+    /// response will do, as requests without credentials are expected to be refused.
+    pub fn reachable<C>(
+        mut self,
+        client: &hyper::Client<C>,
+        endpoints: Vec<(&'static str, String)>,
+    ) -> impl Future<Item = ConfigReport, Error = RequestError> + Send
+    where
+        C: 'static + hyper::client::connect::Connect,
+    {
+        let checks = endpoints.into_iter().map(|(name, url)| {
+            let subject = format!("{} {} is reachable", name, url);
+            let request = match url.parse::<hyper::Uri>() {
+                Ok(uri) => uri,
+                Err(e) => return future::Either::A(future::ok((subject, Err(e.to_string())))),
+            };
+            future::Either::B(
+                tokio_timer::Timeout::new(client.get(request), REACHABILITY_TIMEOUT).then(
+                    move |r| {
+                        let outcome = match r {
+                            Ok(_) => Ok(()),
+                            Err(ref e) if e.is_elapsed() => {
+                                Err(format!("no response within {:?}", REACHABILITY_TIMEOUT))
+                            }
+                            Err(e) => Err(match e.into_inner() {
+                                Some(e) => e.to_string(),
+                                None => "the timer failed".to_string(),
+                            }),
+                        };
+                        Ok((subject, outcome))
+                    },
+                ),
+            )
+        });
+        future::join_all(checks.collect::<Vec<_>>()).map(move |outcomes| {
+            for (subject, outcome) in outcomes {
+                self.check(subject, outcome);
+            }
+            self
+        })
+    }
+
+    /// Whether all checks passed.
+    pub fn is_ok(&self) -> bool {
+        self.problems().next().is_none()
+    }
+
+    /// The checks which failed.
+    pub fn problems(&self) -> impl Iterator<Item = &ConfigCheck> {
+        self.checks.iter().filter(|c| c.problem.is_some())
+    }
+}
+
+/// Lists the checks, one per line, e.g. for the log of a failed startup.
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            match check.problem {
+                None => writeln!(f, "ok: {}", check.subject)?,
+                Some(ref problem) => writeln!(f, "FAILED: {}: {}", check.subject, problem)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::parse_application_secret;
+    use crate::transport::tests::{FakeConnector, FakeReply};
+    use crate::types::tests::SECRET;
+
+    #[test]
+    fn test_config_report() {
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        let report = ConfigReport::for_secret(&secret);
+        assert!(report.is_ok(), "{}", report);
+
+        secret.token_uri = "not a URL".to_string();
+        let report = ConfigReport::for_secret(&secret);
+        assert!(!report.is_ok());
+        assert!(report
+            .to_string()
+            .starts_with("FAILED: application secret: Bad application secret: token_uri"));
+
+        let connector = FakeConnector::new(vec![FakeReply::Json(405, ""), FakeReply::Refused]);
+        let report = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(ConfigReport::default().reachable(
+                &connector.client(),
+                vec![
+                    ("token endpoint", "http://example.com/token".to_string()),
+                    (
+                        "device authorization endpoint",
+                        "http://example.com/device".to_string(),
+                    ),
+                ],
+            ))
+            .unwrap();
+        assert_eq!(None, report.checks[0].problem);
+        assert!(report.checks[1].problem.is_some());
+        assert!(!report.is_ok());
+    }
+}
@@ -0,0 +1,184 @@
+//! Detects revoked consent early, by checking stored tokens with the provider in the background,
+//! rather than once refreshing them fails.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+
+use crate::tokeninfo;
+use crate::transport::{self, TokenRequest};
+use crate::types::{ApplicationSecret, JsonError, RequestError};
+
+/// An endpoint telling whether an access token is still valid.
+#[derive(Clone, Debug)]
+pub enum ConsentEndpoint {
+    /// Google's tokeninfo endpoint, or one like it, refusing revoked access tokens with an
+    /// `invalid_token` error.
+    TokenInfo(String),
+    /// An RFC 7662 token introspection endpoint, to which the client authenticates like to the
+    /// token endpoint.
+    Introspection(String),
+}
+
+/// Checks the stored tokens of an authenticator, see `Authenticator::consent_check()`.
+///
+/// A token is checked when it is returned from storage, and wasn't checked for `interval`. The
+/// check runs in the background on the tokio executor of the caller, and is skipped without
+/// one. Tokens found revoked are invalidated, see `TokenStorage::invalidate()`, and
+/// `AuthenticatorDelegate::reauth_required()` is called; failed checks are logged and retried
+/// after `interval`.
+#[derive(Debug)]
+pub struct ConsentCheck {
+    endpoint: ConsentEndpoint,
+    interval: Duration,
+    /// When the tokens were checked last, by scope hash.
+    checked: Mutex<HashMap<u64, Instant>>,
+}
+
+impl ConsentCheck {
+    /// Check tokens at `endpoint`, at most once per `interval`.
+    pub fn new(endpoint: ConsentEndpoint, interval: Duration) -> ConsentCheck {
+        ConsentCheck {
+            endpoint,
+            interval,
+            checked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether the token stored under `scope_hash` is due to be checked, in which case
+    /// it counts as checked from now on.
+    pub(crate) fn start(&self, scope_hash: u64) -> bool {
+        let mut checked = self.checked.lock().unwrap();
+        let now = Instant::now();
+        match checked.get(&scope_hash) {
+            Some(at) if now.duration_since(*at) < self.interval => false,
+            _ => {
+                checked.insert(scope_hash, now);
+                true
+            }
+        }
+    }
+
+    /// Resolves to whether `access_token` was revoked.
+    pub(crate) fn revoked<C>(
+        &self,
+        client: hyper::Client<C>,
+        secret: &ApplicationSecret,
+        access_token: &str,
+    ) -> Box<dyn Future<Item = bool, Error = RequestError> + Send>
+    where
+        C: 'static + hyper::client::connect::Connect,
+    {
+        let response = match self.endpoint {
+            ConsentEndpoint::TokenInfo(ref url) => {
+                let response = tokeninfo::post(client, url, access_token);
+                Box::new(response) as Box<dyn Future<Item = _, Error = _> + Send>
+            }
+            ConsentEndpoint::Introspection(ref url) => {
+                let secret = ApplicationSecret {
+                    token_uri: url.clone(),
+                    token_uri_fallbacks: Vec::new(),
+                    ..secret.clone()
+                };
+                let request = TokenRequest::new()
+                    .param("token", access_token)
+                    .param("token_type_hint", "access_token");
+                Box::new(transport::post_token_request(client, &secret, request))
+            }
+        };
+        let introspection = match self.endpoint {
+            ConsentEndpoint::TokenInfo(_) => false,
+            ConsentEndpoint::Introspection(_) => true,
+        };
+        Box::new(
+            response
+                .map_err(RequestError::ClientError)
+                .and_then(|response| {
+                    let status = response.status();
+                    transport::read_body(response).map(move |body| (status, body))
+                })
+                .and_then(move |(status, body)| {
+                    #[derive(Deserialize)]
+                    struct Introspection {
+                        active: bool,
+                    }
+                    if introspection && status.is_success() {
+                        return serde_json::from_str::<Introspection>(&body)
+                            .map(|i| !i.active)
+                            .map_err(RequestError::JSONError);
+                    }
+                    if status.is_success() {
+                        return Ok(false);
+                    }
+                    match JsonError::from_response(&body) {
+                        Some(ref e) if e.error == "invalid_token" => Ok(true),
+                        Some(e) => Err(RequestError::from(e)),
+                        None => Err(RequestError::BadServerResponse(format!(
+                            "checking the token failed with {}: {}",
+                            status, body
+                        ))),
+                    }
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::parse_application_secret;
+    use crate::tokeninfo::GOOGLE_TOKENINFO_URL;
+    use crate::transport::tests::{FakeConnector, FakeReply};
+    use crate::types::tests::SECRET;
+
+    fn revoked(endpoint: ConsentEndpoint, reply: FakeReply) -> Result<bool, RequestError> {
+        let secret = parse_application_secret(SECRET).unwrap();
+        let connector = FakeConnector::new(vec![reply]);
+        let check = ConsentCheck::new(endpoint, Duration::from_secs(60));
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(check.revoked(connector.client(), &secret, "token"))
+    }
+
+    #[test]
+    fn test_revoked() {
+        let tokeninfo = ConsentEndpoint::TokenInfo("http://example.com/tokeninfo".to_string());
+        let valid = r#"{"aud": "client", "scope": "email", "expires_in": "3599"}"#;
+        assert!(!revoked(tokeninfo.clone(), FakeReply::Json(200, valid)).unwrap());
+        let invalid = r#"{"error": "invalid_token", "error_description": "Invalid Value"}"#;
+        assert!(revoked(tokeninfo.clone(), FakeReply::Json(400, invalid)).unwrap());
+        assert!(revoked(tokeninfo, FakeReply::Json(503, "unavailable")).is_err());
+
+        let introspection =
+            ConsentEndpoint::Introspection("http://example.com/introspect".to_string());
+        let active = r#"{"active": true, "client_id": "client"}"#;
+        assert!(!revoked(introspection.clone(), FakeReply::Json(200, active)).unwrap());
+        let inactive = r#"{"active": false}"#;
+        assert!(revoked(introspection.clone(), FakeReply::Json(200, inactive)).unwrap());
+        match revoked(
+            introspection,
+            FakeReply::Json(401, r#"{"error": "invalid_client"}"#),
+        ) {
+            Err(RequestError::InvalidClient) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_start() {
+        let check = ConsentCheck::new(
+            ConsentEndpoint::TokenInfo(GOOGLE_TOKENINFO_URL.to_string()),
+            Duration::from_secs(60),
+        );
+        assert!(check.start(1));
+        assert!(!check.start(1));
+        assert!(check.start(2));
+        let check = ConsentCheck::new(
+            ConsentEndpoint::TokenInfo(GOOGLE_TOKENINFO_URL.to_string()),
+            Duration::from_secs(0),
+        );
+        assert!(check.start(1));
+        assert!(check.start(1));
+    }
+}
@@ -1,25 +1,58 @@
 use std::iter::{FromIterator, IntoIterator};
+use std::sync::Arc;
 use std::time::Duration;
 
 use ::log::{error, log};
-use chrono::{self, Utc};
-use futures::stream::Stream;
 use futures::{future, prelude::*};
-use http;
 use hyper;
 use hyper::header;
-use itertools::Itertools;
 use serde_json as json;
 use tokio_timer;
-use url::form_urlencoded;
 
 use crate::authenticator_delegate::{DefaultFlowDelegate, FlowDelegate, PollInformation, Retry};
+use crate::backoff::BackoffPolicy;
+use crate::config_check::ConfigReport;
+use crate::time::{self, Deadline};
+use crate::transport::{self, TokenRequest};
 use crate::types::{
-    ApplicationSecret, Flow, FlowType, GetToken, JsonError, PollError, RequestError, Token,
+    lenient_seconds, ApplicationSecret, DefaultTokenResponseParser, Flow, FlowType, GetToken,
+    JsonError, PollError, RequestError, Token, TokenResponseParser, TransportError,
 };
 
 pub const GOOGLE_DEVICE_CODE_URL: &'static str = "https://accounts.google.com/o/oauth2/device/code";
 
+/// The longest polling interval accepted from a provider; longer intervals are shortened to it.
+const MAX_POLL_INTERVAL_SECS: u64 = 60 * 60;
+
+/// The variant of the device flow protocol spoken by the provider.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceFlowProtocol {
+    /// Google's implementation, which predates the standard: the client secret is always sent
+    /// and tokens are polled using the `http://oauth.net/grant_type/device/1.0` grant type.
+    /// (default)
+    Google,
+    /// The protocol as standardized in [RFC 8628](https://tools.ietf.org/html/rfc8628), as
+    /// implemented e.g. by Azure AD and Okta. Clients authenticate at both endpoints using their
+    /// `token_endpoint_auth_method`, and public clients, with an empty secret, only send their ID.
+    Rfc8628,
+}
+
+impl DeviceFlowProtocol {
+    fn poll_grant_type(self) -> &'static str {
+        match self {
+            DeviceFlowProtocol::Google => "http://oauth.net/grant_type/device/1.0",
+            DeviceFlowProtocol::Rfc8628 => "urn:ietf:params:oauth:grant-type:device_code",
+        }
+    }
+
+    fn device_code_param(self) -> &'static str {
+        match self {
+            DeviceFlowProtocol::Google => "code",
+            DeviceFlowProtocol::Rfc8628 => "device_code",
+        }
+    }
+}
+
 /// Implements the [Oauth2 Device Flow](https://developers.google.com/youtube/v3/guides/authentication#devices)
 /// It operates in two steps:
 /// * obtain a code to show to the user
@@ -30,6 +63,10 @@ pub struct DeviceFlow<FD> {
     device_code_url: String,
     flow_delegate: FD,
     wait: Duration,
+    protocol: DeviceFlowProtocol,
+    renewals: u32,
+    deadline: Option<Duration>,
+    poll_backoff: Option<Arc<dyn BackoffPolicy>>,
 }
 
 impl DeviceFlow<DefaultFlowDelegate> {
@@ -41,6 +78,10 @@ impl DeviceFlow<DefaultFlowDelegate> {
             device_code_url: GOOGLE_DEVICE_CODE_URL.to_string(),
             flow_delegate: DefaultFlowDelegate,
             wait: Duration::from_secs(120),
+            protocol: DeviceFlowProtocol::Google,
+            renewals: 0,
+            deadline: None,
+            poll_backoff: None,
         }
     }
 }
@@ -61,9 +102,19 @@ impl<FD> DeviceFlow<FD> {
             device_code_url: self.device_code_url,
             flow_delegate: delegate,
             wait: self.wait,
+            protocol: self.protocol,
+            renewals: self.renewals,
+            deadline: self.deadline,
+            poll_backoff: self.poll_backoff,
         }
     }
 
+    /// Use the provided protocol variant. Use `DeviceFlowProtocol::Rfc8628` for providers other
+    /// than Google.
+    pub fn protocol(self, protocol: DeviceFlowProtocol) -> Self {
+        DeviceFlow { protocol, ..self }
+    }
+
     /// Use the provided wait duration.
     pub fn wait_duration(self, duration: Duration) -> Self {
         DeviceFlow {
@@ -71,6 +122,139 @@ impl<FD> DeviceFlow<FD> {
             ..self
         }
     }
+
+    /// If the codes expire before the user granted access, request new ones up to `renewals`
+    /// times and present them using `FlowDelegate::user_code_renewed()`, rather than failing
+    /// with `PollError::Expired`. (default: 0)
+    pub fn renew_expired_codes(self, renewals: u32) -> Self {
+        DeviceFlow { renewals, ..self }
+    }
+
+    /// Space polls for the token by the delays of `backoff`, e.g. `ExponentialBackoff`, to poll
+    /// less often the longer the user takes, or after failed polls; the delays are at least the
+    /// interval required by the provider, and the one returned by `FlowDelegate::pending()`.
+    /// (default: poll at the larger of those two)
+    pub fn poll_backoff<P: 'static + BackoffPolicy>(self, backoff: P) -> Self {
+        DeviceFlow {
+            poll_backoff: Some(Arc::new(backoff)),
+            ..self
+        }
+    }
+
+    /// Fail with `RequestError::TimedOut` if the flow doesn't finish within `deadline`, which
+    /// covers requesting and renewing codes, waiting for the user and polling, so that
+    /// unattended programs don't wait for a user forever. Applies to the flow run by the
+    /// `Authenticator` and to each call of `resume()`. (default: none)
+    pub fn deadline(self, deadline: Duration) -> Self {
+        DeviceFlow {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+}
+
+impl<FD> DeviceFlow<FD>
+where
+    FD: FlowDelegate + Clone + Send + 'static,
+{
+    /// Requests a device code for `scopes` and presents it to the user using the flow delegate,
+    /// without polling for the token. Store the returned `PendingDeviceAuthorization` and pass it
+    /// to `resume()`, possibly in a different process, to obtain the token.
+    pub fn start<C, I, T>(
+        &self,
+        client: hyper::Client<C>,
+        scopes: I,
+    ) -> impl Future<Item = PendingDeviceAuthorization, Error = RequestError> + Send
+    where
+        C: hyper::client::connect::Connect + Sync + 'static,
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        let mut fd = self.flow_delegate.clone();
+        DeviceFlowImpl::<FD, C>::request_code(
+            self.application_secret.clone(),
+            client,
+            self.device_code_url.clone(),
+            scopes.clone(),
+            self.protocol,
+        )
+        .map(move |(pollinf, device_code)| {
+            fd.present_user_code(&pollinf);
+            PendingDeviceAuthorization {
+                device_code,
+                user_code: pollinf.user_code,
+                verification_url: pollinf.verification_url,
+                verification_url_complete: pollinf.verification_url_complete,
+                expires_at: time::to_secs(&pollinf.expires_at),
+                interval: pollinf.interval.as_secs(),
+                scopes,
+            }
+        })
+    }
+
+    /// Polls for the token of an authorization begun with `start()`, for at most the configured
+    /// wait duration.
+    pub fn resume<C>(
+        &self,
+        client: hyper::Client<C>,
+        pending: PendingDeviceAuthorization,
+    ) -> impl Future<Item = Token, Error = RequestError> + Send
+    where
+        C: hyper::client::connect::Connect + Sync + 'static,
+    {
+        let pollinf = pending.poll_information();
+        time::time_boxed(
+            DeviceFlowImpl::<FD, C>::poll_until_token(
+                self.application_secret.clone(),
+                client,
+                pending.device_code,
+                pollinf,
+                self.flow_delegate.clone(),
+                self.protocol,
+                PollSchedule {
+                    wait: self.wait,
+                    backoff: self.poll_backoff.clone(),
+                },
+            ),
+            self.deadline,
+        )
+    }
+}
+
+/// A device authorization begun by `DeviceFlow::start()`, which can be stored and resumed later
+/// using `DeviceFlow::resume()`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingDeviceAuthorization {
+    /// The code used to poll for the token. It must be kept private to the application.
+    pub device_code: String,
+    /// The code the user has to enter at the `verification_url`.
+    pub user_code: String,
+    pub verification_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification_url_complete: Option<String>,
+    /// When the codes expire, in seconds since the epoch.
+    pub expires_at: i64,
+    /// The minimal polling interval, in seconds.
+    pub interval: u64,
+    pub scopes: Vec<String>,
+}
+
+impl PendingDeviceAuthorization {
+    /// Returns true if the codes have expired, and the authorization has to be started over.
+    pub fn expired(&self) -> bool {
+        self.expires_at <= time::now()
+    }
+
+    pub fn poll_information(&self) -> PollInformation {
+        PollInformation {
+            user_code: self.user_code.clone(),
+            verification_url: self.verification_url.clone(),
+            verification_url_complete: self.verification_url_complete.clone(),
+            expires_at: time::from_secs(self.expires_at),
+            interval: Duration::from_secs(self.interval.min(MAX_POLL_INTERVAL_SECS)),
+        }
+    }
 }
 
 impl<FD, C> crate::authenticator::AuthFlow<C> for DeviceFlow<FD>
@@ -87,6 +271,10 @@ where
             device_code_url: self.device_code_url,
             fd: self.flow_delegate,
             wait: Duration::from_secs(1200),
+            protocol: self.protocol,
+            renewals: self.renewals,
+            deadline: self.deadline,
+            poll_backoff: self.poll_backoff,
         }
     }
 }
@@ -99,6 +287,43 @@ pub struct DeviceFlowImpl<FD, C> {
     device_code_url: String,
     fd: FD,
     wait: Duration,
+    protocol: DeviceFlowProtocol,
+    /// How often expired codes are renewed.
+    renewals: u32,
+    deadline: Option<Duration>,
+    poll_backoff: Option<Arc<dyn BackoffPolicy>>,
+}
+
+/// When to poll for the token: until `wait` has passed, as often as the provider and the flow
+/// delegate permit, or less often as determined by `backoff`.
+#[derive(Clone)]
+struct PollSchedule {
+    wait: Duration,
+    backoff: Option<Arc<dyn BackoffPolicy>>,
+}
+
+/// The outcome of a poll of the token endpoint.
+enum PollStatus {
+    /// The user didn't grant or deny access yet.
+    Pending,
+    /// Like `Pending`, and the provider asks to poll less often.
+    SlowDown,
+    Granted(Token),
+}
+
+/// How much longer to wait between polls after a `slow_down` error, as RFC 8628 section 3.5
+/// requires.
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+
+impl PollSchedule {
+    /// The delay before the `attempt`th poll after the first, given the `previous` delay and
+    /// the `minimum` one.
+    fn delay(&self, attempt: u32, previous: Duration, minimum: Duration) -> Duration {
+        match self.backoff {
+            Some(ref backoff) => std::cmp::max(minimum, backoff.delay(attempt, previous)),
+            None => minimum,
+        }
+    }
 }
 
 impl<FD, C> Flow for DeviceFlowImpl<FD, C> {
@@ -112,22 +337,32 @@ impl<
         C: hyper::client::connect::Connect + Sync + 'static,
     > GetToken for DeviceFlowImpl<FD, C>
 {
-    fn token<I, T>(
-        &mut self,
-        scopes: I,
-    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
     where
         T: Into<String>,
         I: IntoIterator<Item = T>,
     {
         self.retrieve_device_token(Vec::from_iter(scopes.into_iter().map(Into::into)))
     }
-    fn api_key(&mut self) -> Option<String> {
+    fn api_key(&self) -> Option<String> {
         None
     }
     fn application_secret(&self) -> ApplicationSecret {
         self.application_secret.clone()
     }
+    /// Checks the secret, and that the token and device authorization endpoints respond.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let endpoints = vec![
+            ("token endpoint", self.application_secret.token_uri.clone()),
+            (
+                "device authorization endpoint",
+                self.device_code_url.clone(),
+            ),
+        ];
+        Box::new(
+            ConfigReport::for_secret(&self.application_secret).reachable(&self.client, endpoints),
+        )
+    }
 }
 
 impl<FD, C> DeviceFlowImpl<FD, C>
@@ -138,28 +373,77 @@ where
     FD: FlowDelegate + Clone + Send + 'static,
 {
     /// Essentially what `GetToken::token` does: Retrieve a token for the given scopes without
-    /// caching.
+    /// caching. Expired codes are renewed up to `renewals` times, all within the deadline.
     fn retrieve_device_token<'a>(
-        &mut self,
+        &self,
         scopes: Vec<String>,
     ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
         let application_secret = self.application_secret.clone();
         let client = self.client.clone();
-        let wait = self.wait;
-        let mut fd = self.fd.clone();
-        let request_code = Self::request_code(
-            application_secret.clone(),
-            client.clone(),
-            self.device_code_url.clone(),
-            scopes,
-        )
-        .and_then(move |(pollinf, device_code)| {
-            fd.present_user_code(&pollinf);
-            Ok((pollinf, device_code))
-        });
+        let device_code_url = self.device_code_url.clone();
+        let schedule = PollSchedule {
+            wait: self.wait,
+            backoff: self.poll_backoff.clone(),
+        };
+        let protocol = self.protocol;
         let fd = self.fd.clone();
-        Box::new(request_code.and_then(move |(pollinf, device_code)| {
-            future::loop_fn(0, move |i| {
+        let renewals = self.renewals;
+        let flow = future::loop_fn(0, move |renewed| {
+            let (application_secret, client, mut fd) =
+                (application_secret.clone(), client.clone(), fd.clone());
+            let schedule = schedule.clone();
+            Self::request_code(
+                application_secret.clone(),
+                client.clone(),
+                device_code_url.clone(),
+                scopes.clone(),
+                protocol,
+            )
+            .and_then(move |(pollinf, device_code)| {
+                if renewed == 0 {
+                    fd.present_user_code(&pollinf);
+                } else {
+                    fd.user_code_renewed(&pollinf);
+                }
+                Self::poll_until_token(
+                    application_secret,
+                    client,
+                    device_code,
+                    pollinf,
+                    fd,
+                    protocol,
+                    schedule,
+                )
+            })
+            .then(move |r| match r {
+                Ok(token) => Ok(future::Loop::Break(token)),
+                Err(RequestError::Poll(PollError::Expired(_))) if renewed < renewals => {
+                    Ok(future::Loop::Continue(renewed + 1))
+                }
+                Err(e) => Err(e),
+            })
+        });
+        Box::new(time::time_boxed(flow, self.deadline))
+    }
+
+    /// Polls the token endpoint until the user granted or denied access, or the `schedule`'s
+    /// wait has passed. Polls are at least `pollinf.interval` apart, which grows with every
+    /// `slow_down` error of the provider. The deadlines are kept on the monotonic clock, so that
+    /// adjusting the system clock doesn't end or extend polling.
+    fn poll_until_token(
+        application_secret: ApplicationSecret,
+        client: hyper::Client<C>,
+        device_code: String,
+        pollinf: PollInformation,
+        fd: FD,
+        protocol: DeviceFlowProtocol,
+        schedule: PollSchedule,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        let give_up = Deadline::after(schedule.wait);
+        let expiry = Deadline::at_secs(time::to_secs(&pollinf.expires_at));
+        Box::new(future::loop_fn(
+            (0, pollinf.interval, pollinf),
+            move |(attempt, delay, mut pollinf)| {
                 // Make a copy of everything every time, because the loop function needs to be
                 // repeatable, i.e. we can't move anything out.
                 let pt = Self::poll_token(
@@ -168,46 +452,50 @@ where
                     device_code.clone(),
                     pollinf.clone(),
                     fd.clone(),
+                    protocol,
+                    expiry,
                 );
-                let maxn = wait.as_secs() / pollinf.interval.as_secs();
                 let mut fd = fd.clone();
-                let pollinf = pollinf.clone();
-                tokio_timer::sleep(pollinf.interval)
-                    .then(|_| pt)
-                    .then(move |r| match r {
-                        Ok(None) if i < maxn => match fd.pending(&pollinf) {
-                            Retry::Abort | Retry::Skip => {
-                                Box::new(Err(RequestError::Poll(PollError::TimedOut)).into_future())
+                let schedule = schedule.clone();
+                tokio_timer::sleep(delay).then(|_| pt).then(move |r| {
+                    if let Ok(PollStatus::SlowDown) = r {
+                        // The longer interval applies to all later polls.
+                        pollinf.interval += SLOW_DOWN_INCREMENT;
+                    }
+                    let minimum = pollinf.interval;
+                    let next = move |pollinf, minimum| {
+                        let delay = schedule.delay(attempt + 1, delay, minimum);
+                        (attempt + 1, delay, pollinf)
+                    };
+                    match r {
+                        Ok(PollStatus::Pending) | Ok(PollStatus::SlowDown) if !give_up.passed() => {
+                            match fd.pending(&pollinf) {
+                                Retry::Abort | Retry::Skip => {
+                                    Err(RequestError::Poll(PollError::TimedOut))
+                                }
+                                Retry::After(d) => Ok(future::Loop::Continue(next(
+                                    pollinf,
+                                    std::cmp::max(d, minimum),
+                                ))),
                             }
-                            Retry::After(d) => Box::new(
-                                tokio_timer::sleep(d)
-                                    .then(move |_| Ok(future::Loop::Continue(i + 1))),
-                            )
-                                as Box<
-                                    dyn Future<
-                                            Item = future::Loop<Token, u64>,
-                                            Error = RequestError,
-                                        > + Send,
-                                >,
-                        },
-                        Ok(Some(tok)) => Box::new(Ok(future::Loop::Break(tok)).into_future()),
+                        }
+                        Ok(PollStatus::Granted(tok)) => Ok(future::Loop::Break(tok)),
                         Err(e @ PollError::AccessDenied)
                         | Err(e @ PollError::TimedOut)
-                        | Err(e @ PollError::Expired(_)) => {
-                            Box::new(Err(RequestError::Poll(e)).into_future())
-                        }
-                        Err(ref e) if i < maxn => {
+                        | Err(e @ PollError::Expired(_)) => Err(RequestError::Poll(e)),
+                        Err(ref e) if !give_up.passed() => {
                             error!("Unknown error from poll token api: {}", e);
-                            Box::new(Ok(future::Loop::Continue(i + 1)).into_future())
+                            Ok(future::Loop::Continue(next(pollinf, minimum)))
                         }
-                        // Too many attempts.
-                        Ok(None) | Err(_) => {
+                        // Waited too long.
+                        Ok(_) | Err(_) => {
                             error!("Too many poll attempts");
-                            Box::new(Err(RequestError::Poll(PollError::TimedOut)).into_future())
+                            Err(RequestError::Poll(PollError::TimedOut))
                         }
-                    })
-            })
-        }))
+                    }
+                })
+            },
+        ))
     }
 
     /// The first step involves asking the server for a code that the user
@@ -230,92 +518,99 @@ where
         client: hyper::Client<C>,
         device_code_url: String,
         scopes: Vec<String>,
+        protocol: DeviceFlowProtocol,
     ) -> impl Future<Item = (PollInformation, String), Error = RequestError> {
-        // note: cloned() shouldn't be needed, see issue
-        // https://github.com/servo/rust-url/issues/81
-        let req = form_urlencoded::Serializer::new(String::new())
-            .extend_pairs(&[
-                ("client_id", application_secret.client_id.clone()),
-                (
-                    "scope",
-                    scopes
-                        .into_iter()
-                        .intersperse(" ".to_string())
-                        .collect::<String>(),
-                ),
-            ])
-            .finish();
-
-        // note: works around bug in rustlang
-        // https://github.com/rust-lang/rust/issues/22252
-        let request = hyper::Request::post(device_code_url)
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .body(hyper::Body::from(req))
-            .into_future();
+        // RFC 8628 authenticates clients like the token endpoint; Google only takes the client ID.
+        let (request, authorization) = match protocol {
+            DeviceFlowProtocol::Rfc8628 => {
+                match transport::client_authentication(&application_secret) {
+                    Ok(authentication) => authentication,
+                    Err(e) => return future::Either::A(future::err(e)),
+                }
+            }
+            DeviceFlowProtocol::Google => (
+                TokenRequest::new().param("client_id", &application_secret.client_id),
+                None,
+            ),
+        };
+        let req = match request.scopes(&scopes, application_secret.scope_separator) {
+            Ok(request) => request.body(),
+            Err(e) => return future::Either::A(future::err(e)),
+        };
+
+        let mut request = hyper::Request::post(device_code_url);
         request
-            .then(
-                move |request: Result<hyper::Request<hyper::Body>, http::Error>| {
-                    let request = request.unwrap();
-                    client.request(request)
-                },
-            )
-            .then(
-                |r: Result<hyper::Response<hyper::Body>, hyper::error::Error>| {
-                    match r {
-                        Err(err) => {
-                            return Err(RequestError::ClientError(err));
-                        }
-                        Ok(res) => {
-                            // This return type is defined in https://tools.ietf.org/html/draft-ietf-oauth-device-flow-15#section-3.2
-                            // The alias is present as Google use a non-standard name for verification_uri.
-                            // According to the standard interval is optional, however, all tested implementations provide it.
-                            // verification_uri_complete is optional in the standard but not provided in tested implementations.
-                            #[derive(Deserialize)]
-                            struct JsonData {
-                                device_code: String,
-                                user_code: String,
-                                #[serde(alias = "verification_url")]
-                                verification_uri: String,
-                                expires_in: Option<i64>,
-                                interval: i64,
-                            }
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json");
+        if let Some(ref authorization) = authorization {
+            request.header(header::AUTHORIZATION, authorization.as_str());
+        }
+        let request = match request.body(hyper::Body::from(req)) {
+            Ok(request) => request,
+            Err(e) => {
+                return future::Either::A(future::err(RequestError::ClientError(
+                    TransportError::Other(Box::new(e)),
+                )))
+            }
+        };
+        future::Either::B(
+            client
+                .request(request)
+                .map_err(RequestError::client_error)
+                .and_then(transport::read_body)
+                .map(transport::form_to_json)
+                .and_then(|json_str: String| {
+                    // This return type is defined in https://tools.ietf.org/html/draft-ietf-oauth-device-flow-15#section-3.2
+                    // The alias is present as Google use a non-standard name for verification_uri.
+                    // According to the standard interval is optional and defaults to 5 seconds.
+                    #[derive(Deserialize)]
+                    struct JsonData {
+                        device_code: String,
+                        user_code: String,
+                        #[serde(alias = "verification_url")]
+                        verification_uri: String,
+                        verification_uri_complete: Option<String>,
+                        #[serde(default, deserialize_with = "lenient_seconds")]
+                        expires_in: Option<i64>,
+                        #[serde(default, deserialize_with = "lenient_seconds")]
+                        interval: Option<i64>,
+                    }
 
-                            let json_str: String = res
-                                .into_body()
-                                .concat2()
-                                .wait()
-                                .map(|c| String::from_utf8(c.into_bytes().to_vec()).unwrap())
-                                .unwrap(); // TODO: error handling
-
-                            // check for error
-                            match json::from_str::<JsonError>(&json_str) {
-                                Err(_) => {} // ignore, move on
-                                Ok(res) => return Err(RequestError::from(res)),
-                            }
+                    // check for error
+                    if let Some(res) = JsonError::from_response(&json_str) {
+                        return Err(RequestError::from(res));
+                    }
 
-                            let decoded: JsonData = json::from_str(&json_str).unwrap();
+                    let decoded: JsonData =
+                        json::from_str(&json_str).map_err(RequestError::JSONError)?;
 
-                            let expires_in = decoded.expires_in.unwrap_or(60 * 60);
+                    let expires_in = decoded.expires_in.unwrap_or(60 * 60);
 
-                            let pi = PollInformation {
-                                user_code: decoded.user_code,
-                                verification_url: decoded.verification_uri,
-                                expires_at: Utc::now() + chrono::Duration::seconds(expires_in),
-                                interval: Duration::from_secs(i64::abs(decoded.interval) as u64),
-                            };
-                            Ok((pi, decoded.device_code))
-                        }
-                    }
-                },
-            )
+                    let pi = PollInformation {
+                        user_code: decoded.user_code,
+                        verification_url: decoded.verification_uri,
+                        verification_url_complete: decoded.verification_uri_complete,
+                        expires_at: time::from_secs(time::now().saturating_add(expires_in)),
+                        interval: Duration::from_secs(
+                            decoded
+                                .interval
+                                .unwrap_or(5)
+                                .unsigned_abs()
+                                .min(MAX_POLL_INTERVAL_SECS),
+                        ),
+                    };
+                    Ok((pi, decoded.device_code))
+                }),
+        )
     }
 
     /// If the first call is successful, this method may be called.
-    /// As long as we are waiting for authentication, it will return `Ok(None)`.
+    /// As long as we are waiting for authentication, it will return `PollStatus::Pending`, or
+    /// `PollStatus::SlowDown` if the provider asks to poll less often.
     /// You should call it within the interval given the previously returned
     /// `PollInformation.interval` field.
     ///
-    /// The operation was successful once you receive an Ok(Some(Token)) for the first time.
+    /// The operation was successful once you receive a `PollStatus::Granted` for the first time.
     /// Subsequent calls will return the previous result, which may also be an error state.
     ///
     /// Do not call after `PollError::Expired|PollError::AccessDenied` was among the
@@ -334,37 +629,35 @@ where
         device_code: String,
         pi: PollInformation,
         mut fd: FD,
-    ) -> impl Future<Item = Option<Token>, Error = PollError> {
-        let expired = if pi.expires_at <= Utc::now() {
-            fd.expired(&pi.expires_at);
-            Err(PollError::Expired(pi.expires_at)).into_future()
-        } else {
-            Ok(()).into_future()
-        };
+        protocol: DeviceFlowProtocol,
+        expiry: Deadline,
+    ) -> impl Future<Item = PollStatus, Error = PollError> {
+        let (mut expired_fd, expires_at) = (fd.clone(), pi.expires_at);
+        let expired = future::lazy(move || {
+            if expiry.passed() {
+                expired_fd.expired(&expires_at);
+                Err(PollError::Expired(expires_at))
+            } else {
+                Ok(())
+            }
+        });
 
         // We should be ready for a new request
-        let req = form_urlencoded::Serializer::new(String::new())
-            .extend_pairs(&[
-                ("client_id", &application_secret.client_id[..]),
-                ("client_secret", &application_secret.client_secret),
-                ("code", &device_code),
-                ("grant_type", "http://oauth.net/grant_type/device/1.0"),
-            ])
-            .finish();
-
-        let request = hyper::Request::post(&application_secret.token_uri)
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .body(hyper::Body::from(req))
-            .unwrap(); // TODO: Error checking
         expired
-            .and_then(move |_| client.request(request).map_err(|e| PollError::HttpError(e)))
-            .map(|res| {
-                res.into_body()
-                    .concat2()
-                    .wait()
-                    .map(|c| String::from_utf8(c.into_bytes().to_vec()).unwrap())
-                    .unwrap() // TODO: error handling
+            .and_then(move |_| {
+                let request = transport::TokenRequest::new()
+                    .param(protocol.device_code_param(), &device_code)
+                    .param("grant_type", protocol.poll_grant_type());
+                transport::post_token_request(client, &application_secret, request)
+                    .map_err(PollError::HttpError)
+            })
+            .and_then(|res| {
+                transport::read_body(res).map_err(|e| match e {
+                    RequestError::ClientError(e) => PollError::HttpError(e),
+                    e => PollError::Other(e.to_string()),
+                })
             })
+            .map(transport::form_to_json)
             .and_then(move |json_str: String| {
                 #[derive(Deserialize)]
                 struct JsonError {
@@ -379,7 +672,12 @@ where
                                 fd.denied();
                                 return Err(PollError::AccessDenied);
                             }
-                            "authorization_pending" => return Ok(None),
+                            "authorization_pending" => return Ok(PollStatus::Pending),
+                            "slow_down" => return Ok(PollStatus::SlowDown),
+                            "expired_token" => {
+                                fd.expired(&pi.expires_at);
+                                return Err(PollError::Expired(pi.expires_at));
+                            }
                             s => {
                                 return Err(PollError::Other(format!(
                                     "server message '{}' not understood",
@@ -390,11 +688,10 @@ where
                     }
                 }
 
-                // yes, we expect that !
-                let mut t: Token = json::from_str(&json_str).unwrap();
-                t.set_expiry_absolute();
-
-                Ok(Some(t.clone()))
+                match DefaultTokenResponseParser.parse_token_response(&json_str) {
+                    Ok(t) => Ok(PollStatus::Granted(t)),
+                    Err(e) => Err(PollError::Other(format!("bad token response: {}", e))),
+                }
             })
     }
 }
@@ -409,6 +706,9 @@ mod tests {
     use super::*;
     use crate::authenticator::AuthFlow;
     use crate::helper::parse_application_secret;
+    use crate::transport::tests::{FakeConnector, FakeReply};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
 
     #[test]
     fn test_device_end2end() {
@@ -431,7 +731,7 @@ mod tests {
             .keep_alive(false)
             .build::<_, hyper::Body>(https);
 
-        let mut flow = DeviceFlow::new(app_secret)
+        let flow = DeviceFlow::new(app_secret)
             .delegate(FD)
             .device_code_url(device_code_url)
             .build_token_getter(client);
@@ -536,4 +836,296 @@ mod tests {
             _m.assert();
         }
     }
+
+    #[test]
+    fn test_device_rfc8628() {
+        #[derive(Clone)]
+        struct FD;
+        impl FlowDelegate for FD {
+            fn present_user_code(&mut self, pi: &PollInformation) {
+                assert_eq!("https://example.com/device", pi.verification_url);
+                assert_eq!(
+                    Some("https://example.com/device?user_code=usercode"),
+                    pi.verification_url_complete.as_deref()
+                );
+            }
+        }
+
+        let server_url = mockito::server_url();
+        let mut app_secret = parse_application_secret(crate::types::tests::SECRET).unwrap();
+        app_secret.client_id = "publicclient".to_string();
+        app_secret.client_secret = String::new();
+        app_secret.token_uri = format!("{}/token", server_url);
+        let device_code_url = format!("{}/devicecode", server_url);
+
+        let https = HttpsConnector::new(1);
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(https);
+
+        let flow = DeviceFlow::new(app_secret)
+            .delegate(FD)
+            .device_code_url(device_code_url)
+            .protocol(DeviceFlowProtocol::Rfc8628)
+            .build_token_getter(client);
+
+        let mut rt = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .panic_handler(|e| std::panic::resume_unwind(e))
+            .build()
+            .unwrap();
+
+        let code_response = r#"{"device_code": "devicecode", "user_code": "usercode", "verification_uri": "https://example.com/device", "verification_uri_complete": "https://example.com/device?user_code=usercode", "expires_in": 900, "interval": 1}"#;
+        let _m = mockito::mock("POST", "/devicecode")
+            .match_body(mockito::Matcher::Exact(
+                "client_id=publicclient&scope=openid+profile".to_string(),
+            ))
+            .with_status(200)
+            .with_body(code_response)
+            .create();
+        let token_response = r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 3600}"#;
+        let _m = mockito::mock("POST", "/token")
+            .match_body(mockito::Matcher::Exact(
+                "client_id=publicclient&device_code=devicecode&grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code".to_string(),
+            ))
+            .with_status(200)
+            .with_body(token_response)
+            .create();
+
+        let fut = flow.token(vec!["openid", "profile"]).then(|token| {
+            let token = token.unwrap();
+            assert_eq!("accesstoken", token.access_token);
+            Ok(()) as Result<(), ()>
+        });
+        rt.block_on(fut).expect("block_on");
+
+        _m.assert();
+    }
+
+    #[test]
+    fn test_device_slow_down() {
+        #[derive(Clone)]
+        struct FD(Arc<Mutex<Vec<Duration>>>);
+        impl FlowDelegate for FD {
+            fn pending(&mut self, pi: &PollInformation) -> Retry {
+                let mut intervals = self.0.lock().unwrap();
+                intervals.push(pi.interval);
+                if intervals.len() < 2 {
+                    Retry::After(Duration::from_secs(0))
+                } else {
+                    Retry::Abort
+                }
+            }
+        }
+
+        let server_url = mockito::server_url();
+        let mut app_secret = parse_application_secret(crate::types::tests::SECRET).unwrap();
+        app_secret.token_uri = format!("{}/slow_down/token", server_url);
+
+        let https = HttpsConnector::new(1);
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(https);
+
+        let intervals = Arc::new(Mutex::new(vec![]));
+        let flow = DeviceFlow::new(app_secret)
+            .delegate(FD(intervals.clone()))
+            .device_code_url(format!("{}/slow_down/devicecode", server_url))
+            .protocol(DeviceFlowProtocol::Rfc8628)
+            .build_token_getter(client);
+
+        let mut rt = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .panic_handler(|e| std::panic::resume_unwind(e))
+            .build()
+            .unwrap();
+
+        let code_response = r#"{"device_code": "devicecode", "user_code": "usercode", "verification_uri": "https://example.com/device", "expires_in": 900, "interval": 0}"#;
+        let _m = mockito::mock("POST", "/slow_down/devicecode")
+            .with_status(200)
+            .with_body(code_response)
+            .create();
+        let slow_down = mockito::mock("POST", "/slow_down/token")
+            .with_status(400)
+            .with_body(r#"{"error": "slow_down"}"#)
+            .expect(2)
+            .create();
+
+        let started = Instant::now();
+        match rt.block_on(flow.token(vec!["openid"])) {
+            Err(RequestError::Poll(PollError::TimedOut)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        slow_down.assert();
+        // Every slow_down lengthens the interval, and the second poll waited for it although
+        // the delegate asked for no delay.
+        let slower = SLOW_DOWN_INCREMENT * 2;
+        assert_eq!(
+            vec![SLOW_DOWN_INCREMENT, slower],
+            *intervals.lock().unwrap()
+        );
+        assert!(started.elapsed() >= SLOW_DOWN_INCREMENT);
+    }
+
+    #[test]
+    fn test_device_code_renewal() {
+        /// Records the user codes presented.
+        #[derive(Clone, Default)]
+        struct FD(Arc<Mutex<Vec<String>>>);
+        impl FlowDelegate for FD {
+            fn present_user_code(&mut self, pi: &PollInformation) {
+                self.0.lock().unwrap().push(pi.user_code.clone());
+            }
+            fn user_code_renewed(&mut self, pi: &PollInformation) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("renewed {}", pi.user_code));
+            }
+        }
+
+        let connector = FakeConnector::new(vec![
+            // Expired by the time it is polled first.
+            FakeReply::Json(
+                200,
+                r#"{"device_code": "dc1", "user_code": "FIRST", "verification_uri": "https://example.com/device", "expires_in": 0, "interval": 1}"#,
+            ),
+            FakeReply::Json(
+                200,
+                r#"{"device_code": "dc2", "user_code": "SECOND", "verification_uri": "https://example.com/device", "expires_in": 900, "interval": 1}"#,
+            ),
+            FakeReply::Json(
+                200,
+                r#"{"access_token": "accesstoken", "token_type": "Bearer", "expires_in": 3600}"#,
+            ),
+        ]);
+        let app_secret = parse_application_secret(crate::types::tests::SECRET).unwrap();
+        let fd = FD::default();
+        let flow = DeviceFlow::new(app_secret)
+            .delegate(fd.clone())
+            .protocol(DeviceFlowProtocol::Rfc8628)
+            .renew_expired_codes(1)
+            .build_token_getter(connector.client());
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let token = rt.block_on(flow.token(vec!["openid"])).unwrap();
+        assert_eq!("accesstoken", token.access_token);
+        assert_eq!(
+            vec!["FIRST".to_string(), "renewed SECOND".to_string()],
+            *fd.0.lock().unwrap()
+        );
+        assert_eq!(0, connector.remaining());
+    }
+
+    #[test]
+    fn test_device_github_form_encoded() {
+        let server_url = mockito::server_url();
+        let mut app_secret = crate::github::GitHub::application_secret("githubclient", "", vec![]);
+        app_secret.token_uri = format!("{}/login/oauth/access_token", server_url);
+
+        let https = HttpsConnector::new(1);
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(https);
+
+        let flow = DeviceFlow::new(app_secret)
+            .delegate(DefaultFlowDelegate)
+            .device_code_url(format!("{}/login/device/code", server_url))
+            .protocol(DeviceFlowProtocol::Rfc8628)
+            .build_token_getter(client);
+
+        let mut rt = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .panic_handler(|e| std::panic::resume_unwind(e))
+            .build()
+            .unwrap();
+
+        let _m = mockito::mock("POST", "/login/device/code")
+            .match_header("accept", "application/json")
+            .with_status(200)
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body("device_code=devicecode&expires_in=899&interval=1&user_code=WDJB-MJHT&verification_uri=https%3A%2F%2Fgithub.com%2Flogin%2Fdevice")
+            .create();
+        let _m = mockito::mock("POST", "/login/oauth/access_token")
+            .with_status(200)
+            .with_header("content-type", "application/x-www-form-urlencoded")
+            .with_body("access_token=gho_accesstoken&scope=repo&token_type=bearer")
+            .create();
+
+        let fut = flow.token(vec!["repo"]).then(|token| {
+            let token = token.unwrap();
+            assert_eq!("gho_accesstoken", token.access_token);
+            assert_eq!(None, token.refresh_token);
+            assert_eq!(None, token.expires_at());
+            assert!(!token.expired());
+            Ok(()) as Result<(), ()>
+        });
+        rt.block_on(fut).expect("block_on");
+
+        _m.assert();
+    }
+
+    #[test]
+    fn test_device_start_resume() {
+        let server_url = mockito::server_url();
+        let mut app_secret = parse_application_secret(crate::types::tests::SECRET).unwrap();
+        app_secret.token_uri = format!("{}/token", server_url);
+
+        let https = HttpsConnector::new(1);
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(https);
+        let flow = DeviceFlow::new(app_secret)
+            .device_code_url(format!("{}/code", server_url))
+            .wait_duration(Duration::from_secs(5));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let code_response = r#"{"device_code": "devicecode", "user_code": "usercode", "verification_url": "https://example.com/verify", "expires_in": 1800, "interval": 1}"#;
+        let _m = mockito::mock("POST", "/code")
+            .with_status(200)
+            .with_body(code_response)
+            .create();
+        let pending = rt
+            .block_on(flow.start(client.clone(), vec!["scope"]))
+            .unwrap();
+        assert_eq!("devicecode", pending.device_code);
+        assert_eq!(vec!["scope".to_string()], pending.scopes);
+        assert!(!pending.expired());
+
+        // Another process picks up the stored state.
+        let stored = serde_json::to_string(&pending).unwrap();
+        let pending: PendingDeviceAuthorization = serde_json::from_str(&stored).unwrap();
+
+        let token_response = r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 3600}"#;
+        let _m = mockito::mock("POST", "/token")
+            .match_body(mockito::Matcher::Regex("code=devicecode".to_string()))
+            .with_status(200)
+            .with_body(token_response)
+            .create();
+        let token = rt.block_on(flow.resume(client, pending)).unwrap();
+        assert_eq!("accesstoken", token.access_token);
+        _m.assert();
+    }
+
+    #[test]
+    fn test_poll_schedule() {
+        let secs = Duration::from_secs;
+        let fixed = PollSchedule {
+            wait: secs(120),
+            backoff: None,
+        };
+        assert_eq!(secs(5), fixed.delay(3, secs(5), secs(5)));
+
+        let backoff = PollSchedule {
+            wait: secs(120),
+            backoff: Some(Arc::new(crate::backoff::ExponentialBackoff::new(
+                secs(1),
+                secs(30),
+            ))),
+        };
+        // The provider's interval is the least delay, however fast the backoff starts.
+        assert_eq!(secs(5), backoff.delay(1, secs(0), secs(5)));
+        assert_eq!(secs(16), backoff.delay(5, secs(8), secs(5)));
+        assert_eq!(secs(30), backoff.delay(9, secs(30), secs(5)));
+    }
 }
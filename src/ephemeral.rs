@@ -0,0 +1,372 @@
+//! Credentials which don't outlive the job using them, for short-lived jobs and tests which
+//! shouldn't leave usable refresh tokens behind.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ::log::{log, warn};
+use futures::{future, prelude::*};
+use tokio::executor::Executor;
+
+use crate::config_check::ConfigReport;
+use crate::stats::{AuthenticatorStats, SignInOutcome, TokenInfo};
+use crate::storage::RefreshFailure;
+use crate::transport::{self, TokenRequest};
+use crate::types::{ApplicationSecret, GetToken, JsonError, RequestError, Token};
+
+/// Google's token revocation endpoint, see
+/// https://developers.google.com/identity/protocols/oauth2/native-app#tokenrevoke.
+pub const GOOGLE_REVOCATION_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+/// Wraps a token source, revoking the tokens it handed out when closed or dropped.
+///
+/// Refresh tokens are revoked where present, which at Google also revokes the access tokens
+/// obtained with them; access tokens without a refresh token are revoked themselves. Tokens are
+/// revoked at the `revocation_url()` using the token source's application secret to authenticate
+/// (RFC 7009).
+///
+/// Revoke explicitly using `close()`, which tells whether revocation succeeded. Dropping the
+/// authenticator instead revokes on a best-effort basis: within a tokio runtime, revocation is
+/// spawned onto it, and may not finish if the runtime shuts down right away; otherwise, dropping
+/// blocks until done. Failures are logged.
+pub struct EphemeralAuthenticator<G, C>
+where
+    G: GetToken,
+    C: 'static + hyper::client::connect::Connect,
+{
+    inner: G,
+    client: hyper::Client<C>,
+    revocation_url: String,
+    /// The tokens to revoke, without duplicates.
+    issued: Arc<Mutex<Vec<String>>>,
+}
+
+impl<G, C> EphemeralAuthenticator<G, C>
+where
+    G: GetToken,
+    C: 'static + hyper::client::connect::Connect,
+{
+    /// Hand out the tokens of `inner`, and revoke them at Google's revocation endpoint using
+    /// `client`.
+    pub fn new(inner: G, client: hyper::Client<C>) -> EphemeralAuthenticator<G, C> {
+        EphemeralAuthenticator {
+            inner,
+            client,
+            revocation_url: GOOGLE_REVOCATION_URL.to_string(),
+            issued: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Revoke tokens at `url`, e.g. the `revocation_endpoint` of the provider's
+    /// `DiscoveryDocument`. (default: `GOOGLE_REVOCATION_URL`)
+    pub fn revocation_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.revocation_url = url.into();
+        self
+    }
+
+    /// Revokes the tokens handed out so far. All of them are tried; the first failure is
+    /// returned. Tokens already revoked or expired count as revoked.
+    pub fn close(self) -> impl Future<Item = (), Error = RequestError> + Send {
+        self.revoke_issued()
+    }
+
+    fn record(
+        &self,
+        token: Box<dyn Future<Item = Token, Error = RequestError> + Send>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        let issued = self.issued.clone();
+        Box::new(token.map(move |token| {
+            remember(&issued, &token);
+            token
+        }))
+    }
+
+    /// Takes the tokens handed out so far, so that they are revoked only once.
+    fn revoke_issued(&self) -> Box<dyn Future<Item = (), Error = RequestError> + Send> {
+        let issued = std::mem::take(&mut *self.issued.lock().unwrap());
+        let secret = ApplicationSecret {
+            token_uri: self.revocation_url.clone(),
+            token_uri_fallbacks: Vec::new(),
+            ..self.inner.application_secret()
+        };
+        let revocations: Vec<_> = issued
+            .into_iter()
+            .map(|token| revoke(self.client.clone(), &secret, &token).then(Ok::<_, RequestError>))
+            .collect();
+        Box::new(
+            future::join_all(revocations)
+                .and_then(|results| results.into_iter().collect::<Result<Vec<()>, _>>())
+                .map(|_| ()),
+        )
+    }
+}
+
+fn remember(issued: &Mutex<Vec<String>>, token: &Token) {
+    let revocable = token.refresh_token.as_ref().unwrap_or(&token.access_token);
+    let mut issued = issued.lock().unwrap();
+    if !issued.contains(revocable) {
+        issued.push(revocable.clone());
+    }
+}
+
+/// Revokes `token` at the `token_uri` of `secret`.
+pub(crate) fn revoke<C>(
+    client: hyper::Client<C>,
+    secret: &ApplicationSecret,
+    token: &str,
+) -> impl Future<Item = (), Error = RequestError> + Send
+where
+    C: 'static + hyper::client::connect::Connect,
+{
+    transport::post_token_request(client, secret, TokenRequest::new().param("token", token))
+        .map_err(RequestError::ClientError)
+        .and_then(|response| {
+            let status = response.status();
+            transport::read_body(response).map(move |body| (status, body))
+        })
+        .and_then(|(status, body)| {
+            if status.is_success() {
+                return Ok(());
+            }
+            match JsonError::from_response(&body) {
+                // Google refuses to revoke tokens which are revoked or expired already.
+                Some(ref e) if e.error == "invalid_token" => Ok(()),
+                Some(e) => Err(RequestError::from(e)),
+                None => Err(RequestError::BadServerResponse(format!(
+                    "revoking the token failed with {}: {}",
+                    status, body
+                ))),
+            }
+        })
+}
+
+impl<G, C> GetToken for EphemeralAuthenticator<G, C>
+where
+    G: GetToken,
+    C: 'static + hyper::client::connect::Connect,
+{
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.record(self.inner.token(scopes))
+    }
+
+    fn token_info<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let issued = self.issued.clone();
+        Box::new(self.inner.token_info(scopes).map(move |info| {
+            remember(&issued, &info.token);
+            info
+        }))
+    }
+
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.record(self.inner.force_refresh(scopes))
+    }
+
+    fn token_valid_for<I, T>(
+        &self,
+        duration: Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.record(self.inner.token_valid_for(duration, scopes))
+    }
+
+    fn api_key(&self) -> Option<String> {
+        self.inner.api_key()
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        self.inner.application_secret()
+    }
+
+    /// ID tokens can't be revoked, and are handed out as they are.
+    fn id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.inner.id_token(audience)
+    }
+
+    fn refresh_failures<I, T>(&self, scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.inner.refresh_failures(scopes)
+    }
+
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.inner.invalidate(access_token)
+    }
+
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        self.inner.validate()
+    }
+
+    fn stats(&self) -> AuthenticatorStats {
+        self.inner.stats()
+    }
+
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        self.inner.last_sign_in()
+    }
+}
+
+impl<G, C> Drop for EphemeralAuthenticator<G, C>
+where
+    G: GetToken,
+    C: 'static + hyper::client::connect::Connect,
+{
+    fn drop(&mut self) {
+        if self.issued.lock().unwrap().is_empty() {
+            return;
+        }
+        let revocation = self
+            .revoke_issued()
+            .map_err(|e| warn!("Failed to revoke tokens: {}", e));
+        let mut executor = tokio::executor::DefaultExecutor::current();
+        if executor.status().is_ok() {
+            if executor.spawn(Box::new(revocation)).is_err() {
+                warn!("Failed to revoke tokens: the runtime is shutting down");
+            }
+            return;
+        }
+        // Outside of a runtime, or within one shutting down, which can't be entered again.
+        let revoked = std::thread::spawn(move || {
+            tokio::runtime::current_thread::Runtime::new()
+                .map_err(|e| warn!("Failed to revoke tokens: {}", e))
+                .and_then(|mut runtime| runtime.block_on(revocation))
+        });
+        let _ = revoked.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::parse_application_secret;
+    use crate::transport::tests::{FakeConnector, FakeReply};
+    use crate::types::tests::SECRET;
+
+    /// Hands out the same token every time.
+    struct Fixed(Option<String>);
+
+    impl GetToken for Fixed {
+        fn token<I, T>(&self, _: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        where
+            T: Into<String>,
+            I: IntoIterator<Item = T>,
+        {
+            let token = Token::new(
+                "access".to_string(),
+                "Bearer".to_string(),
+                self.0.clone(),
+                Some(3600),
+            );
+            Box::new(future::ok(token))
+        }
+
+        fn api_key(&self) -> Option<String> {
+            None
+        }
+
+        fn application_secret(&self) -> ApplicationSecret {
+            parse_application_secret(SECRET).unwrap()
+        }
+    }
+
+    fn ephemeral(
+        refresh_token: Option<&str>,
+        replies: Vec<FakeReply>,
+    ) -> (EphemeralAuthenticator<Fixed, FakeConnector>, FakeConnector) {
+        let connector = FakeConnector::new(replies);
+        let auth =
+            EphemeralAuthenticator::new(Fixed(refresh_token.map(String::from)), connector.client());
+        (auth, connector)
+    }
+
+    #[test]
+    fn test_close() {
+        // The refresh token is revoked once, however often it was handed out.
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (auth, connector) = ephemeral(Some("refresh"), vec![FakeReply::Json(200, "")]);
+        auth.token(vec!["a"]).wait().unwrap();
+        auth.force_refresh(vec!["b"]).wait().unwrap();
+        rt.block_on(auth.close()).unwrap();
+        assert_eq!(0, connector.remaining());
+
+        // Tokens which were revoked already count as revoked.
+        let (auth, _) = ephemeral(
+            None,
+            vec![FakeReply::Json(400, r#"{"error": "invalid_token"}"#)],
+        );
+        auth.token(vec!["a"]).wait().unwrap();
+        rt.block_on(auth.close()).unwrap();
+
+        let (auth, _) = ephemeral(
+            None,
+            vec![FakeReply::Json(401, r#"{"error": "invalid_client"}"#)],
+        );
+        auth.token(vec!["a"]).wait().unwrap();
+        match rt.block_on(auth.close()) {
+            Err(RequestError::InvalidClient) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        // Forwarded to the inner source, which has no checks.
+        let (auth, _) = ephemeral(None, vec![]);
+        let report = auth.validate().wait().unwrap();
+        assert_eq!(
+            vec!["token source"],
+            report
+                .problems()
+                .map(|c| c.subject.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_revoke_on_drop() {
+        // Nothing is revoked if no token was handed out.
+        drop(ephemeral(Some("refresh"), vec![]));
+
+        // Outside of a runtime, dropping blocks until the token is revoked.
+        let (auth, connector) = ephemeral(Some("refresh"), vec![FakeReply::Json(200, "")]);
+        auth.token(vec!["a"]).wait().unwrap();
+        drop(auth);
+        assert_eq!(0, connector.remaining());
+
+        // Within one, the revocation is spawned.
+        let (auth, connector) = ephemeral(None, vec![FakeReply::Json(200, "")]);
+        auth.token(vec!["a"]).wait().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.executor().spawn(future::lazy(move || {
+            drop(auth);
+            Ok(())
+        }));
+        rt.shutdown_on_idle().wait().unwrap();
+        assert_eq!(0, connector.remaining());
+    }
+}
@@ -0,0 +1,1051 @@
+//! This module provides a token source (`GetToken`) for workload identity federation, which
+//! exchanges credentials of another identity provider, like AWS, Azure, GitHub Actions or any
+//! OIDC provider, for Google access tokens, without a service account key.
+//!
+//! The configuration is the JSON file of type `external_account` generated by
+//! `gcloud iam workload-identity-pools create-cred-config`. Its `credential_source` describes
+//! where the subject token is obtained:
+//! - `file`: read from a file, e.g. a Kubernetes projected service account token.
+//! - `url`: fetched using HTTP GET, e.g. from the Azure instance metadata service.
+//! - `environment_id` `aws1`: a signed AWS `GetCallerIdentity` request, using the credentials
+//!   from the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment
+//!   variables, or else those of the EC2 instance's role.
+//! - `environment_id` `github_actions`: the OIDC token of the running GitHub Actions job, see
+//!   `ExternalAccountKey::github_actions()`. This is an extension of the format.
+//!
+//! The subject token is exchanged for an access token at the Security Token Service
+//! ([RFC 8693](https://tools.ietf.org/html/rfc8693)), which is then exchanged for an access token
+//! of a service account if `service_account_impersonation_url` is set.
+//!
+//! Resources:
+//! - [Workload identity federation](https://cloud.google.com/iam/docs/workload-identity-federation)
+//! - [AWS Signature Version 4](https://docs.aws.amazon.com/general/latest/gr/signature-version-4.html)
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, prelude::*};
+use hyper::header;
+use ring::{digest, hmac};
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::config_check::ConfigReport;
+use crate::time;
+use crate::token_cache::TokenCache;
+use crate::transport::{self, TokenRequest};
+use crate::types::{ApplicationSecret, GetToken, JsonError, RequestError, ScopeSeparator, Token};
+
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+/// The lifetime requested for tokens of impersonated service accounts, unless configured.
+const IMPERSONATION_LIFETIME_SECS: i64 = 3600;
+const STS_TOKEN_URL: &str = "https://sts.googleapis.com/v1/token";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+const GITHUB_ACTIONS_ENVIRONMENT: &str = "github_actions";
+const AWS_DEFAULT_VERIFICATION_URL: &str =
+    "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15";
+
+/// The configuration of workload identity federation, as found in a JSON file of type
+/// `external_account`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExternalAccountKey {
+    #[serde(rename = "type")]
+    pub key_type: Option<String>,
+    /// The resource name of the workload identity pool provider.
+    pub audience: String,
+    /// The type of the subject token, e.g. `urn:ietf:params:oauth:token-type:jwt`.
+    pub subject_token_type: String,
+    /// The Security Token Service endpoint.
+    pub token_url: String,
+    pub service_account_impersonation_url: Option<String>,
+    /// Options for impersonating the service account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_account_impersonation: Option<ServiceAccountImpersonation>,
+    pub credential_source: CredentialSource,
+    /// The client authenticating to the Security Token Service, if any.
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub quota_project_id: Option<String>,
+    /// The project billed for workforce pool requests without client credentials.
+    pub workforce_pool_user_project: Option<String>,
+}
+
+impl ExternalAccountKey {
+    /// A configuration exchanging the OIDC token of the running GitHub Actions job, for
+    /// deploying to Google Cloud without a service account key. `provider` is the resource name
+    /// of the workload identity pool provider, like
+    /// `projects/123/locations/global/workloadIdentityPools/pool/providers/github`.
+    ///
+    /// The job needs the `id-token: write` permission. Set `service_account_impersonation_url`
+    /// to act as a service account.
+    pub fn github_actions<S: AsRef<str>>(provider: S) -> ExternalAccountKey {
+        ExternalAccountKey::for_provider(
+            provider.as_ref(),
+            CredentialSource {
+                environment_id: Some(GITHUB_ACTIONS_ENVIRONMENT.to_string()),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// A configuration exchanging a Kubernetes service account token projected into the pod
+    /// at `token_path`, for the workload identity pool provider `provider` (see
+    /// `github_actions()`). The file is read again for every exchange, so rotated tokens are
+    /// picked up.
+    ///
+    /// The projected token's audience must be one allowed by the provider, by default
+    /// `https://iam.googleapis.com/<provider>`.
+    pub fn kubernetes<S: AsRef<str>, P: AsRef<std::path::Path>>(
+        provider: S,
+        token_path: P,
+    ) -> ExternalAccountKey {
+        ExternalAccountKey::for_provider(
+            provider.as_ref(),
+            CredentialSource {
+                file: Some(token_path.as_ref().to_string_lossy().into_owned()),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn for_provider(provider: &str, credential_source: CredentialSource) -> ExternalAccountKey {
+        ExternalAccountKey {
+            key_type: Some("external_account".to_string()),
+            audience: format!("//iam.googleapis.com/{}", provider),
+            subject_token_type: JWT_TOKEN_TYPE.to_string(),
+            token_url: STS_TOKEN_URL.to_string(),
+            service_account_impersonation_url: None,
+            service_account_impersonation: None,
+            credential_source,
+            client_id: None,
+            client_secret: None,
+            quota_project_id: None,
+            workforce_pool_user_project: None,
+        }
+    }
+}
+
+/// Options for impersonating the service account of an `ExternalAccountKey`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ServiceAccountImpersonation {
+    /// The lifetime of the service account's tokens, by default one hour. Lifetimes up to 12
+    /// hours, for jobs outliving a token, require the organization policy
+    /// `iam.allowServiceAccountCredentialLifetimeExtension` to list the service account.
+    pub token_lifetime_seconds: Option<i64>,
+}
+
+/// Where the subject token of an `ExternalAccountKey` is obtained.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CredentialSource {
+    /// A file containing the subject token.
+    pub file: Option<String>,
+    /// A URL returning the subject token; with `environment_id` `aws1`, the metadata URL
+    /// returning the instance role's credentials.
+    pub url: Option<String>,
+    /// Headers sent to `url`.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// `aws1` for AWS, `github_actions` for GitHub Actions.
+    pub environment_id: Option<String>,
+    /// The metadata URL returning the AWS availability zone.
+    pub region_url: Option<String>,
+    /// The AWS `GetCallerIdentity` URL, containing `{region}`.
+    pub regional_cred_verification_url: Option<String>,
+    /// The URL returning an IMDSv2 session token, required by instances enforcing IMDSv2.
+    pub imdsv2_session_token_url: Option<String>,
+    pub format: Option<CredentialFormat>,
+}
+
+/// How the subject token is contained in a file or response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CredentialFormat {
+    /// `text` (the default) or `json`.
+    #[serde(rename = "type")]
+    pub format_type: String,
+    /// The field containing the subject token, for `json`.
+    pub subject_token_field_name: Option<String>,
+}
+
+impl CredentialFormat {
+    fn extract(&self, content: &str) -> Result<String, RequestError> {
+        match self.format_type.as_str() {
+            "text" => Ok(content.trim().to_string()),
+            "json" => {
+                let field = self.subject_token_field_name.as_ref().ok_or_else(|| {
+                    RequestError::UserError(
+                        "credential_source lacks subject_token_field_name".to_string(),
+                    )
+                })?;
+                let value: serde_json::Value =
+                    serde_json::from_str(content).map_err(RequestError::JSONError)?;
+                match value.get(field).and_then(|v| v.as_str()) {
+                    Some(token) => Ok(token.to_string()),
+                    None => Err(RequestError::BadServerResponse(format!(
+                        "subject token field {} missing",
+                        field
+                    ))),
+                }
+            }
+            other => Err(RequestError::UserError(format!(
+                "unsupported credential_source format {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// A token source (`GetToken`) yielding access tokens using workload identity federation. Like
+/// `ServiceAccountAccess`, it caches tokens and obtains new ones once they expire; use it
+/// directly rather than with an `Authenticator`.
+#[derive(Clone)]
+pub struct ExternalAccountAccess<C> {
+    client: C,
+    key: ExternalAccountKey,
+}
+
+impl ExternalAccountAccess<DefaultHyperClient> {
+    /// Create a new ExternalAccountAccess with the provided configuration.
+    pub fn new(key: ExternalAccountKey) -> Self {
+        ExternalAccountAccess {
+            client: DefaultHyperClient::default(),
+            key,
+        }
+    }
+
+    /// Keep connections of the default hyper client alive between token requests.
+    pub fn keep_alive(self, keep_alive: bool) -> Self {
+        ExternalAccountAccess {
+            client: DefaultHyperClient {
+                keep_alive,
+                ..self.client
+            },
+            ..self
+        }
+    }
+}
+
+impl<C> ExternalAccountAccess<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> ExternalAccountAccess<NewC> {
+        ExternalAccountAccess {
+            client: hyper_client,
+            key: self.key,
+        }
+    }
+
+    /// Build the configured ExternalAccountAccess.
+    pub fn build(self) -> impl GetToken + Send + Sync
+    where
+        C::Connector: Send + Sync,
+    {
+        ExternalAccountAccessImpl {
+            client: self.client.build_hyper_client(),
+            key: Arc::new(self.key),
+            cache: TokenCache::new(),
+            env: |name| std::env::var(name).ok(),
+        }
+    }
+}
+
+struct ExternalAccountAccessImpl<C> {
+    client: hyper::Client<C, hyper::Body>,
+    key: Arc<ExternalAccountKey>,
+    cache: TokenCache,
+    /// Reads environment variables.
+    env: fn(&str) -> Option<String>,
+}
+
+// Not derived, as that would require `C: Clone`.
+impl<C> Clone for ExternalAccountAccessImpl<C> {
+    fn clone(&self) -> Self {
+        ExternalAccountAccessImpl {
+            client: self.client.clone(),
+            key: self.key.clone(),
+            cache: self.cache.clone(),
+            env: self.env,
+        }
+    }
+}
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = RequestError> + Send>;
+
+/// AWS credentials.
+#[derive(Clone, Debug, PartialEq)]
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+/// Percent-encodes all but the unreserved characters of RFC 3986, as required by SigV4, and
+/// `/` if `keep_slash`.
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+/// Signs a request without body using AWS Signature Version 4 at `now` (seconds since the
+/// epoch). Returns all headers to send, including `host`, `x-amz-date` and `Authorization`,
+/// sorted by name.
+fn aws_sign(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    url: &url::Url,
+    headers: &[(&str, &str)],
+    now: i64,
+) -> Result<Vec<(String, String)>, RequestError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| RequestError::UserError(format!("{} has no host", url)))?;
+    let (year, month, day, hour, minute, second) = time::utc_fields(now);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let amz_date = format!("{}T{:02}{:02}{:02}Z", date, hour, minute, second);
+
+    let mut signed: Vec<(String, String)> = vec![
+        ("host".to_string(), host.to_string()),
+        ("x-amz-date".to_string(), amz_date.clone()),
+    ];
+    if let Some(ref token) = credentials.session_token {
+        signed.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+    signed.extend(
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), value.trim().to_string())),
+    );
+    signed.sort();
+
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (uri_encode(&k, false), uri_encode(&v, false)))
+        .collect();
+    query.sort();
+    let query: Vec<String> = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    let canonical_headers: String = signed
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value))
+        .collect();
+    let signed_headers: Vec<&str> = signed.iter().map(|(name, _)| name.as_str()).collect();
+    let signed_headers = signed_headers.join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        uri_encode(url.path(), true),
+        query.join("&"),
+        canonical_headers,
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, b"").as_ref())
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+    let mut key = format!("AWS4{}", credentials.secret_access_key).into_bytes();
+    for part in &[date.as_str(), region, service, "aws4_request"] {
+        key = hmac_sha256(&key, part);
+    }
+    let signature = hex(&hmac_sha256(&key, &string_to_sign));
+    signed.insert(
+        0,
+        (
+            "Authorization".to_string(),
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credentials.access_key_id, scope, signed_headers, signature
+            ),
+        ),
+    );
+    Ok(signed)
+}
+
+/// Returns the subject token for AWS: a `GetCallerIdentity` request signed using
+/// `credentials`, serialized as JSON and URL-encoded.
+fn aws_subject_token(
+    credentials: &AwsCredentials,
+    region: &str,
+    verification_url: &str,
+    audience: &str,
+    now: i64,
+) -> Result<String, RequestError> {
+    let verification_url = verification_url.replace("{region}", region);
+    let url = url::Url::parse(&verification_url).map_err(|e| {
+        RequestError::UserError(format!("invalid regional_cred_verification_url: {}", e))
+    })?;
+    let headers = aws_sign(
+        credentials,
+        region,
+        "sts",
+        "POST",
+        &url,
+        &[("x-goog-cloud-target-resource", audience)],
+        now,
+    )?;
+    let headers: Vec<serde_json::Value> = headers
+        .into_iter()
+        .map(|(key, value)| serde_json::json!({"key": key, "value": value}))
+        .collect();
+    let request = serde_json::json!({
+        "url": verification_url,
+        "method": "POST",
+        "headers": headers,
+    });
+    Ok(uri_encode(&request.to_string(), false))
+}
+
+impl<C: 'static + hyper::client::connect::Connect> ExternalAccountAccessImpl<C> {
+    /// Obtains the subject token described by the `credential_source`.
+    fn subject_token(&self) -> BoxFuture<String> {
+        let source = &self.key.credential_source;
+        let format = source.format.clone().unwrap_or(CredentialFormat {
+            format_type: "text".to_string(),
+            subject_token_field_name: None,
+        });
+        if let Some(ref environment_id) = source.environment_id {
+            if environment_id.starts_with("aws") {
+                return self.aws_subject_token();
+            }
+            if environment_id == GITHUB_ACTIONS_ENVIRONMENT {
+                return self.github_actions_token();
+            }
+            return Box::new(future::err(RequestError::UserError(format!(
+                "unsupported credential_source environment {}",
+                environment_id
+            ))));
+        }
+        if let Some(ref file) = source.file {
+            let token = fs::read_to_string(file)
+                .map_err(RequestError::LowLevelError)
+                .and_then(|content| format.extract(&content));
+            return Box::new(future::result(token));
+        }
+        if let Some(ref url) = source.url {
+            let headers: Vec<(String, String)> = source
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let request =
+                transport::build_request(hyper::Method::GET, url, &headers, hyper::Body::empty());
+            let client = self.client.clone();
+            return Box::new(
+                future::result(request)
+                    .and_then(move |request| transport::fetch(&client, request))
+                    .and_then(move |body| format.extract(&body)),
+            );
+        }
+        Box::new(future::err(RequestError::UserError(
+            "credential_source contains neither file, url nor environment_id".to_string(),
+        )))
+    }
+
+    /// Requests the job's OIDC token from GitHub Actions, for the audience
+    /// `https://iam.googleapis.com/<provider>`, which workload identity pools expect by default.
+    fn github_actions_token(&self) -> BoxFuture<String> {
+        let env = self.env;
+        let (url, bearer) = match (
+            env("ACTIONS_ID_TOKEN_REQUEST_URL"),
+            env("ACTIONS_ID_TOKEN_REQUEST_TOKEN"),
+        ) {
+            (Some(url), Some(bearer)) => (url, bearer),
+            _ => {
+                return Box::new(future::err(RequestError::UserError(
+                    "ACTIONS_ID_TOKEN_REQUEST_URL is unset; the job needs the id-token: write \
+                     permission"
+                        .to_string(),
+                )))
+            }
+        };
+        let mut url = match url::Url::parse(&url) {
+            Ok(url) => url,
+            Err(e) => {
+                return Box::new(future::err(RequestError::UserError(format!(
+                    "invalid ACTIONS_ID_TOKEN_REQUEST_URL: {}",
+                    e
+                ))))
+            }
+        };
+        let audience = format!("https:{}", self.key.audience);
+        url.query_pairs_mut().append_pair("audience", &audience);
+        let headers = vec![(
+            header::AUTHORIZATION.to_string(),
+            format!("Bearer {}", bearer),
+        )];
+        let request = transport::build_request(
+            hyper::Method::GET,
+            url.as_str(),
+            &headers,
+            hyper::Body::empty(),
+        );
+        let format = CredentialFormat {
+            format_type: "json".to_string(),
+            subject_token_field_name: Some("value".to_string()),
+        };
+        let client = self.client.clone();
+        Box::new(
+            future::result(request)
+                .and_then(move |request| transport::fetch(&client, request))
+                .and_then(move |body| format.extract(&body)),
+        )
+    }
+
+    /// Obtains an IMDSv2 session token if the configuration requires one, and returns the
+    /// headers to send to the metadata server.
+    fn aws_metadata_headers(&self) -> BoxFuture<Vec<(String, String)>> {
+        let url = match self.key.credential_source.imdsv2_session_token_url {
+            Some(ref url) => url.clone(),
+            None => return Box::new(future::ok(Vec::new())),
+        };
+        let ttl = vec![(
+            "x-aws-ec2-metadata-token-ttl-seconds".to_string(),
+            "300".to_string(),
+        )];
+        let client = self.client.clone();
+        Box::new(
+            future::result(transport::build_request(
+                hyper::Method::PUT,
+                &url,
+                &ttl,
+                hyper::Body::empty(),
+            ))
+            .and_then(move |request| transport::fetch(&client, request))
+            .map(|token| vec![("x-aws-ec2-metadata-token".to_string(), token)]),
+        )
+    }
+
+    fn aws_region(&self, headers: &[(String, String)]) -> BoxFuture<String> {
+        let env = self.env;
+        if let Some(region) = env("AWS_REGION").or_else(|| env("AWS_DEFAULT_REGION")) {
+            return Box::new(future::ok(region));
+        }
+        let url = match self.key.credential_source.region_url {
+            Some(ref url) => url.clone(),
+            None => {
+                return Box::new(future::err(RequestError::UserError(
+                    "AWS_REGION is unset and credential_source lacks region_url".to_string(),
+                )))
+            }
+        };
+        let client = self.client.clone();
+        Box::new(
+            future::result(transport::build_request(
+                hyper::Method::GET,
+                &url,
+                headers,
+                hyper::Body::empty(),
+            ))
+            .and_then(move |request| transport::fetch(&client, request))
+            // The availability zone, e.g. `us-east-2b`.
+            .map(|zone| {
+                let zone = zone.trim();
+                match zone.char_indices().last() {
+                    Some((last, _)) => zone[..last].to_string(),
+                    None => String::new(),
+                }
+            }),
+        )
+    }
+
+    fn aws_credentials(&self, headers: Vec<(String, String)>) -> BoxFuture<AwsCredentials> {
+        let env = self.env;
+        if let (Some(access_key_id), Some(secret_access_key)) =
+            (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY"))
+        {
+            return Box::new(future::ok(AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                session_token: env("AWS_SESSION_TOKEN"),
+            }));
+        }
+        let url = match self.key.credential_source.url {
+            Some(ref url) => url.trim_end_matches('/').to_string(),
+            None => {
+                return Box::new(future::err(RequestError::UserError(
+                    "AWS credentials are unset and credential_source lacks url".to_string(),
+                )))
+            }
+        };
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct RoleCredentials {
+            access_key_id: String,
+            secret_access_key: String,
+            token: Option<String>,
+        }
+
+        let client = self.client.clone();
+        let role_request =
+            transport::build_request(hyper::Method::GET, &url, &headers, hyper::Body::empty());
+        Box::new(
+            future::result(role_request)
+                .and_then({
+                    let client = client.clone();
+                    move |request| transport::fetch(&client, request)
+                })
+                .and_then(move |role| {
+                    let role_url = format!("{}/{}", url, role.trim());
+                    future::result(transport::build_request(
+                        hyper::Method::GET,
+                        &role_url,
+                        &headers,
+                        hyper::Body::empty(),
+                    ))
+                    .and_then(move |request| transport::fetch(&client, request))
+                })
+                .and_then(|body| {
+                    let creds: RoleCredentials =
+                        serde_json::from_str(&body).map_err(RequestError::JSONError)?;
+                    Ok(AwsCredentials {
+                        access_key_id: creds.access_key_id,
+                        secret_access_key: creds.secret_access_key,
+                        session_token: creds.token,
+                    })
+                }),
+        )
+    }
+
+    fn aws_subject_token(&self) -> BoxFuture<String> {
+        let this = self.clone();
+        Box::new(self.aws_metadata_headers().and_then(move |headers| {
+            this.aws_region(&headers)
+                .join(this.aws_credentials(headers))
+                .and_then(move |(region, credentials)| {
+                    let source = &this.key.credential_source;
+                    let url = source
+                        .regional_cred_verification_url
+                        .as_deref()
+                        .unwrap_or(AWS_DEFAULT_VERIFICATION_URL);
+                    aws_subject_token(&credentials, &region, url, &this.key.audience, time::now())
+                })
+        }))
+    }
+
+    /// Exchanges `subject_token` at the Security Token Service.
+    fn exchange(&self, subject_token: String, scopes: Vec<String>) -> BoxFuture<Token> {
+        let key = &self.key;
+        let scopes = if key.service_account_impersonation_url.is_some() {
+            vec![CLOUD_PLATFORM_SCOPE.to_string()]
+        } else {
+            scopes
+        };
+        let mut request = TokenRequest::new()
+            .param("grant_type", TOKEN_EXCHANGE_GRANT_TYPE)
+            .param("audience", &key.audience)
+            .param("requested_token_type", ACCESS_TOKEN_TYPE)
+            .param("subject_token_type", &key.subject_token_type)
+            .param("subject_token", &subject_token);
+        request = match request.scopes(&scopes, ScopeSeparator::Space) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let response = match (&key.client_id, &key.client_secret) {
+            (Some(client_id), client_secret) => {
+                let secret = ApplicationSecret {
+                    client_id: client_id.clone(),
+                    client_secret: client_secret.clone().unwrap_or_default(),
+                    token_uri: key.token_url.clone(),
+                    token_endpoint_auth_method: crate::types::ClientAuthMethod::HttpBasic,
+                    ..Default::default()
+                };
+                future::Either::A(transport::post_token_request(
+                    self.client.clone(),
+                    &secret,
+                    request,
+                ))
+            }
+            (None, _) => {
+                if let Some(ref project) = key.workforce_pool_user_project {
+                    let options = serde_json::json!({ "userProject": project });
+                    request = request.param("options", &options.to_string());
+                }
+                future::Either::B(transport::post_form(
+                    self.client.clone(),
+                    vec![key.token_url.clone()],
+                    request.body(),
+                    None,
+                ))
+            }
+        };
+        Box::new(
+            response
+                .map_err(RequestError::ClientError)
+                .and_then(transport::read_body)
+                .and_then(|body| {
+                    if let Some(e) = JsonError::from_response(&body) {
+                        return Err(RequestError::from(e));
+                    }
+
+                    #[derive(Deserialize)]
+                    struct StsResponse {
+                        access_token: String,
+                        token_type: String,
+                        expires_in: Option<i64>,
+                    }
+                    let t: StsResponse =
+                        serde_json::from_str(&body).map_err(RequestError::JSONError)?;
+                    Ok(Token::new(t.access_token, t.token_type, None, t.expires_in))
+                }),
+        )
+    }
+
+    /// Exchanges the federated `token` for a token of the service account to impersonate.
+    fn impersonate(&self, url: &str, token: Token, scopes: Vec<String>) -> BoxFuture<Token> {
+        transport::generate_access_token(
+            &self.client,
+            url,
+            &token.access_token,
+            &scopes,
+            &[],
+            self.impersonation_lifetime(),
+        )
+    }
+
+    fn impersonation_lifetime(&self) -> i64 {
+        self.key
+            .service_account_impersonation
+            .as_ref()
+            .and_then(|i| i.token_lifetime_seconds)
+            .unwrap_or(IMPERSONATION_LIFETIME_SECS)
+    }
+
+    fn request_token(&self, scopes: Vec<String>) -> BoxFuture<Token> {
+        if self.key.service_account_impersonation_url.is_some() {
+            if let Err(e) = transport::check_impersonation_lifetime(self.impersonation_lifetime()) {
+                return Box::new(future::err(e));
+            }
+        }
+        let this = self.clone();
+        Box::new(self.subject_token().and_then(move |subject_token| {
+            this.exchange(subject_token, scopes.clone())
+                .and_then(
+                    move |token| match this.key.service_account_impersonation_url.clone() {
+                        Some(url) => this.impersonate(&url, token, scopes),
+                        None => Box::new(future::ok(token)),
+                    },
+                )
+        }))
+    }
+}
+
+impl<C: 'static> GetToken for ExternalAccountAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.cache
+            .token(scopes, |scopes| self.request_token(scopes))
+    }
+
+    /// Obtains a new token unless the cached one remains valid for `duration`.
+    fn token_valid_for<I, T>(
+        &self,
+        duration: Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.cache
+            .token_valid_for(duration, scopes, |scopes| self.request_token(scopes))
+    }
+
+    /// Drops the cached token for `scopes`, then requests a new one.
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes = self.cache.forget(scopes);
+        self.token(scopes)
+    }
+
+    /// Drops the cached token with `access_token`, so that a new one is requested next.
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.cache.invalidate(access_token)
+    }
+
+    /// Checks that the subject token of the `credential_source` is obtained, and that the
+    /// Security Token Service and the impersonation endpoint, if any, respond.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let mut report = ConfigReport::default();
+        let mut endpoints = vec![("Security Token Service", self.key.token_url.clone())];
+        if let Some(ref url) = self.key.service_account_impersonation_url {
+            let lifetime = transport::check_impersonation_lifetime(self.impersonation_lifetime());
+            report.check(
+                "impersonation lifetime",
+                lifetime.map_err(|e| e.to_string()),
+            );
+            endpoints.push(("service account impersonation endpoint", url.clone()));
+        }
+        let subject = self
+            .subject_token()
+            .then(|token| Ok(token.map(|_| ()).map_err(|e| e.to_string())));
+        Box::new(report.reachable(&self.client, endpoints).join(subject).map(
+            |(mut report, subject)| {
+                report.check("subject token of the credential_source", subject);
+                report
+            },
+        ))
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        Default::default()
+    }
+
+    fn api_key(&self) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper_rustls::HttpsConnector;
+    use mockito::{self, mock, Matcher};
+
+    fn client() -> hyper::Client<HttpsConnector<hyper::client::HttpConnector>> {
+        hyper::Client::builder()
+            .keep_alive(false)
+            .build(HttpsConnector::new(1))
+    }
+
+    fn access(
+        key: ExternalAccountKey,
+    ) -> ExternalAccountAccessImpl<HttpsConnector<hyper::client::HttpConnector>> {
+        ExternalAccountAccessImpl {
+            client: client(),
+            key: Arc::new(key),
+            cache: TokenCache::new(),
+            env: |_| None,
+        }
+    }
+
+    fn key(credential_source: serde_json::Value) -> ExternalAccountKey {
+        serde_json::from_value(serde_json::json!({
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/provider",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("{}/external/sts", mockito::server_url()),
+            "credential_source": credential_source,
+        }))
+        .unwrap()
+    }
+
+    /// The `get-vanilla` case of the AWS Signature Version 4 test suite.
+    #[test]
+    fn test_aws_sign() {
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let url = url::Url::parse("https://example.amazonaws.com/").unwrap();
+        let headers = aws_sign(
+            &credentials,
+            "us-east-1",
+            "service",
+            "GET",
+            &url,
+            &[],
+            1_440_938_160,
+        )
+        .unwrap();
+        assert_eq!(
+            ("x-amz-date".to_string(), "20150830T123600Z".to_string()),
+            headers[2]
+        );
+        assert_eq!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            headers[0].1
+        );
+        assert_eq!("a%2Fb%20c", uri_encode("a/b c", false));
+    }
+
+    #[test]
+    fn test_file_source_with_impersonation() {
+        let dir = std::env::temp_dir().join(format!("yup-oauth2-external-{}", time::now()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token.json");
+        fs::write(&path, r#"{"id_token": "oidc-token"}"#).unwrap();
+        let mut key = key(serde_json::json!({
+            "file": path.to_str().unwrap(),
+            "format": {"type": "json", "subject_token_field_name": "id_token"},
+        }));
+        key.service_account_impersonation_url = Some(format!(
+            "{}/external/sa:generateAccessToken",
+            mockito::server_url()
+        ));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _sts = mock("POST", "/external/sts")
+            .match_body(Matcher::Regex(
+                "subject_token=oidc-token.*scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform"
+                    .to_string(),
+            ))
+            .with_body(r#"{"access_token": "federated", "issued_token_type": "urn:ietf:params:oauth:token-type:access_token", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+        let _sa = mock("POST", "/external/sa:generateAccessToken")
+            .match_header("authorization", "Bearer federated")
+            .match_body(Matcher::Regex(r#""scope":\["drive"\]"#.to_string()))
+            .with_body(r#"{"accessToken": "impersonated", "expireTime": "2099-01-01T00:00:00Z"}"#)
+            .expect(1)
+            .create();
+        let access = access(key);
+        for _ in 0..2 {
+            let token = rt.block_on(access.token(vec!["drive"])).unwrap();
+            assert_eq!("impersonated", token.access_token);
+            assert_eq!(
+                Some(4_070_908_800),
+                token.expires_at().map(|t| time::to_secs(&t))
+            );
+        }
+        _sts.assert();
+        _sa.assert();
+
+        let report = rt.block_on(access.validate()).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        fs::remove_dir_all(dir).unwrap();
+        let report = rt.block_on(access.validate()).unwrap();
+        assert_eq!(
+            vec!["subject token of the credential_source"],
+            report
+                .problems()
+                .map(|c| c.subject.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_aws_source() {
+        let server = mockito::server_url();
+        let key = key(serde_json::json!({
+            "environment_id": "aws1",
+            "region_url": format!("{}/external/aws/placement/availability-zone", server),
+            "url": format!("{}/external/aws/security-credentials", server),
+            "regional_cred_verification_url": "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15",
+            "imdsv2_session_token_url": format!("{}/external/aws/token", server),
+        }));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _token = mock("PUT", "/external/aws/token")
+            .match_header("x-aws-ec2-metadata-token-ttl-seconds", "300")
+            .with_body("imds-session")
+            .create();
+        let _zone = mock("GET", "/external/aws/placement/availability-zone")
+            .match_header("x-aws-ec2-metadata-token", "imds-session")
+            .with_body("us-east-2b")
+            .create();
+        let _role = mock("GET", "/external/aws/security-credentials")
+            .with_body("my-role")
+            .create();
+        let _creds = mock("GET", "/external/aws/security-credentials/my-role")
+            .match_header("x-aws-ec2-metadata-token", "imds-session")
+            .with_body(r#"{"Code": "Success", "AccessKeyId": "AKID", "SecretAccessKey": "secret", "Token": "session"}"#)
+            .create();
+        let _sts = mock("POST", "/external/sts")
+            .match_body(Matcher::Regex(
+                "subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Ajwt&subject_token=%257B.*AWS4-HMAC-SHA256.*sts.us-east-2.amazonaws.com.*x-amz-security-token.*&scope=drive"
+                    .to_string(),
+            ))
+            .with_body(r#"{"access_token": "federated", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+        let token = rt.block_on(access(key).token(vec!["drive"])).unwrap();
+        assert_eq!("federated", token.access_token);
+        _sts.assert();
+    }
+
+    #[test]
+    fn test_github_actions_source() {
+        let mut key = ExternalAccountKey::github_actions(
+            "projects/123/locations/global/workloadIdentityPools/pool/providers/github",
+        );
+        key.token_url = format!("{}/external/sts", mockito::server_url());
+        let mut access = access(key);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Outside of a job, or without the id-token permission.
+        match rt.block_on(access.token(vec!["drive"])) {
+            Err(RequestError::UserError(msg)) => assert!(msg.contains("id-token")),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        access.env = |name| match name {
+            "ACTIONS_ID_TOKEN_REQUEST_URL" => Some(format!(
+                "{}/external/github?api-version=2.0",
+                mockito::server_url()
+            )),
+            "ACTIONS_ID_TOKEN_REQUEST_TOKEN" => Some("runtime-token".to_string()),
+            _ => None,
+        };
+        let _gh = mock(
+            "GET",
+            Matcher::Regex(
+                "^/external/github\\?api-version=2.0&audience=https%3A%2F%2Fiam.googleapis.com%2F\
+                 projects%2F123%2F"
+                    .to_string(),
+            ),
+        )
+        .match_header("authorization", "Bearer runtime-token")
+        .with_body(r#"{"count": 1, "value": "github-jwt"}"#)
+        .create();
+        let _sts = mock("POST", "/external/sts")
+            .match_body(Matcher::Regex(
+                "audience=%2F%2Fiam.googleapis.com%2Fprojects%2F123.*subject_token=github-jwt"
+                    .to_string(),
+            ))
+            .with_body(
+                r#"{"access_token": "federated", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create();
+        let token = rt.block_on(access.token(vec!["drive"])).unwrap();
+        assert_eq!("federated", token.access_token);
+        _sts.assert();
+    }
+}
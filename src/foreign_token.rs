@@ -0,0 +1,217 @@
+//! Readers for the token caches of Google's auth libraries for other languages, so that a
+//! project using several languages can share a single cache of signed-in credentials.
+//!
+//! Supported are the `authorized_user` files written by `Credentials.to_json()` of Python's
+//! google-auth (e.g. the `token.json` of the Python quickstarts), and the tokens of Node's
+//! google-auth-library as stored by `JSON.stringify(client.credentials)`.
+//!
+//! Resources:
+//! - [google-auth for Python](https://googleapis.dev/python/google-auth/latest/reference/google.oauth2.credentials.html)
+//! - [google-auth-library for Node.js](https://github.com/googleapis/google-auth-library-nodejs#oauth2)
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::storage::{TokenKey, TokenStorage};
+use crate::time;
+use crate::types::Token;
+
+/// A token read from the cache of another library.
+#[derive(Clone, Debug)]
+pub struct ForeignToken {
+    /// The access token, with refresh token and expiry if the cache recorded them.
+    pub token: Token,
+    /// The scopes the token was granted for. Empty if the cache didn't record them.
+    pub scopes: Vec<String>,
+    /// The client ID of the application the token was issued to, if recorded.
+    pub client_id: Option<String>,
+    /// The client secret of the application, if recorded.
+    pub client_secret: Option<String>,
+    /// The token endpoint to refresh the token at, if recorded.
+    pub token_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PythonCredentials {
+    token: Option<String>,
+    refresh_token: Option<String>,
+    expiry: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    token_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NodeCredentials {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    token_type: Option<String>,
+    /// Milliseconds since the epoch.
+    expiry_date: Option<i64>,
+    scope: Option<String>,
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn require_access_token(access_token: Option<String>) -> io::Result<String> {
+    match access_token {
+        Some(access_token) if !access_token.is_empty() => Ok(access_token),
+        _ => Err(invalid_data(
+            "The cached credentials contain no access token",
+        )),
+    }
+}
+
+impl ForeignToken {
+    /// Parse credentials written by Python's google-auth, like
+    /// `{"token": "ya29...", "refresh_token": "1//...", "expiry": "2019-06-30T14:05:09.123456Z",
+    /// "scopes": [...], "client_id": ..., "client_secret": ..., "token_uri": ...}`.
+    pub fn from_python<S: AsRef<str>>(json: S) -> io::Result<ForeignToken> {
+        let creds: PythonCredentials = serde_json::from_str(json.as_ref())
+            .map_err(|e| invalid_data(format!("Bad google-auth credentials: {}", e)))?;
+        let expires_at = match creds.expiry {
+            Some(expiry) => Some(time::parse_rfc3339(&expiry).ok_or_else(|| {
+                invalid_data(format!("Bad expiry of google-auth credentials: {}", expiry))
+            })?),
+            None => None,
+        };
+        let mut token = Token::new(
+            require_access_token(creds.token)?,
+            "Bearer".to_string(),
+            creds.refresh_token,
+            None,
+        );
+        token.set_expires_at(expires_at.map(time::from_secs));
+        Ok(ForeignToken {
+            token,
+            scopes: creds.scopes,
+            client_id: creds.client_id,
+            client_secret: creds.client_secret,
+            token_uri: creds.token_uri,
+        })
+    }
+
+    /// Parse credentials of Node's google-auth-library, like `{"access_token": "ya29...",
+    /// "refresh_token": "1//...", "scope": "openid email", "token_type": "Bearer",
+    /// "expiry_date": 1561903509000}`.
+    pub fn from_node<S: AsRef<str>>(json: S) -> io::Result<ForeignToken> {
+        let creds: NodeCredentials = serde_json::from_str(json.as_ref())
+            .map_err(|e| invalid_data(format!("Bad google-auth-library credentials: {}", e)))?;
+        let mut token = Token::new(
+            require_access_token(creds.access_token)?,
+            creds.token_type.unwrap_or_else(|| "Bearer".to_string()),
+            creds.refresh_token,
+            None,
+        );
+        token.set_expires_at(
+            creds
+                .expiry_date
+                .map(|ms| time::from_secs(ms.div_euclid(1000))),
+        );
+        Ok(ForeignToken {
+            token,
+            scopes: creds
+                .scope
+                .map(|s| s.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+            client_id: None,
+            client_secret: None,
+            token_uri: None,
+        })
+    }
+
+    /// Parse credentials in either format, telling them apart by their fields.
+    pub fn parse<S: AsRef<str>>(json: S) -> io::Result<ForeignToken> {
+        let value: serde_json::Value = serde_json::from_str(json.as_ref())
+            .map_err(|e| invalid_data(format!("Bad cached credentials: {}", e)))?;
+        if value.get("access_token").is_some() {
+            ForeignToken::from_node(json)
+        } else if value.get("token").is_some() {
+            ForeignToken::from_python(json)
+        } else {
+            Err(invalid_data("Unknown format of cached credentials"))
+        }
+    }
+
+    /// Read credentials in either format from a file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<ForeignToken> {
+        ForeignToken::parse(fs::read_to_string(path)?)
+    }
+
+    /// The key under which an `Authenticator` of the application `client_id` looks the token up.
+    /// `client_id` should match the recorded one, if any.
+    pub fn key<S: Into<String>>(&self, client_id: S) -> TokenKey {
+        TokenKey::new(client_id, self.scopes.iter().cloned())
+    }
+
+    /// Store the token in `storage` for use by an `Authenticator` of the application
+    /// `client_id`.
+    pub fn store<S, T>(&self, client_id: S, storage: &mut T) -> Result<(), T::Error>
+    where
+        S: Into<String>,
+        T: TokenStorage,
+    {
+        let key = self.key(client_id);
+        storage.set(
+            key.scope_hash(),
+            &key.scopes().iter().map(|s| s.as_str()).collect(),
+            Some(self.token.clone()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_foreign_tokens() {
+        let python = r#"{"token": "ya29.python", "refresh_token": "1//refresh",
+            "token_uri": "https://oauth2.googleapis.com/token", "client_id": "id",
+            "client_secret": "secret", "scopes": ["openid", "email"],
+            "universe_domain": "googleapis.com", "account": "",
+            "expiry": "2019-06-30T14:05:09.123456Z"}"#;
+        let python = ForeignToken::parse(python).unwrap();
+        assert_eq!("ya29.python", python.token.access_token);
+        assert_eq!(Some("1//refresh"), python.token.refresh_token.as_deref());
+        assert_eq!(
+            Some(1_561_903_509),
+            python.token.expires_at().map(|t| time::to_secs(&t))
+        );
+        assert_eq!(vec!["openid", "email"], python.scopes);
+        assert_eq!(Some("id"), python.client_id.as_deref());
+        assert_eq!(Some("secret"), python.client_secret.as_deref());
+
+        let node = r#"{"access_token": "ya29.node", "refresh_token": "1//refresh",
+            "scope": "openid email", "token_type": "Bearer", "id_token": "eyJ...",
+            "expiry_date": 1561903509123}"#;
+        let node = ForeignToken::parse(node).unwrap();
+        assert_eq!("ya29.node", node.token.access_token);
+        assert_eq!(
+            Some(1_561_903_509),
+            node.token.expires_at().map(|t| time::to_secs(&t))
+        );
+        assert_eq!(vec!["openid", "email"], node.scopes);
+        assert_eq!(None, node.client_id);
+        assert_eq!(python.key("id"), node.key("id"));
+
+        let mut storage = MemoryStorage::new();
+        node.store("id", &mut storage).unwrap();
+        let key = TokenKey::new("id", vec!["email", "openid"]);
+        let stored = storage
+            .get(key.scope_hash(), &vec!["email", "openid"])
+            .unwrap()
+            .unwrap();
+        assert_eq!("ya29.node", stored.access_token);
+
+        assert!(ForeignToken::from_python(r#"{"token": null, "refresh_token": "x"}"#).is_err());
+        assert!(ForeignToken::from_python(r#"{"token": "x", "expiry": "soon"}"#).is_err());
+        assert!(ForeignToken::parse(r#"{"type": "service_account"}"#).is_err());
+    }
+}
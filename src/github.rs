@@ -0,0 +1,60 @@
+//! Support for GitHub OAuth apps and GitHub apps.
+//!
+//! GitHub returns form-encoded responses unless JSON is requested explicitly, and access tokens
+//! of OAuth apps don't expire; such tokens have no `refresh_token` and `expires_at()` returns
+//! `None`. Both are handled transparently.
+//!
+//! ```no_run
+//! # #[cfg(feature = "device")]
+//! # {
+//! use yup_oauth2::{Authenticator, GetToken, GitHub};
+//!
+//! let auth = Authenticator::new(GitHub::device_flow("my-client-id"))
+//!     .build()
+//!     .unwrap();
+//! let tok = auth.token(vec!["repo", "read:org"]);
+//! # }
+//! ```
+#[cfg(feature = "device")]
+use crate::authenticator_delegate::DefaultFlowDelegate;
+#[cfg(feature = "device")]
+use crate::device::{DeviceFlow, DeviceFlowProtocol};
+use crate::types::{ApplicationSecret, ScopeSeparator};
+
+pub const GITHUB_AUTH_URI: &str = "https://github.com/login/oauth/authorize";
+pub const GITHUB_TOKEN_URI: &str = "https://github.com/login/oauth/access_token";
+pub const GITHUB_DEVICE_CODE_URL: &str = "https://github.com/login/device/code";
+
+/// GitHub's OAuth endpoints.
+#[derive(Clone, Copy, Debug)]
+pub struct GitHub;
+
+impl GitHub {
+    /// Returns an `ApplicationSecret` for a GitHub OAuth app or GitHub app. For the device flow,
+    /// the `client_secret` is not required and may be empty.
+    pub fn application_secret<S: Into<String>, T: Into<String>>(
+        client_id: S,
+        client_secret: T,
+        redirect_uris: Vec<String>,
+    ) -> ApplicationSecret {
+        ApplicationSecret {
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            token_uri: GITHUB_TOKEN_URI.to_string(),
+            auth_uri: GITHUB_AUTH_URI.to_string(),
+            redirect_uris,
+            // GitHub takes space-separated scopes, though it also accepts commas.
+            scope_separator: ScopeSeparator::Space,
+            ..Default::default()
+        }
+    }
+
+    /// Returns a `DeviceFlow` for `client_id`. The device flow has to be enabled in the app's
+    /// settings.
+    #[cfg(feature = "device")]
+    pub fn device_flow<S: Into<String>>(client_id: S) -> DeviceFlow<DefaultFlowDelegate> {
+        DeviceFlow::new(GitHub::application_secret(client_id, "", vec![]))
+            .device_code_url(GITHUB_DEVICE_CODE_URL.to_string())
+            .protocol(DeviceFlowProtocol::Rfc8628)
+    }
+}
@@ -0,0 +1,102 @@
+//! Constants for commonly used Google API scopes, enabled by the `google-scopes` feature.
+//!
+//! See [developers.google.com](https://developers.google.com/identity/protocols/googlescopes)
+//! for the complete list and the APIs they grant access to.
+use crate::scope::Scope;
+
+/// View and manage the files in your Google Drive.
+pub const DRIVE: Scope = Scope::from_static("https://www.googleapis.com/auth/drive");
+/// View and manage Google Drive files and folders that you have opened or created with this app.
+pub const DRIVE_FILE: Scope = Scope::from_static("https://www.googleapis.com/auth/drive.file");
+/// View the files in your Google Drive.
+pub const DRIVE_READONLY: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/drive.readonly");
+/// View and manage its own configuration data in your Google Drive.
+pub const DRIVE_APPDATA: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/drive.appdata");
+/// Read, compose, send, and permanently delete all your email from Gmail.
+pub const GMAIL: Scope = Scope::from_static("https://mail.google.com/");
+/// View your email messages and settings.
+pub const GMAIL_READONLY: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/gmail.readonly");
+/// Send email on your behalf.
+pub const GMAIL_SEND: Scope = Scope::from_static("https://www.googleapis.com/auth/gmail.send");
+/// Manage your calendars.
+pub const CALENDAR: Scope = Scope::from_static("https://www.googleapis.com/auth/calendar");
+/// View your calendars.
+pub const CALENDAR_READONLY: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/calendar.readonly");
+/// See, edit, create, and delete your spreadsheets in Google Drive.
+pub const SPREADSHEETS: Scope = Scope::from_static("https://www.googleapis.com/auth/spreadsheets");
+/// View your Google Spreadsheets.
+pub const SPREADSHEETS_READONLY: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/spreadsheets.readonly");
+/// Manage your YouTube account.
+pub const YOUTUBE: Scope = Scope::from_static("https://www.googleapis.com/auth/youtube");
+/// View your YouTube account.
+pub const YOUTUBE_READONLY: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/youtube.readonly");
+/// View and manage your data across Google Cloud Platform services.
+pub const CLOUD_PLATFORM: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/cloud-platform");
+/// View your data across Google Cloud Platform services.
+pub const CLOUD_PLATFORM_READONLY: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/cloud-platform.read-only");
+/// Manage your data and permissions in Google Cloud Storage.
+pub const DEVSTORAGE_FULL_CONTROL: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/devstorage.full_control");
+/// View your data in Google Cloud Storage.
+pub const DEVSTORAGE_READ_ONLY: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/devstorage.read_only");
+/// Manage your data in Google Cloud Storage.
+pub const DEVSTORAGE_READ_WRITE: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/devstorage.read_write");
+/// View and manage your data in Google BigQuery.
+pub const BIGQUERY: Scope = Scope::from_static("https://www.googleapis.com/auth/bigquery");
+/// View and manage Pub/Sub topics and subscriptions.
+pub const PUBSUB: Scope = Scope::from_static("https://www.googleapis.com/auth/pubsub");
+/// Authenticate using OpenID Connect.
+pub const OPENID: Scope = Scope::from_static("openid");
+/// View your email address.
+pub const USERINFO_EMAIL: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/userinfo.email");
+/// See your personal info, including any personal info you've made publicly available.
+pub const USERINFO_PROFILE: Scope =
+    Scope::from_static("https://www.googleapis.com/auth/userinfo.profile");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_is_valid() {
+        let catalog = [
+            DRIVE,
+            DRIVE_FILE,
+            DRIVE_READONLY,
+            DRIVE_APPDATA,
+            GMAIL,
+            GMAIL_READONLY,
+            GMAIL_SEND,
+            CALENDAR,
+            CALENDAR_READONLY,
+            SPREADSHEETS,
+            SPREADSHEETS_READONLY,
+            YOUTUBE,
+            YOUTUBE_READONLY,
+            CLOUD_PLATFORM,
+            CLOUD_PLATFORM_READONLY,
+            DEVSTORAGE_FULL_CONTROL,
+            DEVSTORAGE_READ_ONLY,
+            DEVSTORAGE_READ_WRITE,
+            BIGQUERY,
+            PUBSUB,
+            OPENID,
+            USERINFO_EMAIL,
+            USERINFO_PROFILE,
+        ];
+        for scope in catalog.iter() {
+            assert_eq!(Some(scope), Scope::new(scope.as_str()).ok().as_ref());
+        }
+    }
+}
@@ -13,11 +13,21 @@ use std::fs;
 use std::io::{self, Read};
 use std::path::Path;
 
+use url::percent_encoding::percent_decode;
+use url::Url;
+
+#[cfg(feature = "external-account")]
+use crate::external_account::ExternalAccountKey;
+#[cfg(feature = "impersonated-service-account")]
+use crate::impersonated::ImpersonatedServiceAccountKey;
+#[cfg(feature = "service-account")]
 use crate::service_account::ServiceAccountKey;
 use crate::types::{ApplicationSecret, ConsoleApplicationSecret};
 
-/// Read an application secret from a file.
-pub fn read_application_secret(path: &Path) -> io::Result<ApplicationSecret> {
+/// Read an application secret from a file, as downloaded from the Google Cloud Console
+/// (usually named `client_secret_<client id>.json`). Both the `installed` and the `web` format
+/// are accepted.
+pub fn read_application_secret<P: AsRef<Path>>(path: P) -> io::Result<ApplicationSecret> {
     let mut secret = String::new();
     let mut file = fs::OpenOptions::new().read(true).open(path)?;
     file.read_to_string(&mut secret)?;
@@ -26,31 +36,213 @@ pub fn read_application_secret(path: &Path) -> io::Result<ApplicationSecret> {
 }
 
 /// Read an application secret from a JSON string.
+///
+/// The secret is validated using `validate_application_secret()`.
 pub fn parse_application_secret<S: AsRef<str>>(secret: S) -> io::Result<ApplicationSecret> {
-    let result: serde_json::Result<ConsoleApplicationSecret> =
-        serde_json::from_str(secret.as_ref());
-    match result {
-        Err(e) => Err(io::Error::new(
+    let decoded = decode_console_application_secret(secret.as_ref())?;
+    let secret = match (decoded.web, decoded.installed) {
+        (Some(web), _) => web,
+        (None, Some(installed)) => installed,
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unknown application secret format",
+            ))
+        }
+    };
+    let secret = sanitize_application_secret(secret)?;
+    validate_application_secret(&secret)?;
+    Ok(secret)
+}
+
+/// Read an application secret for the installed or device flow from a file.
+///
+/// Unlike `read_application_secret()`, this fails with a descriptive error if the file contains
+/// the secret of a web application, which the installed flows can't use.
+pub fn read_installed_application_secret<P: AsRef<Path>>(path: P) -> io::Result<ApplicationSecret> {
+    let mut secret = String::new();
+    let mut file = fs::OpenOptions::new().read(true).open(path)?;
+    file.read_to_string(&mut secret)?;
+
+    parse_installed_application_secret(&secret)
+}
+
+/// Read an application secret for the installed or device flow from a JSON string. See
+/// `read_installed_application_secret()`.
+pub fn parse_installed_application_secret<S: AsRef<str>>(
+    secret: S,
+) -> io::Result<ApplicationSecret> {
+    let decoded = decode_console_application_secret(secret.as_ref())?;
+    match decoded.installed {
+        Some(secret) => {
+            let secret = sanitize_application_secret(secret)?;
+            validate_application_secret(&secret)?;
+            Ok(secret)
+        }
+        None if decoded.web.is_some() => Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            format!("Bad application secret: {}", e),
+            "This is a web client; the installed flow requires an installed-type client. \
+             Create an OAuth client ID of type \"Desktop app\" (or \"Other\") in the console.",
         )),
-        Ok(decoded) => {
-            if decoded.web.is_some() {
-                Ok(decoded.web.unwrap())
-            } else if decoded.installed.is_some() {
-                Ok(decoded.installed.unwrap())
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Unknown application secret format",
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unknown application secret format",
+        )),
+    }
+}
+
+fn decode_console_application_secret(secret: &str) -> io::Result<ConsoleApplicationSecret> {
+    serde_json::from_str(secret).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bad application secret: {}", e),
+        )
+    })
+}
+
+/// Google's refresh tokens start with this, followed by about 100 more characters.
+const GOOGLE_REFRESH_TOKEN_PREFIX: &str = "1//";
+
+/// Google refresh tokens shorter than this were cut off, e.g. when copied from a terminal.
+const MIN_GOOGLE_REFRESH_TOKEN_LEN: usize = 40;
+
+/// Clean up a credential, like a client secret, as loaded from a file or an environment
+/// variable: surrounding whitespace, like the trailing newline of a file, is removed. Values
+/// which are empty, or contain whitespace or control characters, e.g. because a line was
+/// wrapped when copying them, are refused with an error naming `what` the credential is.
+pub fn sanitize_credential(what: &str, value: &str) -> io::Result<String> {
+    let invalid = |problem: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bad {}: {}", what, problem),
+        ))
+    };
+    let value = value.trim();
+    if value.is_empty() {
+        return invalid("it is empty");
+    }
+    if value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return invalid("it contains whitespace or control characters; was a line wrapped?");
+    }
+    Ok(value.to_string())
+}
+
+/// Like `sanitize_credential()`, for refresh tokens, which are also URL-decoded if they were
+/// copied from a URL or form body, like `1%2F%2F0g...`. Google refresh tokens too short to be
+/// complete are refused, rather than failing with an opaque `invalid_grant` when refreshing.
+pub fn sanitize_refresh_token(refresh_token: &str) -> io::Result<String> {
+    let mut token = sanitize_credential("refresh token", refresh_token)?;
+    if token.contains('%') {
+        if let Ok(decoded) = percent_decode(token.as_bytes()).decode_utf8() {
+            token = sanitize_credential("refresh token", &decoded)?;
+        }
+    }
+    if token.starts_with(GOOGLE_REFRESH_TOKEN_PREFIX) && token.len() < MIN_GOOGLE_REFRESH_TOKEN_LEN
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Bad refresh token: {} characters are too few for a Google refresh token; \
+                 was it cut off?",
+                token.len()
+            ),
+        ));
+    }
+    Ok(token)
+}
+
+/// Read a refresh token from a file, e.g. one written by `echo $TOKEN > file`, see
+/// `sanitize_refresh_token()`.
+pub fn read_refresh_token<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    sanitize_refresh_token(&fs::read_to_string(path)?)
+}
+
+/// Trims the client ID and secret of `secret`, see `sanitize_credential()`. Public clients
+/// without a client secret are fine.
+fn sanitize_application_secret(mut secret: ApplicationSecret) -> io::Result<ApplicationSecret> {
+    if !secret.client_id.trim().is_empty() {
+        secret.client_id = sanitize_credential("client_id", &secret.client_id)?;
+    }
+    if !secret.client_secret.trim().is_empty() {
+        secret.client_secret = sanitize_credential("client_secret", &secret.client_secret)?;
+    }
+    Ok(secret)
+}
+
+/// Check an application secret for common misconfigurations: missing client ID, endpoint URIs
+/// that are not HTTP(S) URLs and redirect URIs that are neither URLs nor the out-of-band marker.
+pub fn validate_application_secret(secret: &ApplicationSecret) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+    if secret.client_id.is_empty() {
+        return invalid("Bad application secret: client_id is empty".to_string());
+    }
+    for (name, uri) in &[
+        ("auth_uri", &secret.auth_uri),
+        ("token_uri", &secret.token_uri),
+    ] {
+        match Url::parse(uri) {
+            Ok(ref url) if url.scheme() == "https" || url.scheme() == "http" => {}
+            Ok(_) => {
+                return invalid(format!(
+                    "Bad application secret: {} '{}' is not an HTTP(S) URL",
+                    name, uri
+                ))
+            }
+            Err(e) => {
+                return invalid(format!(
+                    "Bad application secret: {} '{}' is not a valid URL: {}",
+                    name, uri, e
                 ))
             }
         }
     }
+    for uri in &secret.redirect_uris {
+        if uri == "oob" || uri.starts_with("urn:ietf:wg:oauth:2.0:oob") {
+            continue;
+        }
+        if let Err(e) = Url::parse(uri) {
+            return invalid(format!(
+                "Bad application secret: redirect URI '{}' is not a valid URL: {}",
+                uri, e
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Read the PEM-encoded certificates in `pem`, like a corporate CA bundle, to be trusted using
+/// `Authenticator::root_certificates()`.
+pub fn root_certificates_from_pem<S: AsRef<[u8]>>(pem: S) -> io::Result<Vec<rustls::Certificate>> {
+    let certificates = rustls::internal::pemfile::certs(&mut pem.as_ref())
+        .map_err(|()| io::Error::new(io::ErrorKind::InvalidData, "Bad PEM certificates"))?;
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No PEM certificates found",
+        ));
+    }
+    for certificate in &certificates {
+        rustls::RootCertStore::empty()
+            .add(certificate)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bad root certificate: {:?}", e),
+                )
+            })?;
+    }
+    Ok(certificates)
+}
+
+/// Read the PEM-encoded certificates in the file at `path`, see `root_certificates_from_pem()`.
+pub fn read_root_certificates<P: AsRef<Path>>(path: P) -> io::Result<Vec<rustls::Certificate>> {
+    root_certificates_from_pem(fs::read(path)?)
 }
 
 /// Read a service account key from a JSON file. You can download the JSON keys from the Google
 /// Cloud Console or the respective console of your service provider.
+#[cfg(feature = "service-account")]
 pub fn service_account_key_from_file<S: AsRef<Path>>(path: S) -> io::Result<ServiceAccountKey> {
     let mut key = String::new();
     let mut file = fs::OpenOptions::new().read(true).open(path)?;
@@ -61,3 +253,128 @@ pub fn service_account_key_from_file<S: AsRef<Path>>(path: S) -> io::Result<Serv
         Ok(decoded) => Ok(decoded),
     }
 }
+
+/// Read the configuration of workload identity federation, i.e. a JSON file of type
+/// `external_account`, as generated by `gcloud iam workload-identity-pools create-cred-config`.
+#[cfg(feature = "external-account")]
+pub fn external_account_key_from_file<S: AsRef<Path>>(path: S) -> io::Result<ExternalAccountKey> {
+    let key = fs::read_to_string(path)?;
+    serde_json::from_str(&key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Read a credential file of type `impersonated_service_account`, as written by
+/// `gcloud auth application-default login --impersonate-service-account`.
+#[cfg(feature = "impersonated-service-account")]
+pub fn impersonated_service_account_key_from_file<S: AsRef<Path>>(
+    path: S,
+) -> io::Result<ImpersonatedServiceAccountKey> {
+    let key = fs::read_to_string(path)?;
+    serde_json::from_str(&key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::tests::SECRET;
+
+    #[test]
+    fn test_parse_application_secret() {
+        let secret = parse_application_secret(SECRET).unwrap();
+        assert_eq!(
+            "14070749909-vgip2f1okm7bkvajhi9jugan6126io9v.apps.googleusercontent.com",
+            secret.client_id
+        );
+        assert_eq!(
+            "https://accounts.google.com/o/oauth2/token",
+            secret.token_uri
+        );
+
+        let web = SECRET.replace("\"installed\"", "\"web\"");
+        let secret = parse_application_secret(&web).unwrap();
+        assert_eq!("UqkDJd5RFwnHoiG5x5Rub8SI", secret.client_secret);
+
+        let unknown = SECRET.replace("\"installed\"", "\"other\"");
+        match parse_application_secret(&unknown) {
+            Err(e) => assert_eq!(io::ErrorKind::InvalidData, e.kind()),
+            Ok(_) => panic!("parsed unknown application secret format"),
+        }
+    }
+
+    #[test]
+    fn test_installed_application_secret() {
+        assert!(parse_installed_application_secret(SECRET).is_ok());
+
+        let web = SECRET.replace("\"installed\"", "\"web\"");
+        match parse_installed_application_secret(&web) {
+            Err(e) => assert!(format!("{}", e).contains("This is a web client")),
+            Ok(_) => panic!("accepted web client for installed flow"),
+        }
+    }
+
+    #[test]
+    fn test_validate_application_secret() {
+        let mut secret = parse_application_secret(SECRET).unwrap();
+        assert!(validate_application_secret(&secret).is_ok());
+
+        secret.token_uri = "accounts.google.com/o/oauth2/token".to_string();
+        let err = validate_application_secret(&secret).unwrap_err();
+        assert!(format!("{}", err).contains("token_uri"));
+
+        secret.token_uri = "ftp://accounts.google.com/o/oauth2/token".to_string();
+        let err = validate_application_secret(&secret).unwrap_err();
+        assert!(format!("{}", err).contains("not an HTTP(S) URL"));
+
+        secret.token_uri = "https://accounts.google.com/o/oauth2/token".to_string();
+        secret.redirect_uris.push("/oauth2callback".to_string());
+        let err = validate_application_secret(&secret).unwrap_err();
+        assert!(format!("{}", err).contains("redirect URI '/oauth2callback'"));
+    }
+
+    #[test]
+    fn test_sanitize_refresh_token() {
+        let google = "1//0gLmQ5J0aSx3NCgYIARAAGBASNwF-L9IrZ7bW8q2Yg5PpW7mHfXxK";
+        assert_eq!(
+            google,
+            sanitize_refresh_token(&format!("{}\r\n", google)).unwrap()
+        );
+        let encoded = google.replace("/", "%2F");
+        assert_eq!(google, sanitize_refresh_token(&encoded).unwrap());
+        // Other providers' tokens are only trimmed.
+        assert_eq!(
+            "my-refresh-token",
+            sanitize_refresh_token(" my-refresh-token\n").unwrap()
+        );
+
+        let err = sanitize_refresh_token(&google[..30]).unwrap_err();
+        assert!(format!("{}", err).contains("cut off"));
+        let err =
+            sanitize_refresh_token("1//0gLmQ5J0aSx3NCgYIARAAGBASNwF\nL9IrZ7bW8q2Yg5PpW7mHfXx")
+                .unwrap_err();
+        assert!(format!("{}", err).contains("line wrapped"));
+        assert!(sanitize_refresh_token("\n").is_err());
+
+        let secret = SECRET.replace("\"client_secret\":\"", "\"client_secret\":\" ");
+        let secret = parse_application_secret(&secret).unwrap();
+        assert!(!secret.client_secret.starts_with(' '));
+    }
+
+    #[test]
+    fn test_root_certificates_from_pem() {
+        let pem = "-----BEGIN CERTIFICATE-----\n\
+         MIIBkTCCATegAwIBAgIUC/WEjBv0Y0xjrJb3JtfNPs1FrqEwCgYIKoZIzj0EAwIw\n\
+         HTEbMBkGA1UEAwwSeXVwLW9hdXRoMiB0ZXN0IENBMCAXDTI2MTAxNDE1NTc1NVoY\n\
+         DzIxMjYwOTIwMTU1NzU1WjAdMRswGQYDVQQDDBJ5dXAtb2F1dGgyIHRlc3QgQ0Ew\n\
+         WTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAARnomQ8QxW2TgCHtt6x47LI8Czyz1TR\n\
+         C7Vzg62+zhwNiOppii3aAoXKXV4M7Qmkt6daoIFkZtPO6hL3K9YHm5QMo1MwUTAd\n\
+         BgNVHQ4EFgQUS2dPHsCvoV758Do/JFzY95Xk/OQwHwYDVR0jBBgwFoAUS2dPHsCv\n\
+         oV758Do/JFzY95Xk/OQwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBF\n\
+         AiEAhF48LNPjiYzF/8hZ4Jw/iIUmNx4p8DzmZSC0WBFpbxgCIDobg9Xvo8NNXIpX\n\
+         V5yW+0WLAeukofsGbK/ANgUmYxp+\n\
+         -----END CERTIFICATE-----";
+        let certificates = root_certificates_from_pem(format!("{}\n{}\n", pem, pem)).unwrap();
+        assert_eq!(2, certificates.len());
+        assert!(root_certificates_from_pem("").is_err());
+        let broken = pem.replace("MIIB", "MIIC");
+        assert!(root_certificates_from_pem(broken).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! Helpers for calling services behind Google's Identity-Aware Proxy (IAP).
+//!
+//! IAP admits requests carrying an ID token whose audience is the OAuth client ID of the IAP,
+//! like `1234-abc.apps.googleusercontent.com`. The token is usually sent in the
+//! `Proxy-Authorization` header, leaving the `Authorization` header to the service behind the
+//! proxy.
+//!
+//! Resources:
+//! - [Programmatic authentication](https://cloud.google.com/iap/docs/authentication-howto)
+
+use futures::prelude::*;
+
+use crate::types::{GetToken, RequestError, Scheme, Token, TokenType};
+
+/// Obtains ID tokens for an Identity-Aware Proxy from a token source issuing ID tokens, like
+/// `ServiceAccountAccess`, `ImpersonatedServiceAccountAccess` or `MetadataServerAccess`, and
+/// attaches them to requests.
+///
+/// The tokens are cached by the token source.
+pub struct IapAccess<G> {
+    source: G,
+    client_id: String,
+}
+
+impl<G: GetToken> IapAccess<G> {
+    /// Obtain tokens from `source` for the IAP with OAuth client ID `client_id`.
+    pub fn new<S: Into<String>>(source: G, client_id: S) -> IapAccess<G> {
+        IapAccess {
+            source,
+            client_id: client_id.into(),
+        }
+    }
+
+    /// The IAP's OAuth client ID, the audience of the tokens.
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Returns an ID token for the IAP.
+    pub fn token(&self) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.source.id_token(&self.client_id)
+    }
+
+    /// Returns `request` with an ID token for the IAP in its `Proxy-Authorization` header.
+    pub fn authorize<B: Send + 'static>(
+        &self,
+        mut request: hyper::Request<B>,
+    ) -> Box<dyn Future<Item = hyper::Request<B>, Error = RequestError> + Send> {
+        Box::new(self.token().and_then(move |token| {
+            set_proxy_authorization(request.headers_mut(), &token)?;
+            Ok(request)
+        }))
+    }
+}
+
+/// Sets the `Proxy-Authorization` header to `token`, e.g. as returned by `IapAccess::token()`.
+/// Fails if the token can't be sent in a header.
+pub fn set_proxy_authorization(
+    headers: &mut hyper::HeaderMap,
+    token: &Token,
+) -> Result<(), RequestError> {
+    let scheme = Scheme {
+        token_type: TokenType::Bearer,
+        access_token: token.access_token.clone(),
+    };
+    headers.insert(hyper::header::PROXY_AUTHORIZATION, scheme.header_value()?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ApplicationSecret;
+    use futures::future;
+    use std::sync::Mutex;
+
+    /// Issues ID tokens naming their audience, and records the requested audiences.
+    #[derive(Default)]
+    struct IdTokens(Mutex<Vec<String>>);
+
+    impl GetToken for IdTokens {
+        fn token<I, T>(
+            &self,
+            _scopes: I,
+        ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        where
+            T: Into<String>,
+            I: IntoIterator<Item = T>,
+        {
+            Box::new(future::err(RequestError::UserError(
+                "only ID tokens are issued".to_string(),
+            )))
+        }
+
+        fn invalidate(&self, _access_token: &str) -> Result<bool, RequestError> {
+            Ok(false)
+        }
+
+        fn application_secret(&self) -> ApplicationSecret {
+            Default::default()
+        }
+
+        fn api_key(&self) -> Option<String> {
+            None
+        }
+
+        fn id_token(
+            &self,
+            audience: &str,
+        ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+            self.0.lock().unwrap().push(audience.to_string());
+            Box::new(future::ok(Token::from_jwt(format!(
+                "id-token-for-{}",
+                audience
+            ))))
+        }
+    }
+
+    #[test]
+    fn test_iap_authorize() {
+        let iap = IapAccess::new(IdTokens::default(), "1234-abc.apps.googleusercontent.com");
+        let request = hyper::Request::get("https://internal.example.com/")
+            .header(hyper::header::AUTHORIZATION, "Basic YXBwOnNlY3JldA==")
+            .body(())
+            .unwrap();
+        let request = iap.authorize(request).wait().unwrap();
+        assert_eq!(
+            "Bearer id-token-for-1234-abc.apps.googleusercontent.com",
+            request.headers()[hyper::header::PROXY_AUTHORIZATION]
+        );
+        // The service's own credentials are kept.
+        assert_eq!(
+            "Basic YXBwOnNlY3JldA==",
+            request.headers()[hyper::header::AUTHORIZATION]
+        );
+        assert_eq!(
+            vec!["1234-abc.apps.googleusercontent.com".to_string()],
+            *iap.source.0.lock().unwrap()
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! This module provides a token source (`GetToken`) for credential files of type
+//! `impersonated_service_account`, as written by
+//! `gcloud auth application-default login --impersonate-service-account`.
+//!
+//! Such a file contains the source credentials, which are used to obtain a `cloud-platform`
+//! token of the caller, and the `generateAccessToken` URL of the service account to impersonate.
+//! The caller's token is then exchanged for a token of the service account, optionally through a
+//! chain of `delegates`, each of which must be allowed to impersonate the next one.
+//!
+//! Resources:
+//! - [Service account impersonation](https://cloud.google.com/iam/docs/service-account-impersonation)
+//! - [`generateAccessToken`](https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateAccessToken)
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, prelude::*};
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::config_check::ConfigReport;
+#[cfg(feature = "external-account")]
+use crate::external_account::{ExternalAccountAccess, ExternalAccountKey};
+use crate::refresh::RefreshFlow;
+#[cfg(feature = "service-account")]
+use crate::service_account::{ServiceAccountAccess, ServiceAccountKey};
+use crate::token_cache::TokenCache;
+use crate::transport;
+use crate::types::{
+    ApplicationSecret, DefaultTokenResponseParser, GetToken, RefreshResult, RequestError, Token,
+};
+
+#[cfg(any(feature = "external-account", feature = "service-account"))]
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const GOOGLE_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+/// The lifetime of impersonated tokens requested by gcloud.
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = RequestError> + Send>;
+
+/// The contents of a credential file of type `impersonated_service_account`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImpersonatedServiceAccountKey {
+    #[serde(rename = "type")]
+    pub key_type: Option<String>,
+    /// The `generateAccessToken` URL of the service account to impersonate.
+    pub service_account_impersonation_url: String,
+    /// Service accounts impersonated in turn before the target one, as
+    /// `projects/-/serviceAccounts/<email>`.
+    #[serde(default)]
+    pub delegates: Vec<String>,
+    /// The credentials of the caller.
+    pub source_credentials: SourceCredentials,
+    pub quota_project_id: Option<String>,
+}
+
+/// The credentials used to impersonate a service account, distinguished by their `type`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceCredentials {
+    /// A user's refresh token, as stored by `gcloud auth application-default login`.
+    AuthorizedUser(AuthorizedUserSecret),
+    /// A service account key.
+    #[cfg(feature = "service-account")]
+    ServiceAccount(Box<ServiceAccountKey>),
+    /// A workload identity federation configuration.
+    #[cfg(feature = "external-account")]
+    ExternalAccount(Box<ExternalAccountKey>),
+}
+
+/// The credentials of a user authorized by `gcloud`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuthorizedUserSecret {
+    pub client_id: String,
+    pub client_secret: String,
+    pub refresh_token: String,
+    /// The token endpoint; Google's unless set.
+    #[serde(default = "default_token_uri")]
+    pub token_uri: String,
+    pub quota_project_id: Option<String>,
+}
+
+fn default_token_uri() -> String {
+    GOOGLE_TOKEN_URI.to_string()
+}
+
+/// Obtains tokens of a service account by impersonating it with other credentials.
+///
+/// Tokens are cached in memory until they expire, and requested again after that.
+pub struct ImpersonatedServiceAccountAccess<C> {
+    client: C,
+    key: ImpersonatedServiceAccountKey,
+    lifetime: Duration,
+}
+
+impl ImpersonatedServiceAccountAccess<DefaultHyperClient> {
+    /// Create a new ImpersonatedServiceAccountAccess with the provided configuration.
+    pub fn new(key: ImpersonatedServiceAccountKey) -> Self {
+        ImpersonatedServiceAccountAccess {
+            client: DefaultHyperClient::default(),
+            key,
+            lifetime: DEFAULT_LIFETIME,
+        }
+    }
+
+    /// Keep connections to the token endpoints alive between requests. Disabled by default.
+    pub fn keep_alive(self, keep_alive: bool) -> Self {
+        ImpersonatedServiceAccountAccess {
+            client: DefaultHyperClient {
+                keep_alive,
+                ..self.client
+            },
+            ..self
+        }
+    }
+}
+
+impl<C> ImpersonatedServiceAccountAccess<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> ImpersonatedServiceAccountAccess<NewC> {
+        ImpersonatedServiceAccountAccess {
+            client: hyper_client,
+            key: self.key,
+            lifetime: self.lifetime,
+        }
+    }
+
+    /// How long the service account's tokens are valid. (default: one hour)
+    ///
+    /// Lifetimes up to 12 hours, for jobs outliving a token, require the organization policy
+    /// `iam.allowServiceAccountCredentialLifetimeExtension` to list the service account; longer
+    /// ones are refused with `RequestError::UserError` when a token is requested.
+    pub fn lifetime(self, lifetime: Duration) -> Self {
+        ImpersonatedServiceAccountAccess { lifetime, ..self }
+    }
+
+    /// Build the configured ImpersonatedServiceAccountAccess.
+    pub fn build(self) -> impl GetToken + Send + Sync
+    where
+        C::Connector: Send + Sync,
+    {
+        let client = self.client.build_hyper_client();
+        let source = source_token(client.clone(), self.key.source_credentials.clone());
+        ImpersonatedServiceAccountAccessImpl {
+            client,
+            key: Arc::new(self.key),
+            source,
+            cache: TokenCache::new(),
+            lifetime_secs: self.lifetime.as_secs() as i64,
+        }
+    }
+}
+
+/// Returns a function obtaining a `cloud-platform` token using `credentials`.
+fn source_token<C>(
+    client: hyper::Client<C>,
+    credentials: SourceCredentials,
+) -> Arc<dyn Fn() -> BoxFuture<Token> + Send + Sync>
+where
+    C: 'static + hyper::client::connect::Connect + Send + Sync,
+{
+    match credentials {
+        SourceCredentials::AuthorizedUser(user) => {
+            let secret = ApplicationSecret {
+                client_id: user.client_id,
+                client_secret: user.client_secret,
+                token_uri: user.token_uri,
+                ..Default::default()
+            };
+            let refresh_token = user.refresh_token;
+            Arc::new(move || -> BoxFuture<Token> {
+                Box::new(
+                    RefreshFlow::refresh_token_with_parser(
+                        client.clone(),
+                        secret.clone(),
+                        refresh_token.clone(),
+                        DefaultTokenResponseParser,
+                    )
+                    .and_then(|result| match result {
+                        RefreshResult::Success(token) => Ok(token),
+                        result => Err(RequestError::Refresh(result)),
+                    }),
+                )
+            })
+        }
+        #[cfg(feature = "service-account")]
+        SourceCredentials::ServiceAccount(key) => {
+            let access = ServiceAccountAccess::new(*key).hyper_client(client).build();
+            Arc::new(move || access.token(vec![CLOUD_PLATFORM_SCOPE]))
+        }
+        #[cfg(feature = "external-account")]
+        SourceCredentials::ExternalAccount(key) => {
+            let access = ExternalAccountAccess::new(*key)
+                .hyper_client(client)
+                .build();
+            Arc::new(move || access.token(vec![CLOUD_PLATFORM_SCOPE]))
+        }
+    }
+}
+
+struct ImpersonatedServiceAccountAccessImpl<C> {
+    client: hyper::Client<C, hyper::Body>,
+    key: Arc<ImpersonatedServiceAccountKey>,
+    source: Arc<dyn Fn() -> BoxFuture<Token> + Send + Sync>,
+    cache: TokenCache,
+    lifetime_secs: i64,
+}
+
+impl<C: 'static + hyper::client::connect::Connect> ImpersonatedServiceAccountAccessImpl<C> {
+    /// Obtains a token of the source credentials, and exchanges it for one of the service
+    /// account for `scopes`.
+    fn fetch_token(&self, scopes: Vec<String>) -> BoxFuture<Token> {
+        let client = self.client.clone();
+        let key = self.key.clone();
+        let lifetime_secs = self.lifetime_secs;
+        Box::new((self.source)().and_then(move |source| {
+            transport::generate_access_token(
+                &client,
+                &key.service_account_impersonation_url,
+                &source.access_token,
+                &scopes,
+                &key.delegates,
+                lifetime_secs,
+            )
+        }))
+    }
+}
+
+impl<C: 'static> GetToken for ImpersonatedServiceAccountAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        if let Err(e) = transport::check_impersonation_lifetime(self.lifetime_secs) {
+            return Box::new(future::err(e));
+        }
+        self.cache.token(scopes, |scopes| self.fetch_token(scopes))
+    }
+
+    /// Obtains a new token unless the cached one remains valid for `duration`.
+    fn token_valid_for<I, T>(
+        &self,
+        duration: Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        if let Err(e) = transport::check_impersonation_lifetime(self.lifetime_secs) {
+            return Box::new(future::err(e));
+        }
+        self.cache
+            .token_valid_for(duration, scopes, |scopes| self.fetch_token(scopes))
+    }
+
+    /// Drops the cached token for `scopes`, then requests a new one.
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes = self.cache.forget(scopes);
+        self.token(scopes)
+    }
+
+    /// Drops the cached token with `access_token`, so that a new one is requested next.
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.cache.invalidate(access_token)
+    }
+
+    /// Checks the lifetime, that the source credentials yield a token, and that the
+    /// impersonation endpoint responds.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let mut report = ConfigReport::default();
+        let lifetime = transport::check_impersonation_lifetime(self.lifetime_secs);
+        report.check("token lifetime", lifetime.map_err(|e| e.to_string()));
+        let source = (self.source)().then(|token| Ok(token.map(|_| ()).map_err(|e| e.to_string())));
+        let endpoints = vec![(
+            "service account impersonation endpoint",
+            self.key.service_account_impersonation_url.clone(),
+        )];
+        Box::new(report.reachable(&self.client, endpoints).join(source).map(
+            |(mut report, source)| {
+                report.check("source credentials", source);
+                report
+            },
+        ))
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        Default::default()
+    }
+
+    fn api_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns an ID token of the service account for `audience`, cached until it expires.
+    fn id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        let (client, key) = (self.client.clone(), self.key.clone());
+        self.cache.id_token(audience, || {
+            let audience = audience.to_string();
+            (self.source)().and_then(move |source| {
+                transport::generate_id_token(
+                    &client,
+                    &key.service_account_impersonation_url,
+                    &source.access_token,
+                    &audience,
+                    &key.delegates,
+                )
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper_rustls::HttpsConnector;
+    use mockito::{self, mock, Matcher};
+
+    #[test]
+    fn test_impersonate_with_authorized_user() {
+        let key: ImpersonatedServiceAccountKey = serde_json::from_value(serde_json::json!({
+            "type": "impersonated_service_account",
+            "service_account_impersonation_url": format!(
+                "{}/impersonated/v1/projects/-/serviceAccounts/deploy@p.iam.gserviceaccount.com:generateAccessToken",
+                mockito::server_url()
+            ),
+            "delegates": ["projects/-/serviceAccounts/hop@p.iam.gserviceaccount.com"],
+            "source_credentials": {
+                "type": "authorized_user",
+                "client_id": "id",
+                "client_secret": "secret",
+                "refresh_token": "1/user",
+                "token_uri": format!("{}/impersonated/token", mockito::server_url()),
+            },
+        }))
+        .unwrap();
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let too_long = ImpersonatedServiceAccountAccess::new(key.clone())
+            .hyper_client(client.clone())
+            .lifetime(Duration::from_secs(13 * 3600))
+            .build();
+        match rt.block_on(too_long.token(vec!["openid"])) {
+            Err(RequestError::UserError(msg)) => assert!(msg.contains("12 hours")),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let access = ImpersonatedServiceAccountAccess::new(key)
+            .hyper_client(client)
+            .lifetime(Duration::from_secs(12 * 3600))
+            .build();
+
+        let _refresh = mock("POST", "/impersonated/token")
+            .match_body(Matcher::Regex("refresh_token=1%2Fuser".to_string()))
+            .with_body(
+                r#"{"access_token": "user-token", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create();
+        // The lifetime granted is shorter than the one requested.
+        let expires_at = crate::time::now() + 3600;
+        let (year, month, day, hour, minute, second) = crate::time::utc_fields(expires_at);
+        let expire_time = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, hour, minute, second
+        );
+        let _generate = mock(
+            "POST",
+            "/impersonated/v1/projects/-/serviceAccounts/deploy@p.iam.gserviceaccount.com:generateAccessToken",
+        )
+        .match_header("authorization", "Bearer user-token")
+        .match_body(Matcher::Regex(
+            r#""delegates":\["projects/-/serviceAccounts/hop@p.iam.gserviceaccount.com"\],"lifetime":"43200s","scope":\["https://www.googleapis.com/auth/devstorage.read_only"\]"#
+                .to_string(),
+        ))
+        .with_body(format!(
+            r#"{{"accessToken": "impersonated", "expireTime": "{}"}}"#,
+            expire_time
+        ))
+        .expect(1)
+        .create();
+
+        let scopes = vec!["https://www.googleapis.com/auth/devstorage.read_only"];
+        let token = rt.block_on(access.token(scopes.clone())).unwrap();
+        assert_eq!("impersonated", token.access_token);
+        assert_eq!(
+            Some(expires_at),
+            token.expires_at().map(|t| crate::time::to_secs(&t))
+        );
+        // Cached.
+        let token = rt.block_on(access.token(scopes)).unwrap();
+        assert_eq!("impersonated", token.access_token);
+        _refresh.assert();
+        _generate.assert();
+    }
+}
@@ -3,18 +3,24 @@
 // Refer to the project root for licensing information.
 //
 use std::convert::AsRef;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::{Arc, Mutex};
 
-use futures::prelude::*;
-use futures::stream::Stream;
 use futures::sync::oneshot;
+use futures::{future, prelude::*};
 use hyper;
-use hyper::{header, StatusCode, Uri};
+use hyper::{StatusCode, Uri};
+use ring::digest;
 use url::form_urlencoded;
-use url::percent_encoding::{percent_encode, QUERY_ENCODE_SET};
+use url::percent_encoding::{percent_encode, EncodeSet, QUERY_ENCODE_SET};
 
 use crate::authenticator_delegate::{DefaultFlowDelegate, FlowDelegate};
-use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+use crate::config_check::ConfigReport;
+use crate::random::{RandomSource, Rng};
+use crate::transport;
+use crate::types::{
+    ApplicationSecret, GetToken, JsonError, RedirectUriKind, RequestError, ScopeSeparator, Token,
+};
 
 const OOB_REDIRECT_URI: &'static str = "urn:ietf:wg:oauth:2.0:oob";
 
@@ -25,63 +31,100 @@ fn build_authentication_request_url<'a, T, I>(
     auth_uri: &str,
     client_id: &str,
     scopes: I,
+    scope_separator: ScopeSeparator,
     redirect_uri: Option<String>,
+    extra_params: &[(&str, &str)],
 ) -> String
 where
     T: AsRef<str> + 'a,
     I: IntoIterator<Item = &'a T>,
 {
-    let mut url = String::new();
-    let mut scopes_string = scopes.into_iter().fold(String::new(), |mut acc, sc| {
-        acc.push_str(sc.as_ref());
-        acc.push_str(" ");
-        acc
-    });
-    // Remove last space
-    scopes_string.pop();
+    let scopes_string = scope_separator.join(scopes);
 
-    url.push_str(auth_uri);
-    vec![
-        format!("?scope={}", scopes_string),
-        format!("&access_type=offline"),
-        format!(
-            "&redirect_uri={}",
-            redirect_uri.unwrap_or(OOB_REDIRECT_URI.to_string())
-        ),
-        format!("&response_type=code"),
-        format!("&client_id={}", client_id),
-    ]
-    .into_iter()
-    .fold(url, |mut u, param| {
-        u.push_str(&percent_encode(param.as_ref(), QUERY_ENCODE_SET).to_string());
-        u
-    })
+    let redirect_uri = redirect_uri.unwrap_or(OOB_REDIRECT_URI.to_string());
+    let mut params = vec![("scope", scopes_string.as_str())];
+    if is_google_auth_uri(auth_uri) {
+        params.push(("access_type", "offline"));
+    }
+    params.extend_from_slice(&[
+        ("redirect_uri", redirect_uri.as_str()),
+        ("response_type", "code"),
+        ("client_id", client_id),
+    ]);
+    // Some providers' authorization endpoints already contain a query, e.g. to select a policy.
+    let mut separator = if auth_uri.contains('?') { '&' } else { '?' };
+    let mut url = auth_uri.to_string();
+    for (name, value) in params.iter().chain(extra_params) {
+        url.push(separator);
+        url.push_str(name);
+        url.push('=');
+        url.push_str(&percent_encode(value.as_bytes(), QueryValueEncodeSet).to_string());
+        separator = '&';
+    }
+    url
+}
+
+/// Whether `auth_uri` is Google's authorization endpoint, which only issues refresh tokens if
+/// asked for `access_type=offline`. Other providers don't know the parameter; some reject it.
+fn is_google_auth_uri(auth_uri: &str) -> bool {
+    url::Url::parse(auth_uri)
+        .ok()
+        .map_or(false, |url| url.host_str() == Some("accounts.google.com"))
+}
+
+/// Like `QUERY_ENCODE_SET`, but also encodes the characters which separate query parameters, so
+/// that e.g. redirect URIs containing a query survive.
+#[derive(Clone)]
+struct QueryValueEncodeSet;
+
+impl EncodeSet for QueryValueEncodeSet {
+    fn contains(&self, byte: u8) -> bool {
+        QUERY_ENCODE_SET.contains(byte) || b"&+=;".contains(&byte)
+    }
 }
 
 impl<FD: FlowDelegate + 'static + Send + Clone, C: hyper::client::connect::Connect + 'static>
     GetToken for InstalledFlowImpl<FD, C>
 {
-    fn token<I, T>(
-        &mut self,
-        scopes: I,
-    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
     where
         T: Into<String>,
         I: IntoIterator<Item = T>,
     {
-        Box::new(self.obtain_token(scopes.into_iter().map(Into::into).collect()))
+        Box::new(crate::time::time_boxed(
+            self.obtain_token(scopes.into_iter().map(Into::into).collect()),
+            self.deadline,
+        ))
     }
-    fn api_key(&mut self) -> Option<String> {
+    fn api_key(&self) -> Option<String> {
         None
     }
     fn application_secret(&self) -> ApplicationSecret {
         self.appsecret.clone()
     }
+    /// Checks the secret and the redirect path, and that the authorization and token endpoints
+    /// respond.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let endpoints = vec![
+            ("authorization endpoint", self.appsecret.auth_uri.clone()),
+            ("token endpoint", self.appsecret.token_uri.clone()),
+        ];
+        let mut report = ConfigReport::for_secret(&self.appsecret);
+        let path = self.server_config.check_path().map_err(|e| e.to_string());
+        report.check("redirect path", path);
+        Box::new(report.reachable(&self.client, endpoints))
+    }
 }
 
 /// The InstalledFlow implementation.
 pub struct InstalledFlowImpl<FD: FlowDelegate, C: hyper::client::connect::Connect + 'static> {
     method: InstalledFlowReturnMethod,
+    server_config: ServerConfig,
+    headless_fallback: bool,
+    oauth21: bool,
+    redirect_uri: Option<String>,
+    rng: Rng,
+    deadline: Option<std::time::Duration>,
     client: hyper::client::Client<C, hyper::Body>,
     fd: FD,
     appsecret: ApplicationSecret,
@@ -99,6 +142,18 @@ pub enum InstalledFlowReturnMethod {
     /// the server with a URL containing the code (preferred, but not as reliable). The
     /// parameter is the port to listen on.
     HTTPRedirect(u16),
+    /// For browsers running on a different machine than the application, e.g. on the user's
+    /// laptop while the application runs on a host the user is logged in to via SSH. The
+    /// browser is redirected to one of the application secret's registered loopback redirect
+    /// URIs, like `http://localhost`, which won't load as nothing listens there on the user's
+    /// machine; the user then pastes the address from the browser's address bar into the
+    /// application, see `FlowDelegate::present_user_url()`.
+    ///
+    /// To use a relay page displaying the code instead, set it with
+    /// `InstalledFlow::redirect_uri()`. Without `present_user_url()`, e.g. if the application
+    /// can't read from a terminal, print the URL of `InstalledFlow::start()` and pass the
+    /// pasted address to `InstalledFlow::finish_redirect()`.
+    Remote,
 }
 
 /// InstalledFlowImpl provides tokens for services that follow the "Installed" OAuth flow. (See
@@ -108,6 +163,142 @@ pub struct InstalledFlow<FD: FlowDelegate> {
     method: InstalledFlowReturnMethod,
     flow_delegate: FD,
     appsecret: ApplicationSecret,
+    server_config: ServerConfig,
+    headless_fallback: bool,
+    oauth21: bool,
+    redirect_uri: Option<String>,
+    rng: Rng,
+    deadline: Option<std::time::Duration>,
+}
+
+/// Options of the local server receiving the redirect.
+#[derive(Clone, Debug, Default)]
+struct ServerConfig {
+    https: bool,
+    success_page: Option<RedirectPage>,
+    error_page: Option<RedirectPage>,
+    /// The address to bind to; `None` binds to 127.0.0.1 and redirects to `localhost`.
+    address: Option<IpAddr>,
+    /// The path to redirect to, e.g. `/oauth2callback`; `None` accepts any path.
+    path: Option<String>,
+}
+
+impl ServerConfig {
+    fn bind_address(&self, port: u16) -> SocketAddr {
+        SocketAddr::new(self.address.unwrap_or(Ipv4Addr::LOCALHOST.into()), port)
+    }
+
+    /// Fails with `RequestError::UserError` if the `redirect_path()` doesn't start with a slash,
+    /// which would make the redirect URI invalid.
+    fn check_path(&self) -> Result<(), RequestError> {
+        match self.path {
+            Some(ref path) if !path.starts_with('/') => Err(RequestError::UserError(format!(
+                "The redirect path {:?} doesn't start with '/'",
+                path
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn accepts_path(&self, path: &str) -> bool {
+        self.path.as_ref().map(|p| p == path).unwrap_or(true)
+    }
+
+    /// Adopts the host and path of the first loopback URI among the `redirect_uris` of
+    /// `appsecret`, unless `redirect_address()` or `redirect_path()` set them.
+    fn with_registered(&self, appsecret: &ApplicationSecret) -> ServerConfig {
+        let mut config = self.clone();
+        let registered = match appsecret
+            .redirect_uri(RedirectUriKind::Loopback)
+            .and_then(|uri| url::Url::parse(uri).ok())
+        {
+            Some(url) => url,
+            None => return config,
+        };
+        if config.address.is_none() {
+            config.address = match registered.host_str() {
+                Some("127.0.0.1") => Some(Ipv4Addr::LOCALHOST.into()),
+                Some("[::1]") => Some(Ipv6Addr::LOCALHOST.into()),
+                _ => None,
+            };
+        }
+        if config.path.is_none() && registered.path() != "/" {
+            config.path = Some(registered.path().to_string());
+        }
+        config
+    }
+
+    /// The URI the provider has to redirect to, if the server listens on `port`.
+    fn redirect_uri(&self, port: u16) -> String {
+        let scheme = if self.https { "https" } else { "http" };
+        let host = match self.address {
+            None => "localhost".to_string(),
+            Some(IpAddr::V4(ip)) => ip.to_string(),
+            Some(IpAddr::V6(ip)) => format!("[{}]", ip),
+        };
+        format!(
+            "{}://{}:{}{}",
+            scheme,
+            host,
+            port,
+            self.path.as_deref().unwrap_or("")
+        )
+    }
+}
+
+/// What the local server receiving the redirect shows in the browser afterwards.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RedirectPage {
+    /// Show this HTML page. In error pages, `{error}` is replaced by the HTML-escaped error
+    /// reported by the provider.
+    Html(String),
+    /// Redirect the browser to this URL, e.g. to the application's website.
+    Redirect(String),
+}
+
+const DEFAULT_SUCCESS_PAGE: &str =
+    "<html><head><title>Success</title></head><body>You may now close this window.</body></html>";
+const DEFAULT_ERROR_PAGE: &str = "<html><head><title>Error</title></head><body>Authorization \
+     failed: {error}. You may now close this window.</body></html>";
+
+impl RedirectPage {
+    fn response(
+        &self,
+        status: StatusCode,
+        error: Option<&str>,
+    ) -> Result<hyper::Response<hyper::Body>, hyper::http::Error> {
+        match *self {
+            RedirectPage::Html(ref html) => {
+                let html = match error {
+                    Some(error) => html.replace("{error}", &escape_html(error)),
+                    None => html.clone(),
+                };
+                hyper::Response::builder()
+                    .status(status)
+                    .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+                    .body(hyper::Body::from(html))
+            }
+            RedirectPage::Redirect(ref url) => hyper::Response::builder()
+                .status(StatusCode::FOUND)
+                .header(hyper::header::LOCATION, url.as_str())
+                .body(hyper::Body::empty()),
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.chars()
+        .fold(String::with_capacity(s.len()), |mut acc, c| {
+            match c {
+                '&' => acc.push_str("&amp;"),
+                '<' => acc.push_str("&lt;"),
+                '>' => acc.push_str("&gt;"),
+                '"' => acc.push_str("&quot;"),
+                '\'' => acc.push_str("&#39;"),
+                c => acc.push(c),
+            }
+            acc
+        })
 }
 
 impl InstalledFlow<DefaultFlowDelegate> {
@@ -120,6 +311,12 @@ impl InstalledFlow<DefaultFlowDelegate> {
             method,
             flow_delegate: DefaultFlowDelegate,
             appsecret: secret,
+            server_config: ServerConfig::default(),
+            headless_fallback: false,
+            oauth21: false,
+            redirect_uri: None,
+            rng: Rng::default(),
+            deadline: None,
         }
     }
 }
@@ -134,8 +331,450 @@ where
             method: self.method,
             flow_delegate: delegate,
             appsecret: self.appsecret,
+            server_config: self.server_config,
+            headless_fallback: self.headless_fallback,
+            oauth21: self.oauth21,
+            redirect_uri: self.redirect_uri,
+            rng: self.rng,
+            deadline: self.deadline,
+        }
+    }
+
+    /// Use `source` to generate the `state` and PKCE code verifier of authorizations begun by
+    /// `start()`. (default: `OsRandom`)
+    pub fn random_source<R: 'static + RandomSource>(mut self, source: R) -> Self {
+        self.rng = Rng::new(source);
+        self
+    }
+
+    /// With the `HTTPRedirect*` return methods, fall back to `Remote` if the redirect listener
+    /// can't be started, or if running in an SSH session, where the browser most likely runs on a
+    /// different machine than the listener. The user is then asked to paste the code, or the
+    /// address the browser was redirected to, using `FlowDelegate::present_user_url()`.
+    /// (default: false)
+    pub fn headless_fallback(mut self, fallback: bool) -> Self {
+        self.headless_fallback = fallback;
+        self
+    }
+
+    /// Fail with `RequestError::TimedOut` if the flow run by the `Authenticator` doesn't finish
+    /// within `deadline`, which covers waiting for the redirect or the pasted code and
+    /// exchanging the code, so that unattended programs don't wait for a user forever. The
+    /// redirect listener is shut down then. (default: none)
+    pub fn deadline(mut self, deadline: std::time::Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Enforce OAuth 2.1: authorizations always use PKCE, and the redirect URI must be one of the
+    /// application secret's `redirect_uris`, except for the port of loopback redirect URIs like
+    /// `http://127.0.0.1`. Violations fail with `RequestError::PolicyViolation` before the user
+    /// is involved. The flow never uses the implicit or password grants. (default: false)
+    pub fn oauth21(mut self, strict: bool) -> Self {
+        self.oauth21 = strict;
+        self
+    }
+
+    /// Serve the local redirect listener over HTTPS, using an ephemeral self-signed certificate,
+    /// for providers which refuse `http://localhost` redirect URIs. As the browser will warn about
+    /// the certificate, its fingerprint is shown to the user using
+    /// `FlowDelegate::present_certificate_fingerprint()`. Only effective with the
+    /// `HTTPRedirect*` return methods.
+    #[cfg(feature = "https-redirect")]
+    pub fn https_redirect(mut self, https: bool) -> Self {
+        self.server_config.https = https;
+        self
+    }
+
+    /// Bind the redirect listener to `address` instead of 127.0.0.1, e.g. to `::1` on machines
+    /// where `localhost` resolves to the IPv6 loopback address. The redirect URI then contains
+    /// the address instead of `localhost`.
+    pub fn redirect_address(mut self, address: IpAddr) -> Self {
+        self.server_config.address = Some(address);
+        self
+    }
+
+    /// Use `path`, which must start with a slash, in the redirect URI, for client registrations
+    /// which require an exact redirect URI like `http://127.0.0.1:8080/oauth2callback`. Together
+    /// with `InstalledFlowReturnMethod::HTTPRedirect(port)`, this fixes the redirect URI entirely.
+    /// Requests to other paths are refused. Starting the local server fails with
+    /// `RequestError::UserError` for a path without the leading slash.
+    pub fn redirect_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.server_config.path = Some(path.into());
+        self
+    }
+
+    /// Use `uri` as the redirect URI instead of selecting one, e.g. to pick one of several
+    /// registered custom scheme URIs. With the `HTTPRedirect*` return methods, `uri` must reach
+    /// the local server. The flow delegate's `redirect_uri()` takes precedence.
+    ///
+    /// By default, the `HTTPRedirect*` return methods redirect to the local server, at the host and
+    /// path of the first loopback URI among the application secret's `redirect_uris`, like
+    /// `http://127.0.0.1/callback`, unless `redirect_address()` or `redirect_path()` are set.
+    /// Otherwise, the first registered out-of-band URI is used, else the first custom scheme URI,
+    /// and else the out-of-band URI regardless.
+    pub fn redirect_uri<S: Into<String>>(mut self, uri: S) -> Self {
+        self.redirect_uri = Some(uri.into());
+        self
+    }
+
+    /// The page shown in the browser after the redirect listener received the authorization
+    /// code. (default: a page asking to close the window)
+    pub fn success_page(mut self, page: RedirectPage) -> Self {
+        self.server_config.success_page = Some(page);
+        self
+    }
+
+    /// The page shown in the browser if the provider redirected with an error, e.g. because the
+    /// user denied access.
+    pub fn error_page(mut self, page: RedirectPage) -> Self {
+        self.server_config.error_page = Some(page);
+        self
+    }
+}
+
+impl<FD> InstalledFlow<FD>
+where
+    FD: FlowDelegate,
+{
+    /// Begins an authorization for `scopes` without waiting for the user, for applications
+    /// which receive the authorization code elsewhere, e.g. in a later request to a web handler.
+    /// Send the user to the returned `PendingAuthorization`'s `url`, then complete
+    /// the authorization using `finish()`. The `PendingAuthorization` can be serialized to
+    /// resume in a different process.
+    ///
+    /// The redirect URI is selected as described for `redirect_uri()`; for
+    /// `HTTPRedirect(port)`, it is the one of a local server listening on `port`, like
+    /// `http://localhost:<port>`. No local server is started.
+    ///
+    /// Fails if the flow's `RandomSource` does.
+    pub fn start<I, T>(&self, scopes: I) -> Result<PendingAuthorization, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        let server_uri = match self.method {
+            InstalledFlowReturnMethod::HTTPRedirect(port) => Some(
+                self.server_config
+                    .with_registered(&self.appsecret)
+                    .redirect_uri(port),
+            ),
+            _ => None,
+        };
+        let redirect_uri = choose_redirect_uri(
+            self.flow_delegate.redirect_uri(),
+            &self.redirect_uri,
+            server_uri,
+            matches!(self.method, InstalledFlowReturnMethod::Remote),
+            &self.appsecret,
+        );
+        start_authorization(&self.rng, &self.appsecret, redirect_uri, scopes)
+    }
+
+    /// Completes an authorization begun with `start()` by exchanging the authorization `code`
+    /// for a token.
+    ///
+    /// `state` is the `state` parameter the authorization server redirected to the redirect URI
+    /// with; if it doesn't match the one of `pending`, the request is refused. Pass `None` only
+    /// if the user copied the code manually and there is no `state`.
+    pub fn finish<C>(
+        &self,
+        client: hyper::Client<C>,
+        pending: &PendingAuthorization,
+        code: &str,
+        state: Option<&str>,
+    ) -> impl Future<Item = Token, Error = RequestError> + Send
+    where
+        C: hyper::client::connect::Connect + 'static,
+    {
+        if state.map(|s| s != pending.state).unwrap_or(false) {
+            return future::Either::A(future::err(RequestError::UserError(
+                "state of authorization response doesn't match".to_string(),
+            )));
+        }
+        if self.oauth21 {
+            if let Err(e) = check_oauth21_redirect_uri(&self.appsecret, &pending.redirect_uri) {
+                return future::Either::A(future::err(e));
+            }
+        }
+        future::Either::B(exchange_code(
+            client,
+            &self.appsecret,
+            code,
+            &pending.redirect_uri,
+            Some(&pending.pkce_verifier),
+        ))
+    }
+
+    /// Completes an authorization begun with `start()` using the `uri` the authorization server
+    /// redirected to, e.g. the deep link `com.example.app:/oauth2redirect?code=...&state=...`
+    /// the operating system passed to the application registered for the custom URI scheme.
+    ///
+    /// As other applications may receive such links as well, `uri` must carry the `state` of
+    /// `pending`, and be a redirect to its redirect URI. Errors reported by the provider are
+    /// returned like those of the token endpoint, e.g. `RequestError::AccessDenied`.
+    pub fn finish_redirect<C>(
+        &self,
+        client: hyper::Client<C>,
+        pending: &PendingAuthorization,
+        uri: &str,
+    ) -> impl Future<Item = Token, Error = RequestError> + Send
+    where
+        C: hyper::client::connect::Connect + 'static,
+    {
+        fn without_query(uri: &str) -> &str {
+            uri.split(['?', '#']).next().unwrap_or("")
+        }
+        if without_query(uri) != without_query(&pending.redirect_uri) {
+            return future::Either::A(future::err(RequestError::UserError(format!(
+                "{} is no redirect to {}",
+                without_query(uri),
+                pending.redirect_uri
+            ))));
+        }
+        // The state is checked first, so that errors forged by another application aren't
+        // taken for the provider's.
+        let response = AuthorizationResponse::from_uri(uri);
+        match response.state {
+            Some(ref state) if *state == pending.state => {}
+            Some(_) => {
+                return future::Either::A(future::err(RequestError::UserError(
+                    "state of authorization response doesn't match".to_string(),
+                )))
+            }
+            None => {
+                return future::Either::A(future::err(RequestError::UserError(
+                    "authorization response has no state".to_string(),
+                )))
+            }
+        }
+        let state = pending.state.clone();
+        match response.into_code() {
+            Ok(code) => future::Either::B(self.finish(client, pending, &code, Some(&state))),
+            Err(e) => future::Either::A(future::err(e)),
+        }
+    }
+}
+
+/// The parameters the authorization server redirected to the redirect URI with
+/// (RFC 6749, section 4.1.2).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuthorizationResponse {
+    /// The authorization code, if access was granted.
+    pub code: Option<String>,
+    /// The `state` of the authorization.
+    pub state: Option<String>,
+    /// The error code, like `access_denied`, if access wasn't granted.
+    pub error: Option<String>,
+    /// A human-readable description of the error.
+    pub error_description: Option<String>,
+}
+
+impl AuthorizationResponse {
+    /// Parses the query of a redirect URI, like `code=...&state=...`.
+    pub fn from_query(query: &str) -> AuthorizationResponse {
+        let mut response = AuthorizationResponse::default();
+        for (param, val) in form_urlencoded::parse(query.as_bytes()) {
+            let field = match param.as_ref() {
+                "code" => &mut response.code,
+                "state" => &mut response.state,
+                "error" => &mut response.error,
+                "error_description" => &mut response.error_description,
+                _ => continue,
+            };
+            *field = Some(val.into_owned());
+        }
+        response
+    }
+
+    /// Parses the parameters of the redirect `uri`, taken from its query, or else from its
+    /// fragment, where some providers put them for custom URI schemes.
+    pub fn from_uri(uri: &str) -> AuthorizationResponse {
+        let (rest, fragment) = match uri.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment)),
+            None => (uri, None),
+        };
+        let query = rest.split_once('?').map(|(_, query)| query);
+        let response = AuthorizationResponse::from_query(query.unwrap_or(""));
+        match fragment {
+            Some(fragment) if response.code.is_none() && response.error.is_none() => {
+                AuthorizationResponse::from_query(fragment)
+            }
+            _ => response,
+        }
+    }
+
+    /// Returns the authorization code, or the error reported by the provider.
+    pub fn into_code(self) -> Result<String, RequestError> {
+        match (self.code, self.error) {
+            (_, Some(error)) => Err(RequestError::from(JsonError::new(
+                error,
+                self.error_description,
+            ))),
+            (Some(code), None) => Ok(code),
+            (None, None) => Err(RequestError::UserError(
+                "authorization response contains neither code nor error".to_string(),
+            )),
+        }
+    }
+}
+
+/// An authorization begun by `InstalledFlow::start()`, to be completed by
+/// `InstalledFlow::finish()`.
+///
+/// It contains the PKCE code verifier
+/// ([RFC 7636](https://tools.ietf.org/html/rfc7636)), so it must be kept private to the
+/// application, just like tokens.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingAuthorization {
+    /// The URL the user has to visit to grant access.
+    pub url: String,
+    /// The `state` parameter sent to the authorization server, protecting against CSRF.
+    pub state: String,
+    /// The PKCE code verifier, sent with the authorization code.
+    pub pkce_verifier: String,
+    /// The redirect URI the authorization server will send the code to.
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+}
+
+/// Parses the query of a redirect to the redirect URI into the authorization code or the error
+/// (RFC 6749, section 4.1.2.1). Returns `None` if the query contains neither.
+fn parse_authorization_response(query: &str) -> Option<Result<String, String>> {
+    let response = AuthorizationResponse::from_query(query);
+    match (response.code, response.error) {
+        (_, Some(error)) => Some(Err(match response.error_description {
+            Some(description) => format!("{}: {}", error, description),
+            None => error,
+        })),
+        (Some(code), None) => Some(Ok(code)),
+        (None, None) => None,
+    }
+}
+
+/// Extracts the authorization code from what the user pasted: either the code itself or, if
+/// the browser was redirected to a URI nobody listens on, the address of the page it ended up on.
+fn parse_pasted_code(input: &str) -> Result<String, RequestError> {
+    let input = input.trim();
+    let query = input
+        .split_once('?')
+        .map(|(_, q)| q.split('#').next().unwrap_or(""));
+    match query.and_then(parse_authorization_response) {
+        Some(Ok(code)) => Ok(code),
+        Some(Err(e)) => Err(RequestError::UserError(format!(
+            "authorization was refused: {}",
+            e
+        ))),
+        None if input.is_empty() => Err(RequestError::UserError("couldn't read code".to_string())),
+        None => Ok(input.to_string()),
+    }
+}
+
+/// Whether this process runs in an SSH session.
+fn is_ssh_session() -> bool {
+    std::env::var_os("SSH_CONNECTION").is_some() || std::env::var_os("SSH_TTY").is_some()
+}
+
+/// Creates a `PendingAuthorization` with a fresh `state` and PKCE code verifier.
+pub(crate) fn start_authorization(
+    rng: &Rng,
+    appsecret: &ApplicationSecret,
+    redirect_uri: String,
+    scopes: Vec<String>,
+) -> Result<PendingAuthorization, RequestError> {
+    let state = rng.random_string(16)?;
+    let pkce_verifier = rng.random_string(32)?;
+    let pkce_challenge = pkce_challenge(&pkce_verifier);
+    let url = build_authentication_request_url(
+        &appsecret.auth_uri,
+        &appsecret.client_id,
+        scopes.iter(),
+        appsecret.scope_separator,
+        Some(redirect_uri.clone()),
+        &[
+            ("state", &state),
+            ("code_challenge", &pkce_challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    );
+    Ok(PendingAuthorization {
+        url,
+        state,
+        pkce_verifier,
+        redirect_uri,
+        scopes,
+    })
+}
+
+/// The S256 PKCE code challenge of `verifier`.
+fn pkce_challenge(verifier: &str) -> String {
+    base64::encode_config(
+        digest::digest(&digest::SHA256, verifier.as_bytes()).as_ref(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Returns the redirect URI set by the flow delegate, or else by `InstalledFlow::redirect_uri()`,
+/// or else the one of the local server, or else one of the `redirect_uris` of `appsecret` the
+/// user can obtain the code from. For a `remote` browser, that is preferably a loopback URI.
+fn choose_redirect_uri(
+    delegate_uri: Option<String>,
+    configured_uri: &Option<String>,
+    server_uri: Option<String>,
+    remote: bool,
+    appsecret: &ApplicationSecret,
+) -> String {
+    delegate_uri
+        .or_else(|| configured_uri.clone())
+        .or(server_uri)
+        .or_else(|| {
+            if remote {
+                appsecret
+                    .redirect_uri(RedirectUriKind::Loopback)
+                    .map(String::from)
+            } else {
+                None
+            }
+        })
+        .or_else(|| {
+            appsecret
+                .redirect_uri(RedirectUriKind::OutOfBand)
+                .or_else(|| appsecret.redirect_uri(RedirectUriKind::CustomScheme))
+                .map(String::from)
+        })
+        .unwrap_or_else(|| OOB_REDIRECT_URI.to_string())
+}
+
+/// Fails unless `redirect_uri` is one of the `redirect_uris` of `appsecret`, as OAuth 2.1
+/// requires the exact match of redirect URIs. Only the ports of loopback redirect URIs may
+/// differ, so that native apps can listen on any free port (RFC 8252, section 7.3).
+pub(crate) fn check_oauth21_redirect_uri(
+    appsecret: &ApplicationSecret,
+    redirect_uri: &str,
+) -> Result<(), RequestError> {
+    fn without_loopback_port(uri: &str) -> Option<url::Url> {
+        let mut url = url::Url::parse(uri).ok()?;
+        match url.host_str() {
+            Some("localhost") | Some("127.0.0.1") | Some("[::1]") if url.scheme() == "http" => {
+                url.set_port(None).ok()?;
+                Some(url)
+            }
+            _ => None,
         }
     }
+    let loopback = without_loopback_port(redirect_uri);
+    let registered = appsecret.redirect_uris.iter().any(|uri| {
+        uri == redirect_uri || (loopback.is_some() && without_loopback_port(uri) == loopback)
+    });
+    if registered {
+        Ok(())
+    } else {
+        Err(RequestError::PolicyViolation(format!(
+            "OAuth 2.1 requires the redirect URI {} to be one of the application's redirect_uris",
+            redirect_uri
+        )))
+    }
 }
 
 impl<FD, C> crate::authenticator::AuthFlow<C> for InstalledFlow<FD>
@@ -148,6 +787,12 @@ where
     fn build_token_getter(self, client: hyper::Client<C>) -> Self::TokenGetter {
         InstalledFlowImpl {
             method: self.method,
+            server_config: self.server_config.with_registered(&self.appsecret),
+            headless_fallback: self.headless_fallback,
+            oauth21: self.oauth21,
+            redirect_uri: self.redirect_uri,
+            rng: self.rng,
+            deadline: self.deadline,
             fd: self.flow_delegate,
             appsecret: self.appsecret,
             client,
@@ -165,7 +810,7 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
     ///
     /// It's recommended not to use the DefaultFlowDelegate, but a specialized one.
     fn obtain_token<'a>(
-        &mut self,
+        &self,
         scopes: Vec<String>, // Note: I haven't found a better way to give a list of strings here, due to ownership issues with futures.
     ) -> impl 'a + Future<Item = Token, Error = RequestError> + Send {
         let rduri = self.fd.redirect_uri();
@@ -175,87 +820,68 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
             InstalledFlowReturnMethod::HTTPRedirectEphemeral => Some(0),
             _ => None,
         };
-        let server = if let Some(port) = server_bind_port {
-            match InstalledFlowServer::new(port) {
-                Result::Err(e) => Err(RequestError::ClientError(e)),
-                Result::Ok(server) => Ok(Some(server)),
-            }
-        } else {
-            Ok(None)
+        let server = match server_bind_port {
+            Some(_) if self.headless_fallback && is_ssh_session() => Ok(None),
+            Some(port) => match InstalledFlowServer::new(port, &self.server_config) {
+                // A misconfigured flow fails rather than falling back.
+                Err(e @ RequestError::UserError(_)) => Err(e),
+                Err(_) if self.headless_fallback => Ok(None),
+                r => r.map(Some),
+            },
+            None => Ok(None),
         };
-        let port = if let Ok(Some(ref srv)) = server {
-            Some(srv.port)
+        let server_uri = if let Ok(Some(ref srv)) = server {
+            Some(srv.redirect_uri())
         } else {
             None
         };
+        // Without the listener it was meant to use, the flow falls back to a remote browser.
+        let remote = matches!(self.method, InstalledFlowReturnMethod::Remote)
+            || (server_bind_port.is_some() && server_uri.is_none());
+        let redirect_uri = choose_redirect_uri(
+            rduri,
+            &self.redirect_uri,
+            server_uri,
+            remote,
+            &self.appsecret,
+        );
+        let (server, pkce_verifier) = if self.oauth21 {
+            let server = check_oauth21_redirect_uri(&self.appsecret, &redirect_uri).and(server);
+            match self.rng.random_string(32) {
+                Ok(verifier) => (server, Some(verifier)),
+                Err(e) => (Err(e), None),
+            }
+        } else {
+            (server, None)
+        };
+        let code_challenge = pkce_verifier.as_ref().map(|v| pkce_challenge(v));
         let client = self.client.clone();
         let (appsecclone, appsecclone2) = (self.appsecret.clone(), self.appsecret.clone());
         let auth_delegate = self.fd.clone();
+        let auth_redirect_uri = redirect_uri.clone();
         server
             .into_future()
             // First: Obtain authorization code from user.
             .and_then(move |server| {
-                Self::ask_authorization_code(server, auth_delegate, &appsecclone, scopes.iter())
+                Self::ask_authorization_code(
+                    server,
+                    auth_delegate,
+                    &appsecclone,
+                    scopes.iter(),
+                    auth_redirect_uri,
+                    code_challenge,
+                )
             })
             // Exchange the authorization code provided by Google/the provider for a refresh and an
             // access token.
             .and_then(move |authcode| {
-                let request = Self::request_token(appsecclone2, authcode, rduri, port);
-                let result = client.request(request);
-                // Handle result here, it makes ownership tracking easier.
-                result
-                    .and_then(move |r| {
-                        r.into_body()
-                            .concat2()
-                            .map(|c| String::from_utf8(c.into_bytes().to_vec()).unwrap())
-                        // TODO: error handling
-                    })
-                    .then(|body_or| {
-                        let resp = match body_or {
-                            Err(e) => return Err(RequestError::ClientError(e)),
-                            Ok(s) => s,
-                        };
-
-                        let token_resp: Result<JSONTokenResponse, serde_json::Error> =
-                            serde_json::from_str(&resp);
-
-                        match token_resp {
-                            Err(e) => {
-                                return Err(RequestError::JSONError(e));
-                            }
-                            Ok(tok) => {
-                                if tok.error.is_some() {
-                                    Err(RequestError::NegativeServerResponse(
-                                        tok.error.unwrap(),
-                                        tok.error_description,
-                                    ))
-                                } else {
-                                    Ok(tok)
-                                }
-                            }
-                        }
-                    })
-            })
-            // Return the combined token.
-            .and_then(|tokens| {
-                // Successful response
-                if tokens.access_token.is_some() {
-                    let mut token = Token {
-                        access_token: tokens.access_token.unwrap(),
-                        refresh_token: Some(tokens.refresh_token.unwrap()),
-                        token_type: tokens.token_type.unwrap(),
-                        expires_in: tokens.expires_in,
-                        expires_in_timestamp: None,
-                    };
-
-                    token.set_expiry_absolute();
-                    Ok(token)
-                } else {
-                    Err(RequestError::NegativeServerResponse(
-                        tokens.error.unwrap(),
-                        tokens.error_description,
-                    ))
-                }
+                exchange_code(
+                    client,
+                    &appsecclone2,
+                    &authcode,
+                    &redirect_uri,
+                    pkce_verifier.as_deref(),
+                )
             })
     }
 
@@ -264,91 +890,113 @@ impl<'c, FD: 'static + FlowDelegate + Clone + Send, C: 'c + hyper::client::conne
         mut auth_delegate: FD,
         appsecret: &ApplicationSecret,
         scopes: S,
+        redirect_uri: String,
+        code_challenge: Option<String>,
     ) -> Box<dyn Future<Item = String, Error = RequestError> + Send>
     where
         T: AsRef<str> + 'a,
         S: Iterator<Item = &'a T>,
     {
-        if server.is_none() {
-            let url = build_authentication_request_url(
-                &appsecret.auth_uri,
-                &appsecret.client_id,
-                scopes,
-                auth_delegate.redirect_uri(),
-            );
-            Box::new(
-                auth_delegate
-                    .present_user_url(&url, true /* need_code */)
-                    .then(|r| {
-                        match r {
-                            Ok(Some(mut code)) => {
-                                // Partial backwards compatibility in case an implementation adds a new line
-                                // due to previous behaviour.
-                                let ends_with_newline =
-                                    code.chars().last().map(|c| c == '\n').unwrap_or(false);
-                                if ends_with_newline {
-                                    code.pop();
-                                }
-                                Ok(code)
-                            }
+        let pkce_params = match code_challenge {
+            Some(ref challenge) => vec![
+                ("code_challenge", challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+            None => vec![],
+        };
+        match server {
+            None => {
+                let url = build_authentication_request_url(
+                    &appsecret.auth_uri,
+                    &appsecret.client_id,
+                    scopes,
+                    appsecret.scope_separator,
+                    Some(redirect_uri),
+                    &pkce_params,
+                );
+                Box::new(
+                    auth_delegate
+                        .present_user_url(&url, true /* need_code */)
+                        .then(|r| match r {
+                            Ok(Some(input)) => parse_pasted_code(&input),
                             _ => Err(RequestError::UserError("couldn't read code".to_string())),
-                        }
-                    }),
-            )
-        } else {
-            let mut server = server.unwrap();
-            // The redirect URI must be this very localhost URL, otherwise authorization is refused
-            // by certain providers.
-            let url = build_authentication_request_url(
-                &appsecret.auth_uri,
-                &appsecret.client_id,
-                scopes,
-                auth_delegate
-                    .redirect_uri()
-                    .or_else(|| Some(format!("http://localhost:{}", server.port))),
-            );
-            Box::new(
-                auth_delegate
-                    .present_user_url(&url, false /* need_code */)
-                    .then(move |_| server.block_till_auth())
-                    .map_err(|e| {
-                        RequestError::UserError(format!(
-                            "could not obtain token via redirect: {}",
-                            e
-                        ))
-                    }),
-            )
+                        }),
+                )
+            }
+            Some(server) => {
+                // The redirect URI must be this very localhost URL, otherwise authorization is
+                // refused by certain providers.
+                let url = build_authentication_request_url(
+                    &appsecret.auth_uri,
+                    &appsecret.client_id,
+                    scopes,
+                    appsecret.scope_separator,
+                    Some(redirect_uri),
+                    &pkce_params,
+                );
+                if let Some(ref fingerprint) = server.certificate_fingerprint {
+                    auth_delegate.present_certificate_fingerprint(fingerprint);
+                }
+                Box::new(
+                    auth_delegate
+                        .present_user_url(&url, false /* need_code */)
+                        .then(move |_| server.auth_code())
+                        .map_err(|e| {
+                            RequestError::UserError(format!(
+                                "could not obtain token via redirect: {}",
+                                e
+                            ))
+                        }),
+                )
+            }
         }
     }
+}
 
-    /// Sends the authorization code to the provider in order to obtain access and refresh tokens.
-    fn request_token<'a>(
-        appsecret: ApplicationSecret,
-        authcode: String,
-        custom_redirect_uri: Option<String>,
-        port: Option<u16>,
-    ) -> hyper::Request<hyper::Body> {
-        let redirect_uri = custom_redirect_uri.unwrap_or_else(|| match port {
-            None => OOB_REDIRECT_URI.to_string(),
-            Some(port) => format!("http://localhost:{}", port),
-        });
-
-        let body = form_urlencoded::Serializer::new(String::new())
-            .extend_pairs(vec![
-                ("code".to_string(), authcode.to_string()),
-                ("client_id".to_string(), appsecret.client_id.clone()),
-                ("client_secret".to_string(), appsecret.client_secret.clone()),
-                ("redirect_uri".to_string(), redirect_uri),
-                ("grant_type".to_string(), "authorization_code".to_string()),
-            ])
-            .finish();
-
-        let request = hyper::Request::post(appsecret.token_uri)
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .body(hyper::Body::from(body))
-            .unwrap(); // TODO: error check
-        request
+/// Exchanges the authorization code for access and refresh tokens.
+pub(crate) fn exchange_code<C>(
+    client: hyper::Client<C>,
+    appsecret: &ApplicationSecret,
+    authcode: &str,
+    redirect_uri: &str,
+    code_verifier: Option<&str>,
+) -> impl Future<Item = Token, Error = RequestError> + Send
+where
+    C: hyper::client::connect::Connect + 'static,
+{
+    let mut request = transport::TokenRequest::new()
+        .param("code", authcode)
+        .param("redirect_uri", redirect_uri)
+        .param("grant_type", "authorization_code");
+    if let Some(code_verifier) = code_verifier {
+        request = request.param("code_verifier", code_verifier);
     }
+    transport::post_token_request(client, appsecret, request)
+        .map_err(RequestError::ClientError)
+        .and_then(transport::read_body)
+        .map(transport::form_to_json)
+        .and_then(|resp| {
+            if let Some(err) = JsonError::from_response(&resp) {
+                return Err(RequestError::from(err));
+            }
+            match serde_json::from_str::<JSONTokenResponse>(&resp) {
+                Err(e) => Err(RequestError::JSONError(e)),
+                Ok(JSONTokenResponse {
+                    access_token: Some(access_token),
+                    refresh_token,
+                    token_type: Some(token_type),
+                    expires_in,
+                }) => Ok(Token::new(
+                    access_token,
+                    token_type,
+                    refresh_token,
+                    expires_in,
+                )),
+                Ok(_) => Err(RequestError::BadServerResponse(
+                    "Token response lacks fields".to_string(),
+                )),
+            }
+        })
 }
 
 #[derive(Deserialize)]
@@ -357,51 +1005,131 @@ struct JSONTokenResponse {
     refresh_token: Option<String>,
     token_type: Option<String>,
     expires_in: Option<i64>,
-
-    error: Option<String>,
-    error_description: Option<String>,
 }
 
 struct InstalledFlowServer {
     port: u16,
+    config: ServerConfig,
+    /// The SHA-256 fingerprint of the certificate, if serving HTTPS.
+    certificate_fingerprint: Option<String>,
     shutdown_tx: Option<oneshot::Sender<()>>,
-    auth_code_rx: Option<oneshot::Receiver<String>>,
+    auth_code_rx: Option<oneshot::Receiver<Result<String, String>>>,
     threadpool: Option<tokio_threadpool::ThreadPool>,
 }
 
 impl InstalledFlowServer {
-    fn new(port: u16) -> Result<InstalledFlowServer, hyper::error::Error> {
-        let (auth_code_tx, auth_code_rx) = oneshot::channel::<String>();
+    fn new(port: u16, config: &ServerConfig) -> Result<InstalledFlowServer, RequestError> {
+        config.check_path()?;
+        let (auth_code_tx, auth_code_rx) = oneshot::channel::<Result<String, String>>();
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
         let threadpool = tokio_threadpool::Builder::new()
             .pool_size(1)
             .name_prefix("InstalledFlowServer-")
             .build();
-        let service_maker = InstalledFlowServiceMaker::new(auth_code_tx);
-
-        let addr: std::net::SocketAddr = ([127, 0, 0, 1], port).into();
-        let builder = hyper::server::Server::try_bind(&addr)?;
-        let server = builder.http1_only(true).serve(service_maker);
-        let port = server.local_addr().port();
-        let server_future = server
-            .with_graceful_shutdown(shutdown_rx)
-            .map_err(|err| panic!("Failed badly: {}", err));
+        let service_maker = InstalledFlowServiceMaker::new(auth_code_tx, config.clone());
 
-        threadpool.spawn(server_future);
+        let addr = config.bind_address(port);
+        let (port, certificate_fingerprint) = if config.https {
+            Self::spawn_https(&threadpool, &addr, service_maker, shutdown_rx)?
+        } else {
+            let builder =
+                hyper::server::Server::try_bind(&addr).map_err(RequestError::client_error)?;
+            let server = builder.http1_only(true).serve(service_maker);
+            let port = server.local_addr().port();
+            let server_future = server
+                .with_graceful_shutdown(shutdown_rx)
+                .map_err(|err| panic!("Failed badly: {}", err));
+            threadpool.spawn(server_future);
+            (port, None)
+        };
 
         Result::Ok(InstalledFlowServer {
             port: port,
+            config: config.clone(),
+            certificate_fingerprint,
             shutdown_tx: Some(shutdown_tx),
             auth_code_rx: Some(auth_code_rx),
             threadpool: Some(threadpool),
         })
     }
 
-    fn block_till_auth(&mut self) -> Result<String, oneshot::Canceled> {
+    /// Serves HTTPS on `addr` using a new self-signed certificate for `localhost`. Returns the
+    /// port and the certificate's fingerprint.
+    #[cfg(feature = "https-redirect")]
+    fn spawn_https(
+        threadpool: &tokio_threadpool::ThreadPool,
+        addr: &SocketAddr,
+        service_maker: InstalledFlowServiceMaker,
+        shutdown_rx: oneshot::Receiver<()>,
+    ) -> Result<(u16, Option<String>), RequestError> {
+        use std::io;
+
+        let tls_error = |e: &dyn std::fmt::Display| {
+            RequestError::LowLevelError(io::Error::new(io::ErrorKind::Other, e.to_string()))
+        };
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .map_err(|e| tls_error(&e))?;
+        let cert_der = cert.serialize_der().map_err(|e| tls_error(&e))?;
+        let fingerprint = digest::digest(&digest::SHA256, &cert_der)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let mut tls_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        tls_config
+            .set_single_cert(vec![rustls::Certificate(cert_der)], key)
+            .map_err(|e| tls_error(&e))?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+
+        let listener = tokio::net::TcpListener::bind(addr).map_err(RequestError::LowLevelError)?;
+        let port = listener
+            .local_addr()
+            .map_err(RequestError::LowLevelError)?
+            .port();
+        // Failed handshakes, e.g. because the user didn't accept the certificate at first, must
+        // not stop the server.
+        let incoming = listener
+            .incoming()
+            .then(|r| Ok::<_, io::Error>(r.ok()))
+            .filter_map(|s| s)
+            .and_then(move |s| acceptor.accept(s).then(|r| Ok(r.ok())))
+            .filter_map(|s| s);
+        let server_future = hyper::server::Server::builder(incoming)
+            .http1_only(true)
+            .serve(service_maker)
+            .with_graceful_shutdown(shutdown_rx)
+            .map_err(|err| panic!("Failed badly: {}", err));
+        threadpool.spawn(server_future);
+        Ok((port, Some(fingerprint)))
+    }
+
+    #[cfg(not(feature = "https-redirect"))]
+    fn spawn_https(
+        _: &tokio_threadpool::ThreadPool,
+        _: &SocketAddr,
+        _: InstalledFlowServiceMaker,
+        _: oneshot::Receiver<()>,
+    ) -> Result<(u16, Option<String>), RequestError> {
+        unreachable!("https_redirect() requires the https-redirect feature")
+    }
+
+    /// The URI the provider has to redirect to.
+    fn redirect_uri(&self) -> String {
+        self.config.redirect_uri(self.port)
+    }
+
+    /// Waits for the redirect, returning the authorization code or the error reported by the
+    /// provider. The server is shut down once the redirect arrived, or if the future is dropped.
+    fn auth_code(mut self) -> impl Future<Item = String, Error = String> + Send {
         match self.auth_code_rx.take() {
-            Some(auth_code_rx) => auth_code_rx.wait(),
-            None => Result::Err(oneshot::Canceled),
+            Some(auth_code_rx) => future::Either::A(auth_code_rx.then(move |r| {
+                drop(self);
+                r.unwrap_or_else(|canceled| Err(canceled.to_string()))
+            })),
+            None => future::Either::B(future::err(oneshot::Canceled.to_string())),
         }
     }
 }
@@ -440,15 +1168,25 @@ impl futures::Future for InstalledFlowHandlerResponseFuture {
     }
 }
 
+/// Passes the authorization code, or the provider's error, from the server to the flow.
+type AuthCodeSender = Arc<Mutex<Option<oneshot::Sender<Result<String, String>>>>>;
+
 /// Creates InstalledFlowService on demand
 struct InstalledFlowServiceMaker {
-    auth_code_tx: Arc<Mutex<Option<oneshot::Sender<String>>>>,
+    auth_code_tx: AuthCodeSender,
+    config: Arc<ServerConfig>,
 }
 
 impl InstalledFlowServiceMaker {
-    fn new(auth_code_tx: oneshot::Sender<String>) -> InstalledFlowServiceMaker {
+    fn new(
+        auth_code_tx: oneshot::Sender<Result<String, String>>,
+        config: ServerConfig,
+    ) -> InstalledFlowServiceMaker {
         let auth_code_tx = Arc::new(Mutex::new(Option::Some(auth_code_tx)));
-        InstalledFlowServiceMaker { auth_code_tx }
+        InstalledFlowServiceMaker {
+            auth_code_tx,
+            config: Arc::new(config),
+        }
     }
 }
 
@@ -463,6 +1201,7 @@ impl<Ctx> hyper::service::MakeService<Ctx> for InstalledFlowServiceMaker {
     fn make_service(&mut self, _ctx: Ctx) -> Self::Future {
         let service = InstalledFlowService {
             auth_code_tx: self.auth_code_tx.clone(),
+            config: self.config.clone(),
         };
         futures::future::ok(service)
     }
@@ -470,7 +1209,8 @@ impl<Ctx> hyper::service::MakeService<Ctx> for InstalledFlowServiceMaker {
 
 /// HTTP service handling the redirect from the provider.
 struct InstalledFlowService {
-    auth_code_tx: Arc<Mutex<Option<oneshot::Sender<String>>>>,
+    auth_code_tx: AuthCodeSender,
+    config: Arc<ServerConfig>,
 }
 
 impl hyper::service::Service for InstalledFlowService {
@@ -491,36 +1231,51 @@ impl hyper::service::Service for InstalledFlowService {
                     .path_and_query(path_and_query.clone())
                     .build();
 
-                if url.is_err() {
-                    let response = hyper::Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(hyper::Body::from("Unparseable URL"));
-
-                    match response {
-                        Ok(response) => InstalledFlowHandlerResponseFuture::new(Box::new(
-                            futures::future::ok(response),
-                        )),
-                        Err(err) => InstalledFlowHandlerResponseFuture::new(Box::new(
-                            futures::future::err(err),
-                        )),
+                match url {
+                    Err(_) => {
+                        let response = hyper::Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(hyper::Body::from("Unparseable URL"));
+
+                        match response {
+                            Ok(response) => InstalledFlowHandlerResponseFuture::new(Box::new(
+                                futures::future::ok(response),
+                            )),
+                            Err(err) => InstalledFlowHandlerResponseFuture::new(Box::new(
+                                futures::future::err(err),
+                            )),
+                        }
                     }
-                } else {
-                    self.handle_url(url.unwrap());
-                    let response =
-                        hyper::Response::builder()
-                            .status(StatusCode::OK)
-                            .body(hyper::Body::from(
-                                "<html><head><title>Success</title></head><body>You may now \
-                                 close this window.</body></html>",
+                    Ok(url) => {
+                        if !self.config.accepts_path(url.path()) {
+                            let response = hyper::Response::builder()
+                                .status(StatusCode::NOT_FOUND)
+                                .body(hyper::Body::from("Not Found"));
+                            return InstalledFlowHandlerResponseFuture::new(Box::new(
+                                futures::future::result(response),
                             ));
+                        }
+                        let response = match self.handle_url(url) {
+                            Ok(()) => match self.config.success_page {
+                                Some(ref page) => page.response(StatusCode::OK, None),
+                                None => RedirectPage::Html(DEFAULT_SUCCESS_PAGE.to_string())
+                                    .response(StatusCode::OK, None),
+                            },
+                            Err(error) => match self.config.error_page {
+                                Some(ref page) => page.response(StatusCode::OK, Some(&error)),
+                                None => RedirectPage::Html(DEFAULT_ERROR_PAGE.to_string())
+                                    .response(StatusCode::OK, Some(&error)),
+                            },
+                        };
 
-                    match response {
-                        Ok(response) => InstalledFlowHandlerResponseFuture::new(Box::new(
-                            futures::future::ok(response),
-                        )),
-                        Err(err) => InstalledFlowHandlerResponseFuture::new(Box::new(
-                            futures::future::err(err),
-                        )),
+                        match response {
+                            Ok(response) => InstalledFlowHandlerResponseFuture::new(Box::new(
+                                futures::future::ok(response),
+                            )),
+                            Err(err) => InstalledFlowHandlerResponseFuture::new(Box::new(
+                                futures::future::err(err),
+                            )),
+                        }
                     }
                 }
             }
@@ -543,25 +1298,30 @@ impl hyper::service::Service for InstalledFlowService {
 }
 
 impl InstalledFlowService {
-    fn handle_url(&mut self, url: hyper::Uri) {
+    /// Passes the authorization code, or the error reported by the provider, on to
+    /// `auth_code()`. Returns the error, if any.
+    fn handle_url(&mut self, url: hyper::Uri) -> Result<(), String> {
         // The provider redirects to the specified localhost URL, appending the authorization
         // code, like this: http://localhost:8080/xyz/?code=4/731fJ3BheyCouCniPufAd280GHNV5Ju35yYcGs
+        // or an error (RFC 6749, section 4.1.2.1), like this:
+        // http://localhost:8080/xyz/?error=access_denied&error_description=...
         // We take that code and send it to the ask_authorization_code() function that
         // waits for it.
-        for (param, val) in form_urlencoded::parse(url.query().unwrap_or("").as_bytes()) {
-            if param == "code".to_string() {
-                let mut auth_code_tx = self.auth_code_tx.lock().unwrap();
-                match auth_code_tx.take() {
-                    Some(auth_code_tx) => {
-                        let _ = auth_code_tx.send(val.to_owned().to_string());
-                    }
-                    None => {
-                        // call to the server after a previous call. Each server is only designed
-                        // to receive a single request.
-                    }
-                };
+        let result = match parse_authorization_response(url.query().unwrap_or("")) {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        let mut auth_code_tx = self.auth_code_tx.lock().unwrap();
+        match auth_code_tx.take() {
+            Some(auth_code_tx) => {
+                let _ = auth_code_tx.send(result.clone());
             }
-        }
+            None => {
+                // call to the server after a previous call. Each server is only designed
+                // to receive a single request.
+            }
+        };
+        result.map(|_| ())
     }
 }
 
@@ -581,7 +1341,6 @@ mod tests {
     use crate::authenticator::AuthFlow;
     use crate::authenticator_delegate::FlowDelegate;
     use crate::helper::*;
-    use crate::types::StringError;
 
     #[test]
     fn test_end2end() {
@@ -616,7 +1375,7 @@ mod tests {
                     }
                     if rduri.is_none() {
                         return Box::new(
-                            Err(Box::new(StringError::new("no redirect uri!", None))
+                            Err(Box::new(RequestError::UserError("no redirect uri!".into()))
                                 as Box<dyn Error + Send>)
                             .into_future(),
                         );
@@ -646,10 +1405,9 @@ mod tests {
             .build::<_, hyper::Body>(https);
 
         let fd = FD("authorizationcode".to_string(), client.clone());
-        let mut inf =
-            InstalledFlow::new(app_secret.clone(), InstalledFlowReturnMethod::Interactive)
-                .delegate(fd)
-                .build_token_getter(client.clone());
+        let inf = InstalledFlow::new(app_secret.clone(), InstalledFlowReturnMethod::Interactive)
+            .delegate(fd)
+            .build_token_getter(client.clone());
 
         let mut rt = tokio::runtime::Builder::new()
             .core_threads(1)
@@ -660,7 +1418,7 @@ mod tests {
         // Successful path.
         {
             let _m = mock("POST", "/token")
-            .match_body(mockito::Matcher::Regex(".*code=authorizationcode.*client_id=9022167.*".to_string()))
+            .match_body(mockito::Matcher::Regex(".*client_id=9022167.*code=authorizationcode.*".to_string()))
             .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678}"#)
             .expect(1)
             .create();
@@ -678,15 +1436,14 @@ mod tests {
         }
         // Successful path with HTTP redirect.
         {
-            let mut inf =
-                InstalledFlow::new(app_secret, InstalledFlowReturnMethod::HTTPRedirect(8081))
-                    .delegate(FD(
-                        "authorizationcodefromlocalserver".to_string(),
-                        client.clone(),
-                    ))
-                    .build_token_getter(client.clone());
+            let inf = InstalledFlow::new(app_secret, InstalledFlowReturnMethod::HTTPRedirect(8081))
+                .delegate(FD(
+                    "authorizationcodefromlocalserver".to_string(),
+                    client.clone(),
+                ))
+                .build_token_getter(client.clone());
             let _m = mock("POST", "/token")
-            .match_body(mockito::Matcher::Regex(".*code=authorizationcodefromlocalserver.*client_id=9022167.*".to_string()))
+            .match_body(mockito::Matcher::Regex(".*client_id=9022167.*code=authorizationcodefromlocalserver.*".to_string()))
             .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 12345678}"#)
             .expect(1)
             .create();
@@ -706,7 +1463,7 @@ mod tests {
         {
             let _m = mock("POST", "/token")
                 .match_body(mockito::Matcher::Regex(
-                    ".*code=authorizationcode.*client_id=9022167.*".to_string(),
+                    ".*client_id=9022167.*code=authorizationcode.*".to_string(),
                 ))
                 .with_status(400)
                 .with_body(r#"{"error": "invalid_code"}"#)
@@ -726,6 +1483,409 @@ mod tests {
         rt.shutdown_on_idle().wait().expect("shutdown");
     }
 
+    #[test]
+    fn test_random_source() {
+        // The code verifier octets from RFC 7636, appendix B.
+        struct Rfc7636;
+        impl RandomSource for Rfc7636 {
+            fn fill(&self, dest: &mut [u8]) -> Result<(), std::io::Error> {
+                let octets = [
+                    116, 24, 223, 180, 151, 153, 224, 37, 79, 250, 96, 125, 216, 173, 187, 186, 22,
+                    212, 37, 77, 105, 214, 191, 240, 91, 88, 5, 88, 83, 132, 141, 121,
+                ];
+                dest.copy_from_slice(&octets[..dest.len()]);
+                Ok(())
+            }
+        }
+
+        let app_secret = parse_application_secret(crate::types::tests::SECRET).unwrap();
+        let flow = InstalledFlow::new(app_secret, InstalledFlowReturnMethod::Interactive)
+            .random_source(Rfc7636);
+        let pending = flow
+            .start(vec!["https://googleapis.com/some/scope"])
+            .unwrap();
+        assert_eq!("dBjftJeZ4CVP-mB92K27ug", pending.state);
+        assert_eq!(
+            "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk",
+            pending.pkce_verifier
+        );
+        assert!(pending
+            .url
+            .contains("code_challenge=E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"));
+
+        // A failing source fails the authorization rather than panicking.
+        struct Failing;
+        impl RandomSource for Failing {
+            fn fill(&self, _dest: &mut [u8]) -> Result<(), std::io::Error> {
+                Err(std::io::Error::new(std::io::ErrorKind::Other, "no entropy"))
+            }
+        }
+        let app_secret = parse_application_secret(crate::types::tests::SECRET).unwrap();
+        let flow = InstalledFlow::new(app_secret, InstalledFlowReturnMethod::Interactive)
+            .random_source(Failing);
+        match flow.start(vec!["email"]) {
+            Err(RequestError::LowLevelError(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_two_phase() {
+        let mut app_secret = parse_application_secret(crate::types::tests::SECRET).unwrap();
+        app_secret.token_uri = format!("{}/token", mockito::server_url());
+        let flow = InstalledFlow::new(app_secret, InstalledFlowReturnMethod::HTTPRedirect(8081));
+
+        let pending = flow
+            .start(vec!["https://googleapis.com/some/scope"])
+            .unwrap();
+        assert_eq!("http://localhost:8081", pending.redirect_uri);
+        assert_ne!(
+            pending.state,
+            flow.start(Vec::<String>::new()).unwrap().state
+        );
+        let uri = Uri::from_str(&pending.url).unwrap();
+        let params: Vec<(String, String)> = form_urlencoded::parse(uri.query().unwrap().as_bytes())
+            .into_owned()
+            .collect();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!(pending.state, param("state"));
+        assert_eq!("S256", param("code_challenge_method"));
+        // Example from RFC 7636, appendix B.
+        let rfc_verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        assert_eq!(
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM",
+            base64::encode_config(
+                digest::digest(&digest::SHA256, rfc_verifier.as_bytes()).as_ref(),
+                base64::URL_SAFE_NO_PAD
+            )
+        );
+        assert_eq!(43, param("code_challenge").len());
+
+        // The pending authorization survives a roundtrip through storage.
+        let pending: PendingAuthorization =
+            serde_json::from_str(&serde_json::to_string(&pending).unwrap()).unwrap();
+
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _m = mock("POST", "/token")
+            .match_body(mockito::Matcher::Regex(format!(
+                "code=authcode&redirect_uri=http%3A%2F%2Flocalhost%3A8081&grant_type=authorization_code&code_verifier={}$",
+                pending.pkce_verifier
+            )))
+            .with_body(r#"{"access_token": "accesstoken", "refresh_token": "refreshtoken", "token_type": "Bearer", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+
+        let result = rt.block_on(flow.finish(client.clone(), &pending, "authcode", Some("forged")));
+        match result {
+            Err(RequestError::UserError(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let token = rt
+            .block_on(flow.finish(client, &pending, "authcode", Some(&pending.state)))
+            .unwrap();
+        assert_eq!("accesstoken", token.access_token);
+        assert_eq!(Some("refreshtoken".to_string()), token.refresh_token);
+        _m.assert();
+    }
+
+    #[test]
+    fn test_finish_redirect() {
+        let mut app_secret = parse_application_secret(crate::types::tests::SECRET).unwrap();
+        app_secret.token_uri = format!("{}/deep_link/token", mockito::server_url());
+        app_secret.redirect_uris = vec!["com.example.app:/oauth2redirect".to_string()];
+        let flow = InstalledFlow::new(app_secret, InstalledFlowReturnMethod::Interactive);
+        let pending = flow.start(vec!["email"]).unwrap();
+        assert_eq!("com.example.app:/oauth2redirect", pending.redirect_uri);
+
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut refused =
+            |uri: &str| match rt.block_on(flow.finish_redirect(client.clone(), &pending, uri)) {
+                Err(e) => e,
+                Ok(t) => panic!("{} wasn't refused: {:?}", uri, t),
+            };
+        let state = pending.state.clone();
+        for uri in &[
+            "com.example.app:/oauth2redirect?code=authcode&state=forged".to_string(),
+            "com.example.app:/oauth2redirect?code=authcode".to_string(),
+            "com.example.app:/oauth2redirect?error=access_denied&state=forged".to_string(),
+            "com.example.app:/oauth2redirect?error=access_denied".to_string(),
+            format!("com.evil.app:/oauth2redirect?code=authcode&state={}", state),
+        ] {
+            match refused(uri) {
+                RequestError::UserError(_) => {}
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+        match refused(&format!(
+            "com.example.app:/oauth2redirect?error=access_denied&state={}",
+            state
+        )) {
+            RequestError::AccessDenied(e) => assert_eq!("access_denied", e.error),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let _m = mock("POST", "/deep_link/token")
+            .match_body(mockito::Matcher::Regex(
+                "code=authcode&redirect_uri=com.example.app%3A%2Foauth2redirect&".to_string(),
+            ))
+            .with_body(
+                r#"{"access_token": "accesstoken", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create();
+        // Some providers put the parameters in the fragment.
+        let uri = format!(
+            "com.example.app:/oauth2redirect#state={}&code=authcode",
+            state
+        );
+        let token = rt
+            .block_on(flow.finish_redirect(client, &pending, &uri))
+            .unwrap();
+        assert_eq!("accesstoken", token.access_token);
+        _m.assert();
+
+        assert_eq!(
+            AuthorizationResponse {
+                code: Some("a/b".to_string()),
+                state: Some("s".to_string()),
+                ..Default::default()
+            },
+            AuthorizationResponse::from_uri("com.example.app:/cb?code=a%2Fb&state=s#ignored")
+        );
+    }
+
+    #[test]
+    fn test_parse_pasted_code() {
+        assert_eq!("4/abc", parse_pasted_code("4/abc\n").unwrap());
+        assert_eq!(
+            "4/abc",
+            parse_pasted_code(" http://localhost:8080/?state=xyz&code=4%2Fabc#frag ").unwrap()
+        );
+        match parse_pasted_code("http://localhost/?error=access_denied") {
+            Err(RequestError::UserError(e)) => assert!(e.contains("access_denied")),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(parse_pasted_code("  \n").is_err());
+    }
+
+    #[test]
+    fn test_headless_fallback() {
+        // Occupy a port so that the redirect listener can't bind to it.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app_secret = ApplicationSecret {
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            token_uri: format!("{}/headless/token", mockito::server_url()),
+            auth_uri: "https://example.com/auth".to_string(),
+            ..Default::default()
+        };
+
+        #[derive(Clone)]
+        struct FD;
+        impl FlowDelegate for FD {
+            fn present_user_url<S: AsRef<str> + fmt::Display>(
+                &mut self,
+                url: S,
+                need_code: bool,
+            ) -> Box<dyn Future<Item = Option<String>, Error = Box<dyn Error + Send>> + Send>
+            {
+                assert!(need_code);
+                assert!(url.as_ref().starts_with("https://example.com/auth?"));
+                assert!(url
+                    .as_ref()
+                    .contains("redirect_uri=urn:ietf:wg:oauth:2.0:oob"));
+                Box::new(Ok(Some("authorizationcode\n".to_string())).into_future())
+            }
+        }
+
+        let client = hyper::Client::builder().build::<_, hyper::Body>(HttpsConnector::new(1));
+        let inf = InstalledFlow::new(
+            app_secret.clone(),
+            InstalledFlowReturnMethod::HTTPRedirect(port),
+        )
+        .delegate(FD);
+        let inf = AuthFlow::build_token_getter(inf, client.clone());
+        let mut rt = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .build()
+            .unwrap();
+        assert!(rt.block_on(inf.token(vec!["email"])).is_err());
+
+        let _m = mock("POST", "/headless/token")
+            .match_body(mockito::Matcher::Regex(
+                ".*code=authorizationcode.*redirect_uri=urn.*".to_string(),
+            ))
+            .with_body(
+                r#"{"access_token": "accesstoken", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create();
+        let inf = InstalledFlow::new(app_secret, InstalledFlowReturnMethod::HTTPRedirect(port))
+            .delegate(FD)
+            .headless_fallback(true);
+        let inf = AuthFlow::build_token_getter(inf, client);
+        let tok = rt.block_on(inf.token(vec!["email"])).unwrap();
+        assert_eq!("accesstoken", tok.access_token);
+        _m.assert();
+        drop(listener);
+    }
+
+    #[test]
+    fn test_oauth21() {
+        let app_secret = ApplicationSecret {
+            client_id: "id".to_string(),
+            token_uri: format!("{}/oauth21/token", mockito::server_url()),
+            auth_uri: "https://example.com/auth".to_string(),
+            redirect_uris: vec![
+                "http://127.0.0.1/callback".to_string(),
+                "https://app.example.com/callback".to_string(),
+            ],
+            ..Default::default()
+        };
+        for ok in &[
+            "http://127.0.0.1/callback",
+            "http://127.0.0.1:8080/callback",
+            "https://app.example.com/callback",
+        ] {
+            assert!(
+                check_oauth21_redirect_uri(&app_secret, ok).is_ok(),
+                "{}",
+                ok
+            );
+        }
+        for refused in &[
+            "http://127.0.0.1:8080/other",
+            "https://app.example.com:8443/callback",
+            "https://app.example.com/callback/",
+            OOB_REDIRECT_URI,
+        ] {
+            match check_oauth21_redirect_uri(&app_secret, refused) {
+                Err(RequestError::PolicyViolation(_)) => {}
+                r => panic!("{} wasn't refused: {:?}", refused, r),
+            }
+        }
+
+        #[derive(Clone)]
+        struct FD;
+        impl FlowDelegate for FD {
+            fn present_user_url<S: AsRef<str> + fmt::Display>(
+                &mut self,
+                url: S,
+                _need_code: bool,
+            ) -> Box<dyn Future<Item = Option<String>, Error = Box<dyn Error + Send>> + Send>
+            {
+                assert!(url.as_ref().contains("code_challenge_method=S256"));
+                Box::new(Ok(Some("authorizationcode".to_string())).into_future())
+            }
+        }
+        let client = hyper::Client::builder().build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // The out-of-band redirect URI isn't registered, so the user isn't even asked.
+        let inf = InstalledFlow::new(app_secret.clone(), InstalledFlowReturnMethod::Interactive)
+            .delegate(FD)
+            .oauth21(true);
+        let inf = AuthFlow::build_token_getter(inf, client.clone());
+        match rt.block_on(inf.token(vec!["email"])) {
+            Err(RequestError::PolicyViolation(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let mut app_secret = app_secret;
+        app_secret.redirect_uris.push(OOB_REDIRECT_URI.to_string());
+        let _m = mock("POST", "/oauth21/token")
+            .match_body(mockito::Matcher::Regex(
+                "^client_id=id&code=authorizationcode&.*&code_verifier=[A-Za-z0-9_-]{43}$"
+                    .to_string(),
+            ))
+            .with_body(
+                r#"{"access_token": "accesstoken", "token_type": "Bearer", "expires_in": 3600}"#,
+            )
+            .expect(1)
+            .create();
+        let inf = InstalledFlow::new(app_secret, InstalledFlowReturnMethod::Interactive)
+            .delegate(FD)
+            .oauth21(true);
+        let inf = AuthFlow::build_token_getter(inf, client);
+        let tok = rt.block_on(inf.token(vec!["email"])).unwrap();
+        assert_eq!("accesstoken", tok.access_token);
+        _m.assert();
+    }
+
+    #[test]
+    fn test_redirect_uri_selection() {
+        let app_secret = ApplicationSecret {
+            client_id: "id".to_string(),
+            auth_uri: "https://example.com/auth".to_string(),
+            redirect_uris: vec![
+                "https://app.example.com/callback".to_string(),
+                "com.example.app:/oauth2redirect".to_string(),
+                "http://127.0.0.1/callback".to_string(),
+            ],
+            ..Default::default()
+        };
+        let redirect_uri = |flow: InstalledFlow<DefaultFlowDelegate>| {
+            flow.start(vec!["email"]).unwrap().redirect_uri
+        };
+        let interactive = |secret: &ApplicationSecret| {
+            InstalledFlow::new(secret.clone(), InstalledFlowReturnMethod::Interactive)
+        };
+        let redirect = |secret: &ApplicationSecret| {
+            InstalledFlow::new(
+                secret.clone(),
+                InstalledFlowReturnMethod::HTTPRedirect(8080),
+            )
+        };
+        assert_eq!(
+            "com.example.app:/oauth2redirect",
+            redirect_uri(interactive(&app_secret))
+        );
+        assert_eq!(
+            "http://127.0.0.1:8080/callback",
+            redirect_uri(redirect(&app_secret))
+        );
+        assert_eq!(
+            "http://[::1]:8080/callback",
+            redirect_uri(redirect(&app_secret).redirect_address(Ipv6Addr::LOCALHOST.into()))
+        );
+        assert_eq!(
+            "com.example.other:/cb",
+            redirect_uri(interactive(&app_secret).redirect_uri("com.example.other:/cb"))
+        );
+
+        let mut app_secret = app_secret;
+        app_secret.redirect_uris = vec![
+            "com.example.app:/oauth2redirect".to_string(),
+            OOB_REDIRECT_URI.to_string(),
+        ];
+        assert_eq!(OOB_REDIRECT_URI, redirect_uri(interactive(&app_secret)));
+        let remote = |secret: &ApplicationSecret| {
+            InstalledFlow::new(secret.clone(), InstalledFlowReturnMethod::Remote)
+        };
+        assert_eq!(OOB_REDIRECT_URI, redirect_uri(remote(&app_secret)));
+        app_secret
+            .redirect_uris
+            .push("http://localhost".to_string());
+        assert_eq!("http://localhost", redirect_uri(remote(&app_secret)));
+        assert_eq!(OOB_REDIRECT_URI, redirect_uri(interactive(&app_secret)));
+        app_secret.redirect_uris.pop();
+        assert_eq!("http://localhost:8080", redirect_uri(redirect(&app_secret)));
+    }
+
     #[test]
     fn test_request_url_builder() {
         assert_eq!(
@@ -738,15 +1898,57 @@ mod tests {
                 "812741506391-h38jh0j4fv0ce1krdkiq0hfvt6n5am\
                  rf.apps.googleusercontent.com",
                 vec![&"email".to_string(), &"profile".to_string()],
-                None
+                ScopeSeparator::Space,
+                None,
+                &[]
             )
         );
     }
 
+    #[test]
+    fn test_request_url_builder_encoding() {
+        assert_eq!(
+            "https://example.b2clogin.com/authorize?p=b2c_1_signin&scope=openid%20offline_access\
+             &redirect_uri=http://localhost:8080/cb?a%3D1%26b%3D2\
+             &response_type=code&client_id=client",
+            build_authentication_request_url(
+                "https://example.b2clogin.com/authorize?p=b2c_1_signin",
+                "client",
+                vec![&"openid".to_string(), &"offline_access".to_string()],
+                ScopeSeparator::Space,
+                Some("http://localhost:8080/cb?a=1&b=2".to_string()),
+                &[]
+            )
+        );
+    }
+
+    #[cfg(feature = "https-redirect")]
+    #[test]
+    fn test_https_server() {
+        let config = ServerConfig {
+            https: true,
+            ..Default::default()
+        };
+        let server = InstalledFlowServer::new(0, &config).unwrap();
+        assert_eq!(
+            format!("https://localhost:{}", server.port),
+            server.redirect_uri()
+        );
+        let fingerprint = server.certificate_fingerprint.clone().unwrap();
+        assert_eq!(32 * 3 - 1, fingerprint.len());
+        assert!(fingerprint
+            .split(':')
+            .all(|b| b.len() == 2 && u8::from_str_radix(b, 16).is_ok()));
+
+        let plain = InstalledFlowServer::new(0, &ServerConfig::default()).unwrap();
+        assert_eq!(None, plain.certificate_fingerprint);
+        assert!(plain.redirect_uri().starts_with("http://localhost:"));
+    }
+
     #[test]
     fn test_server_random_local_port() {
-        let addr1 = InstalledFlowServer::new(0).unwrap();
-        let addr2 = InstalledFlowServer::new(0).unwrap();
+        let addr1 = InstalledFlowServer::new(0, &ServerConfig::default()).unwrap();
+        let addr2 = InstalledFlowServer::new(0, &ServerConfig::default()).unwrap();
         assert_ne!(addr1.port, addr2.port);
     }
 
@@ -755,11 +1957,104 @@ mod tests {
         let (tx, rx) = oneshot::channel();
         let mut handler = InstalledFlowService {
             auth_code_tx: Arc::new(Mutex::new(Option::Some(tx))),
+            config: Arc::new(ServerConfig::default()),
         };
         // URLs are usually a bit botched
         let url: Uri = "http://example.com:1234/?code=ab/c%2Fd#".parse().unwrap();
-        handler.handle_url(url);
-        assert_eq!(rx.wait().unwrap(), "ab/c/d".to_string());
+        assert_eq!(Ok(()), handler.handle_url(url));
+        assert_eq!(rx.wait().unwrap(), Ok("ab/c/d".to_string()));
+
+        let (tx, rx) = oneshot::channel();
+        handler.auth_code_tx = Arc::new(Mutex::new(Option::Some(tx)));
+        let url: Uri =
+            "http://example.com:1234/?error=access_denied&error_description=User+said+no"
+                .parse()
+                .unwrap();
+        let error = "access_denied: User said no".to_string();
+        assert_eq!(Err(error.clone()), handler.handle_url(url));
+        assert_eq!(rx.wait().unwrap(), Err(error));
+    }
+
+    #[test]
+    fn test_server_address_and_path() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let client: hyper::Client<hyper::client::HttpConnector, hyper::Body> =
+            hyper::Client::builder()
+                .executor(runtime.executor())
+                .build_http();
+        let config = ServerConfig {
+            address: Some(Ipv4Addr::LOCALHOST.into()),
+            path: Some("/oauth2callback".to_string()),
+            ..Default::default()
+        };
+        let server = InstalledFlowServer::new(0, &config).unwrap();
+        assert_eq!(
+            format!("http://127.0.0.1:{}/oauth2callback", server.port),
+            server.redirect_uri()
+        );
+
+        let uri = format!("http://127.0.0.1:{}/favicon.ico?code=wrong", server.port);
+        let response = client.get(uri.parse().unwrap()).wait().unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let uri = format!("{}?code=authcode", server.redirect_uri());
+        let response = client.get(uri.parse().unwrap()).wait().unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(Ok("authcode".to_string()), server.auth_code().wait());
+
+        let config = ServerConfig {
+            address: Some(std::net::Ipv6Addr::LOCALHOST.into()),
+            ..Default::default()
+        };
+        assert_eq!("http://[::1]:8080", config.redirect_uri(8080));
+        assert_eq!("[::1]:8080".parse(), Ok(config.bind_address(8080)));
+
+        let config = ServerConfig {
+            path: Some("oauth2callback".to_string()),
+            ..Default::default()
+        };
+        match InstalledFlowServer::new(0, &config) {
+            Err(RequestError::UserError(e)) => assert!(e.contains("oauth2callback"), "{}", e),
+            r => panic!("unexpected result {:?}", r.map(|s| s.redirect_uri())),
+        }
+    }
+
+    #[test]
+    fn test_server_pages() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let client: hyper::Client<hyper::client::HttpConnector, hyper::Body> =
+            hyper::Client::builder()
+                .executor(runtime.executor())
+                .build_http();
+        let config = ServerConfig {
+            success_page: Some(RedirectPage::Redirect(
+                "https://example.com/done".to_string(),
+            )),
+            error_page: Some(RedirectPage::Html("<p>{error}</p>".to_string())),
+            ..Default::default()
+        };
+
+        let server = InstalledFlowServer::new(0, &config).unwrap();
+        let uri = format!(
+            "http://127.0.0.1:{}/?error=access_denied&error_description=%3Cno%3E",
+            server.port
+        );
+        let response = client.get(uri.parse().unwrap()).wait().unwrap();
+        assert!(response.status().is_success());
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(&b"<p>access_denied: &lt;no&gt;</p>"[..], &body[..]);
+        assert_eq!(
+            Err("access_denied: <no>".to_string()),
+            server.auth_code().wait()
+        );
+
+        let server = InstalledFlowServer::new(0, &config).unwrap();
+        let uri = format!("http://127.0.0.1:{}/?code=authcode", server.port);
+        let response = client.get(uri.parse().unwrap()).wait().unwrap();
+        assert_eq!(StatusCode::FOUND, response.status());
+        assert_eq!(
+            "https://example.com/done",
+            response.headers()[hyper::header::LOCATION]
+        );
     }
 
     #[test]
@@ -769,7 +2064,7 @@ mod tests {
             hyper::Client::builder()
                 .executor(runtime.executor())
                 .build_http();
-        let mut server = InstalledFlowServer::new(0).unwrap();
+        let server = InstalledFlowServer::new(0, &ServerConfig::default()).unwrap();
 
         let response = client
             .get(
@@ -803,7 +2098,7 @@ mod tests {
             }
         }
 
-        match server.block_till_auth() {
+        match server.auth_code().wait() {
             Result::Ok(response) => {
                 assert_eq!(response, "ab/c/d".to_string());
             }
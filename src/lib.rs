@@ -19,6 +19,25 @@
 //! for a detailed description of the protocol. This crate implements OAuth for Service Accounts
 //! based on the Google APIs; it may or may not work with other providers.
 //!
+//! # Workload identity federation
+//! Workloads running on AWS, Azure, in CI systems or anywhere else with an OIDC identity can
+//! obtain Google access tokens without a service account key, using an `external_account`
+//! configuration and the `ExternalAccountAccess`, which obtains the subject token from a file,
+//! a URL or the AWS environment. GitHub Actions jobs can use
+//! `ExternalAccountKey::github_actions()` for keyless deploys, and Kubernetes pods
+//! `ExternalAccountKey::kubernetes()`. The `ProjectedToken` provides a pod's rotating service
+//! account token itself, e.g. for cluster-internal services.
+//!
+//! Tokens obtained entirely elsewhere, like a sidecar-issued token or one provisioned for local
+//! development, can be handed out with the `StaticTokenSource`. The `ChainedTokenSource` tries
+//! several token sources in order, e.g. to run the same binary on a developer's machine and on
+//! Google Cloud.
+//!
+//! # Identity-Aware Proxy
+//! Services behind Google's Identity-Aware Proxy accept ID tokens issued for the proxy's OAuth
+//! client ID. The `IapAccess` obtains them from a service account, an impersonated service
+//! account or the metadata server and sets the `Proxy-Authorization` header of requests.
+//!
 //! # Installed Flow Usage
 //! The `InstalledFlow` involves showing a URL to the user (or opening it in a browser)
 //! and then either prompting the user to enter a displayed code, or make the authorizing
@@ -34,12 +53,17 @@
 //! The returned `Token` is stored permanently in the given token storage in order to
 //! authorize future API requests to the same scopes.
 //!
+//! # Web Flow Usage
+//! Web applications authorizing many users use the `WebFlow`, which keeps the state of pending
+//! authorizations in a `SessionStore` and the tokens of every user in a `TokenStorage`.
+//!
 //! The following example, which is derived from the (actual and runnable) example in
 //! `examples/test-installed/`, shows the basics of using this crate:
 //!
 //! ```test_harness,no_run
 //! use futures::prelude::*;
 //! use yup_oauth2::GetToken;
+//! # #[cfg(all(feature = "installed", feature = "disk-storage"))]
 //! use yup_oauth2::{Authenticator, InstalledFlow};
 //!
 //! use hyper::client::Client;
@@ -47,6 +71,7 @@
 //!
 //! use std::path::Path;
 //!
+//! # #[cfg(all(feature = "installed", feature = "disk-storage"))]
 //! fn main() {
 //!     // Read application secret from a file. Sometimes it's easier to compile it directly into
 //!     // the binary. The clientsecret file contains JSON like `{"installed":{"client_id": ... }}`
@@ -57,7 +82,7 @@
 //!      // authentication tokens are persisted to a file named tokencache.json. The
 //!      // authenticator takes care of caching tokens to disk and refreshing tokens once
 //!      // they've expired.
-//!     let mut auth = Authenticator::new(
+//!     let auth = Authenticator::new(
 //!         InstalledFlow::new(secret, yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect(0))
 //!     )
 //!     .persist_tokens_to_disk("tokencache.json")
@@ -80,30 +105,176 @@
 //! }
 //! ```
 //!
+//! # Cargo features
+//! * `chrono` (default): expose points in time, like `Token::expires_at()`, as
+//!   `chrono::DateTime<Utc>`. Without it, `Timestamp` is a `std::time::SystemTime`.
+//! * `device` (default): the `DeviceFlow`, including `GitHub::device_flow()` and
+//!   `AzureAd::device_flow()`.
+//! * `disk-storage` (default): the `DiskTokenStorage`, used by
+//!   `Authenticator::persist_tokens_to_disk()`, writing JSON files.
+//! * `external-account` (default): the `ExternalAccountAccess` for workload identity
+//!   federation, and `external_account_key_from_file()`.
+//! * `google-scopes`: provide constants for common Google API scopes in `google_scopes`.
+//! * `impersonated-service-account` (default): the `ImpersonatedServiceAccountAccess` for
+//!   credential files of type `impersonated_service_account`, and
+//!   `impersonated_service_account_key_from_file()`.
+//! * `https-redirect`: allow the `InstalledFlow`'s redirect listener to serve HTTPS using a
+//!   self-signed certificate, see `InstalledFlow::https_redirect()`. Implies `installed`.
+//! * `installed` (default): the `InstalledFlow` and the `WebFlow`, which share the
+//!   authorization code grant.
+//! * `metadata-server` (default): the `MetadataServerAccess` for workloads running on Google
+//!   Cloud.
+//! * `serde_cbor`: let the `DiskTokenStorage` read and write CBOR files, see `StorageFormat`.
+//! * `service-account` (default): the `ServiceAccountAccess` and
+//!   `service_account_key_from_file()`.
+//! * `toml`: let the `DiskTokenStorage` read and write TOML files, see `StorageFormat`.
+//!
+//! Applications which only refresh tokens obtained elsewhere, using an `Authenticator` with a
+//! custom `AuthFlow` and the `MemoryStorage`, may disable the default features except for
+//! `chrono`.
+//!
 #[macro_use]
 extern crate serde_derive;
 
+mod audit;
 mod authenticator;
 mod authenticator_delegate;
+mod azure;
+mod backoff;
+mod chain;
+#[cfg(all(feature = "device", feature = "disk-storage", feature = "installed"))]
+mod cli;
+mod config_check;
+mod consent;
+#[cfg(feature = "device")]
 mod device;
+mod ephemeral;
+#[cfg(feature = "external-account")]
+mod external_account;
+mod foreign_token;
+mod github;
 mod helper;
+mod iap;
+#[cfg(feature = "impersonated-service-account")]
+mod impersonated;
+#[cfg(feature = "installed")]
 mod installed;
+#[cfg(feature = "metadata-server")]
+mod metadata;
+mod oidc;
+mod pool;
+mod projected_token;
+mod random;
 mod refresh;
+mod retry_budget;
+mod scope;
+mod secret_storage;
+#[cfg(feature = "service-account")]
 mod service_account;
+mod static_token;
+mod stats;
 mod storage;
+mod storage_combinators;
+#[cfg(feature = "disk-storage")]
+mod storage_format;
+mod time;
+#[cfg(any(
+    feature = "external-account",
+    feature = "impersonated-service-account",
+    feature = "metadata-server",
+    feature = "service-account"
+))]
+mod token_cache;
+mod tokeninfo;
+mod transport;
 mod types;
+mod userinfo;
+mod validation;
+#[cfg(feature = "installed")]
+mod web;
 
-pub use crate::authenticator::{AuthFlow, Authenticator};
+#[cfg(feature = "google-scopes")]
+pub mod google_scopes;
+
+pub use crate::audit::{AuditEvent, AuditEventKind, AuditSink, LogAuditSink};
+pub use crate::authenticator::{AuthFlow, Authenticator, ScopedAuthenticator};
 pub use crate::authenticator_delegate::{
     AuthenticatorDelegate, DefaultAuthenticatorDelegate, DefaultFlowDelegate, FlowDelegate,
-    PollInformation,
+    PollInformation, Retry,
+};
+pub use crate::azure::{
+    AzureAd, AzureTokenResponseParser, AZURE_AUTH_URI_TEMPLATE, AZURE_DEVICE_CODE_URI_TEMPLATE,
+    AZURE_OFFLINE_ACCESS_SCOPE, AZURE_TOKEN_URI_TEMPLATE,
 };
-pub use crate::device::{DeviceFlow, GOOGLE_DEVICE_CODE_URL};
+pub use crate::backoff::{BackoffPolicy, DecorrelatedJitter, ExponentialBackoff, FixedBackoff};
+pub use crate::chain::ChainedTokenSource;
+#[cfg(all(feature = "device", feature = "disk-storage", feature = "installed"))]
+pub use crate::cli::{Cli, LoginMethod, LoginStatus};
+pub use crate::config_check::{ConfigCheck, ConfigReport, REACHABILITY_TIMEOUT};
+pub use crate::consent::{ConsentCheck, ConsentEndpoint};
+#[cfg(feature = "device")]
+pub use crate::device::{
+    DeviceFlow, DeviceFlowProtocol, PendingDeviceAuthorization, GOOGLE_DEVICE_CODE_URL,
+};
+pub use crate::ephemeral::{EphemeralAuthenticator, GOOGLE_REVOCATION_URL};
+#[cfg(feature = "external-account")]
+pub use crate::external_account::{
+    CredentialFormat, CredentialSource, ExternalAccountAccess, ExternalAccountKey,
+    ServiceAccountImpersonation,
+};
+pub use crate::foreign_token::ForeignToken;
+pub use crate::github::{GitHub, GITHUB_AUTH_URI, GITHUB_DEVICE_CODE_URL, GITHUB_TOKEN_URI};
 pub use crate::helper::*;
-pub use crate::installed::{InstalledFlow, InstalledFlowReturnMethod};
+pub use crate::iap::{set_proxy_authorization, IapAccess};
+#[cfg(feature = "impersonated-service-account")]
+pub use crate::impersonated::{
+    AuthorizedUserSecret, ImpersonatedServiceAccountAccess, ImpersonatedServiceAccountKey,
+    SourceCredentials,
+};
+#[cfg(feature = "installed")]
+pub use crate::installed::{
+    AuthorizationResponse, InstalledFlow, InstalledFlowReturnMethod, PendingAuthorization,
+    RedirectPage,
+};
+#[cfg(feature = "metadata-server")]
+pub use crate::metadata::{MetadataServerAccess, GCE_METADATA_HOST};
+pub use crate::oidc::{DiscoveryDocument, DocumentCache, Jwk, Jwks};
+pub use crate::pool::{AuthenticatorPool, DEFAULT_POOL_CAPACITY};
+pub use crate::projected_token::{ProjectedToken, KUBERNETES_SERVICE_ACCOUNT_TOKEN_PATH};
+pub use crate::random::{OsRandom, RandomSource};
+pub use crate::refresh::RefreshFlow;
+pub use crate::retry_budget::RetryBudget;
+pub use crate::scope::{Scope, ScopePolicy};
+pub use crate::secret_storage::{SecretFile, SecretStorage};
+#[cfg(feature = "service-account")]
 pub use crate::service_account::*;
-pub use crate::storage::{DiskTokenStorage, MemoryStorage, NullStorage, TokenStorage};
+pub use crate::static_token::StaticTokenSource;
+pub use crate::stats::{
+    AuthenticatorStats, CredentialStats, SignInOutcome, TokenInfo, TokenSource,
+};
+#[cfg(feature = "disk-storage")]
+pub use crate::storage::DiskTokenStorage;
+pub use crate::storage::{
+    migrate, MemoryStorage, MigrationError, NullStorage, RefreshFailure, RefreshFailureKind,
+    RefreshStart, StoredToken, TokenKey, TokenStorage,
+};
+pub use crate::storage_combinators::{
+    CachedStorage, Cipher, EncryptedStorage, LayeredStorage, ProfileStorage, ReadOnlyStorage,
+    StorageError,
+};
+#[cfg(feature = "disk-storage")]
+pub use crate::storage_format::StorageFormat;
+pub use crate::time::Timestamp;
+pub use crate::tokeninfo::{tokeninfo, AccessTokenInfo, GOOGLE_TOKENINFO_URL};
 pub use crate::types::{
-    ApplicationSecret, ConsoleApplicationSecret, FlowType, GetToken, PollError, RefreshResult,
-    RequestError, Scheme, Token, TokenType,
+    ApplicationSecret, ClientAuthMethod, ConsoleApplicationSecret, DefaultTokenResponseParser,
+    FlowType, GetToken, JsonError, PollError, RedirectUriKind, RefreshResult, RequestError, Scheme,
+    ScopeSeparator, StrictTokenResponseParser, Token, TokenProvider, TokenResponseParser,
+    TokenType, TransportError, DEFAULT_EXPIRY_MARGIN,
+};
+pub use crate::userinfo::{userinfo, UserInfo, GOOGLE_USERINFO_URL};
+pub use crate::validation::{
+    validate_access_token_claims, AccessTokenClaims, TokenValidation, ValidationError,
 };
+#[cfg(feature = "installed")]
+pub use crate::web::{MemorySessionStore, SessionStore, WebAuthenticator, WebFlow};
@@ -0,0 +1,319 @@
+//! This module provides a token source (`GetToken`) for workloads running on Google Cloud, e.g.
+//! on Compute Engine, GKE, Cloud Run or Cloud Functions, which obtains the tokens of the
+//! attached service account from the metadata server.
+//!
+//! Resources:
+//! - [Metadata server](https://cloud.google.com/compute/docs/metadata/overview)
+//! - [Obtaining ID tokens](https://cloud.google.com/compute/docs/instances/verifying-instance-identity)
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, prelude::*};
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::config_check::ConfigReport;
+use crate::token_cache::TokenCache;
+use crate::transport;
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+
+/// The metadata server's host, unless overridden by the `GCE_METADATA_HOST` environment
+/// variable.
+pub const GCE_METADATA_HOST: &str = "metadata.google.internal";
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = RequestError> + Send>;
+
+/// Obtains access tokens and ID tokens of the service account attached to the workload from the
+/// metadata server. It caches tokens until they expire; use it directly rather than with an
+/// `Authenticator`.
+pub struct MetadataServerAccess<C> {
+    client: C,
+    host: Option<String>,
+    service_account: String,
+}
+
+impl MetadataServerAccess<DefaultHyperClient> {
+    /// Create a new MetadataServerAccess for the default service account.
+    pub fn new() -> Self {
+        MetadataServerAccess {
+            client: DefaultHyperClient::default(),
+            host: None,
+            service_account: "default".to_string(),
+        }
+    }
+
+    /// Keep connections to the metadata server alive between token requests.
+    pub fn keep_alive(self, keep_alive: bool) -> Self {
+        MetadataServerAccess {
+            client: DefaultHyperClient {
+                keep_alive,
+                ..self.client
+            },
+            ..self
+        }
+    }
+}
+
+impl Default for MetadataServerAccess<DefaultHyperClient> {
+    fn default() -> Self {
+        MetadataServerAccess::new()
+    }
+}
+
+impl<C> MetadataServerAccess<C>
+where
+    C: HyperClientBuilder,
+    C::Connector: 'static,
+{
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(
+        self,
+        hyper_client: NewC,
+    ) -> MetadataServerAccess<NewC> {
+        MetadataServerAccess {
+            client: hyper_client,
+            host: self.host,
+            service_account: self.service_account,
+        }
+    }
+
+    /// Contact the metadata server at `host`, like `localhost:8080`. (default: the
+    /// `GCE_METADATA_HOST` environment variable, or `metadata.google.internal`)
+    pub fn host<S: Into<String>>(self, host: S) -> Self {
+        MetadataServerAccess {
+            host: Some(host.into()),
+            ..self
+        }
+    }
+
+    /// Obtain tokens of the attached service account with email `service_account`. (default:
+    /// `default`)
+    pub fn service_account<S: Into<String>>(self, service_account: S) -> Self {
+        MetadataServerAccess {
+            service_account: service_account.into(),
+            ..self
+        }
+    }
+
+    /// Build the configured MetadataServerAccess.
+    pub fn build(self) -> impl GetToken + Send + Sync
+    where
+        C::Connector: Send + Sync,
+    {
+        let host = self
+            .host
+            .or_else(|| std::env::var("GCE_METADATA_HOST").ok())
+            .unwrap_or_else(|| GCE_METADATA_HOST.to_string());
+        MetadataServerAccessImpl {
+            client: self.client.build_hyper_client(),
+            base_url: Arc::new(format!(
+                "http://{}/computeMetadata/v1/instance/service-accounts/{}",
+                host, self.service_account
+            )),
+            cache: TokenCache::new(),
+        }
+    }
+}
+
+struct MetadataServerAccessImpl<C> {
+    client: hyper::Client<C, hyper::Body>,
+    /// The URL of the service account's metadata directory.
+    base_url: Arc<String>,
+    cache: TokenCache,
+}
+
+impl<C: 'static + hyper::client::connect::Connect> MetadataServerAccessImpl<C> {
+    /// Fetches the service account's metadata entry `path`, with the query `params`.
+    fn get(&self, path: &str, params: &[(&str, &str)]) -> BoxFuture<String> {
+        let mut url = match url::Url::parse(&format!("{}/{}", self.base_url, path)) {
+            Ok(url) => url,
+            Err(e) => {
+                return Box::new(future::err(RequestError::UserError(format!(
+                    "invalid metadata server URL: {}",
+                    e
+                ))))
+            }
+        };
+        if !params.is_empty() {
+            url.query_pairs_mut().extend_pairs(params);
+        }
+        let headers = vec![("Metadata-Flavor".to_string(), "Google".to_string())];
+        let request = transport::build_request(
+            hyper::Method::GET,
+            url.as_str(),
+            &headers,
+            hyper::Body::empty(),
+        );
+        let client = self.client.clone();
+        Box::new(
+            future::result(request).and_then(move |request| transport::fetch(&client, request)),
+        )
+    }
+
+    /// Fetches a new access token for `scopes`.
+    fn fetch_token(&self, scopes: Vec<String>) -> BoxFuture<Token> {
+        let joined = scopes.join(",");
+        let params: Vec<(&str, &str)> = if scopes.is_empty() {
+            vec![]
+        } else {
+            vec![("scopes", &joined)]
+        };
+        Box::new(
+            self.get("token", &params)
+                .and_then(|body| serde_json::from_str(&body).map_err(RequestError::JSONError)),
+        )
+    }
+}
+
+impl<C: 'static> GetToken for MetadataServerAccessImpl<C>
+where
+    C: hyper::client::connect::Connect,
+{
+    /// Returns an access token for `scopes`. On Compute Engine, the scopes granted to the
+    /// instance limit those of the token.
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.cache.token(scopes, |scopes| self.fetch_token(scopes))
+    }
+
+    /// Fetches a new token unless the cached one remains valid for `duration`.
+    fn token_valid_for<I, T>(
+        &self,
+        duration: Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.cache
+            .token_valid_for(duration, scopes, |scopes| self.fetch_token(scopes))
+    }
+
+    /// Drops the cached token for `scopes`, then requests a new one.
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let scopes = self.cache.forget(scopes);
+        self.token(scopes)
+    }
+
+    /// Drops the cached token with `access_token`, so that a new one is requested next.
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.cache.invalidate(access_token)
+    }
+
+    /// Checks that the metadata server knows the service account.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let subject = format!("service account {}", self.base_url);
+        Box::new(self.get("email", &[]).then(move |email| {
+            let mut report = ConfigReport::default();
+            report.check(subject, email.map(|_| ()).map_err(|e| e.to_string()));
+            Ok(report)
+        }))
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        Default::default()
+    }
+
+    fn api_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns a Google-signed ID token for `audience`, including the service account's email,
+    /// cached until it expires.
+    fn id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.cache.id_token(audience, || {
+            self.get("identity", &[("audience", audience), ("format", "full")])
+                .map(|body| Token::from_jwt(body.trim().to_string()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time;
+    use hyper_rustls::HttpsConnector;
+    use mockito::{self, mock, Matcher};
+
+    #[test]
+    fn test_metadata_server() {
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let access = MetadataServerAccess::new()
+            .hyper_client(client)
+            .host(mockito::server_address().to_string())
+            .build();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let base = "/computeMetadata/v1/instance/service-accounts/default";
+
+        let _token = mock(
+            "GET",
+            Matcher::Regex(format!(
+                "^{}/token\\?scopes=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fpubsub%2Copenid$",
+                base
+            )),
+        )
+        .match_header("metadata-flavor", "Google")
+        .with_body(
+            r#"{"access_token": "ya29.metadata", "expires_in": 3599, "token_type": "Bearer"}"#,
+        )
+        .expect(1)
+        .create();
+        let scopes = vec!["https://www.googleapis.com/auth/pubsub", "openid"];
+        let token = rt.block_on(access.token(scopes.clone())).unwrap();
+        assert_eq!("ya29.metadata", token.access_token);
+        rt.block_on(access.token(scopes)).unwrap();
+        _token.assert();
+
+        let exp = time::now() + 3600;
+        let id_token = format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.c2ln",
+            base64::encode_config(
+                &serde_json::json!({"aud": "https://app.run.app", "exp": exp}).to_string(),
+                base64::URL_SAFE_NO_PAD
+            )
+        );
+        let _identity = mock(
+            "GET",
+            Matcher::Regex(format!(
+                "^{}/identity\\?audience=https%3A%2F%2Fapp.run.app&format=full$",
+                base
+            )),
+        )
+        .with_body(&id_token)
+        .expect(1)
+        .create();
+        let token = rt.block_on(access.id_token("https://app.run.app")).unwrap();
+        assert_eq!(id_token, token.access_token);
+        assert_eq!(Some(exp), token.expires_at().map(|t| time::to_secs(&t)));
+        rt.block_on(access.id_token("https://app.run.app")).unwrap();
+        _identity.assert();
+
+        let _email = mock("GET", format!("{}/email", base).as_str())
+            .match_header("metadata-flavor", "Google")
+            .with_body("sa@project.iam.gserviceaccount.com")
+            .create();
+        let report = rt.block_on(access.validate()).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        let missing = MetadataServerAccess::new()
+            .host(mockito::server_address().to_string())
+            .service_account("missing@project.iam.gserviceaccount.com")
+            .build();
+        assert!(!rt.block_on(missing.validate()).unwrap().is_ok());
+    }
+}
@@ -0,0 +1,362 @@
+//! OpenID Connect discovery documents and JSON Web Key Sets, with HTTP caching.
+//!
+//! Both kinds of documents change rarely and are served with caching headers, e.g. by Google
+//! with `Cache-Control: max-age=...`. A `DocumentCache` keeps them according to these headers,
+//! optionally on disk, so that short-lived processes don't fetch them on every run and can work
+//! offline while the cached documents are fresh.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::{future, prelude::*};
+use hyper::{header, StatusCode};
+
+use crate::time;
+use crate::transport;
+use crate::types::RequestError;
+
+/// The path of the discovery document, relative to the issuer (OpenID Connect Discovery 1.0,
+/// section 4).
+const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+
+/// The provider metadata published at `<issuer>/.well-known/openid-configuration`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DiscoveryDocument {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: Option<String>,
+    pub device_authorization_endpoint: Option<String>,
+    pub userinfo_endpoint: Option<String>,
+    pub revocation_endpoint: Option<String>,
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub scopes_supported: Vec<String>,
+    #[serde(default)]
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    /// All other metadata.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A JSON Web Key Set (RFC 7517, section 5), as published at a provider's `jwks_uri`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+impl Jwks {
+    /// Returns the key with the key ID `kid`.
+    pub fn find(&self, kid: &str) -> Option<&Jwk> {
+        self.keys.iter().find(|k| k.kid.as_deref() == Some(kid))
+    }
+}
+
+/// A JSON Web Key (RFC 7517, section 4). Only the public key parameters of RSA (`n`, `e`) and
+/// elliptic curve keys (`crv`, `x`, `y`) are represented.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    pub alg: Option<String>,
+    #[serde(rename = "use")]
+    pub use_: Option<String>,
+    pub n: Option<String>,
+    pub e: Option<String>,
+    pub crv: Option<String>,
+    pub x: Option<String>,
+    pub y: Option<String>,
+}
+
+/// A cached response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedDocument {
+    body: String,
+    etag: Option<String>,
+    /// Until when the document may be used without asking the server, in seconds since the
+    /// epoch.
+    fresh_until: i64,
+}
+
+/// Caches JSON documents fetched using HTTP GET, following the `Cache-Control` (`max-age`,
+/// `no-cache`, `no-store`) and `Age` headers of the responses. Expired documents with an `ETag`
+/// are revalidated using `If-None-Match`.
+///
+/// Clones share the cached documents, also between threads.
+#[derive(Clone, Debug, Default)]
+pub struct DocumentCache {
+    location: Option<PathBuf>,
+    documents: Arc<Mutex<HashMap<String, CachedDocument>>>,
+}
+
+impl DocumentCache {
+    /// A cache forgetting everything once dropped.
+    pub fn new() -> DocumentCache {
+        DocumentCache::default()
+    }
+
+    /// A cache persisted to the file at `location`, which is created if it doesn't exist yet.
+    pub fn persist_to_disk<P: AsRef<Path>>(location: P) -> io::Result<DocumentCache> {
+        let location = location.as_ref().to_owned();
+        let documents = match fs::read(&location) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(DocumentCache {
+            location: Some(location),
+            documents: Arc::new(Mutex::new(documents)),
+        })
+    }
+
+    /// Returns the discovery document of `issuer`, e.g. `https://accounts.google.com`.
+    pub fn discovery_document<C>(
+        &self,
+        client: hyper::Client<C>,
+        issuer: &str,
+    ) -> impl Future<Item = DiscoveryDocument, Error = RequestError> + Send
+    where
+        C: 'static + hyper::client::connect::Connect,
+    {
+        let uri = format!("{}{}", issuer.trim_end_matches('/'), DISCOVERY_PATH);
+        self.get(client, &uri)
+            .and_then(|body| serde_json::from_str(&body).map_err(RequestError::JSONError))
+    }
+
+    /// Returns the key set published at `jwks_uri`.
+    pub fn jwks<C>(
+        &self,
+        client: hyper::Client<C>,
+        jwks_uri: &str,
+    ) -> impl Future<Item = Jwks, Error = RequestError> + Send
+    where
+        C: 'static + hyper::client::connect::Connect,
+    {
+        self.get(client, jwks_uri)
+            .and_then(|body| serde_json::from_str(&body).map_err(RequestError::JSONError))
+    }
+
+    /// Returns the body of `uri`, from the cache if it is fresh.
+    pub fn get<C>(
+        &self,
+        client: hyper::Client<C>,
+        uri: &str,
+    ) -> impl Future<Item = String, Error = RequestError> + Send
+    where
+        C: 'static + hyper::client::connect::Connect,
+    {
+        let cached = self.documents.lock().unwrap().get(uri).cloned();
+        if let Some(ref cached) = cached {
+            if cached.fresh_until > time::now() {
+                return future::Either::A(future::ok(cached.body.clone()));
+            }
+        }
+
+        let mut request = hyper::Request::get(uri);
+        request.header(header::ACCEPT, "application/json");
+        if let Some(etag) = cached.as_ref().and_then(|c| c.etag.as_ref()) {
+            request.header(header::IF_NONE_MATCH, etag.as_str());
+        }
+        let request = match request.body(hyper::Body::empty()) {
+            Ok(request) => request,
+            Err(e) => {
+                return future::Either::A(future::err(RequestError::UserError(format!(
+                    "invalid document URI {}: {}",
+                    uri, e
+                ))))
+            }
+        };
+        let (cache, uri) = (self.clone(), uri.to_string());
+        future::Either::B(
+            client
+                .request(request)
+                .map_err(RequestError::client_error)
+                .and_then(move |response| {
+                    let status = response.status();
+                    let fresh_until = fresh_until(response.headers());
+                    let etag = response
+                        .headers()
+                        .get(header::ETAG)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string);
+                    let body = match cached {
+                        Some(cached) if status == StatusCode::NOT_MODIFIED => {
+                            future::Either::A(future::ok(CachedDocument {
+                                etag: etag.or(cached.etag),
+                                ..cached
+                            }))
+                        }
+                        _ if status.is_success() => {
+                            future::Either::B(transport::read_body(response).map(move |body| {
+                                CachedDocument {
+                                    body,
+                                    etag,
+                                    fresh_until: 0,
+                                }
+                            }))
+                        }
+                        _ => {
+                            return future::Either::A(future::err(RequestError::BadServerResponse(
+                                format!("fetching {} failed with status {}", uri, status),
+                            )))
+                        }
+                    };
+                    future::Either::B(body.and_then(move |document| {
+                        let body = document.body.clone();
+                        if let Some(fresh_until) = fresh_until {
+                            cache.store(
+                                uri,
+                                CachedDocument {
+                                    fresh_until,
+                                    ..document
+                                },
+                            )?;
+                        }
+                        Ok(body)
+                    }))
+                }),
+        )
+    }
+
+    fn store(&self, uri: String, document: CachedDocument) -> Result<(), RequestError> {
+        let mut documents = self.documents.lock().unwrap();
+        documents.insert(uri, document);
+        if let Some(ref location) = self.location {
+            let serialized = serde_json::to_vec(&*documents).map_err(RequestError::JSONError)?;
+            fs::write(location, serialized).map_err(RequestError::LowLevelError)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns until when a response with `headers` may be used without revalidation, or `None` if
+/// it must not be stored.
+fn fresh_until(headers: &header::HeaderMap) -> Option<i64> {
+    let mut max_age = 0;
+    let mut no_cache = false;
+    // no-store wins over every other directive, wherever it appears.
+    for directive in headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+    {
+        match directive.split_once('=') {
+            _ if directive == "no-store" => return None,
+            _ if directive == "no-cache" => no_cache = true,
+            Some(("max-age", secs)) => max_age = secs.trim_matches('"').parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    if no_cache {
+        return Some(0);
+    }
+    let age: i64 = headers
+        .get(header::AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    Some(time::now().saturating_add(max_age).saturating_sub(age))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISCOVERY: &str = r#"{
+        "issuer": "https://accounts.google.com",
+        "authorization_endpoint": "https://accounts.google.com/o/oauth2/v2/auth",
+        "token_endpoint": "https://oauth2.googleapis.com/token",
+        "jwks_uri": "https://www.googleapis.com/oauth2/v3/certs",
+        "id_token_signing_alg_values_supported": ["RS256"],
+        "claims_supported": ["aud", "email"]
+    }"#;
+
+    #[test]
+    fn test_fresh_until() {
+        // The clock may tick between computing and checking the expected time.
+        let within = |offset: i64, headers: &header::HeaderMap| {
+            let before = time::now() + offset;
+            let fresh = fresh_until(headers).unwrap();
+            assert!(
+                before <= fresh && fresh <= time::now() + offset,
+                "{}",
+                fresh
+            );
+        };
+        let mut headers = header::HeaderMap::new();
+        within(0, &headers);
+        headers.insert(
+            header::CACHE_CONTROL,
+            "public, max-age=20000, must-revalidate".parse().unwrap(),
+        );
+        headers.insert(header::AGE, "100".parse().unwrap());
+        within(19900, &headers);
+        headers.append(header::CACHE_CONTROL, "no-store".parse().unwrap());
+        assert_eq!(None, fresh_until(&headers));
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        assert_eq!(Some(0), fresh_until(&headers));
+        headers.insert(header::CACHE_CONTROL, "no-cache, no-store".parse().unwrap());
+        assert_eq!(None, fresh_until(&headers));
+    }
+
+    #[test]
+    fn test_document_cache() {
+        let path = std::env::temp_dir().join(format!("yup-oauth2-documents-{}.json", time::now()));
+        let issuer = format!("{}/documents", mockito::server_url());
+        let jwks_uri = format!("{}/documents/certs", mockito::server_url());
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build_http::<hyper::Body>();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        // Fresh documents are only fetched once, even by the next process.
+        let _discovery = mockito::mock("GET", "/documents/.well-known/openid-configuration")
+            .with_header("cache-control", "public, max-age=3600")
+            .with_body(DISCOVERY)
+            .expect(1)
+            .create();
+        for _ in 0..2 {
+            let cache = DocumentCache::persist_to_disk(&path).unwrap();
+            let document = rt
+                .block_on(cache.discovery_document(client.clone(), &issuer))
+                .unwrap();
+            assert_eq!(
+                "https://www.googleapis.com/oauth2/v3/certs",
+                document.jwks_uri
+            );
+            assert_eq!(
+                vec!["RS256"],
+                document.id_token_signing_alg_values_supported
+            );
+            assert!(document.extra.contains_key("claims_supported"));
+        }
+        _discovery.assert();
+
+        // Stale documents are revalidated.
+        let cache = DocumentCache::new();
+        let _certs = mockito::mock("GET", "/documents/certs")
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_header("cache-control", "no-cache")
+            .with_header("etag", "\"v1\"")
+            .with_body(r#"{"keys": [{"kty": "RSA", "kid": "k1", "use": "sig", "n": "AQAB", "e": "AQAB"}]}"#)
+            .expect(1)
+            .create();
+        let _revalidated = mockito::mock("GET", "/documents/certs")
+            .match_header("if-none-match", "\"v1\"")
+            .with_status(304)
+            .expect(1)
+            .create();
+        let jwks = rt.block_on(cache.jwks(client.clone(), &jwks_uri)).unwrap();
+        assert_eq!(Some("sig"), jwks.find("k1").unwrap().use_.as_deref());
+        assert_eq!(jwks, rt.block_on(cache.jwks(client, &jwks_uri)).unwrap());
+        _certs.assert();
+        _revalidated.assert();
+        let _ = fs::remove_file(path);
+    }
+}
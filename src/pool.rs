@@ -0,0 +1,285 @@
+//! Token sources for many OAuth clients, like the per-tenant applications of a SaaS backend
+//! accessing its customers' Google Workspace domains.
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use futures::future;
+
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+
+/// The number of clients an `AuthenticatorPool` keeps by default.
+pub const DEFAULT_POOL_CAPACITY: usize = 1024;
+
+type Factory<G, C> =
+    Box<dyn Fn(ApplicationSecret, hyper::Client<C>) -> io::Result<G> + Send + Sync>;
+
+struct Entry<G> {
+    client_secret: String,
+    source: Arc<G>,
+    /// When the entry was used last, in uses of the pool.
+    used: u64,
+}
+
+struct Entries<G> {
+    by_client_id: HashMap<String, Entry<G>>,
+    uses: u64,
+}
+
+impl<G> Entries<G> {
+    /// Counts a use of the pool, and returns the kept token source of the client with `secret`.
+    fn kept(&mut self, secret: &ApplicationSecret) -> Option<Arc<G>> {
+        self.uses += 1;
+        let used = self.uses;
+        match self.by_client_id.get_mut(&secret.client_id) {
+            Some(ref mut entry) if entry.client_secret == secret.client_secret => {
+                entry.used = used;
+                Some(entry.source.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Keeps a token source per OAuth client, built on demand by a factory, like a function
+/// building an `Authenticator` which persists the tenant's tokens. All token sources share one
+/// hyper client, and so its connections.
+///
+/// Token sources are keyed by client ID, and rebuilt if the client secret changed. At most
+/// `capacity()` of them are kept, each with its cached tokens; beyond that, the least recently
+/// used one is dropped, and rebuilt when used again. Token sources handed out remain usable
+/// after they were dropped from the pool.
+///
+/// ```
+/// # #[cfg(all(feature = "device", feature = "disk-storage"))]
+/// # {
+/// # use yup_oauth2::{ApplicationSecret, Authenticator, AuthenticatorPool, DeviceFlow};
+/// let client = hyper::Client::builder()
+///     .build::<_, hyper::Body>(hyper_rustls::HttpsConnector::new(1));
+/// let pool = AuthenticatorPool::new(client, |secret: ApplicationSecret, client| {
+///     let tokens = format!("tenants/{}.json", secret.client_id);
+///     Authenticator::new(DeviceFlow::new(secret))
+///         .hyper_client(client)
+///         .persist_tokens_to_disk(tokens)
+///         .build()
+/// })
+/// .capacity(100);
+/// # }
+/// ```
+pub struct AuthenticatorPool<G, C> {
+    client: hyper::Client<C>,
+    factory: Factory<G, C>,
+    capacity: usize,
+    entries: Mutex<Entries<G>>,
+}
+
+impl<G, C> AuthenticatorPool<G, C>
+where
+    G: GetToken,
+    C: 'static + hyper::client::connect::Connect,
+{
+    /// Build the token source of each client using `factory`, passing it `client`.
+    pub fn new<F>(client: hyper::Client<C>, factory: F) -> AuthenticatorPool<G, C>
+    where
+        F: 'static + Fn(ApplicationSecret, hyper::Client<C>) -> io::Result<G> + Send + Sync,
+    {
+        AuthenticatorPool {
+            client,
+            factory: Box::new(factory),
+            capacity: DEFAULT_POOL_CAPACITY,
+            entries: Mutex::new(Entries {
+                by_client_id: HashMap::new(),
+                uses: 0,
+            }),
+        }
+    }
+
+    /// Keep the token sources of up to `capacity` clients, at least one.
+    /// (default: `DEFAULT_POOL_CAPACITY`)
+    pub fn capacity(self, capacity: usize) -> AuthenticatorPool<G, C> {
+        AuthenticatorPool {
+            capacity: capacity.max(1),
+            ..self
+        }
+    }
+
+    /// Returns the token source of the client with `secret`, building it if it isn't kept.
+    ///
+    /// The factory runs without locking the pool, so that building one token source, which may
+    /// read files, doesn't hold up the clients of others.
+    pub fn get(&self, secret: &ApplicationSecret) -> io::Result<Arc<G>> {
+        if let Some(source) = self.entries.lock().unwrap().kept(secret) {
+            return Ok(source);
+        }
+        let source = Arc::new((self.factory)(secret.clone(), self.client.clone())?);
+        let mut entries = self.entries.lock().unwrap();
+        // Another thread may have built one in the meantime, which is kept then.
+        if let Some(source) = entries.kept(secret) {
+            return Ok(source);
+        }
+        let used = entries.uses;
+        let replaced = entries.by_client_id.insert(
+            secret.client_id.clone(),
+            Entry {
+                client_secret: secret.client_secret.clone(),
+                source: source.clone(),
+                used,
+            },
+        );
+        if replaced.is_none() && entries.by_client_id.len() > self.capacity {
+            let evicted = entries
+                .by_client_id
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(client_id, _)| client_id.clone());
+            if let Some(client_id) = evicted {
+                entries.by_client_id.remove(&client_id);
+            }
+        }
+        Ok(source)
+    }
+
+    /// Returns a token for `scopes` from the token source of the client with `secret`.
+    pub fn token<I, T>(
+        &self,
+        secret: &ApplicationSecret,
+        scopes: I,
+    ) -> Box<dyn futures::Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        match self.get(secret) {
+            Ok(source) => source.token(scopes),
+            Err(e) => Box::new(future::err(RequestError::LowLevelError(e))),
+        }
+    }
+
+    /// Drops the token source of the client with `client_id`, e.g. when the tenant left.
+    /// Returns whether it was kept.
+    pub fn remove(&self, client_id: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.by_client_id.remove(client_id).is_some()
+    }
+
+    /// The number of token sources kept.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().by_client_id.len()
+    }
+
+    /// Whether no token source is kept.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::FakeConnector;
+    use futures::Future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Hands out tokens naming its client.
+    struct Tenant(ApplicationSecret);
+
+    impl GetToken for Tenant {
+        fn token<I, T>(&self, _: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+        where
+            T: Into<String>,
+            I: IntoIterator<Item = T>,
+        {
+            let token = Token::from_jwt(format!("{}:{}", self.0.client_id, self.0.client_secret));
+            Box::new(future::ok(token))
+        }
+
+        fn api_key(&self) -> Option<String> {
+            None
+        }
+
+        fn application_secret(&self) -> ApplicationSecret {
+            self.0.clone()
+        }
+    }
+
+    fn secret(client_id: &str, client_secret: &str) -> ApplicationSecret {
+        ApplicationSecret {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let built = Arc::new(AtomicUsize::new(0));
+        let counter = built.clone();
+        let pool = AuthenticatorPool::new(FakeConnector::new(vec![]).client(), move |secret, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            if secret.client_id.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::Other, "no client ID"));
+            }
+            Ok(Tenant(secret))
+        })
+        .capacity(2);
+        let token = |client_id, client_secret| {
+            pool.token(&secret(client_id, client_secret), vec!["scope"])
+                .wait()
+                .map(|t| t.access_token)
+        };
+
+        assert_eq!("a:1", token("a", "1").unwrap());
+        assert_eq!("b:1", token("b", "1").unwrap());
+        assert_eq!("a:1", token("a", "1").unwrap());
+        assert_eq!(2, built.load(Ordering::SeqCst));
+
+        // A third client drops the least recently used one.
+        assert_eq!("c:1", token("c", "1").unwrap());
+        assert_eq!(2, pool.len());
+        assert_eq!("a:1", token("a", "1").unwrap());
+        assert_eq!(3, built.load(Ordering::SeqCst));
+        assert_eq!("b:1", token("b", "1").unwrap());
+        assert_eq!(4, built.load(Ordering::SeqCst));
+
+        // A rotated secret replaces the token source.
+        assert_eq!("b:2", token("b", "2").unwrap());
+        assert_eq!(2, pool.len());
+        assert!(pool.remove("b"));
+        assert!(!pool.remove("b"));
+        match token("", "") {
+            Err(RequestError::LowLevelError(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(1, pool.len());
+    }
+
+    #[test]
+    fn test_pool_builds_unlocked() {
+        let (building, started) = mpsc::channel();
+        let (proceed, go) = mpsc::channel::<()>();
+        let (building, go) = (Mutex::new(building), Mutex::new(go));
+        let pool = Arc::new(AuthenticatorPool::new(
+            FakeConnector::new(vec![]).client(),
+            move |secret, _| {
+                if secret.client_id == "slow" {
+                    building.lock().unwrap().send(()).unwrap();
+                    go.lock().unwrap().recv().unwrap();
+                }
+                Ok(Tenant(secret))
+            },
+        ));
+        let slow = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get(&secret("slow", "1")).is_ok())
+        };
+        started.recv().unwrap();
+        // While the slow token source is built, others are handed out.
+        assert!(pool.get(&secret("a", "1")).is_ok());
+        assert_eq!(1, pool.len());
+        proceed.send(()).unwrap();
+        assert!(slow.join().unwrap());
+        assert_eq!(2, pool.len());
+    }
+}
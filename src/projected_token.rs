@@ -0,0 +1,227 @@
+//! This module provides a token source (`GetToken`) for Kubernetes service account tokens, which
+//! the kubelet projects into a pod's file system and rotates before they expire.
+//!
+//! The token is a JWT whose `exp` claim determines the returned `Token`'s expiry. It is read
+//! again whenever the file changes, which is noticed either when a token is requested or, using
+//! `ProjectedToken::watch()`, by polling the file in the background.
+//!
+//! The token can be sent as a bearer token to cluster-internal services, or exchanged for a
+//! Google access token, see `ExternalAccountKey::kubernetes()`.
+//!
+//! Resources:
+//! - [Service account token volume projection](https://kubernetes.io/docs/tasks/configure-pod-container/configure-service-account/#service-account-token-volume-projection)
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use futures::{future, prelude::*};
+
+use crate::config_check::ConfigReport;
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+
+/// The path of the token mounted into every pod, unless `automountServiceAccountToken` is
+/// disabled.
+pub const KUBERNETES_SERVICE_ACCOUNT_TOKEN_PATH: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// Identifies a version of the token file without reading it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Result<FileStamp, RequestError> {
+        let metadata = fs::metadata(path).map_err(RequestError::LowLevelError)?;
+        Ok(FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        })
+    }
+}
+
+/// A token file projected into the pod, like `/var/run/secrets/tokens/vault-token`.
+///
+/// Requests for tokens ignore the scopes, as the token's audience is fixed in the pod spec.
+#[derive(Clone)]
+pub struct ProjectedToken {
+    path: Arc<PathBuf>,
+    loaded: Arc<Mutex<Option<(FileStamp, Token)>>>,
+}
+
+impl ProjectedToken {
+    /// Read the token from `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> ProjectedToken {
+        ProjectedToken {
+            path: Arc::new(path.into()),
+            loaded: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Read the pod's default service account token, at
+    /// `KUBERNETES_SERVICE_ACCOUNT_TOKEN_PATH`.
+    pub fn in_cluster() -> ProjectedToken {
+        ProjectedToken::new(KUBERNETES_SERVICE_ACCOUNT_TOKEN_PATH)
+    }
+
+    /// The path of the token file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the current token, reading the file if it changed since it was last read.
+    pub fn current(&self) -> Result<Token, RequestError> {
+        self.reload().map(|(_, token)| token)
+    }
+
+    /// Returns a stream checking the file every `interval` and yielding the new token whenever
+    /// it was rotated, including the first time it is read. The stream must be polled by a tokio
+    /// runtime.
+    pub fn watch(
+        &self,
+        interval: Duration,
+    ) -> impl Stream<Item = Token, Error = RequestError> + Send {
+        let this = self.clone();
+        tokio_timer::Interval::new_interval(interval)
+            .map_err(|e| RequestError::LowLevelError(io::Error::new(io::ErrorKind::Other, e)))
+            .and_then(move |_| this.reload())
+            .filter_map(|(changed, token)| if changed { Some(token) } else { None })
+    }
+
+    /// Reads the file if it is new or changed. Returns whether it was read, and the token.
+    fn reload(&self) -> Result<(bool, Token), RequestError> {
+        let stamp = FileStamp::of(&self.path)?;
+        let mut loaded = self.loaded.lock().unwrap();
+        if let Some((ref loaded_stamp, ref token)) = *loaded {
+            if *loaded_stamp == stamp {
+                return Ok((false, token.clone()));
+            }
+        }
+        let token = read_token(&self.path)?;
+        *loaded = Some((stamp, token.clone()));
+        Ok((true, token))
+    }
+}
+
+/// Reads the token at `path`, taking its expiry from the `exp` claim. Tokens which aren't JWTs
+/// or have no `exp` claim, like legacy secret-based tokens, don't expire.
+fn read_token(path: &Path) -> Result<Token, RequestError> {
+    let content = fs::read_to_string(path).map_err(RequestError::LowLevelError)?;
+    let access_token = content.trim().to_string();
+    if access_token.is_empty() {
+        return Err(RequestError::UserError(format!(
+            "token file {} is empty",
+            path.display()
+        )));
+    }
+    Ok(Token::from_jwt(access_token))
+}
+
+impl GetToken for ProjectedToken {
+    fn token<I, T>(&self, _scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        Box::new(future::result(self.current()))
+    }
+
+    /// Reads the file again, even if it appears unchanged.
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        *self.loaded.lock().unwrap() = None;
+        self.token(scopes)
+    }
+
+    /// Drops the token if it is `access_token`, so that the file is read again next.
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        let mut loaded = self.loaded.lock().unwrap();
+        match *loaded {
+            Some((_, ref token)) if token.access_token == access_token => {}
+            _ => return Ok(false),
+        }
+        *loaded = None;
+        Ok(true)
+    }
+
+    /// Reports whether the file holds a token.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let mut report = ConfigReport::default();
+        report.check(
+            format!("token file {}", self.path.display()),
+            self.current().map(|_| ()).map_err(|e| e.to_string()),
+        );
+        Box::new(future::ok(report))
+    }
+
+    /// Returns an empty ApplicationSecret, as projected tokens are rotated by the kubelet.
+    fn application_secret(&self) -> ApplicationSecret {
+        Default::default()
+    }
+
+    fn api_key(&self) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time;
+
+    fn jwt(exp: i64) -> String {
+        let payload = serde_json::json!({"aud": ["vault"], "exp": exp, "sub": "system:serviceaccount:default:app"});
+        format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.c2lnbmF0dXJl",
+            base64::encode_config(&payload.to_string(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn test_projected_token_rotation() {
+        let path = std::env::temp_dir().join(format!("yup-oauth2-projected-{}.jwt", time::now()));
+        let exp = time::now() + 3600;
+        let first = jwt(exp);
+        fs::write(&path, format!("{}\n", first)).unwrap();
+
+        let source = ProjectedToken::new(&path);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let token = rt.block_on(source.token(vec!["ignored"])).unwrap();
+        assert_eq!(first, token.access_token);
+        assert_eq!(Some(exp), token.expires_at().map(|t| time::to_secs(&t)));
+
+        // The kubelet rotates the token. The size differs, so that the change is noticed even
+        // within the resolution of the modification time.
+        let second = jwt(time::now() + 36000);
+        fs::write(&path, &second).unwrap();
+        assert_eq!(second, source.current().unwrap().access_token);
+        assert!(!source.invalidate(&first).unwrap());
+        assert!(source.invalidate(&second).unwrap());
+
+        // The token is read again as it was invalidated.
+        let watch = source.watch(Duration::from_millis(10)).into_future();
+        let (token, _) = rt.block_on(watch).map_err(|(e, _)| e).unwrap();
+        assert_eq!(second, token.unwrap().access_token);
+
+        fs::write(&path, "legacy-token").unwrap();
+        let token = source.current().unwrap();
+        assert_eq!("legacy-token", token.access_token);
+        assert_eq!(None, token.expires_at());
+
+        fs::remove_file(&path).unwrap();
+        match source.current() {
+            Err(RequestError::LowLevelError(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
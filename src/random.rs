@@ -0,0 +1,107 @@
+//! The source of randomness for `state` parameters and PKCE code verifiers.
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::types::RequestError;
+
+/// A cryptographically secure source of random bytes.
+///
+/// By default, the operating system's random number generator (`OsRandom`) is used. Implement
+/// this trait to inject a different source, e.g. a hardware entropy source on platforms without
+/// one provided by the operating system, or a deterministic one in tests. Implementations used
+/// outside of tests must be cryptographically secure, as the `state` and PKCE code verifier
+/// protect the authorization code flow against forged and intercepted redirects.
+///
+/// Flows don't fall back to a weaker source if `fill()` fails; they fail with
+/// `RequestError::LowLevelError` instead.
+pub trait RandomSource: Send + Sync {
+    /// Fills `dest` with random bytes.
+    fn fill(&self, dest: &mut [u8]) -> Result<(), io::Error>;
+}
+
+/// The operating system's random number generator. This is the default `RandomSource`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsRandom;
+
+impl RandomSource for OsRandom {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), io::Error> {
+        SystemRandom::new().fill(dest).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "system random number generator failed",
+            )
+        })
+    }
+}
+
+/// A shared `RandomSource`, defaulting to `OsRandom`.
+#[derive(Clone)]
+pub(crate) struct Rng(Arc<dyn RandomSource>);
+
+impl Default for Rng {
+    fn default() -> Rng {
+        Rng(Arc::new(OsRandom))
+    }
+}
+
+impl fmt::Debug for Rng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Rng")
+    }
+}
+
+impl Rng {
+    #[cfg(any(test, feature = "installed"))]
+    pub(crate) fn new<R: 'static + RandomSource>(source: R) -> Rng {
+        Rng(Arc::new(source))
+    }
+
+    /// Returns `len` random bytes, base64url-encoded.
+    pub(crate) fn random_string(&self, len: usize) -> Result<String, RequestError> {
+        let mut bytes = vec![0u8; len];
+        self.0
+            .fill(&mut bytes)
+            .map_err(RequestError::LowLevelError)?;
+        Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Zeroes;
+
+    impl RandomSource for Zeroes {
+        fn fill(&self, dest: &mut [u8]) -> Result<(), io::Error> {
+            dest.iter_mut().for_each(|b| *b = 0);
+            Ok(())
+        }
+    }
+
+    struct Failing;
+
+    impl RandomSource for Failing {
+        fn fill(&self, _dest: &mut [u8]) -> Result<(), io::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "no entropy"))
+        }
+    }
+
+    #[test]
+    fn test_random_string() {
+        let os = Rng::default();
+        assert_eq!(22, os.random_string(16).unwrap().len());
+        assert_ne!(os.random_string(16).unwrap(), os.random_string(16).unwrap());
+        assert_eq!(
+            "AAAAAAAAAAAAAAAAAAAAAA",
+            Rng::new(Zeroes).random_string(16).unwrap()
+        );
+        match Rng::new(Failing).random_string(16) {
+            Err(RequestError::LowLevelError(e)) => assert_eq!(io::ErrorKind::Other, e.kind()),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
@@ -1,13 +1,12 @@
-use crate::types::{ApplicationSecret, JsonError, RefreshResult, RequestError};
+use crate::helper::sanitize_refresh_token;
+use crate::transport;
+use crate::types::{
+    ApplicationSecret, DefaultTokenResponseParser, JsonError, RefreshResult, RequestError,
+    TokenResponseParser,
+};
 
-use super::Token;
-use chrono::Utc;
-use futures::stream::Stream;
-use futures::Future;
+use futures::{future, Future};
 use hyper;
-use hyper::header;
-use serde_json as json;
-use url::form_urlencoded;
 
 /// Implements the [OAuth2 Refresh Token Flow](https://developers.google.com/youtube/v3/guides/authentication#devices).
 ///
@@ -31,73 +30,96 @@ impl RefreshFlow {
     ///
     /// # Examples
     /// Please see the crate landing page for an example.
-    pub fn refresh_token<'a, C: 'static + hyper::client::connect::Connect>(
+    pub fn refresh_token<C>(
         client: hyper::Client<C>,
         client_secret: ApplicationSecret,
         refresh_token: String,
-    ) -> impl 'a + Future<Item = RefreshResult, Error = RequestError> {
-        let req = form_urlencoded::Serializer::new(String::new())
-            .extend_pairs(&[
-                ("client_id", client_secret.client_id.clone()),
-                ("client_secret", client_secret.client_secret.clone()),
-                ("refresh_token", refresh_token.to_string()),
-                ("grant_type", "refresh_token".to_string()),
-            ])
-            .finish();
-
-        let request = hyper::Request::post(client_secret.token_uri.clone())
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .body(hyper::Body::from(req))
-            .unwrap(); // TODO: error handling
-
-        client
-            .request(request)
-            .then(|r| {
-                match r {
-                    Err(err) => return Err(RefreshResult::Error(err)),
-                    Ok(res) => {
-                        Ok(res
-                            .into_body()
-                            .concat2()
-                            .wait()
-                            .map(|c| String::from_utf8(c.into_bytes().to_vec()).unwrap())
-                            .unwrap()) // TODO: error handling
-                    }
-                }
-            })
-            .then(move |maybe_json_str: Result<String, RefreshResult>| {
-                if let Err(e) = maybe_json_str {
-                    return Ok(e);
-                }
-                let json_str = maybe_json_str.unwrap();
-                #[derive(Deserialize)]
-                struct JsonToken {
-                    access_token: String,
-                    token_type: String,
-                    expires_in: i64,
-                }
+    ) -> impl Future<Item = RefreshResult, Error = RequestError>
+    where
+        C: 'static + hyper::client::connect::Connect,
+    {
+        RefreshFlow::refresh_token_with_parser(
+            client,
+            client_secret,
+            refresh_token,
+            DefaultTokenResponseParser,
+        )
+    }
+
+    /// Like `refresh_token()`, but decodes the token endpoint's response with `parser`, usually
+    /// `DefaultTokenResponseParser`. `Authenticator` refreshes tokens this way.
+    pub fn refresh_token_with_parser<'a, C, P>(
+        client: hyper::Client<C>,
+        client_secret: ApplicationSecret,
+        refresh_token: String,
+        parser: P,
+    ) -> impl 'a + Future<Item = RefreshResult, Error = RequestError>
+    where
+        C: 'static + hyper::client::connect::Connect,
+        P: 'a + TokenResponseParser + Send,
+    {
+        // A malformed token would be refused just the same, only less helpfully.
+        let refresh_token = match sanitize_refresh_token(&refresh_token) {
+            Ok(token) => token,
+            Err(e) => {
+                let refused = JsonError::new("invalid_grant", Some(e.to_string()));
+                return future::Either::A(future::ok(RefreshResult::RefreshError(Box::new(
+                    refused,
+                ))));
+            }
+        };
+        let request = transport::TokenRequest::new()
+            .param("refresh_token", &refresh_token)
+            .param("grant_type", "refresh_token");
+        future::Either::B(transport::post_token_request(client, &client_secret, request)
+            .map_err(RequestError::ClientError)
+            .and_then(transport::read_chunks)
+            .then(
+                move |maybe_body: Result<transport::Chunks, RequestError>| -> Result<RefreshResult, RequestError> {
+                let body = match maybe_body {
+                    Err(RequestError::ClientError(e)) => return Ok(RefreshResult::Error(e)),
+                    Err(e) => return Err(e),
+                    Ok(body) => body,
+                };
 
-                match json::from_str::<JsonError>(&json_str) {
-                    Err(_) => {}
-                    Ok(res) => {
-                        return Ok(RefreshResult::RefreshError(
-                            res.error,
-                            res.error_description,
-                        ))
+                // JSON token responses are parsed from the chunks as received. Error responses,
+                // and form-encoded ones like GitHub's, are handled as text.
+                let parsed = if body.is_json() {
+                    parser.read_token_response(&mut body.reader()).ok()
+                } else {
+                    None
+                };
+                let mut result = match parsed {
+                    Some(t) => RefreshResult::Success(t),
+                    None => parse_text_response(body.into_string()?, &parser)?,
+                };
+                // Most providers don't issue a new refresh token; keep using the present one.
+                if let RefreshResult::Success(ref mut t) = result {
+                    if t.refresh_token.is_none() {
+                        t.refresh_token = Some(refresh_token);
                     }
                 }
+                Ok(result)
+            }))
+    }
+}
 
-                let t: JsonToken = json::from_str(&json_str).unwrap();
-                Ok(RefreshResult::Success(Token {
-                    access_token: t.access_token,
-                    token_type: t.token_type,
-                    refresh_token: Some(refresh_token.to_string()),
-                    expires_in: None,
-                    expires_in_timestamp: Some(Utc::now().timestamp() + t.expires_in),
-                }))
-            })
-            .map_err(RequestError::Refresh)
+/// Parses a token endpoint response as text, after converting form-encoded ones to JSON.
+fn parse_text_response<P: TokenResponseParser>(
+    body: String,
+    parser: &P,
+) -> Result<RefreshResult, RequestError> {
+    let json_str = transport::form_to_json(body);
+    match JsonError::from_response(&json_str) {
+        None => {}
+        Some(ref res) if res.is_reauth_required() => {
+            return Ok(RefreshResult::ReauthRequired(res.error_uri.clone()))
+        }
+        Some(res) => return Ok(RefreshResult::RefreshError(Box::new(res))),
     }
+    parser
+        .parse_token_response(&json_str)
+        .map(RefreshResult::Success)
 }
 
 #[cfg(test)]
@@ -150,7 +172,7 @@ mod tests {
                         assert_eq!("new-access-token", tok.access_token);
                         assert_eq!("Bearer", tok.token_type);
                     }
-                    _ => panic!(format!("unexpected RefreshResult {:?}", rr)),
+                    _ => panic!("unexpected RefreshResult {:?}", rr),
                 }
                 Ok(()) as Result<(), ()>
             });
@@ -164,16 +186,55 @@ mod tests {
                 .match_body(
                     mockito::Matcher::Regex(".*client_id=902216714886-k2v9uei3p1dk6h686jbsn9mo96tnbvto.apps.googleusercontent.com.*refresh_token=my-refresh-token.*".to_string()))
                 .with_status(400)
-                .with_body(r#"{"error": "invalid_token"}"#)
+                .with_body(r#"{"error": "invalid_token", "error_code": 17}"#)
+                .create();
+
+            let fut = RefreshFlow::refresh_token_with_parser(
+                client.clone(),
+                app_secret.clone(),
+                refresh_token.clone(),
+                DefaultTokenResponseParser,
+            )
+            .then(|rr| {
+                let rr = rr.unwrap();
+                match rr {
+                    RefreshResult::RefreshError(e) => {
+                        assert_eq!(e.error, "invalid_token");
+                        assert_eq!(e.error_description, None);
+                        assert_eq!(e.additional_fields["error_code"], 17);
+                        assert!(e.raw.contains("\"error_code\": 17"));
+                    }
+                    _ => panic!("unexpected RefreshResult {:?}", rr),
+                }
+                Ok(())
+            });
+
+            tokio::run(fut);
+            _m.assert();
+        }
+        // Reauth required by session control policy.
+        {
+            let _m = mockito::mock("POST", "/token")
+                .with_status(400)
+                .with_body(r#"{"error": "invalid_grant", "error_description": "reauth related error (invalid_rapt)", "error_uri": "https://support.google.com/a/answer/9368756", "error_subtype": "invalid_rapt"}"#)
                 .create();
 
-            let fut = RefreshFlow::refresh_token(client, app_secret, refresh_token).then(|rr| {
+            let fut = RefreshFlow::refresh_token_with_parser(
+                client,
+                app_secret,
+                refresh_token,
+                DefaultTokenResponseParser,
+            )
+            .then(|rr| {
                 let rr = rr.unwrap();
                 match rr {
-                    RefreshResult::RefreshError(e, None) => {
-                        assert_eq!(e, "invalid_token");
+                    RefreshResult::ReauthRequired(uri) => {
+                        assert_eq!(
+                            uri,
+                            Some("https://support.google.com/a/answer/9368756".to_string())
+                        );
                     }
-                    _ => panic!(format!("unexpected RefreshResult {:?}", rr)),
+                    _ => panic!("unexpected RefreshResult {:?}", rr),
                 }
                 Ok(())
             });
@@ -182,4 +243,217 @@ mod tests {
             _m.assert();
         }
     }
+
+    #[test]
+    fn test_refresh_error_paths() {
+        use crate::transport::tests::{FakeConnector, FakeReply};
+        use crate::types::TransportError;
+
+        let app_secret = helper::parse_application_secret(crate::types::tests::SECRET).unwrap();
+        let connector = FakeConnector::new(vec![
+            FakeReply::Refused,
+            FakeReply::Raw("garbage\r\n\r\n"),
+            FakeReply::Raw("HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{\"access_"),
+            FakeReply::Json(500, "<html>Internal Server Error</html>"),
+            FakeReply::Json(400, r#"{"error": "invalid_grant"}"#),
+            FakeReply::Json(200, r#"{"access_token": "new", "token_type": "Bearer"}"#),
+        ]);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut refresh = || {
+            rt.block_on(RefreshFlow::refresh_token_with_parser(
+                connector.client(),
+                app_secret.clone(),
+                "my-refresh-token".to_string(),
+                DefaultTokenResponseParser,
+            ))
+        };
+
+        match refresh() {
+            Ok(RefreshResult::Error(TransportError::Connect(_))) => {}
+            r => panic!("unexpected result for a refused connection {:?}", r),
+        }
+        match refresh() {
+            Ok(RefreshResult::Error(TransportError::Protocol(_))) => {}
+            r => panic!("unexpected result for a malformed response {:?}", r),
+        }
+        match refresh() {
+            Ok(RefreshResult::Error(TransportError::Closed(_))) => {}
+            r => panic!("unexpected result for a truncated response {:?}", r),
+        }
+        match refresh() {
+            Err(RequestError::JSONError(_)) => {}
+            r => panic!("unexpected result for an HTML error page {:?}", r),
+        }
+        match refresh() {
+            Ok(RefreshResult::RefreshError(e)) => assert_eq!("invalid_grant", e.error),
+            r => panic!("unexpected result for a rejected refresh token {:?}", r),
+        }
+        match refresh() {
+            Ok(RefreshResult::Success(t)) => assert_eq!(
+                (Some("my-refresh-token".to_string()), None),
+                (t.refresh_token.clone(), t.expires_at())
+            ),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(0, connector.remaining());
+
+        // A truncated token is refused without a request.
+        match rt.block_on(RefreshFlow::refresh_token_with_parser(
+            connector.client(),
+            app_secret.clone(),
+            "1//0gLmQ5J0aSx3\n".to_string(),
+            DefaultTokenResponseParser,
+        )) {
+            Ok(RefreshResult::RefreshError(e)) => {
+                assert_eq!("invalid_grant", e.error);
+                assert!(e.error_description.unwrap().contains("cut off"));
+            }
+            r => panic!("unexpected result for a truncated refresh token {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_refresh_custom_parser() {
+        use crate::types::Token;
+
+        /// Parses responses of a provider calling the expiry field `expires`.
+        struct ExpiresParser;
+        impl TokenResponseParser for ExpiresParser {
+            fn parse_token_response(&self, body: &str) -> Result<Token, RequestError> {
+                #[derive(Deserialize)]
+                struct OddToken {
+                    #[serde(rename = "access-token")]
+                    access_token: String,
+                    expires: i64,
+                }
+                let t: OddToken = serde_json::from_str(body).map_err(RequestError::JSONError)?;
+                Ok(Token::new(
+                    t.access_token,
+                    "Bearer".to_string(),
+                    None,
+                    Some(t.expires),
+                ))
+            }
+        }
+
+        let mut app_secret = helper::parse_application_secret(crate::types::tests::SECRET).unwrap();
+        app_secret.token_uri = format!("{}/token", mockito::server_url());
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _m = mockito::mock("POST", "/token")
+            .with_status(200)
+            .with_body(r#"{"access-token": "odd-access-token", "expires": 3600}"#)
+            .expect(2)
+            .create();
+        let fut = RefreshFlow::refresh_token_with_parser(
+            client.clone(),
+            app_secret.clone(),
+            "my-refresh-token".to_string(),
+            ExpiresParser,
+        );
+        match rt.block_on(fut).unwrap() {
+            RefreshResult::Success(tok) => {
+                assert_eq!("odd-access-token", tok.access_token);
+                assert_eq!(Some("my-refresh-token".to_string()), tok.refresh_token);
+                assert!(!tok.expired());
+            }
+            rr => panic!("unexpected RefreshResult {:?}", rr),
+        }
+
+        // The default parser rejects the response with an error instead of panicking.
+        let fut = RefreshFlow::refresh_token_with_parser(
+            client,
+            app_secret,
+            "my-refresh-token".to_string(),
+            DefaultTokenResponseParser,
+        );
+        match rt.block_on(fut) {
+            Err(RequestError::JSONError(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        _m.assert();
+    }
+
+    /// A generic OpenID Connect provider like AWS Cognito, which only accepts client credentials
+    /// using HTTP Basic authentication.
+    #[test]
+    fn test_refresh_client_secret_basic() {
+        use crate::types::ClientAuthMethod;
+
+        let mut app_secret = helper::parse_application_secret(crate::types::tests::SECRET).unwrap();
+        app_secret.token_uri = format!("{}/oauth2/token", mockito::server_url());
+        app_secret.client_id = "cognito client".to_string();
+        app_secret.client_secret = "s3cr:t".to_string();
+        app_secret.token_endpoint_auth_method = ClientAuthMethod::HttpBasic;
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _m = mockito::mock("POST", "/oauth2/token")
+            .match_header(
+                "authorization",
+                format!("Basic {}", base64::encode("cognito+client:s3cr%3At")).as_str(),
+            )
+            .match_body("refresh_token=my-refresh-token&grant_type=refresh_token")
+            .with_status(200)
+            .with_body(r#"{"id_token": "idtoken", "access_token": "accesstoken", "expires_in": 3600, "token_type": "Bearer"}"#)
+            .expect(1)
+            .create();
+        let fut = RefreshFlow::refresh_token_with_parser(
+            client,
+            app_secret,
+            "my-refresh-token".to_string(),
+            DefaultTokenResponseParser,
+        );
+        match rt.block_on(fut).unwrap() {
+            RefreshResult::Success(tok) => assert_eq!("accesstoken", tok.access_token),
+            rr => panic!("unexpected RefreshResult {:?}", rr),
+        }
+        _m.assert();
+    }
+
+    /// Public clients, like native apps using PKCE, have no client secret and only send their
+    /// client ID.
+    #[test]
+    fn test_refresh_public_client() {
+        let app_secret = serde_json::json!({
+            "client_id": "public-client",
+            "token_uri": format!("{}/oauth2/token", mockito::server_url()),
+            "auth_uri": "https://login.example.com/authorize",
+            "redirect_uris": ["http://localhost"],
+        });
+        let app_secret: ApplicationSecret = serde_json::from_value(app_secret).unwrap();
+        assert!(app_secret.client_secret.is_empty());
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build::<_, hyper::Body>(HttpsConnector::new(1));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _m = mockito::mock("POST", "/oauth2/token")
+            .match_header("authorization", mockito::Matcher::Missing)
+            .match_body(
+                "client_id=public-client&refresh_token=my-refresh-token&grant_type=refresh_token",
+            )
+            .with_status(200)
+            .with_body(
+                r#"{"access_token": "accesstoken", "expires_in": 3600, "token_type": "Bearer"}"#,
+            )
+            .expect(1)
+            .create();
+        let fut = RefreshFlow::refresh_token_with_parser(
+            client,
+            app_secret,
+            "my-refresh-token".to_string(),
+            DefaultTokenResponseParser,
+        );
+        match rt.block_on(fut).unwrap() {
+            RefreshResult::Success(tok) => assert_eq!("accesstoken", tok.access_token),
+            rr => panic!("unexpected RefreshResult {:?}", rr),
+        }
+        _m.assert();
+    }
 }
@@ -0,0 +1,174 @@
+//! A circuit breaker protecting the token endpoint from bursts of requests bound to fail, e.g.
+//! once a service account key expired or the user revoked consent.
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ::log::{log, warn};
+
+use crate::backoff::{BackoffPolicy, FixedBackoff};
+use crate::types::RequestError;
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// How often the budget opened since the last success, and for how long the last time.
+    openings: u32,
+    open_for: Duration,
+}
+
+/// Limits the requests an `Authenticator` makes to the provider after consecutive failures.
+///
+/// After `failure_threshold` refreshes or flows failed in a row, the budget opens for
+/// `open_for`: token requests which would contact the provider fail immediately with
+/// `RequestError::CircuitOpen`, while stored valid tokens are still returned. Then a single
+/// trial request is let through, and the budget stays open for another `open_for` unless the
+/// trial succeeds.
+///
+/// The budget is shared by all clones, so that several authenticators, e.g. for different
+/// scopes of the same service account, can share one budget.
+#[derive(Clone)]
+pub struct RetryBudget {
+    failure_threshold: u32,
+    cooldown: Arc<dyn BackoffPolicy>,
+    state: Arc<Mutex<BudgetState>>,
+}
+
+impl fmt::Debug for RetryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryBudget")
+            .field("failure_threshold", &self.failure_threshold)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl RetryBudget {
+    /// Open the budget for `open_for` after `failure_threshold` consecutive failures. A
+    /// threshold of zero is treated as one.
+    pub fn new(failure_threshold: u32, open_for: Duration) -> RetryBudget {
+        RetryBudget {
+            failure_threshold: failure_threshold.max(1),
+            cooldown: Arc::new(FixedBackoff(open_for)),
+            state: Arc::new(Mutex::new(BudgetState::default())),
+        }
+    }
+
+    /// Open the budget for the delays of `cooldown`, rather than a fixed duration: the first
+    /// time after a success for its first delay, if the trial request fails too for its second
+    /// one, and so on, e.g. to back off from a provider rate limiting the client ID for longer.
+    pub fn with_cooldown<P: 'static + BackoffPolicy>(self, cooldown: P) -> RetryBudget {
+        RetryBudget {
+            cooldown: Arc::new(cooldown),
+            ..self
+        }
+    }
+
+    /// Whether requests to the provider are currently refused.
+    pub fn is_open(&self) -> bool {
+        match self.state.lock().unwrap().open_until {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    /// Admits a request to the provider, or fails with `RequestError::CircuitOpen` carrying the
+    /// time until the next request is admitted.
+    pub(crate) fn admit(&self) -> Result<(), RequestError> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match state.open_until {
+            None => Ok(()),
+            Some(until) if now < until => Err(RequestError::CircuitOpen(until - now)),
+            Some(_) => {
+                // Admit a trial request. Until it reports back, or if it never does, the
+                // budget remains open.
+                state.open_until = Some(now + state.open_for);
+                Ok(())
+            }
+        }
+    }
+
+    /// Records a successful request, closing the budget.
+    pub(crate) fn succeeded(&self) {
+        *self.state.lock().unwrap() = BudgetState::default();
+    }
+
+    /// Records a failed request, opening the budget once the threshold is reached.
+    pub(crate) fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures >= self.failure_threshold {
+            state.openings = state.openings.saturating_add(1);
+            state.open_for = self.cooldown.delay(state.openings, state.open_for);
+            warn!(
+                "Suspending token requests for {:?} after {} consecutive failures",
+                state.open_for, state.consecutive_failures
+            );
+            state.open_until = Some(Instant::now() + state.open_for);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new(2, Duration::from_millis(50));
+        budget.admit().unwrap();
+        budget.failed();
+        assert!(!budget.is_open());
+        budget.admit().unwrap();
+        budget.failed();
+        assert!(budget.is_open());
+        match budget.clone().admit() {
+            Err(RequestError::CircuitOpen(d)) => assert!(d <= Duration::from_millis(50)),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // A single trial request is admitted once the budget's time is up; it fails, so the
+        // budget opens again right away.
+        thread::sleep(Duration::from_millis(60));
+        budget.admit().unwrap();
+        assert!(budget.admit().is_err());
+        budget.failed();
+        assert!(budget.admit().is_err());
+
+        thread::sleep(Duration::from_millis(60));
+        budget.admit().unwrap();
+        budget.succeeded();
+        assert!(!budget.is_open());
+        budget.admit().unwrap();
+        budget.admit().unwrap();
+    }
+
+    #[test]
+    fn test_cooldown() {
+        use crate::backoff::ExponentialBackoff;
+
+        let ms = Duration::from_millis;
+        let budget =
+            RetryBudget::new(1, ms(0)).with_cooldown(ExponentialBackoff::new(ms(50), ms(1000)));
+        let open_for = |budget: &RetryBudget| match budget.admit() {
+            Err(RequestError::CircuitOpen(d)) => d,
+            r => panic!("unexpected result {:?}", r),
+        };
+        budget.failed();
+        assert!(open_for(&budget) <= ms(50));
+
+        // The failed trial doubles the cooldown.
+        thread::sleep(ms(60));
+        budget.admit().unwrap();
+        budget.failed();
+        assert!(open_for(&budget) > ms(60));
+
+        // A success resets it.
+        budget.succeeded();
+        budget.failed();
+        assert!(open_for(&budget) <= ms(50));
+    }
+}
@@ -0,0 +1,310 @@
+//! A type for OAuth scopes, validated when constructed.
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use crate::types::RequestError;
+
+/// An OAuth scope, like `https://www.googleapis.com/auth/drive.file` or `openid`.
+///
+/// Scopes are validated when parsed: they must consist of the characters allowed by
+/// [RFC 6749, section 3.3](https://tools.ietf.org/html/rfc6749#section-3.3), i.e. printable
+/// ASCII characters except for space, `"` and `\`, and scopes looking like URLs must be valid
+/// URLs with a host, whose scheme isn't a misspelling of `http` or `https`. This turns typos like
+/// a pasted space or `htps://` into errors before a request is made, instead of obscure errors
+/// returned by the provider.
+///
+/// `Scope`s can be passed wherever scopes are accepted as strings, e.g. to `GetToken::token()`.
+/// With the `google-scopes` feature, common Google scopes are available as constants in
+/// `google_scopes`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Scope(Cow<'static, str>);
+
+impl Scope {
+    /// Parses and validates `scope`.
+    pub fn new<S: Into<String>>(scope: S) -> Result<Scope, RequestError> {
+        let scope = scope.into();
+        validate(&scope)?;
+        Ok(Scope(Cow::Owned(scope)))
+    }
+
+    /// Creates a scope from a constant without validating it, for catalogs of known scopes.
+    pub const fn from_static(scope: &'static str) -> Scope {
+        Scope(Cow::Borrowed(scope))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+fn validate(scope: &str) -> Result<(), RequestError> {
+    let invalid = |reason: &str| {
+        Err(RequestError::InvalidScope(format!(
+            "invalid scope {:?}: {}",
+            scope, reason
+        )))
+    };
+    if scope.is_empty() {
+        return invalid("empty");
+    }
+    if let Some(c) = scope
+        .chars()
+        .find(|&c| !c.is_ascii_graphic() || c == '"' || c == '\\')
+    {
+        return invalid(&format!("contains {:?}", c));
+    }
+    if scope.contains("://") {
+        match url::Url::parse(scope) {
+            Ok(ref url) if misspelled_http(url.scheme()) => {
+                return invalid(&format!("misspelled scheme {:?}", url.scheme()))
+            }
+            Ok(ref url) if url.host().is_none() => return invalid("URL without host"),
+            Ok(_) => {}
+            Err(e) => return invalid(&e.to_string()),
+        }
+    }
+    Ok(())
+}
+
+/// Whether `scheme` is one typo away from `http` or `https`, like `htps`, `htttp` or `htpts`.
+fn misspelled_http(scheme: &str) -> bool {
+    scheme != "http"
+        && scheme != "https"
+        && (one_typo_apart(scheme, "http") || one_typo_apart(scheme, "https"))
+}
+
+/// Whether `a` and `b` differ by exactly one inserted, removed or replaced character, or by two
+/// swapped adjacent characters.
+fn one_typo_apart(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if long.len() - short.len() > 1 {
+        return false;
+    }
+    let common = short.iter().zip(long).take_while(|(x, y)| x == y).count();
+    if short.len() == long.len() {
+        let (i, len) = (common, short.len());
+        let replaced = i < len && short[i + 1..] == long[i + 1..];
+        let swapped = i + 1 < len
+            && short[i] == long[i + 1]
+            && short[i + 1] == long[i]
+            && short[i + 2..] == long[i + 2..];
+        replaced || swapped
+    } else {
+        short[common..] == long[common + 1..]
+    }
+}
+
+impl FromStr for Scope {
+    type Err = RequestError;
+
+    fn from_str(s: &str) -> Result<Scope, RequestError> {
+        Scope::new(s)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl AsRef<str> for Scope {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> String {
+        scope.0.into_owned()
+    }
+}
+
+impl<'a> From<&'a Scope> for String {
+    fn from(scope: &'a Scope) -> String {
+        scope.0.to_string()
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Scope {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Scope, D::Error> {
+        Scope::new(String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// Restricts the scopes an authenticator requests tokens for, see
+/// `Authenticator::scope_policy()`. Requests violating the policy fail with
+/// `RequestError::PolicyViolation` before the token storage or the provider are asked.
+///
+/// Patterns match scopes exactly, or by prefix if they end in `*`, like
+/// `https://www.googleapis.com/auth/drive*`. A scope is permitted if it matches none of the
+/// denied patterns and, unless no scopes were allowed explicitly, one of the allowed ones.
+///
+/// ```
+/// use yup_oauth2::ScopePolicy;
+/// let policy = ScopePolicy::new()
+///     .allow(vec!["https://www.googleapis.com/auth/devstorage*"])
+///     .deny(vec!["https://www.googleapis.com/auth/devstorage.full_control"]);
+/// assert!(policy.check(&["https://www.googleapis.com/auth/devstorage.read_only"]).is_ok());
+/// assert!(policy.check(&["https://www.googleapis.com/auth/cloud-platform"]).is_err());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScopePolicy {
+    allowed: Option<Vec<String>>,
+    denied: Vec<String>,
+}
+
+fn matches(pattern: &str, scope: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => scope.starts_with(prefix),
+        None => pattern == scope,
+    }
+}
+
+impl ScopePolicy {
+    /// A policy permitting all scopes.
+    pub fn new() -> ScopePolicy {
+        ScopePolicy::default()
+    }
+
+    /// Permit only scopes matching one of `patterns`, or of those passed to earlier calls.
+    pub fn allow<I, T>(mut self, patterns: I) -> ScopePolicy
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.allowed
+            .get_or_insert_with(Vec::new)
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Refuse scopes matching one of `patterns`, even if they are allowed.
+    pub fn deny<I, T>(mut self, patterns: I) -> ScopePolicy
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.denied.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Returns whether `scope` is permitted.
+    pub fn permits(&self, scope: &str) -> bool {
+        if self.denied.iter().any(|p| matches(p, scope)) {
+            return false;
+        }
+        match self.allowed {
+            Some(ref allowed) => allowed.iter().any(|p| matches(p, scope)),
+            None => true,
+        }
+    }
+
+    /// Fails with `RequestError::PolicyViolation` naming the scopes which aren't permitted.
+    pub fn check<T: AsRef<str>>(&self, scopes: &[T]) -> Result<(), RequestError> {
+        let refused: Vec<&str> = scopes
+            .iter()
+            .map(|s| s.as_ref())
+            .filter(|s| !self.permits(s))
+            .collect();
+        if refused.is_empty() {
+            Ok(())
+        } else {
+            Err(RequestError::PolicyViolation(format!(
+                "scopes refused by policy: {}",
+                refused.join(" ")
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_validation() {
+        for valid in &[
+            "openid",
+            "offline_access",
+            "read:org",
+            "https://www.googleapis.com/auth/drive.file",
+            "api://8f1a/.default",
+            "http://www.google.com/m8/feeds",
+        ] {
+            let scope: Scope = valid.parse().unwrap();
+            assert_eq!(*valid, scope.as_str());
+        }
+        for invalid in &[
+            "",
+            "drive file",
+            "https://www.googleapis.com/auth/drive.file\n",
+            "https://www.googleapis.com:99999/auth/drive",
+            "htps://www.googleapis.com/auth/drive",
+            "htttp://www.googleapis.com/auth/drive",
+            "htpts://www.googleapis.com/auth/drive",
+            "https://",
+            "naïve",
+        ] {
+            match Scope::new(*invalid) {
+                Err(RequestError::InvalidScope(msg)) => assert!(msg.contains("invalid scope")),
+                r => panic!("{:?} was accepted: {:?}", invalid, r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_scope_conversions() {
+        let scope = Scope::from_static("https://www.googleapis.com/auth/drive.file");
+        assert_eq!(
+            Ok(scope.clone()),
+            Scope::new(scope.to_string()).map_err(|_| ())
+        );
+        let scopes: Vec<String> = vec![&scope].into_iter().map(Into::into).collect();
+        assert_eq!(vec![scope.to_string()], scopes);
+        assert_eq!(
+            "\"https://www.googleapis.com/auth/drive.file\"",
+            serde_json::to_string(&scope).unwrap()
+        );
+        assert!(serde_json::from_str::<Scope>("\"drive file\"").is_err());
+    }
+
+    #[test]
+    fn test_scope_policy() {
+        let cloud_platform = "https://www.googleapis.com/auth/cloud-platform";
+        let policy = ScopePolicy::new().deny(vec![cloud_platform]);
+        assert!(policy.permits("https://www.googleapis.com/auth/drive"));
+        assert!(!policy.permits(cloud_platform));
+        match policy.check(&["openid", cloud_platform]) {
+            Err(RequestError::PolicyViolation(msg)) => {
+                assert!(msg.ends_with(": https://www.googleapis.com/auth/cloud-platform"))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let policy = ScopePolicy::new()
+            .allow(vec!["openid"])
+            .allow(vec!["https://www.googleapis.com/auth/drive*"])
+            .deny(vec!["https://www.googleapis.com/auth/drive"]);
+        assert!(policy
+            .check(&["openid", "https://www.googleapis.com/auth/drive.file"])
+            .is_ok());
+        assert!(!policy.permits("https://www.googleapis.com/auth/drive"));
+        assert!(!policy.permits("email"));
+        assert!(ScopePolicy::new()
+            .allow(Vec::<String>::new())
+            .check(&["openid"])
+            .is_err());
+    }
+}
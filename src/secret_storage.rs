@@ -0,0 +1,97 @@
+//! Sources of the application secret, so that the client ID and secret can be kept in a vault,
+//! like HashiCorp Vault or AWS Secrets Manager, rather than in the application's configuration.
+use std::convert::Infallible;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::helper::read_application_secret;
+use crate::types::{ApplicationSecret, RequestError};
+
+/// Loads the `ApplicationSecret` whenever the authenticator needs it, see
+/// `Authenticator::secret_storage()`. Implementations for vaults may cache the secret, and
+/// should pick up rotated secrets.
+pub trait SecretStorage {
+    type Error: 'static + Error + Send + Sync;
+
+    /// Returns the current application secret.
+    fn load(&self) -> Result<ApplicationSecret, Self::Error>;
+}
+
+/// A fixed secret.
+impl SecretStorage for ApplicationSecret {
+    type Error = Infallible;
+
+    fn load(&self) -> Result<ApplicationSecret, Infallible> {
+        Ok(self.clone())
+    }
+}
+
+/// Reads the secret from a client secret JSON file, as downloaded from the Google developer
+/// console, on every load. The secret is not kept in memory in between, and an updated file
+/// takes effect on the next load.
+#[derive(Clone, Debug)]
+pub struct SecretFile {
+    path: PathBuf,
+}
+
+impl SecretFile {
+    /// Read the secret from the file at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> SecretFile {
+        SecretFile { path: path.into() }
+    }
+}
+
+impl SecretStorage for SecretFile {
+    type Error = io::Error;
+
+    fn load(&self) -> io::Result<ApplicationSecret> {
+        read_application_secret(&self.path)
+    }
+}
+
+/// A `SecretStorage` with its error type erased, as held by the authenticator.
+pub(crate) type LoadSecret = Arc<dyn Fn() -> Result<ApplicationSecret, RequestError> + Send + Sync>;
+
+pub(crate) fn load_secret_from<S>(storage: S) -> LoadSecret
+where
+    S: 'static + SecretStorage + Send + Sync,
+{
+    Arc::new(move || {
+        storage
+            .load()
+            .map_err(|e| RequestError::SecretStorage(Box::new(e)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_secret_file() {
+        let path = std::env::temp_dir().join(format!("yup-secret-{}.json", std::process::id()));
+        let file = SecretFile::new(&path);
+        let load = load_secret_from(file.clone());
+        match load() {
+            Err(RequestError::SecretStorage(_)) => {}
+            _ => panic!("loading a missing file succeeded"),
+        }
+
+        fs::write(&path, crate::types::tests::SECRET).unwrap();
+        assert_eq!(
+            "UqkDJd5RFwnHoiG5x5Rub8SI",
+            file.load().unwrap().client_secret
+        );
+        // A rotated secret is picked up.
+        fs::write(
+            &path,
+            crate::types::tests::SECRET.replace("UqkDJd", "rotated"),
+        )
+        .unwrap();
+        assert_eq!("rotated5RFwnHoiG5x5Rub8SI", load().unwrap().client_secret);
+        fs::remove_file(&path).unwrap();
+    }
+}
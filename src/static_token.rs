@@ -0,0 +1,116 @@
+//! A token source (`GetToken`) handing out a token obtained elsewhere, e.g. a pre-provisioned
+//! token for local development, a token issued by a sidecar, or a fake token in tests of code
+//! which takes any `GetToken`.
+//!
+//! The token is never refreshed: once it expires, requests fail rather than sending a token the
+//! resource server refuses.
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, prelude::*};
+
+use crate::config_check::ConfigReport;
+use crate::time;
+use crate::types::{ApplicationSecret, GetToken, RequestError, Token};
+
+/// Hands out the same token for all scopes.
+#[derive(Clone, Debug)]
+pub struct StaticTokenSource {
+    token: Arc<Token>,
+}
+
+impl StaticTokenSource {
+    /// Hand out `token`, e.g. one created with `Token::new_bearer()`.
+    pub fn new(token: Token) -> StaticTokenSource {
+        StaticTokenSource {
+            token: Arc::new(token),
+        }
+    }
+
+    /// Hand out the bearer token `access_token`, which doesn't expire. Fails like
+    /// `Token::new_bearer()` for malformed tokens.
+    pub fn bearer<S: Into<String>>(access_token: S) -> Result<StaticTokenSource, RequestError> {
+        Token::new_bearer(access_token, None).map(StaticTokenSource::new)
+    }
+
+    /// Hand out the bearer token in the environment variable `name`, e.g. one set to the output
+    /// of `gcloud auth print-access-token` for local development.
+    pub fn from_env(name: &str) -> Result<StaticTokenSource, RequestError> {
+        let access_token = env::var(name).map_err(|e| {
+            RequestError::UserError(format!("Cannot read the token from ${}: {}", name, e))
+        })?;
+        StaticTokenSource::bearer(access_token)
+    }
+
+    /// Returns the token, or fails with `RequestError::UserError` if it expired.
+    pub fn current(&self) -> Result<Token, RequestError> {
+        if self.token.expires_within(Duration::from_secs(0)) {
+            let expires_at = self.token.expires_at().map(|t| time::to_secs(&t));
+            return Err(RequestError::UserError(format!(
+                "The static token expired at {}, and cannot be refreshed",
+                time::display(&time::from_secs(expires_at.unwrap_or_default()))
+            )));
+        }
+        Ok((*self.token).clone())
+    }
+}
+
+impl GetToken for StaticTokenSource {
+    fn token<I, T>(&self, _scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        Box::new(future::result(self.current()))
+    }
+
+    /// Reports whether the token expired.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let mut report = ConfigReport::default();
+        report.check(
+            "static token",
+            self.current().map(|_| ()).map_err(|e| e.to_string()),
+        );
+        Box::new(future::ok(report))
+    }
+
+    /// Returns an empty ApplicationSecret, as the token cannot be refreshed.
+    fn application_secret(&self) -> ApplicationSecret {
+        Default::default()
+    }
+
+    fn api_key(&self) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_token_source() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let source = StaticTokenSource::bearer("ya29.local").unwrap();
+        let token = rt.block_on(source.token(vec!["any", "scopes"])).unwrap();
+        assert_eq!("ya29.local", token.access_token);
+        assert!(!source.invalidate("ya29.local").unwrap());
+        assert!(rt.block_on(source.validate()).unwrap().is_ok());
+        assert!(StaticTokenSource::bearer("").is_err());
+
+        env::set_var("YUP_OAUTH2_STATIC_TOKEN_TEST", "ya29.env\n");
+        let source = StaticTokenSource::from_env("YUP_OAUTH2_STATIC_TOKEN_TEST").unwrap();
+        assert_eq!("ya29.env", source.current().unwrap().access_token);
+        assert!(StaticTokenSource::from_env("YUP_OAUTH2_STATIC_TOKEN_UNSET").is_err());
+
+        let mut expired = Token::new_bearer("ya29.old", None).unwrap();
+        expired.set_expires_at(Some(time::from_secs(time::now() - 60)));
+        let source = StaticTokenSource::new(expired);
+        match rt.block_on(source.token(vec!["scope"])) {
+            Err(RequestError::UserError(e)) => assert!(e.contains("expired"), "{}", e),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(!rt.block_on(source.validate()).unwrap().is_ok());
+    }
+}
@@ -0,0 +1,254 @@
+//! Health information about the credentials of an authenticator.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::storage::RefreshFailure;
+use crate::time::{self, Timestamp};
+use crate::types::{jwt_claims, Token};
+
+/// A snapshot of the credentials an authenticator handed out tokens for since it was built, as
+/// returned by `GetToken::stats()`. Long-running services may export it, e.g. as metrics or on
+/// a health endpoint.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuthenticatorStats {
+    /// One entry per set of scopes, ordered by scopes.
+    pub credentials: Vec<CredentialStats>,
+}
+
+/// What happened to the token for one set of scopes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CredentialStats {
+    /// The scopes of the token, sorted.
+    pub scopes: Vec<String>,
+    /// How often a token was obtained from the flow, e.g. by asking the user.
+    pub obtained_count: u64,
+    /// How often the token was refreshed successfully.
+    pub refresh_count: u64,
+    /// How often refreshing the token failed.
+    pub failed_refresh_count: u64,
+    /// The most recent failure, if refreshing failed since the last success.
+    pub last_error: Option<RefreshFailure>,
+    last_refresh: Option<i64>,
+}
+
+impl CredentialStats {
+    fn new(scopes: &[String]) -> CredentialStats {
+        CredentialStats {
+            scopes: scopes.to_vec(),
+            obtained_count: 0,
+            refresh_count: 0,
+            failed_refresh_count: 0,
+            last_error: None,
+            last_refresh: None,
+        }
+    }
+
+    /// When a new token was last obtained or refreshed.
+    pub fn last_refresh(&self) -> Option<Timestamp> {
+        self.last_refresh.map(time::from_secs)
+    }
+}
+
+/// The outcome of an interactive sign-in, as returned by `GetToken::last_sign_in()`. It
+/// serializes to JSON like
+/// `{"account":"jane@example.com","scopes":["email"],"expires_at":1572003600,"storage":"tokens.json"}`,
+/// with `expires_at` in seconds since the epoch, so that wrapper scripts can print it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignInOutcome {
+    /// The account signed in, if the access token is a JWT naming it, like those of Azure AD.
+    /// Google's access tokens are opaque, so request an ID token or the userinfo endpoint to
+    /// find out the account.
+    pub account: Option<String>,
+    /// The scopes granted, sorted.
+    pub scopes: Vec<String>,
+    expires_at: Option<i64>,
+    /// Where the token was stored, e.g. the path of a `DiskTokenStorage`, see
+    /// `TokenStorage::location()`.
+    pub storage: Option<String>,
+}
+
+impl SignInOutcome {
+    pub(crate) fn new(token: &Token, scopes: &[String], storage: Option<String>) -> SignInOutcome {
+        let account = jwt_claims(&token.access_token).and_then(|claims| {
+            ["email", "upn", "preferred_username"]
+                .iter()
+                .filter_map(|claim| claims.get(*claim).and_then(|v| v.as_str()))
+                .next()
+                .map(str::to_string)
+        });
+        SignInOutcome {
+            account,
+            scopes: scopes.to_vec(),
+            expires_at: token.expires_at().map(|t| time::to_secs(&t)),
+            storage,
+        }
+    }
+
+    /// When the token obtained expires.
+    pub fn expires_at(&self) -> Option<Timestamp> {
+        self.expires_at.map(time::from_secs)
+    }
+}
+
+/// Where a token handed out by an authenticator came from, see `TokenInfo`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenSource {
+    /// The token storage, which holds a token that is still valid.
+    Storage,
+    /// The provider refreshed the stored token.
+    Refresh,
+    /// The flow obtained a new token, e.g. by asking the user, or using a service account key.
+    Flow,
+    /// The token source doesn't tell, see `GetToken::token_info()`.
+    Unknown,
+}
+
+/// A token together with where it came from, as returned by `GetToken::token_info()`, e.g. to
+/// find out why a user was asked to sign in again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenInfo {
+    /// The token, as returned by `GetToken::token()`.
+    pub token: Token,
+    /// Where the token came from.
+    pub obtained_via: TokenSource,
+    pub(crate) obtained_at: Option<i64>,
+    /// Where the token is stored, see `TokenStorage::location()`.
+    pub storage: Option<String>,
+}
+
+impl TokenInfo {
+    /// When the token was obtained from the provider. Unknown for tokens stored by another
+    /// process, or before the authenticator was built.
+    pub fn obtained_at(&self) -> Option<Timestamp> {
+        self.obtained_at.map(time::from_secs)
+    }
+
+    /// How long ago the token was obtained from the provider, if known.
+    pub fn age(&self) -> Option<Duration> {
+        let age = time::now().saturating_sub(self.obtained_at?);
+        Some(Duration::from_secs(age.max(0) as u64))
+    }
+}
+
+/// Collects `CredentialStats`, keyed by scope hash.
+#[derive(Debug, Default)]
+pub(crate) struct StatsRecorder {
+    credentials: Mutex<HashMap<u64, CredentialStats>>,
+    last_sign_in: Mutex<Option<SignInOutcome>>,
+}
+
+impl StatsRecorder {
+    fn update<F: FnOnce(&mut CredentialStats)>(&self, scope_hash: u64, scopes: &[String], f: F) {
+        let mut credentials = self.credentials.lock().unwrap();
+        f(credentials
+            .entry(scope_hash)
+            .or_insert_with(|| CredentialStats::new(scopes)))
+    }
+
+    /// Records that a token was obtained from the flow.
+    pub(crate) fn obtained(&self, scope_hash: u64, scopes: &[String]) {
+        self.update(scope_hash, scopes, |c| {
+            c.obtained_count += 1;
+            c.last_refresh = Some(time::now());
+            c.last_error = None;
+        })
+    }
+
+    /// Records the outcome of a sign-in.
+    pub(crate) fn signed_in(&self, outcome: SignInOutcome) {
+        *self.last_sign_in.lock().unwrap() = Some(outcome);
+    }
+
+    pub(crate) fn last_sign_in(&self) -> Option<SignInOutcome> {
+        self.last_sign_in.lock().unwrap().clone()
+    }
+
+    /// When the token for `scope_hash` was last obtained or refreshed.
+    pub(crate) fn last_refresh(&self, scope_hash: u64) -> Option<i64> {
+        self.credentials
+            .lock()
+            .unwrap()
+            .get(&scope_hash)
+            .and_then(|c| c.last_refresh)
+    }
+
+    /// Records that a token was refreshed.
+    pub(crate) fn refreshed(&self, scope_hash: u64, scopes: &[String]) {
+        self.update(scope_hash, scopes, |c| {
+            c.refresh_count += 1;
+            c.last_refresh = Some(time::now());
+            c.last_error = None;
+        })
+    }
+
+    /// Records that refreshing a token failed.
+    pub(crate) fn failed(&self, scope_hash: u64, scopes: &[String], failure: &RefreshFailure) {
+        self.update(scope_hash, scopes, |c| {
+            c.failed_refresh_count += 1;
+            c.last_error = Some(failure.clone());
+        })
+    }
+
+    pub(crate) fn snapshot(&self) -> AuthenticatorStats {
+        let mut credentials: Vec<CredentialStats> =
+            self.credentials.lock().unwrap().values().cloned().collect();
+        credentials.sort_by(|a, b| a.scopes.cmp(&b.scopes));
+        AuthenticatorStats { credentials }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RefreshFailureKind;
+
+    #[test]
+    fn test_stats_recorder() {
+        let recorder = StatsRecorder::default();
+        let (drive, gmail) = (vec!["drive".to_string()], vec!["gmail".to_string()]);
+        recorder.obtained(2, &gmail);
+        recorder.obtained(1, &drive);
+        recorder.refreshed(1, &drive);
+        let failure = RefreshFailure::new(RefreshFailureKind::Transport, "timeout");
+        recorder.failed(1, &drive, &failure);
+
+        let stats = recorder.snapshot();
+        assert_eq!(2, stats.credentials.len());
+        let c = &stats.credentials[0];
+        assert_eq!(drive, c.scopes);
+        assert_eq!(
+            (1, 1, 1),
+            (c.obtained_count, c.refresh_count, c.failed_refresh_count)
+        );
+        assert_eq!(Some(failure), c.last_error);
+        assert!(c.last_refresh().is_some());
+        assert_eq!(gmail, stats.credentials[1].scopes);
+
+        recorder.refreshed(1, &drive);
+        assert_eq!(None, recorder.snapshot().credentials[0].last_error);
+    }
+
+    #[test]
+    fn test_sign_in_outcome() {
+        let claims = serde_json::json!({"upn": "jane@contoso.com", "exp": 1572003600});
+        let jwt = format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.c2ln",
+            base64::encode_config(&claims.to_string(), base64::URL_SAFE_NO_PAD)
+        );
+        let outcome = SignInOutcome::new(
+            &Token::from_jwt(jwt),
+            &["openid".to_string()],
+            Some("tokens.json".to_string()),
+        );
+        assert_eq!(Some("jane@contoso.com".to_string()), outcome.account);
+        assert_eq!(
+            r#"{"account":"jane@contoso.com","scopes":["openid"],"expires_at":1572003600,"storage":"tokens.json"}"#,
+            serde_json::to_string(&outcome).unwrap()
+        );
+
+        let opaque = Token::new("ya29.opaque".to_string(), "Bearer".to_string(), None, None);
+        let outcome = SignInOutcome::new(&opaque, &[], None);
+        assert_eq!((None, None), (outcome.expires_at(), outcome.account));
+    }
+}
@@ -4,15 +4,38 @@
 //
 
 use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "disk-storage")]
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
+#[cfg(feature = "disk-storage")]
 use std::fs;
 use std::hash::{Hash, Hasher};
+#[cfg(feature = "disk-storage")]
 use std::io;
+#[cfg(feature = "disk-storage")]
 use std::io::{Read, Write};
+#[cfg(feature = "disk-storage")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "disk-storage")]
+use std::thread;
+#[cfg(feature = "disk-storage")]
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "disk-storage")]
+use crate::storage_format::StorageFormat;
+use crate::time::{self, Timestamp};
 use crate::types::Token;
+#[cfg(feature = "disk-storage")]
+use fs2::FileExt;
+#[cfg(feature = "disk-storage")]
+use futures::Async;
 use itertools::Itertools;
+use serde::Deserialize;
+
+/// The number of refresh failures kept per token. Beyond that, the oldest ones except for the
+/// very first are discarded, so that it remains visible since when refreshing has been failing.
+const MAX_REFRESH_FAILURES: usize = 16;
 
 /// Implements a specialized storage to set and retrieve `Token` instances.
 /// The `scope_hash` represents the signature of the scopes for which the given token
@@ -32,19 +55,404 @@ pub trait TokenStorage {
     ) -> Result<(), Self::Error>;
     /// A `None` result indicates that there is no token for the given scope_hash.
     fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error>;
+
+    /// Records that refreshing the token returned by `get()` for the same arguments failed.
+    /// Storages persisting tokens should persist failures alongside the token, and discard them
+    /// once the token is replaced by `set()`. The default implementation records nothing.
+    fn record_refresh_failure(
+        &mut self,
+        _scope_hash: u64,
+        _scopes: &Vec<&str>,
+        _failure: RefreshFailure,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns the refresh failures recorded for the token returned by `get()` for the same
+    /// arguments, oldest first.
+    fn refresh_failures(
+        &self,
+        _scope_hash: u64,
+        _scopes: &Vec<&str>,
+    ) -> Result<Vec<RefreshFailure>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Marks the tokens whose access token is `access_token` as expired, so that they are
+    /// refreshed when requested next, or removes them if they have no refresh token. Returns
+    /// whether any token matched. The default implementation matches nothing.
+    fn invalidate(&mut self, _access_token: &str) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Describes where tokens are stored, e.g. a file path, for display to the user. The
+    /// default implementation returns `None`.
+    fn location(&self) -> Option<String> {
+        None
+    }
+
+    /// Called before the authenticator refreshes the expired token returned by `get()` for the
+    /// same arguments. Storages shared between processes may lock the token until
+    /// `refresh_finished()`, and return the token as stored now, which the authenticator returns
+    /// instead of refreshing if it is valid, or `RefreshStart::Busy` while another process is
+    /// refreshing it. They must not block. The default implementation returns
+    /// `RefreshStart::Ready(None)`.
+    fn refresh_started(
+        &mut self,
+        _scope_hash: u64,
+        _scopes: &Vec<&str>,
+    ) -> Result<RefreshStart, Self::Error> {
+        Ok(RefreshStart::Ready(None))
+    }
+
+    /// Called once the refresh for which `refresh_started()` returned `RefreshStart::Ready` is
+    /// over, whether it succeeded, failed or was abandoned. The default implementation does
+    /// nothing.
+    fn refresh_finished(
+        &mut self,
+        _scope_hash: u64,
+        _scopes: &Vec<&str>,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Lists all stored tokens, e.g. for `migrate()`, or returns `None` if the storage can't
+    /// enumerate its tokens. The default implementation returns `None`, so such storages can't
+    /// be migrated from.
+    fn stored_tokens(&self) -> Result<Option<Vec<StoredToken>>, Self::Error> {
+        Ok(None)
+    }
+}
+
+/// What `TokenStorage::refresh_started()` found.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RefreshStart {
+    /// The token may be refreshed. Holds the token as stored now, if the storage can tell.
+    Ready(Option<Token>),
+    /// Another process is refreshing the token; the authenticator asks again shortly.
+    Busy,
+}
+
+/// A token as listed by `TokenStorage::stored_tokens()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredToken {
+    pub scope_hash: u64,
+    /// The scopes the token was stored for. Tokens stored by old versions of this crate have
+    /// none, and are only found by `scope_hash`.
+    pub scopes: Vec<String>,
+    pub token: Token,
+    /// The failed attempts to refresh the token, oldest first.
+    pub refresh_failures: Vec<RefreshFailure>,
+}
+
+impl From<&JSONToken> for StoredToken {
+    fn from(t: &JSONToken) -> StoredToken {
+        StoredToken {
+            scope_hash: t.hash,
+            scopes: t.scopes.clone().unwrap_or_default(),
+            token: t.token.clone(),
+            refresh_failures: t.refresh_failures.clone(),
+        }
+    }
 }
 
-/// Calculate a hash value describing the scopes, and return a sorted Vec of the scopes.
+/// Why `migrate()` failed.
+#[derive(Debug)]
+pub enum MigrationError<R, W> {
+    /// Listing the tokens of the old storage failed.
+    Read(R),
+    /// Storing a token in the new storage failed.
+    Write(W),
+    /// The old storage can't list its tokens.
+    Unsupported,
+}
+
+impl<R: fmt::Display, W: fmt::Display> fmt::Display for MigrationError<R, W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MigrationError::Read(ref e) => write!(f, "reading the stored tokens failed: {}", e),
+            MigrationError::Write(ref e) => write!(f, "storing a token failed: {}", e),
+            MigrationError::Unsupported => "the storage can't list its tokens".fmt(f),
+        }
+    }
+}
+
+impl<R, W> Error for MigrationError<R, W>
+where
+    R: 'static + Error,
+    W: 'static + Error,
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            MigrationError::Read(ref e) => Some(e),
+            MigrationError::Write(ref e) => Some(e),
+            MigrationError::Unsupported => None,
+        }
+    }
+}
+
+/// Copies all tokens of `from` to `to`, with their refresh failures, e.g. to move the tokens of
+/// an application's users from a file to the system keyring, or to a file in another
+/// `StorageFormat`. Returns the number of tokens copied.
+///
+/// Tokens stored in `to` under the same keys are replaced. `from` is left as is, so nothing is
+/// lost if the migration fails midway; remove it once the application uses `to`. Fails with
+/// `MigrationError::Unsupported` if `from` can't list its tokens.
+pub fn migrate<A, B>(from: &A, to: &mut B) -> Result<usize, MigrationError<A::Error, B::Error>>
+where
+    A: TokenStorage,
+    B: TokenStorage,
+{
+    let tokens = from
+        .stored_tokens()
+        .map_err(MigrationError::Read)?
+        .ok_or(MigrationError::Unsupported)?;
+    for stored in &tokens {
+        let scopes = stored.scopes.iter().map(String::as_str).collect();
+        to.set(stored.scope_hash, &scopes, Some(stored.token.clone()))
+            .map_err(MigrationError::Write)?;
+        for failure in &stored.refresh_failures {
+            to.record_refresh_failure(stored.scope_hash, &scopes, failure.clone())
+                .map_err(MigrationError::Write)?;
+        }
+    }
+    Ok(tokens.len())
+}
+
+/// A failed attempt to refresh a stored token.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RefreshFailure {
+    timestamp: i64,
+    pub kind: RefreshFailureKind,
+    /// The error, as reported by the provider or the HTTP client.
+    pub message: String,
+}
+
+impl RefreshFailure {
+    /// A failure which happened just now.
+    pub fn new<S: Into<String>>(kind: RefreshFailureKind, message: S) -> RefreshFailure {
+        RefreshFailure {
+            timestamp: time::now(),
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// When the refresh failed.
+    pub fn at(&self) -> Timestamp {
+        time::from_secs(self.timestamp)
+    }
+}
+
+/// Why a refresh failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshFailureKind {
+    /// The provider couldn't be reached.
+    Transport,
+    /// The provider refused the refresh token, e.g. because the authorization was revoked.
+    Rejected,
+    /// The provider requires the user to sign in again.
+    ReauthRequired,
+}
+
+/// Returns the index of the token to return for `scope_hash` and `scopes`: the one stored under
+/// `scope_hash`, or else the first one stored by an older version of this crate whose scopes
+/// contain all of `scopes`.
+fn find_token(tokens: &[JSONToken], scope_hash: u64, scopes: &Vec<&str>) -> Option<usize> {
+    if let Some(idx) = tokens.iter().position(|t| t.hash == scope_hash) {
+        return Some(idx);
+    }
+    let scopes: Vec<_> = scopes.iter().sorted().unique().collect();
+    if scopes.is_empty() {
+        return None;
+    }
+    tokens.iter().position(|t| match legacy_scopes(t) {
+        Some(token_scopes) => {
+            let matched = token_scopes
+                .iter()
+                .filter(|x| scopes.contains(&&&x[..]))
+                .count();
+            // we may have some of the tokens as denormalized (many namespaces repeated)
+            matched >= scopes.len()
+        }
+        None => false,
+    })
+}
+
+/// The scopes of `token` if it was stored by a version of this crate before `TokenKey`.
+fn legacy_scopes(token: &JSONToken) -> Option<&Vec<String>> {
+    let scopes = token.scopes.as_ref()?;
+    if legacy_scope_hash(scopes) == token.hash {
+        Some(scopes)
+    } else {
+        None
+    }
+}
+
+/// The `scope_hash` of versions of this crate before `TokenKey`: the `DefaultHasher` hash of the
+/// sorted scopes, whatever the application.
+fn legacy_scope_hash(scopes: &[String]) -> u64 {
+    let mut sorted = scopes.to_vec();
+    sorted.sort();
+    let mut hasher = DefaultHasher::new();
+    sorted.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Appends `failure` to the failures of the token `find_token()` returns, if any.
+fn push_refresh_failure(
+    tokens: &mut [JSONToken],
+    scope_hash: u64,
+    scopes: &Vec<&str>,
+    failure: RefreshFailure,
+) {
+    if let Some(idx) = find_token(tokens, scope_hash, scopes) {
+        let failures = &mut tokens[idx].refresh_failures;
+        failures.push(failure);
+        if failures.len() > MAX_REFRESH_FAILURES {
+            failures.remove(1);
+        }
+    }
+}
+
+/// Expires or removes the tokens whose access token is `access_token`, see
+/// `TokenStorage::invalidate()`.
+fn invalidate_token(tokens: &mut Vec<JSONToken>, access_token: &str) -> bool {
+    let before = tokens.len();
+    tokens.retain(|t| t.token.access_token != access_token || t.token.refresh_token.is_some());
+    let mut matched = tokens.len() != before;
+    for t in tokens.iter_mut() {
+        if t.token.access_token == access_token {
+            t.token.expire();
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Identifies the tokens an application obtains for an account and a set of scopes. Tokens are
+/// stored under its `scope_hash()`, which is the same across platforms and versions of this
+/// crate, so that external caches can share the keys of the crate's token storages.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TokenKey {
+    client_id: String,
+    account: Option<String>,
+    scopes: Vec<String>,
+}
+
+impl TokenKey {
+    /// The key of the tokens of the application `client_id` for `scopes`, which are sorted and
+    /// deduplicated.
+    pub fn new<S, I, T>(client_id: S, scopes: I) -> TokenKey
+    where
+        S: Into<String>,
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let mut scopes: Vec<String> = scopes.into_iter().map(Into::into).collect();
+        scopes.sort();
+        scopes.dedup();
+        TokenKey {
+            client_id: client_id.into(),
+            account: None,
+            scopes,
+        }
+    }
+
+    /// The key of the tokens of a particular account, e.g. a user ID, rather than of whichever
+    /// account authorized the application.
+    pub fn with_account<S: Into<String>>(self, account: S) -> TokenKey {
+        TokenKey {
+            account: Some(account.into()),
+            ..self
+        }
+    }
+
+    /// The application's client ID.
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// The account, if the key is tied to one.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// The sorted scopes.
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    /// The value passed as `scope_hash` to `TokenStorage`: the 64 bit FNV-1a hash of the client
+    /// ID, the account and the scopes, each followed by a 0xff byte, with the account prefixed
+    /// by a 0x01 byte if present.
+    pub fn scope_hash(&self) -> u64 {
+        let mut bytes = self.client_id.as_bytes().to_vec();
+        // 0xff never occurs in UTF-8.
+        bytes.push(0xff);
+        if let Some(account) = &self.account {
+            bytes.push(0x01);
+            bytes.extend_from_slice(account.as_bytes());
+        }
+        bytes.push(0xff);
+        for scope in &self.scopes {
+            bytes.extend_from_slice(scope.as_bytes());
+            bytes.push(0xff);
+        }
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+/// Calculate a hash value describing the scopes, and return a sorted Vec of the scopes. This is
+/// the `TokenKey` of tokens not tied to an application, like those of a token source's own
+/// cache.
+#[cfg(any(
+    feature = "external-account",
+    feature = "impersonated-service-account",
+    feature = "metadata-server",
+    feature = "service-account"
+))]
 pub fn hash_scopes<I, T>(scopes: I) -> (u64, Vec<String>)
 where
     T: Into<String>,
     I: IntoIterator<Item = T>,
 {
-    let mut sv: Vec<String> = scopes.into_iter().map(Into::into).collect();
-    sv.sort();
-    let mut sh = DefaultHasher::new();
-    sv.hash(&mut sh);
-    (sh.finish(), sv)
+    let key = TokenKey::new("", scopes);
+    (key.scope_hash(), key.scopes)
+}
+
+/// The scopes to store a token with. Without scopes, it is only found by `scope_hash`, like the
+/// tokens of old versions of this crate, as an empty list would match any scopes.
+fn stored_scopes(scopes: &[&str]) -> Option<Vec<String>> {
+    if scopes.is_empty() {
+        return None;
+    }
+    Some(scopes.iter().map(|x| x.to_string()).collect())
+}
+
+/// Removes the token stored under `scope_hash`, or else the one with exactly `scopes` stored by
+/// an older version of this crate.
+fn remove_token(tokens: &mut Vec<JSONToken>, scope_hash: u64, scopes: &Vec<&str>) {
+    let scopes: Vec<_> = scopes.iter().sorted().unique().collect();
+    let matched = tokens
+        .iter()
+        .position(|t| t.hash == scope_hash)
+        .or_else(|| {
+            tokens.iter().position(|t| match legacy_scopes(t) {
+                Some(token_scopes) => token_scopes
+                    .iter()
+                    .sorted()
+                    .unique()
+                    .eq(scopes.iter().copied()),
+                None => false,
+            })
+        });
+    if let Some(idx) = matched {
+        tokens.remove(idx);
+    }
 }
 
 /// A storage that remembers nothing.
@@ -97,17 +505,15 @@ impl TokenStorage for MemoryStorage {
         scopes: &Vec<&str>,
         token: Option<Token>,
     ) -> Result<(), NullError> {
-        let matched = self.tokens.iter().find_position(|x| x.hash == scope_hash);
-        if let Some((idx, _)) = matched {
-            self.tokens.remove(idx);
-        }
+        remove_token(&mut self.tokens, scope_hash, scopes);
 
         match token {
             Some(t) => {
                 self.tokens.push(JSONToken {
                     hash: scope_hash,
-                    scopes: Some(scopes.iter().map(|x| x.to_string()).collect()),
+                    scopes: stored_scopes(scopes),
                     token: t.clone(),
+                    refresh_failures: Vec::new(),
                 });
                 ()
             }
@@ -117,51 +523,184 @@ impl TokenStorage for MemoryStorage {
     }
 
     fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, NullError> {
-        let scopes: Vec<_> = scopes.iter().sorted().unique().collect();
+        Ok(find_token(&self.tokens, scope_hash, scopes).map(|idx| self.tokens[idx].token.clone()))
+    }
 
-        for t in &self.tokens {
-            if let Some(token_scopes) = &t.scopes {
-                let matched = token_scopes
-                    .iter()
-                    .filter(|x| scopes.contains(&&&x[..]))
-                    .count();
-                if matched >= scopes.len() {
-                    return Result::Ok(Some(t.token.clone()));
-                }
-            } else if scope_hash == t.hash {
-                return Result::Ok(Some(t.token.clone()));
-            }
-        }
-        Result::Ok(None)
+    fn record_refresh_failure(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        failure: RefreshFailure,
+    ) -> Result<(), NullError> {
+        push_refresh_failure(&mut self.tokens, scope_hash, scopes, failure);
+        Ok(())
+    }
+
+    fn refresh_failures(
+        &self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<Vec<RefreshFailure>, NullError> {
+        Ok(find_token(&self.tokens, scope_hash, scopes)
+            .map(|idx| self.tokens[idx].refresh_failures.clone())
+            .unwrap_or_default())
+    }
+
+    fn invalidate(&mut self, access_token: &str) -> Result<bool, NullError> {
+        Ok(invalidate_token(&mut self.tokens, access_token))
+    }
+
+    fn stored_tokens(&self) -> Result<Option<Vec<StoredToken>>, NullError> {
+        Ok(Some(self.tokens.iter().map(StoredToken::from).collect()))
     }
 }
 
 /// A single stored token.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct JSONToken {
+    #[serde(deserialize_with = "lenient_hash")]
     pub hash: u64,
     pub scopes: Option<Vec<String>>,
     pub token: Token,
+    /// Failed attempts to refresh `token`, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub refresh_failures: Vec<RefreshFailure>,
+}
+
+/// Deserializes a scope hash, which the TOML format stores as a string if it exceeds the range
+/// of TOML integers.
+fn lenient_hash<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Hash {
+        Integer(u64),
+        Text(String),
+    }
+    match Hash::deserialize(deserializer)? {
+        Hash::Integer(hash) => Ok(hash),
+        Hash::Text(text) => text
+            .parse()
+            .map_err(|_| serde::de::Error::custom(format!("invalid scope hash: {}", text))),
+    }
 }
 
 /// List of tokens in a JSON object
+#[cfg(feature = "disk-storage")]
 #[derive(Serialize, Deserialize)]
 struct JSONTokens {
     pub tokens: Vec<JSONToken>,
 }
 
-/// Serializes tokens to a JSON file on disk.
+/// How long `DiskTokenStorage` waits for another process to release the file lock.
+#[cfg(feature = "disk-storage")]
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An exclusive advisory lock on a lock file (`flock()`, or `LockFileEx()` on Windows), released
+/// when dropped. The operating system releases the lock when the process exits, so that a
+/// crashed process leaves no lock behind. The lock file itself is kept.
+#[cfg(feature = "disk-storage")]
+struct FileLock {
+    file: fs::File,
+}
+
+#[cfg(feature = "disk-storage")]
+impl FileLock {
+    /// Locks the file at `path`, waiting up to `LOCK_TIMEOUT` for another process to release
+    /// it. While waiting, the thread is marked as blocking if it belongs to a
+    /// `tokio_threadpool`, so that the pool's other tasks move on to another thread.
+    fn acquire(path: PathBuf) -> Result<FileLock, io::Error> {
+        if let Some(lock) = FileLock::try_acquire(&path)? {
+            return Ok(lock);
+        }
+        let mut wait = Some(|| {
+            let started = Instant::now();
+            loop {
+                if let Some(lock) = FileLock::try_acquire(&path)? {
+                    return Ok(lock);
+                }
+                if started.elapsed() > LOCK_TIMEOUT {
+                    return Err(lock_timeout(&path));
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        match tokio_threadpool::blocking(|| wait.take().unwrap()()) {
+            Ok(Async::Ready(result)) => result,
+            // Not on a thread pool, or the pool is out of blocking threads.
+            _ => wait.take().unwrap()(),
+        }
+    }
+
+    /// Locks the file at `path`, creating it if needed, unless another process holds the lock.
+    fn try_acquire(path: &Path) -> Result<Option<FileLock>, io::Error> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(FileLock { file })),
+            Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "disk-storage")]
+fn lock_timeout(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("timed out waiting for the lock {}", path.display()),
+    )
+}
+
+#[cfg(feature = "disk-storage")]
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+/// Serializes tokens to a file on disk, as JSON unless another `format()` is chosen. Files in
+/// any of the enabled formats are read.
+///
+/// The file may be shared by several processes, like concurrent invocations of a command line
+/// tool. Changes are made while briefly locking a file next to it, `<location>.write.lock`, and
+/// applied to the file as it is then, so that they don't overwrite the tokens stored by other
+/// processes. Before refreshing a token, another file, `<location>.lock`, is locked and the file
+/// is read again, so that a token another process refreshed meanwhile is used instead of
+/// refreshing it once more; that lock is held until the refreshes of this storage are over.
+/// Other processes wait for it without blocking, see `TokenStorage::refresh_started()`.
+#[cfg(feature = "disk-storage")]
 #[derive(Default)]
 pub struct DiskTokenStorage {
     location: String,
     tokens: Vec<JSONToken>,
+    /// Held from `refresh_started()` until `refresh_finished()` of all scopes refreshing.
+    refresh_lock: Option<FileLock>,
+    /// The hashes of the scopes between `refresh_started()` and `refresh_finished()`.
+    refreshing: HashSet<u64>,
+    /// Since when `refresh_started()` has been waiting for another process to release the lock.
+    waiting_since: Option<Instant>,
+    format: StorageFormat,
 }
 
+#[cfg(feature = "disk-storage")]
 impl DiskTokenStorage {
     pub fn new<S: AsRef<str>>(location: S) -> Result<DiskTokenStorage, io::Error> {
         let mut dts = DiskTokenStorage {
             location: location.as_ref().to_owned(),
             tokens: Vec::new(),
+            refresh_lock: None,
+            refreshing: HashSet::new(),
+            waiting_since: None,
+            format: StorageFormat::Json,
         };
 
         // best-effort
@@ -178,28 +717,74 @@ impl DiskTokenStorage {
         }
     }
 
+    /// The stored tokens, with the scopes they were stored for.
+    #[cfg(all(feature = "device", feature = "installed"))]
+    pub(crate) fn tokens(&self) -> impl Iterator<Item = (&[String], &Token)> {
+        self.tokens
+            .iter()
+            .map(|t| (t.scopes.as_deref().unwrap_or(&[]), &t.token))
+    }
+
+    /// Write the tokens in `format`. The file is converted on the next change.
+    /// (default: `StorageFormat::Json`)
+    pub fn format(self, format: StorageFormat) -> DiskTokenStorage {
+        DiskTokenStorage { format, ..self }
+    }
+
     fn load_from_file(&mut self) -> Result<(), io::Error> {
         let mut f = fs::OpenOptions::new().read(true).open(&self.location)?;
-        let mut contents = String::new();
+        let mut contents = Vec::new();
 
-        match f.read_to_string(&mut contents) {
+        match f.read_to_end(&mut contents) {
             Result::Err(e) => return Result::Err(e),
             Result::Ok(_sz) => (),
         }
 
         let tokens: JSONTokens;
 
-        match serde_json::from_str(&contents) {
-            Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        match StorageFormat::deserialize(&contents).and_then(|value| {
+            serde_json::from_value(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }) {
+            Result::Err(e) => return Result::Err(e),
             Result::Ok(t) => tokens = t,
         }
 
-        for t in tokens.tokens {
-            self.tokens.push(t);
-        }
+        self.tokens = tokens.tokens;
         return Result::Ok(());
     }
 
+    fn refresh_lock_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.lock", self.location))
+    }
+
+    /// Takes the lock guarding writes to the file, to be released as soon as they are done.
+    fn write_lock(&self) -> Result<FileLock, io::Error> {
+        FileLock::acquire(PathBuf::from(format!("{}.write.lock", self.location)))
+    }
+
+    /// Reads the file again; a missing file holds no tokens.
+    fn reload(&mut self) -> Result<(), io::Error> {
+        match self.load_from_file() {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.tokens.clear();
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
+    /// Applies `change` to the tokens as currently stored and writes them, holding the write
+    /// lock meanwhile.
+    fn update<R, F>(&mut self, change: F) -> Result<R, io::Error>
+    where
+        F: FnOnce(&mut Vec<JSONToken>) -> R,
+    {
+        let _lock = self.write_lock()?;
+        self.reload()?;
+        let result = change(&mut self.tokens);
+        self.dump_to_file().map(|()| result)
+    }
+
     pub fn dump_to_file(&mut self) -> Result<(), io::Error> {
         let mut jsontokens = JSONTokens { tokens: Vec::new() };
 
@@ -209,9 +794,9 @@ impl DiskTokenStorage {
 
         let serialized;
 
-        match serde_json::to_string(&jsontokens) {
+        match serde_json::to_value(&jsontokens) {
             Result::Err(e) => return Result::Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            Result::Ok(s) => serialized = s,
+            Result::Ok(value) => serialized = self.format.serialize(&value)?,
         }
 
         let mut f = fs::OpenOptions::new()
@@ -219,10 +804,11 @@ impl DiskTokenStorage {
             .write(true)
             .truncate(true)
             .open(&self.location)?;
-        f.write(serialized.as_ref()).map(|_| ())
+        f.write_all(&serialized)
     }
 }
 
+#[cfg(feature = "disk-storage")]
 impl TokenStorage for DiskTokenStorage {
     type Error = io::Error;
     fn set(
@@ -231,41 +817,459 @@ impl TokenStorage for DiskTokenStorage {
         scopes: &Vec<&str>,
         token: Option<Token>,
     ) -> Result<(), Self::Error> {
-        let matched = self.tokens.iter().find_position(|x| x.hash == scope_hash);
-        if let Some((idx, _)) = matched {
-            self.tokens.remove(idx);
-        }
+        self.update(|tokens| {
+            remove_token(tokens, scope_hash, scopes);
 
-        match token {
-            None => (),
-            Some(t) => {
-                self.tokens.push(JSONToken {
-                    hash: scope_hash,
-                    scopes: Some(scopes.iter().map(|x| x.to_string()).collect()),
-                    token: t.clone(),
-                });
-                ()
+            match token {
+                None => (),
+                Some(t) => {
+                    tokens.push(JSONToken {
+                        hash: scope_hash,
+                        scopes: stored_scopes(scopes),
+                        token: t.clone(),
+                        refresh_failures: Vec::new(),
+                    });
+                    ()
+                }
             }
-        }
-        self.dump_to_file()
+        })
     }
     fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error> {
-        let scopes: Vec<_> = scopes.iter().sorted().unique().collect();
+        Ok(find_token(&self.tokens, scope_hash, scopes).map(|idx| self.tokens[idx].token.clone()))
+    }
 
-        for t in &self.tokens {
-            if let Some(token_scopes) = &t.scopes {
-                let matched = token_scopes
-                    .iter()
-                    .filter(|x| scopes.contains(&&&x[..]))
-                    .count();
-                // we may have some of the tokens as denormalized (many namespaces repeated)
-                if matched >= scopes.len() {
-                    return Result::Ok(Some(t.token.clone()));
+    fn record_refresh_failure(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        failure: RefreshFailure,
+    ) -> Result<(), Self::Error> {
+        self.update(|tokens| push_refresh_failure(tokens, scope_hash, scopes, failure))
+    }
+
+    fn refresh_failures(
+        &self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<Vec<RefreshFailure>, Self::Error> {
+        Ok(find_token(&self.tokens, scope_hash, scopes)
+            .map(|idx| self.tokens[idx].refresh_failures.clone())
+            .unwrap_or_default())
+    }
+
+    fn invalidate(&mut self, access_token: &str) -> Result<bool, Self::Error> {
+        if !self
+            .tokens
+            .iter()
+            .any(|t| t.token.access_token == access_token)
+        {
+            return Ok(false);
+        }
+        self.update(|tokens| invalidate_token(tokens, access_token))
+    }
+
+    fn location(&self) -> Option<String> {
+        Some(self.location.clone())
+    }
+
+    fn stored_tokens(&self) -> Result<Option<Vec<StoredToken>>, Self::Error> {
+        Ok(Some(self.tokens.iter().map(StoredToken::from).collect()))
+    }
+
+    /// Takes the refresh lock, and returns the token as stored in the file now. While another
+    /// process holds the lock, returns `RefreshStart::Busy`, or fails after waiting for
+    /// `LOCK_TIMEOUT`.
+    fn refresh_started(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<RefreshStart, Self::Error> {
+        if self.refresh_lock.is_none() {
+            let path = self.refresh_lock_path();
+            match FileLock::try_acquire(&path)? {
+                Some(lock) => {
+                    self.refresh_lock = Some(lock);
+                    self.waiting_since = None;
+                }
+                None => {
+                    let waiting_since = *self.waiting_since.get_or_insert_with(Instant::now);
+                    if waiting_since.elapsed() > LOCK_TIMEOUT {
+                        self.waiting_since = None;
+                        return Err(lock_timeout(&path));
+                    }
+                    return Ok(RefreshStart::Busy);
                 }
-            } else if scope_hash == t.hash {
-                return Result::Ok(Some(t.token.clone()));
             }
         }
-        Result::Ok(None)
+        self.refreshing.insert(scope_hash);
+        if let Err(e) = self.write_lock().and_then(|_lock| self.reload()) {
+            self.refresh_finished(scope_hash, scopes)?;
+            return Err(e);
+        }
+        self.get(scope_hash, scopes).map(RefreshStart::Ready)
+    }
+
+    /// Releases the refresh lock, unless other scopes are still refreshing.
+    fn refresh_finished(
+        &mut self,
+        scope_hash: u64,
+        _scopes: &Vec<&str>,
+    ) -> Result<(), Self::Error> {
+        self.refreshing.remove(&scope_hash);
+        if self.refreshing.is_empty() {
+            self.refresh_lock = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_key() {
+        let key = TokenKey::new("client", vec!["b", "a", "b"]);
+        assert_eq!(&["a".to_string(), "b".to_string()], key.scopes());
+        assert_eq!(key, TokenKey::new("client", vec!["a", "b"]));
+        // The hash must not change, as it identifies stored tokens.
+        assert_eq!(9188186176763328699, key.scope_hash());
+        assert_eq!(
+            763862646787557219,
+            TokenKey::new("", Vec::<String>::new()).scope_hash()
+        );
+        let user = key.clone().with_account("user");
+        assert_eq!(Some("user"), user.account());
+        assert_ne!(key.scope_hash(), user.scope_hash());
+        assert_ne!(
+            user.scope_hash(),
+            TokenKey::new("client", vec!["a", "b"])
+                .with_account("")
+                .scope_hash()
+        );
+        assert_ne!(
+            key.scope_hash(),
+            TokenKey::new("other", vec!["a", "b"]).scope_hash()
+        );
+
+        // Tokens stored under the keys of older versions are replaced.
+        let mut storage = MemoryStorage::new();
+        let mut token = Token::from_jwt("old".to_string());
+        let legacy = legacy_scope_hash(&["a".to_string(), "b".to_string()]);
+        storage
+            .set(legacy, &vec!["a", "b"], Some(token.clone()))
+            .unwrap();
+        token.access_token = "new".to_string();
+        let scopes = key.scopes().iter().map(|s| s.as_str()).collect();
+        storage.set(key.scope_hash(), &scopes, Some(token)).unwrap();
+        assert_eq!(1, storage.tokens.len());
+        assert_eq!(
+            "new",
+            storage
+                .get(key.scope_hash(), &scopes)
+                .unwrap()
+                .unwrap()
+                .access_token
+        );
+    }
+
+    #[test]
+    fn test_same_scopes_of_other_clients() {
+        let first = TokenKey::new("first", vec!["scope"]);
+        let second = TokenKey::new("second", vec!["scope"]);
+        let scopes = vec!["scope"];
+        let mut storage = MemoryStorage::new();
+        storage
+            .set(
+                first.scope_hash(),
+                &scopes,
+                Some(Token::from_jwt("first".to_string())),
+            )
+            .unwrap();
+        assert_eq!(None, storage.get(second.scope_hash(), &scopes).unwrap());
+        storage
+            .set(
+                second.scope_hash(),
+                &scopes,
+                Some(Token::from_jwt("second".to_string())),
+            )
+            .unwrap();
+        assert_eq!(2, storage.tokens.len());
+        let access_token = |key: &TokenKey, storage: &MemoryStorage| {
+            storage
+                .get(key.scope_hash(), &scopes)
+                .unwrap()
+                .map(|t| t.access_token)
+        };
+        assert_eq!(Some("first".to_string()), access_token(&first, &storage));
+        storage.set(first.scope_hash(), &scopes, None).unwrap();
+        assert_eq!(None, access_token(&first, &storage));
+        assert_eq!(Some("second".to_string()), access_token(&second, &storage));
+
+        // Tokens stored by older versions are found by their scopes.
+        let mut storage = MemoryStorage::new();
+        let legacy = legacy_scope_hash(&["scope".to_string()]);
+        storage
+            .set(legacy, &scopes, Some(Token::from_jwt("old".to_string())))
+            .unwrap();
+        assert_eq!(Some("old".to_string()), access_token(&first, &storage));
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let refreshable = Token::new(
+            "at1".to_string(),
+            "Bearer".to_string(),
+            Some("rt".to_string()),
+            Some(3600),
+        );
+        let unrefreshable = Token::new("at2".to_string(), "Bearer".to_string(), None, Some(3600));
+        let mut storage = MemoryStorage::new();
+        storage.set(1, &vec!["a"], Some(refreshable)).unwrap();
+        storage.set(2, &vec!["b"], Some(unrefreshable)).unwrap();
+
+        assert!(!storage.invalidate("unknown").unwrap());
+        assert!(storage.invalidate("at1").unwrap());
+        let token = storage.get(1, &vec!["a"]).unwrap().unwrap();
+        assert!(token.expired());
+        assert_eq!(Some("rt".to_string()), token.refresh_token);
+        assert!(storage.invalidate("at2").unwrap());
+        assert!(storage.get(2, &vec!["b"]).unwrap().is_none());
+    }
+
+    #[cfg(feature = "disk-storage")]
+    #[test]
+    fn test_refresh_failures() {
+        let path = std::env::temp_dir().join(format!("yup-oauth2-failures-{}.json", time::now()));
+        let path = path.to_str().unwrap();
+        let scopes = vec!["scope"];
+        let token = Token::new("at".to_string(), "Bearer".to_string(), None, Some(3600));
+
+        let mut storage = DiskTokenStorage::new(path).unwrap();
+        // Nothing is recorded without a token.
+        storage
+            .record_refresh_failure(
+                0,
+                &scopes,
+                RefreshFailure::new(RefreshFailureKind::Transport, "timeout"),
+            )
+            .unwrap();
+        storage.set(0, &scopes, Some(token.clone())).unwrap();
+        assert!(storage.refresh_failures(0, &scopes).unwrap().is_empty());
+        for i in 0..(MAX_REFRESH_FAILURES + 2) {
+            let failure =
+                RefreshFailure::new(RefreshFailureKind::Rejected, format!("invalid_grant {}", i));
+            storage.record_refresh_failure(0, &scopes, failure).unwrap();
+        }
+
+        // Failures survive a restart.
+        let mut storage = DiskTokenStorage::new(path).unwrap();
+        let failures = storage.refresh_failures(0, &scopes).unwrap();
+        assert_eq!(MAX_REFRESH_FAILURES, failures.len());
+        assert_eq!("invalid_grant 0", failures[0].message);
+        assert_eq!(
+            "invalid_grant 17",
+            failures[MAX_REFRESH_FAILURES - 1].message
+        );
+        assert_eq!(RefreshFailureKind::Rejected, failures[0].kind);
+        assert!(time::to_secs(&failures[0].at()) <= time::now());
+
+        // A new token starts with a clean journal.
+        storage.set(0, &scopes, Some(token)).unwrap();
+        assert!(storage.refresh_failures(0, &scopes).unwrap().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "disk-storage")]
+    #[test]
+    fn test_storage_formats() {
+        let path = std::env::temp_dir().join(format!("yup-oauth2-formats-{}", time::now()));
+        let path = path.to_str().unwrap();
+        let scopes = vec!["scope"];
+        let token = Token::new(
+            "at".to_string(),
+            "Bearer".to_string(),
+            Some("rt".to_string()),
+            Some(3600),
+        );
+        let hash = u64::MAX - 1;
+
+        let mut storage = DiskTokenStorage::new(path).unwrap();
+        storage.set(hash, &scopes, Some(token.clone())).unwrap();
+        assert!(fs::read_to_string(path).unwrap().starts_with('{'));
+        let failure = RefreshFailure::new(RefreshFailureKind::Transport, "timeout");
+        let formats = [
+            #[cfg(feature = "toml")]
+            StorageFormat::Toml,
+            #[cfg(feature = "serde_cbor")]
+            StorageFormat::Cbor,
+            StorageFormat::Json,
+        ];
+        for &format in &formats {
+            // The file is read whatever its format, and converted on the next change.
+            let mut storage = DiskTokenStorage::new(path).unwrap().format(format);
+            assert_eq!(Some(token.clone()), storage.get(hash, &scopes).unwrap());
+            storage
+                .record_refresh_failure(hash, &scopes, failure.clone())
+                .unwrap();
+            assert_eq!(
+                Some(format),
+                StorageFormat::detect(&fs::read(path).unwrap())
+            );
+        }
+        let storage = DiskTokenStorage::new(path).unwrap();
+        assert_eq!(
+            formats.len(),
+            storage.refresh_failures(hash, &scopes).unwrap().len()
+        );
+        assert_eq!(Some(token), storage.get(hash, &scopes).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "disk-storage")]
+    #[test]
+    fn test_migrate() {
+        let path = std::env::temp_dir().join(format!("yup-oauth2-migrate-{}", time::now()));
+        let path = path.to_str().unwrap();
+        let token = |at: &str| Token::new(at.to_string(), "Bearer".to_string(), None, Some(3600));
+        let mut memory = MemoryStorage::new();
+        memory.set(1, &vec!["a", "b"], Some(token("at1"))).unwrap();
+        memory.set(2, &vec![], Some(token("at2"))).unwrap();
+        let failure = RefreshFailure::new(RefreshFailureKind::Rejected, "invalid_grant");
+        memory
+            .record_refresh_failure(1, &vec!["a", "b"], failure.clone())
+            .unwrap();
+
+        let mut disk = DiskTokenStorage::new(path).unwrap();
+        assert_eq!(2, migrate(&memory, &mut disk).unwrap());
+        let disk = DiskTokenStorage::new(path).unwrap();
+        assert_eq!(
+            memory.stored_tokens().unwrap().unwrap(),
+            disk.stored_tokens().unwrap().unwrap()
+        );
+        assert_eq!(
+            vec![failure],
+            disk.refresh_failures(1, &vec!["a", "b"]).unwrap()
+        );
+        // Tokens without scopes are only found by their hash.
+        assert_eq!(Some(token("at2")), disk.get(2, &vec![]).unwrap());
+        assert_eq!(None, disk.get(3, &vec!["c"]).unwrap());
+
+        let mut copy = MemoryStorage::new();
+        assert_eq!(2, migrate(&disk, &mut copy).unwrap());
+        assert_eq!(Some(token("at1")), copy.get(1, &vec!["a"]).unwrap());
+        match migrate(&NullStorage, &mut copy) {
+            Err(MigrationError::Unsupported) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "disk-storage")]
+    #[test]
+    fn test_shared_disk_storage() {
+        let path = std::env::temp_dir().join(format!("yup-oauth2-shared-{}.json", time::now()));
+        let path = path.to_str().unwrap().to_string();
+        let lock_path = format!("{}.lock", path);
+        let scopes = vec!["scope"];
+        let token = |at: &str, expires_in| {
+            Token::new(
+                at.to_string(),
+                "Bearer".to_string(),
+                Some("rt".to_string()),
+                Some(expires_in),
+            )
+        };
+
+        let mut first = DiskTokenStorage::new(&path).unwrap();
+        first.set(0, &scopes, Some(token("expired", 0))).unwrap();
+        let mut second = DiskTokenStorage::new(&path).unwrap();
+        second
+            .set(1, &vec!["other"], Some(token("other", 3600)))
+            .unwrap();
+
+        // The first process starts refreshing, and holds the lock until the token is stored.
+        match first.refresh_started(0, &scopes).unwrap() {
+            RefreshStart::Ready(Some(ref started)) => assert_eq!("expired", started.access_token),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(locked(&lock_path));
+        assert_eq!(
+            RefreshStart::Busy,
+            second.refresh_started(0, &scopes).unwrap()
+        );
+        // Changes don't wait for refreshes, nor release their lock.
+        second
+            .set(2, &vec!["third"], Some(token("third", 3600)))
+            .unwrap();
+        first.refresh_started(1, &vec!["other"]).unwrap();
+        first
+            .set(0, &scopes, Some(token("refreshed", 3600)))
+            .unwrap();
+        assert!(locked(&lock_path));
+        first.refresh_finished(0, &scopes).unwrap();
+        assert!(locked(&lock_path));
+        first.refresh_finished(1, &vec!["other"]).unwrap();
+        assert!(!locked(&lock_path));
+        assert!(!locked(&format!("{}.write.lock", path)));
+
+        // The second process finds the refreshed token, and releases the lock once done.
+        match second.refresh_started(0, &scopes).unwrap() {
+            RefreshStart::Ready(Some(ref current)) => {
+                assert_eq!("refreshed", current.access_token)
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(locked(&lock_path));
+        second.refresh_finished(0, &scopes).unwrap();
+        assert!(!locked(&lock_path));
+        // A refresh which is abandoned releases the lock as well.
+        first.refresh_started(0, &scopes).unwrap();
+        first.refresh_finished(0, &scopes).unwrap();
+        assert!(!locked(&lock_path));
+        // Neither process overwrote the other's tokens.
+        let third = DiskTokenStorage::new(&path).unwrap();
+        assert!(third.get(1, &vec!["other"]).unwrap().is_some());
+        assert!(third.get(2, &vec!["third"]).unwrap().is_some());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&lock_path).unwrap();
+        fs::remove_file(format!("{}.write.lock", path)).unwrap();
+    }
+
+    /// Whether another process holds the lock on the file at `path`.
+    #[cfg(feature = "disk-storage")]
+    fn locked(path: &str) -> bool {
+        FileLock::try_acquire(Path::new(path)).unwrap().is_none()
+    }
+
+    #[cfg(feature = "disk-storage")]
+    #[test]
+    fn test_left_behind_lock() {
+        let path = std::env::temp_dir().join(format!("yup-oauth2-left-{}.lock", time::now()));
+        // A lock file left behind by a crashed process doesn't keep the lock held.
+        fs::write(&path, "").unwrap();
+        // Of the threads contending for it at the same time, exactly one takes it.
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (path, barrier) = (path.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    let lock = FileLock::try_acquire(&path).unwrap();
+                    // Hold the lock until all threads tried to take it.
+                    barrier.wait();
+                    lock.is_some()
+                })
+            })
+            .collect();
+        let acquired = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .filter(|&acquired| acquired)
+            .count();
+        assert_eq!(1, acquired);
+        // Once released, the lock can be taken again.
+        assert!(FileLock::try_acquire(&path).unwrap().is_some());
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,930 @@
+//! `TokenStorage`s wrapping other storages, to compose caching, encryption and sharing of
+//! tokens without implementing a storage from scratch.
+//!
+//! For example, tokens kept in a shared database which only another process may update,
+//! encrypted with a key from the environment, and cached in memory:
+//!
+//! ```no_run
+//! # #[cfg(feature = "disk-storage")]
+//! # use yup_oauth2::{DiskTokenStorage, MemoryStorage, TokenStorage};
+//! # use yup_oauth2::{Cipher, EncryptedStorage, LayeredStorage, ReadOnlyStorage};
+//! # #[cfg(feature = "disk-storage")]
+//! # fn example<C: Cipher>(cipher: C) {
+//! let shared = DiskTokenStorage::new("/var/lib/tokens.json").unwrap();
+//! let storage = LayeredStorage::new(
+//!     MemoryStorage::new(),
+//!     EncryptedStorage::new(ReadOnlyStorage::new(shared), cipher),
+//! );
+//! # }
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::storage::{MemoryStorage, RefreshFailure, RefreshStart, StoredToken, TokenStorage};
+use crate::types::Token;
+
+/// An error of a storage combinator, wrapping the error of the underlying storage or cipher.
+#[derive(Debug)]
+pub struct StorageError(Box<dyn Error + Send + Sync>);
+
+impl StorageError {
+    fn new<E: 'static + Error + Send + Sync>(error: E) -> StorageError {
+        StorageError(Box::new(error))
+    }
+
+    /// Returns the underlying error.
+    pub fn into_inner(self) -> Box<dyn Error + Send + Sync> {
+        self.0
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+/// Encrypts the secrets stored by an `EncryptedStorage`.
+pub trait Cipher {
+    type Error: 'static + Error + Send + Sync;
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Self::Error>;
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Self::Error>;
+}
+
+/// Stores tokens in `S` with their access and refresh tokens encrypted by `C`; the token type
+/// and expiry are stored as they are.
+///
+/// Ciphers may be randomized, e.g. using a fresh nonce for each encryption. To invalidate an
+/// access token, `EncryptedStorage` therefore remembers the ciphertexts of the tokens it stored
+/// or returned.
+pub struct EncryptedStorage<S, C> {
+    inner: S,
+    cipher: C,
+    /// Ciphertexts of known access tokens, keyed by the access token. Entries are dropped once
+    /// their token is replaced or invalidated.
+    ciphertexts: Mutex<HashMap<String, String>>,
+}
+
+impl<S: TokenStorage, C: Cipher> EncryptedStorage<S, C> {
+    pub fn new(inner: S, cipher: C) -> EncryptedStorage<S, C> {
+        EncryptedStorage {
+            inner,
+            cipher,
+            ciphertexts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn encrypt(&self, plaintext: &str) -> Result<String, StorageError> {
+        let ciphertext = self
+            .cipher
+            .encrypt(plaintext.as_bytes())
+            .map_err(StorageError::new)?;
+        Ok(base64::encode_config(&ciphertext, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn decrypt(&self, ciphertext: &str) -> Result<String, StorageError> {
+        let ciphertext = base64::decode_config(ciphertext, base64::URL_SAFE_NO_PAD)
+            .map_err(StorageError::new)?;
+        let plaintext = self
+            .cipher
+            .decrypt(&ciphertext)
+            .map_err(StorageError::new)?;
+        String::from_utf8(plaintext).map_err(StorageError::new)
+    }
+
+    fn remember(&self, access_token: &str, ciphertext: &str) {
+        self.ciphertexts
+            .lock()
+            .unwrap()
+            .insert(access_token.to_string(), ciphertext.to_string());
+    }
+
+    /// Forgets the ciphertext of the token stored for the scopes, as it's about to be replaced.
+    fn forget(&self, scope_hash: u64, scopes: &Vec<&str>) {
+        if let Ok(Some(stored)) = self.inner.get(scope_hash, scopes) {
+            self.ciphertexts
+                .lock()
+                .unwrap()
+                .retain(|_, ciphertext| *ciphertext != stored.access_token);
+        }
+    }
+}
+
+impl<S: TokenStorage, C: Cipher> TokenStorage for EncryptedStorage<S, C> {
+    type Error = StorageError;
+
+    fn location(&self) -> Option<String> {
+        self.inner.location()
+    }
+
+    fn set(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        token: Option<Token>,
+    ) -> Result<(), StorageError> {
+        self.forget(scope_hash, scopes);
+        let token = match token {
+            Some(mut token) => {
+                let access_token = self.encrypt(&token.access_token)?;
+                self.remember(&token.access_token, &access_token);
+                token.access_token = access_token;
+                token.refresh_token = match token.refresh_token {
+                    Some(ref rt) => Some(self.encrypt(rt)?),
+                    None => None,
+                };
+                Some(token)
+            }
+            None => None,
+        };
+        self.inner
+            .set(scope_hash, scopes, token)
+            .map_err(StorageError::new)
+    }
+
+    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, StorageError> {
+        let mut token = match self
+            .inner
+            .get(scope_hash, scopes)
+            .map_err(StorageError::new)?
+        {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let ciphertext = token.access_token;
+        token.access_token = self.decrypt(&ciphertext)?;
+        self.remember(&token.access_token, &ciphertext);
+        token.refresh_token = match token.refresh_token {
+            Some(ref rt) => Some(self.decrypt(rt)?),
+            None => None,
+        };
+        Ok(Some(token))
+    }
+
+    fn record_refresh_failure(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        failure: RefreshFailure,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .record_refresh_failure(scope_hash, scopes, failure)
+            .map_err(StorageError::new)
+    }
+
+    fn refresh_failures(
+        &self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<Vec<RefreshFailure>, StorageError> {
+        self.inner
+            .refresh_failures(scope_hash, scopes)
+            .map_err(StorageError::new)
+    }
+
+    fn invalidate(&mut self, access_token: &str) -> Result<bool, StorageError> {
+        let ciphertext = self.ciphertexts.lock().unwrap().remove(access_token);
+        match ciphertext {
+            Some(ciphertext) => self
+                .inner
+                .invalidate(&ciphertext)
+                .map_err(StorageError::new),
+            None => Ok(false),
+        }
+    }
+
+    fn refresh_started(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<RefreshStart, StorageError> {
+        let started = self
+            .inner
+            .refresh_started(scope_hash, scopes)
+            .map_err(StorageError::new)?;
+        match started {
+            RefreshStart::Ready(Some(mut token)) => {
+                let ciphertext = token.access_token;
+                token.access_token = self.decrypt(&ciphertext)?;
+                self.remember(&token.access_token, &ciphertext);
+                token.refresh_token = match token.refresh_token {
+                    Some(ref rt) => Some(self.decrypt(rt)?),
+                    None => None,
+                };
+                Ok(RefreshStart::Ready(Some(token)))
+            }
+            started => Ok(started),
+        }
+    }
+
+    fn refresh_finished(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .refresh_finished(scope_hash, scopes)
+            .map_err(StorageError::new)
+    }
+
+    /// Lists the tokens of `S`, decrypted.
+    fn stored_tokens(&self) -> Result<Option<Vec<StoredToken>>, StorageError> {
+        let mut tokens = match self.inner.stored_tokens().map_err(StorageError::new)? {
+            Some(tokens) => tokens,
+            None => return Ok(None),
+        };
+        for stored in &mut tokens {
+            stored.token.access_token = self.decrypt(&stored.token.access_token)?;
+            stored.token.refresh_token = match stored.token.refresh_token {
+                Some(ref rt) => Some(self.decrypt(rt)?),
+                None => None,
+            };
+        }
+        Ok(Some(tokens))
+    }
+}
+
+/// Returns the tokens of `S`, but ignores all changes, e.g. for tokens maintained by another
+/// process.
+pub struct ReadOnlyStorage<S> {
+    inner: S,
+}
+
+impl<S: TokenStorage> ReadOnlyStorage<S> {
+    pub fn new(inner: S) -> ReadOnlyStorage<S> {
+        ReadOnlyStorage { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: TokenStorage> TokenStorage for ReadOnlyStorage<S> {
+    type Error = S::Error;
+
+    fn location(&self) -> Option<String> {
+        self.inner.location()
+    }
+
+    fn set(&mut self, _: u64, _: &Vec<&str>, _: Option<Token>) -> Result<(), S::Error> {
+        Ok(())
+    }
+
+    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, S::Error> {
+        self.inner.get(scope_hash, scopes)
+    }
+
+    fn refresh_failures(
+        &self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<Vec<RefreshFailure>, S::Error> {
+        self.inner.refresh_failures(scope_hash, scopes)
+    }
+
+    fn stored_tokens(&self) -> Result<Option<Vec<StoredToken>>, S::Error> {
+        self.inner.stored_tokens()
+    }
+}
+
+/// Looks up tokens in the storage `A` first, and then in `B`, e.g. a `MemoryStorage` in front
+/// of a slower, shared storage. Tokens found in `B` are copied to `A`; changes are written to
+/// `B` first, then `A`.
+pub struct LayeredStorage<A, B> {
+    front: Mutex<A>,
+    back: B,
+}
+
+impl<A: TokenStorage, B: TokenStorage> LayeredStorage<A, B> {
+    pub fn new(front: A, back: B) -> LayeredStorage<A, B> {
+        LayeredStorage {
+            front: Mutex::new(front),
+            back,
+        }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.front.into_inner().unwrap(), self.back)
+    }
+}
+
+impl<A: TokenStorage, B: TokenStorage> TokenStorage for LayeredStorage<A, B> {
+    type Error = StorageError;
+
+    /// Returns the location of `B`, or if it has none, that of `A`.
+    fn location(&self) -> Option<String> {
+        self.back
+            .location()
+            .or_else(|| self.front.lock().unwrap().location())
+    }
+
+    fn set(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        token: Option<Token>,
+    ) -> Result<(), StorageError> {
+        self.back
+            .set(scope_hash, scopes, token.clone())
+            .map_err(StorageError::new)?;
+        self.front
+            .get_mut()
+            .unwrap()
+            .set(scope_hash, scopes, token)
+            .map_err(StorageError::new)
+    }
+
+    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, StorageError> {
+        let mut front = self.front.lock().unwrap();
+        if let Some(token) = front.get(scope_hash, scopes).map_err(StorageError::new)? {
+            return Ok(Some(token));
+        }
+        let token = self
+            .back
+            .get(scope_hash, scopes)
+            .map_err(StorageError::new)?;
+        if token.is_some() {
+            front
+                .set(scope_hash, scopes, token.clone())
+                .map_err(StorageError::new)?;
+        }
+        Ok(token)
+    }
+
+    fn record_refresh_failure(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        failure: RefreshFailure,
+    ) -> Result<(), StorageError> {
+        self.back
+            .record_refresh_failure(scope_hash, scopes, failure.clone())
+            .map_err(StorageError::new)?;
+        self.front
+            .get_mut()
+            .unwrap()
+            .record_refresh_failure(scope_hash, scopes, failure)
+            .map_err(StorageError::new)
+    }
+
+    /// Returns the failures recorded by `B`, or if there are none, e.g. because `B` doesn't
+    /// record failures, those recorded by `A`.
+    fn refresh_failures(
+        &self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<Vec<RefreshFailure>, StorageError> {
+        let failures = self
+            .back
+            .refresh_failures(scope_hash, scopes)
+            .map_err(StorageError::new)?;
+        if !failures.is_empty() {
+            return Ok(failures);
+        }
+        self.front
+            .lock()
+            .unwrap()
+            .refresh_failures(scope_hash, scopes)
+            .map_err(StorageError::new)
+    }
+
+    fn invalidate(&mut self, access_token: &str) -> Result<bool, StorageError> {
+        let back = self
+            .back
+            .invalidate(access_token)
+            .map_err(StorageError::new)?;
+        let front = self
+            .front
+            .get_mut()
+            .unwrap()
+            .invalidate(access_token)
+            .map_err(StorageError::new)?;
+        Ok(back || front)
+    }
+
+    /// Returns the token of `B`, which is copied to `A`.
+    fn refresh_started(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<RefreshStart, StorageError> {
+        let started = self
+            .back
+            .refresh_started(scope_hash, scopes)
+            .map_err(StorageError::new)?;
+        if let RefreshStart::Ready(Some(ref token)) = started {
+            self.front
+                .get_mut()
+                .unwrap()
+                .set(scope_hash, scopes, Some(token.clone()))
+                .map_err(StorageError::new)?;
+        }
+        Ok(started)
+    }
+
+    fn refresh_finished(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<(), StorageError> {
+        self.back
+            .refresh_finished(scope_hash, scopes)
+            .map_err(StorageError::new)
+    }
+
+    /// Lists the tokens of `B`, and those of `A` which `B` doesn't have, provided both can list
+    /// their tokens.
+    fn stored_tokens(&self) -> Result<Option<Vec<StoredToken>>, StorageError> {
+        let back = self.back.stored_tokens().map_err(StorageError::new)?;
+        let front = self
+            .front
+            .lock()
+            .unwrap()
+            .stored_tokens()
+            .map_err(StorageError::new)?;
+        let (mut tokens, front) = match (back, front) {
+            (Some(back), Some(front)) => (back, front),
+            _ => return Ok(None),
+        };
+        for stored in front {
+            if !tokens.iter().any(|t| t.scope_hash == stored.scope_hash) {
+                tokens.push(stored);
+            }
+        }
+        Ok(Some(tokens))
+    }
+}
+
+/// Partitions the tokens of `S` by a named profile, like the profiles of the AWS CLI, so that
+/// one storage can hold separate credentials, e.g. of a `work` and a `personal` account, for
+/// the same application. See `Authenticator::profile()`.
+///
+/// The tokens of a profile are stored in `S` with each scope prefixed by the profile name and a
+/// space, which never occurs in scopes, and under a scope hash derived from the profile name.
+/// The profile with the empty name is the default one, whose tokens are stored as they are.
+pub struct ProfileStorage<S> {
+    inner: S,
+    profile: Option<String>,
+}
+
+impl<S: TokenStorage> ProfileStorage<S> {
+    pub fn new<P: Into<String>>(inner: S, profile: P) -> ProfileStorage<S> {
+        let profile = profile.into();
+        ProfileStorage {
+            inner,
+            profile: if profile.is_empty() {
+                None
+            } else {
+                Some(profile)
+            },
+        }
+    }
+
+    /// The name of the profile, or `None` for the default profile.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// The scope hash of the profile's token for `scope_hash`: the 64 bit FNV-1a hash of the
+    /// little-endian bytes of `scope_hash`, a 0xff byte and the profile name.
+    fn scope_hash(&self, scope_hash: u64) -> u64 {
+        let profile = match self.profile {
+            Some(ref profile) => profile,
+            None => return scope_hash,
+        };
+        let mut bytes = scope_hash.to_le_bytes().to_vec();
+        bytes.push(0xff);
+        bytes.extend_from_slice(profile.as_bytes());
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    fn scopes(&self, scopes: &[&str]) -> Vec<String> {
+        match self.profile {
+            Some(ref profile) => scopes
+                .iter()
+                .map(|s| format!("{} {}", profile, s))
+                .collect(),
+            None => scopes.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Calls `f` with the scope hash and scopes of the profile's token for `scope_hash` and
+    /// `scopes`.
+    fn with_key<F, R>(&self, scope_hash: u64, scopes: &[&str], f: F) -> R
+    where
+        F: FnOnce(u64, &Vec<&str>) -> R,
+    {
+        let scopes = self.scopes(scopes);
+        f(
+            self.scope_hash(scope_hash),
+            &scopes.iter().map(|s| s.as_str()).collect(),
+        )
+    }
+
+    /// The scopes of a stored token without the profile prefix, or `None` if the token belongs
+    /// to another profile.
+    fn own_scopes(&self, stored: &[String]) -> Option<Vec<String>> {
+        match self.profile {
+            Some(ref profile) => {
+                let prefix = format!("{} ", profile);
+                stored
+                    .iter()
+                    .map(|s| s.strip_prefix(&prefix).map(String::from))
+                    .collect()
+            }
+            None if stored.iter().any(|s| s.contains(' ')) => None,
+            None => Some(stored.to_vec()),
+        }
+    }
+}
+
+impl<S: TokenStorage> TokenStorage for ProfileStorage<S> {
+    type Error = S::Error;
+
+    /// Returns the location of `S`, naming the profile.
+    fn location(&self) -> Option<String> {
+        let location = self.inner.location()?;
+        Some(match self.profile {
+            Some(ref profile) => format!("{} (profile {})", location, profile),
+            None => location,
+        })
+    }
+
+    fn set(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        token: Option<Token>,
+    ) -> Result<(), S::Error> {
+        let scope_hash = self.scope_hash(scope_hash);
+        let scopes = self.scopes(scopes);
+        self.inner.set(
+            scope_hash,
+            &scopes.iter().map(|s| s.as_str()).collect(),
+            token,
+        )
+    }
+
+    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, S::Error> {
+        self.with_key(scope_hash, scopes, |hash, scopes| {
+            self.inner.get(hash, scopes)
+        })
+    }
+
+    fn record_refresh_failure(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        failure: RefreshFailure,
+    ) -> Result<(), S::Error> {
+        let scope_hash = self.scope_hash(scope_hash);
+        let scopes = self.scopes(scopes);
+        self.inner.record_refresh_failure(
+            scope_hash,
+            &scopes.iter().map(|s| s.as_str()).collect(),
+            failure,
+        )
+    }
+
+    fn refresh_failures(
+        &self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<Vec<RefreshFailure>, S::Error> {
+        self.with_key(scope_hash, scopes, |hash, scopes| {
+            self.inner.refresh_failures(hash, scopes)
+        })
+    }
+
+    fn invalidate(&mut self, access_token: &str) -> Result<bool, S::Error> {
+        self.inner.invalidate(access_token)
+    }
+
+    fn refresh_started(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<RefreshStart, S::Error> {
+        let scope_hash = self.scope_hash(scope_hash);
+        let scopes = self.scopes(scopes);
+        self.inner
+            .refresh_started(scope_hash, &scopes.iter().map(|s| s.as_str()).collect())
+    }
+
+    fn refresh_finished(&mut self, scope_hash: u64, scopes: &Vec<&str>) -> Result<(), S::Error> {
+        let scope_hash = self.scope_hash(scope_hash);
+        let scopes = self.scopes(scopes);
+        self.inner
+            .refresh_finished(scope_hash, &scopes.iter().map(|s| s.as_str()).collect())
+    }
+
+    /// Lists the tokens of the profile, with their scopes as requested. Tokens stored without
+    /// scopes, by old versions of this crate, belong to the default profile.
+    fn stored_tokens(&self) -> Result<Option<Vec<StoredToken>>, S::Error> {
+        Ok(self.inner.stored_tokens()?.map(|tokens| {
+            tokens
+                .into_iter()
+                .filter_map(|stored| {
+                    if stored.scopes.is_empty() && self.profile.is_some() {
+                        return None;
+                    }
+                    let scopes = self.own_scopes(&stored.scopes)?;
+                    Some(StoredToken { scopes, ..stored })
+                })
+                .collect()
+        }))
+    }
+}
+
+/// Caches the tokens of a slower storage `S` in memory, e.g. of a keyring or a database, so that
+/// only the first lookup of a token reaches `S`. Changes, like refreshed tokens, are written
+/// through to `S`. Tokens missing from `S` are remembered as missing until they are set.
+///
+/// This is a `LayeredStorage` with a `MemoryStorage` in front.
+pub struct CachedStorage<S> {
+    inner: LayeredStorage<MemoryStorage, S>,
+    /// The scope hashes for which `S` had no token.
+    misses: Mutex<HashSet<u64>>,
+}
+
+impl<S: TokenStorage> CachedStorage<S> {
+    pub fn new(backend: S) -> CachedStorage<S> {
+        CachedStorage {
+            inner: LayeredStorage::new(MemoryStorage::new(), backend),
+            misses: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner().1
+    }
+}
+
+impl<S: TokenStorage> TokenStorage for CachedStorage<S> {
+    type Error = StorageError;
+
+    fn location(&self) -> Option<String> {
+        self.inner.location()
+    }
+
+    fn set(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        token: Option<Token>,
+    ) -> Result<(), StorageError> {
+        self.misses.lock().unwrap().remove(&scope_hash);
+        self.inner.set(scope_hash, scopes, token)
+    }
+
+    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, StorageError> {
+        if self.misses.lock().unwrap().contains(&scope_hash) {
+            return Ok(None);
+        }
+        let token = self.inner.get(scope_hash, scopes)?;
+        if token.is_none() {
+            self.misses.lock().unwrap().insert(scope_hash);
+        }
+        Ok(token)
+    }
+
+    fn record_refresh_failure(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        failure: RefreshFailure,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .record_refresh_failure(scope_hash, scopes, failure)
+    }
+
+    fn refresh_failures(
+        &self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<Vec<RefreshFailure>, StorageError> {
+        self.inner.refresh_failures(scope_hash, scopes)
+    }
+
+    fn invalidate(&mut self, access_token: &str) -> Result<bool, StorageError> {
+        self.inner.invalidate(access_token)
+    }
+
+    fn refresh_started(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<RefreshStart, StorageError> {
+        self.inner.refresh_started(scope_hash, scopes)
+    }
+
+    fn refresh_finished(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+    ) -> Result<(), StorageError> {
+        self.inner.refresh_finished(scope_hash, scopes)
+    }
+
+    fn stored_tokens(&self) -> Result<Option<Vec<StoredToken>>, StorageError> {
+        self.inner.stored_tokens()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{NullError, RefreshFailureKind};
+    use std::cell::Cell;
+
+    /// Not a cipher to use, but randomized like real ones: it prepends a counter as nonce.
+    struct XorCipher(Mutex<u8>);
+
+    impl Cipher for XorCipher {
+        type Error = NullError;
+
+        fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, NullError> {
+            let mut nonce = self.0.lock().unwrap();
+            *nonce += 1;
+            let mut ciphertext = vec![*nonce];
+            ciphertext.extend(plaintext.iter().map(|b| b ^ *nonce));
+            Ok(ciphertext)
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, NullError> {
+            match ciphertext.split_first() {
+                Some((nonce, rest)) => Ok(rest.iter().map(|b| b ^ nonce).collect()),
+                None => Err(NullError),
+            }
+        }
+    }
+
+    fn token(access_token: &str) -> Token {
+        Token::new(
+            access_token.to_string(),
+            "Bearer".to_string(),
+            Some("rt".to_string()),
+            Some(3600),
+        )
+    }
+
+    #[test]
+    fn test_encrypted_storage() {
+        let scopes = vec!["a"];
+        let secret = token("secret");
+        let mut storage = EncryptedStorage::new(MemoryStorage::new(), XorCipher(Mutex::new(0)));
+        storage.set(1, &scopes, Some(secret.clone())).unwrap();
+        assert_eq!(Some(secret.clone()), storage.get(1, &scopes).unwrap());
+
+        let inner = storage.into_inner();
+        let stored = inner.get(1, &scopes).unwrap().unwrap();
+        assert!(!stored.access_token.contains("secret"));
+        assert_ne!(Some("rt".to_string()), stored.refresh_token);
+        assert_eq!(secret.expires_at(), stored.expires_at());
+
+        // Tokens stored earlier can be invalidated once they were read.
+        let mut storage = EncryptedStorage::new(inner, XorCipher(Mutex::new(7)));
+        assert!(!storage.invalidate("secret").unwrap());
+        storage.get(1, &scopes).unwrap();
+        assert!(storage.invalidate("secret").unwrap());
+        assert!(storage.ciphertexts.lock().unwrap().is_empty());
+        assert!(storage.get(1, &scopes).unwrap().unwrap().expired());
+
+        // Replacing or removing a token forgets its ciphertext.
+        storage.set(1, &scopes, Some(token("first"))).unwrap();
+        storage.set(1, &scopes, Some(secret.clone())).unwrap();
+        assert_eq!(
+            vec!["secret"],
+            storage
+                .ciphertexts
+                .lock()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>()
+        );
+        storage.set(1, &scopes, None).unwrap();
+        assert!(storage.ciphertexts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_layered_storage() {
+        let scopes = vec!["a"];
+        let (shared, local) = (token("shared"), token("local"));
+        let mut back = MemoryStorage::new();
+        back.set(1, &scopes, Some(shared.clone())).unwrap();
+        let mut storage = LayeredStorage::new(MemoryStorage::new(), ReadOnlyStorage::new(back));
+        assert_eq!(Some(shared.clone()), storage.get(1, &scopes).unwrap());
+
+        // Changes only reach the front, as the back is read-only.
+        storage.set(2, &vec!["b"], Some(local.clone())).unwrap();
+        let failure = RefreshFailure::new(RefreshFailureKind::Transport, "timeout");
+        storage.record_refresh_failure(1, &scopes, failure).unwrap();
+        assert_eq!(1, storage.refresh_failures(1, &scopes).unwrap().len());
+        assert!(storage.invalidate("shared").unwrap());
+
+        let (front, back) = storage.into_inner();
+        assert!(front.get(1, &scopes).unwrap().unwrap().expired());
+        assert_eq!(Some(local), front.get(2, &vec!["b"]).unwrap());
+        assert_eq!(Some(shared), back.get(1, &scopes).unwrap());
+        assert_eq!(None, back.get(2, &vec!["b"]).unwrap());
+    }
+
+    /// Counts the lookups reaching a `MemoryStorage`.
+    struct CountingStorage(MemoryStorage, Cell<usize>);
+
+    impl TokenStorage for CountingStorage {
+        type Error = NullError;
+
+        fn set(
+            &mut self,
+            hash: u64,
+            scopes: &Vec<&str>,
+            t: Option<Token>,
+        ) -> Result<(), NullError> {
+            self.0.set(hash, scopes, t)
+        }
+
+        fn get(&self, hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, NullError> {
+            self.1.set(self.1.get() + 1);
+            self.0.get(hash, scopes)
+        }
+    }
+
+    #[test]
+    fn test_profile_storage() {
+        let scopes = vec!["email", "openid"];
+        let mut storage = MemoryStorage::new();
+        for profile in &["work", "personal", ""] {
+            let mut profiled = ProfileStorage::new(storage, *profile);
+            assert_eq!(None, profiled.get(1, &scopes).unwrap());
+            profiled.set(1, &scopes, Some(token(profile))).unwrap();
+            storage = profiled.into_inner();
+        }
+        assert_eq!(3, storage.stored_tokens().unwrap().unwrap().len());
+        for profile in &["work", "personal", ""] {
+            let profiled = ProfileStorage::new(storage, *profile);
+            let found = profiled.get(1, &vec!["email"]).unwrap().unwrap();
+            assert_eq!(*profile, found.access_token);
+            let stored = profiled.stored_tokens().unwrap().unwrap();
+            assert_eq!(1, stored.len());
+            assert_eq!(vec!["email", "openid"], stored[0].scopes);
+            storage = profiled.into_inner();
+        }
+
+        // Without scopes, tokens are told apart by the scope hash.
+        let mut work = ProfileStorage::new(storage, "work");
+        assert_eq!(Some("work"), work.profile());
+        work.set(2, &vec![], Some(token("work"))).unwrap();
+        assert_eq!("work", work.get(2, &vec![]).unwrap().unwrap().access_token);
+        work.set(1, &scopes, None).unwrap();
+        assert_eq!(None, work.get(1, &scopes).unwrap());
+        let personal = ProfileStorage::new(work.into_inner(), "personal");
+        assert_eq!(Some("personal"), personal.profile());
+        assert_eq!(None, personal.get(2, &vec![]).unwrap());
+        assert!(personal.get(1, &scopes).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_cached_storage() {
+        let scopes = vec!["a"];
+        let (stored, refreshed) = (token("stored"), token("refreshed"));
+        let mut backend = MemoryStorage::new();
+        backend.set(1, &scopes, Some(stored.clone())).unwrap();
+        let mut storage = CachedStorage::new(CountingStorage(backend, Cell::new(0)));
+
+        for _ in 0..3 {
+            assert_eq!(Some(stored.clone()), storage.get(1, &scopes).unwrap());
+        }
+        for _ in 0..3 {
+            assert_eq!(None, storage.get(2, &vec!["b"]).unwrap());
+        }
+        storage.set(1, &scopes, Some(refreshed.clone())).unwrap();
+        assert_eq!(Some(refreshed.clone()), storage.get(1, &scopes).unwrap());
+        storage.set(2, &vec!["b"], Some(stored.clone())).unwrap();
+        assert_eq!(Some(stored), storage.get(2, &vec!["b"]).unwrap());
+
+        let backend = storage.into_inner();
+        assert_eq!(2, backend.1.get());
+        assert_eq!(Some(refreshed), backend.0.get(1, &scopes).unwrap());
+    }
+}
@@ -0,0 +1,118 @@
+//! The file formats `DiskTokenStorage` writes: JSON, TOML for files people edit by hand, and
+//! CBOR (RFC 8949) for compact ones. The formats are converted from and to `serde_json::Value`,
+//! so that a format only needs to map values.
+use std::io;
+
+use serde_json::Value;
+
+/// The tag marking self-described CBOR (RFC 8949, section 3.4.6), written at the start of CBOR
+/// files so that they are told from text.
+const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// How `DiskTokenStorage` serializes tokens, see `DiskTokenStorage::format()`. Files are read
+/// in whatever format they are in, so that the format may be changed for existing files.
+///
+/// TOML and CBOR need the `toml` and `serde_cbor` features.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageFormat {
+    /// JSON, as written by earlier versions. (default)
+    Json,
+    /// TOML. As TOML integers are signed 64-bit numbers, larger ones are written as strings,
+    /// and as TOML lacks null, null values are left out.
+    #[cfg(feature = "toml")]
+    Toml,
+    /// CBOR, tagged as self-described CBOR.
+    #[cfg(feature = "serde_cbor")]
+    Cbor,
+}
+
+// Not derived, as `#[default]` needs Rust 1.62.
+#[allow(clippy::derivable_impls)]
+impl Default for StorageFormat {
+    fn default() -> StorageFormat {
+        StorageFormat::Json
+    }
+}
+
+impl StorageFormat {
+    /// Returns the format of `contents`: CBOR if it starts like a CBOR map, JSON if it starts
+    /// with `{`, and TOML otherwise. Returns `None` if that format isn't enabled.
+    pub fn detect(contents: &[u8]) -> Option<StorageFormat> {
+        match contents.first() {
+            Some(&b) if contents.starts_with(&CBOR_MAGIC) || b >> 5 == 5 => {
+                #[cfg(feature = "serde_cbor")]
+                return Some(StorageFormat::Cbor);
+                #[cfg(not(feature = "serde_cbor"))]
+                return None;
+            }
+            _ => match contents.iter().find(|b| !b.is_ascii_whitespace()) {
+                Some(b'{') | None => Some(StorageFormat::Json),
+                Some(_) => {
+                    #[cfg(feature = "toml")]
+                    return Some(StorageFormat::Toml);
+                    #[cfg(not(feature = "toml"))]
+                    return None;
+                }
+            },
+        }
+    }
+
+    pub(crate) fn serialize(self, value: &Value) -> io::Result<Vec<u8>> {
+        match self {
+            StorageFormat::Json => serde_json::to_vec(value).map_err(invalid_data),
+            #[cfg(feature = "toml")]
+            StorageFormat::Toml => match to_toml(value) {
+                Some(table @ toml::Value::Table(_)) => toml::to_vec(&table).map_err(invalid_data),
+                _ => Err(invalid_data("only tables can be written as TOML")),
+            },
+            #[cfg(feature = "serde_cbor")]
+            StorageFormat::Cbor => {
+                let mut out = Vec::new();
+                let mut serializer = serde_cbor::Serializer::new(&mut out);
+                serializer.self_describe().map_err(invalid_data)?;
+                serde::Serialize::serialize(value, &mut serializer).map_err(invalid_data)?;
+                Ok(out)
+            }
+        }
+    }
+
+    /// Parses `contents`, in the format detected.
+    pub(crate) fn deserialize(contents: &[u8]) -> io::Result<Value> {
+        match StorageFormat::detect(contents) {
+            Some(StorageFormat::Json) => serde_json::from_slice(contents).map_err(invalid_data),
+            #[cfg(feature = "toml")]
+            Some(StorageFormat::Toml) => toml::from_slice(contents).map_err(invalid_data),
+            #[cfg(feature = "serde_cbor")]
+            Some(StorageFormat::Cbor) => serde_cbor::from_slice(contents).map_err(invalid_data),
+            None => Err(invalid_data(
+                "the token file is in TOML or CBOR, which need the `toml` or `serde_cbor` feature",
+            )),
+        }
+    }
+}
+
+/// Converts `value` to TOML, leaving out null, and writing integers beyond the range of TOML
+/// integers as strings.
+#[cfg(feature = "toml")]
+fn to_toml(value: &Value) -> Option<toml::Value> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Bool(b) => toml::Value::Boolean(*b),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(n), _) => toml::Value::Integer(n),
+            (None, Some(n)) => toml::Value::String(n.to_string()),
+            (None, None) => toml::Value::Float(n.as_f64()?),
+        },
+        Value::String(s) => toml::Value::String(s.clone()),
+        Value::Array(values) => toml::Value::Array(values.iter().filter_map(to_toml).collect()),
+        Value::Object(map) => toml::Value::Table(
+            map.iter()
+                .filter_map(|(k, v)| Some((k.clone(), to_toml(v)?)))
+                .collect(),
+        ),
+    })
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,313 @@
+//! Points in time, as exposed in the public API.
+//!
+//! Internally, timestamps are seconds since the epoch. With the default `chrono` feature they are
+//! exposed as `chrono::DateTime<Utc>`; without it, as `std::time::SystemTime`, which removes
+//! chrono from the dependency tree.
+use std::fmt;
+#[cfg(any(feature = "device", feature = "installed"))]
+use std::time::Duration;
+#[cfg(feature = "device")]
+use std::time::Instant;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(any(feature = "device", feature = "installed"))]
+use futures::{future, Future};
+
+#[cfg(any(feature = "device", feature = "installed"))]
+use crate::types::RequestError;
+
+/// A point in time.
+#[cfg(feature = "chrono")]
+pub type Timestamp = chrono::DateTime<chrono::Utc>;
+/// A point in time.
+#[cfg(not(feature = "chrono"))]
+pub type Timestamp = SystemTime;
+
+/// The range of seconds since the epoch representable as a `Timestamp`, from the year 0 to the end
+/// of the year 9999. Timestamps received from providers are clamped to it.
+const MIN_SECS: i64 = -62_167_219_200;
+const MAX_SECS: i64 = 253_402_300_799;
+
+/// Returns the current time in seconds since the epoch.
+pub(crate) fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// A point in time on the monotonic clock, used to schedule polling. Unlike timestamps, it isn't
+/// moved by adjustments of the system clock.
+#[cfg(feature = "device")]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline(Instant);
+
+#[cfg(feature = "device")]
+impl Deadline {
+    /// The deadline `duration` from now, or in a hundred years if `duration` is longer than that.
+    pub(crate) fn after(duration: Duration) -> Deadline {
+        let now = Instant::now();
+        Deadline(
+            now.checked_add(duration.min(Duration::from_secs(100 * 365 * 24 * 3600)))
+                .unwrap_or(now),
+        )
+    }
+
+    /// The deadline at `secs` since the epoch, as the system clock tells now.
+    pub(crate) fn at_secs(secs: i64) -> Deadline {
+        let remaining = secs.saturating_sub(now()).max(0) as u64;
+        Deadline::after(Duration::from_secs(remaining))
+    }
+
+    pub(crate) fn passed(&self) -> bool {
+        Instant::now() >= self.0
+    }
+}
+
+/// Fails `future` with `RequestError::TimedOut` unless it finishes within `deadline`, if set.
+/// The future is dropped then, which stops waiting for the user, polling or exchanging codes.
+#[cfg(any(feature = "device", feature = "installed"))]
+pub(crate) fn time_boxed<F>(
+    future: F,
+    deadline: Option<Duration>,
+) -> impl Future<Item = F::Item, Error = RequestError>
+where
+    F: Future<Error = RequestError>,
+{
+    match deadline {
+        None => future::Either::A(future),
+        Some(deadline) => future::Either::B(tokio_timer::Timeout::new(future, deadline).map_err(
+            move |e| {
+                if e.is_elapsed() {
+                    return RequestError::TimedOut(deadline);
+                }
+                match e.into_inner() {
+                    Some(e) => e,
+                    None => RequestError::LowLevelError(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "the timer enforcing the deadline failed",
+                    )),
+                }
+            },
+        )),
+    }
+}
+
+#[cfg(feature = "chrono")]
+pub(crate) fn from_secs(secs: i64) -> Timestamp {
+    use chrono::TimeZone;
+    chrono::Utc
+        .timestamp_opt(secs.clamp(MIN_SECS, MAX_SECS), 0)
+        .single()
+        .unwrap_or_else(|| chrono::Utc.timestamp_opt(0, 0).unwrap())
+}
+
+#[cfg(not(feature = "chrono"))]
+pub(crate) fn from_secs(secs: i64) -> Timestamp {
+    system_time_from_secs(secs.clamp(MIN_SECS, MAX_SECS))
+}
+
+#[cfg(feature = "chrono")]
+pub(crate) fn to_secs(t: &Timestamp) -> i64 {
+    t.timestamp()
+}
+
+#[cfg(not(feature = "chrono"))]
+pub(crate) fn to_secs(t: &Timestamp) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+#[cfg(any(not(feature = "chrono"), test))]
+fn system_time_from_secs(secs: i64) -> SystemTime {
+    use std::time::Duration;
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+/// Formats a timestamp for humans, in local time if chrono is available and in UTC otherwise.
+pub(crate) fn display(t: &Timestamp) -> impl fmt::Display {
+    Display(to_secs(t))
+}
+
+struct Display(i64);
+
+impl fmt::Display for Display {
+    #[cfg(feature = "chrono")]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        from_secs(self.0).with_timezone(&chrono::Local).fmt(f)
+    }
+
+    #[cfg(not(feature = "chrono"))]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        format_utc(self.0, f)
+    }
+}
+
+/// Formats `secs` since the epoch like `2019-06-30 14:05:09 UTC`.
+#[cfg(any(not(feature = "chrono"), test))]
+fn format_utc(secs: i64, f: &mut fmt::Formatter) -> fmt::Result {
+    let (year, month, day, hour, minute, second) = utc_fields(secs);
+    write!(
+        f,
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Splits `secs` since the epoch into year, month, day, hour, minute and second in UTC. The date
+/// is computed using the algorithm from
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+#[cfg(any(not(feature = "chrono"), feature = "external-account", test))]
+pub(crate) fn utc_fields(secs: i64) -> (i64, i64, i64, i64, i64, i64) {
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+    )
+}
+
+/// Parses an RFC 3339 timestamp like `2019-06-30T14:05:09.123Z` into seconds since the epoch,
+/// dropping fractional seconds. Timestamps without an offset, as written by Python's
+/// `datetime.isoformat()`, are taken to be in UTC.
+pub(crate) fn parse_rfc3339(s: &str) -> Option<i64> {
+    fn fields(s: &str, sep: char) -> Option<Vec<i64>> {
+        s.split(sep)
+            .map(|f| {
+                if f.is_empty() || !f.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                f.parse().ok()
+            })
+            .collect()
+    }
+    let (date, time) = (s.get(..10)?, s.get(11..)?);
+    if !matches!(s.as_bytes()[10], b'T' | b't' | b' ') {
+        return None;
+    }
+    let (year, month, day) = match fields(date, '-')?[..] {
+        [year, month, day] if (1..=12).contains(&month) && (1..=31).contains(&day) => {
+            (year, month, day)
+        }
+        _ => return None,
+    };
+    let secs_of_day = match fields(time.get(..8)?, ':')?[..] {
+        [hour, minute, second] if hour < 24 && minute < 60 && second <= 60 => {
+            hour * 3600 + minute * 60 + second
+        }
+        _ => return None,
+    };
+    let mut rest = &time[8..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        rest = fraction.trim_start_matches(|c: char| c.is_ascii_digit());
+    }
+    let offset = match rest {
+        "" | "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes()[0] {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            match fields(&rest[1..], ':')?[..] {
+                [hours, minutes] if hours < 24 && minutes < 60 => {
+                    sign * (hours * 3600 + minutes * 60)
+                }
+                _ => return None,
+            }
+        }
+    };
+    Some(days_from_civil(year, month, day) * 86400 + secs_of_day - offset)
+}
+
+/// The inverse of the date computation in `utc_fields()`: the number of days between the epoch
+/// and the given date, see
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    struct Utc(i64);
+
+    impl fmt::Display for Utc {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            format_utc(self.0, f)
+        }
+    }
+
+    #[test]
+    fn test_timestamp_roundtrip() {
+        let now = now();
+        assert_eq!(now, to_secs(&from_secs(now)));
+        assert_eq!(-86400, to_secs(&from_secs(-86400)));
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_561_903_509),
+            system_time_from_secs(1_561_903_509)
+        );
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!("1970-01-01 00:00:00 UTC", Utc(0).to_string());
+        assert_eq!("2019-06-30 14:05:09 UTC", Utc(1_561_903_509).to_string());
+        assert_eq!("2000-02-29 23:59:59 UTC", Utc(951_868_799).to_string());
+        assert_eq!("1969-12-31 23:59:59 UTC", Utc(-1).to_string());
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(Some(0), parse_rfc3339("1970-01-01T00:00:00Z"));
+        assert_eq!(Some(1_561_903_509), parse_rfc3339("2019-06-30T14:05:09Z"));
+        assert_eq!(
+            Some(1_561_903_509),
+            parse_rfc3339("2019-06-30T14:05:09.123456")
+        );
+        assert_eq!(
+            Some(1_561_903_509),
+            parse_rfc3339("2019-06-30T16:05:09+02:00")
+        );
+        assert_eq!(Some(951_868_799), parse_rfc3339("2000-02-29 23:59:59z"));
+        assert_eq!(Some(-1), parse_rfc3339("1969-12-31T23:59:59Z"));
+        assert_eq!(None, parse_rfc3339("2019-06-30"));
+        assert_eq!(None, parse_rfc3339("2019-13-30T14:05:09Z"));
+        assert_eq!(None, parse_rfc3339("2019-06-30T14:05:09 UTC"));
+    }
+
+    #[cfg(feature = "device")]
+    #[test]
+    fn test_deadline() {
+        assert!(Deadline::at_secs(now() - 10).passed());
+        assert!(Deadline::after(Duration::from_secs(0)).passed());
+        assert!(!Deadline::after(Duration::from_secs(60)).passed());
+        assert!(!Deadline::at_secs(now() + 60).passed());
+    }
+}
@@ -0,0 +1,150 @@
+//! The cache of the Google Cloud token sources which reissue tokens rather than refreshing them,
+//! like the metadata server or service accounts. These sources have no refresh token, and hence
+//! return an empty `ApplicationSecret`.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{future, prelude::*};
+
+use crate::storage::{hash_scopes, MemoryStorage, TokenStorage};
+use crate::types::{check_valid_for, RequestError, Token};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = RequestError> + Send>;
+
+/// Access tokens by scopes, and ID tokens by audience, kept until they expire. Clones share the
+/// cache. Workload identity federation has no ID tokens, so they are left out if it's the only
+/// source built.
+#[derive(Clone, Default)]
+pub(crate) struct TokenCache {
+    tokens: Arc<Mutex<MemoryStorage>>,
+    #[cfg(any(
+        feature = "impersonated-service-account",
+        feature = "metadata-server",
+        feature = "service-account"
+    ))]
+    id_tokens: Arc<Mutex<std::collections::HashMap<String, Token>>>,
+}
+
+impl TokenCache {
+    pub(crate) fn new() -> TokenCache {
+        TokenCache::default()
+    }
+
+    /// Returns the cached token for `scopes`, with `hash` from `hash_scopes()`, unless it
+    /// expired or expires within `margin`.
+    pub(crate) fn cached_for(
+        &self,
+        hash: u64,
+        scopes: &[String],
+        margin: Duration,
+    ) -> Option<Token> {
+        let scope_refs = scopes.iter().map(|s| s.as_str()).collect();
+        match self.tokens.lock().unwrap().get(hash, &scope_refs) {
+            Ok(Some(token)) if !token.expired() && !token.expires_within(margin) => Some(token),
+            _ => None,
+        }
+    }
+
+    /// Returns the cached token for `scopes`, or else the one `fetch` obtains for the sorted
+    /// `scopes`, and caches it.
+    pub(crate) fn token<I, T, F, R>(&self, scopes: I, fetch: F) -> BoxFuture<Token>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+        F: FnOnce(Vec<String>) -> R,
+        R: 'static + Future<Item = Token, Error = RequestError> + Send,
+    {
+        self.token_for(Duration::from_secs(0), scopes, fetch)
+    }
+
+    /// Like `token()`, but fetches a new token unless the cached one remains valid for
+    /// `duration`, see `GetToken::token_valid_for()`. Fails with `RequestError::UserError` if the
+    /// new one doesn't either.
+    pub(crate) fn token_valid_for<I, T, F, R>(
+        &self,
+        duration: Duration,
+        scopes: I,
+        fetch: F,
+    ) -> BoxFuture<Token>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+        F: FnOnce(Vec<String>) -> R,
+        R: 'static + Future<Item = Token, Error = RequestError> + Send,
+    {
+        Box::new(
+            self.token_for(duration, scopes, fetch)
+                .and_then(move |token| check_valid_for(token, duration)),
+        )
+    }
+
+    fn token_for<I, T, F, R>(&self, margin: Duration, scopes: I, fetch: F) -> BoxFuture<Token>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+        F: FnOnce(Vec<String>) -> R,
+        R: 'static + Future<Item = Token, Error = RequestError> + Send,
+    {
+        let (hash, scopes) = hash_scopes(scopes);
+        if let Some(token) = self.cached_for(hash, &scopes, margin) {
+            return Box::new(future::ok(token));
+        }
+        let tokens = self.tokens.clone();
+        Box::new(fetch(scopes.clone()).map(move |token| {
+            let _ = tokens.lock().unwrap().set(
+                hash,
+                &scopes.iter().map(|s| s.as_str()).collect(),
+                Some(token.clone()),
+            );
+            token
+        }))
+    }
+
+    /// Drops the cached token for `scopes`, returning the sorted `scopes`.
+    pub(crate) fn forget<I, T>(&self, scopes: I) -> Vec<String>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let (hash, scopes) = hash_scopes(scopes);
+        let _ = self.tokens.lock().unwrap().set(
+            hash,
+            &scopes.iter().map(|s| s.as_str()).collect(),
+            None,
+        );
+        scopes
+    }
+
+    /// Drops the cached token with `access_token`, see `GetToken::invalidate()`.
+    pub(crate) fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .invalidate(access_token)
+            .map_err(|e| RequestError::Cache(Box::new(e)))
+    }
+
+    /// Returns the cached ID token for `audience`, or else the one `fetch` obtains, and caches
+    /// it.
+    #[cfg(any(
+        feature = "impersonated-service-account",
+        feature = "metadata-server",
+        feature = "service-account"
+    ))]
+    pub(crate) fn id_token<F, R>(&self, audience: &str, fetch: F) -> BoxFuture<Token>
+    where
+        F: FnOnce() -> R,
+        R: 'static + Future<Item = Token, Error = RequestError> + Send,
+    {
+        if let Some(token) = self.id_tokens.lock().unwrap().get(audience) {
+            if !token.expired() {
+                return Box::new(future::ok(token.clone()));
+            }
+        }
+        let (id_tokens, audience) = (self.id_tokens.clone(), audience.to_string());
+        Box::new(fetch().map(move |token| {
+            id_tokens.lock().unwrap().insert(audience, token.clone());
+            token
+        }))
+    }
+}
@@ -0,0 +1,176 @@
+//! Looks up what an access token grants at Google's tokeninfo endpoint. A request per lookup is
+//! more expensive than validating a JWT locally, see `validate_access_token_claims()`, but it
+//! works for opaque access tokens, and tells whether the token was revoked.
+use futures::prelude::*;
+
+use crate::time::{self, Timestamp};
+use crate::transport::{self, TokenRequest};
+use crate::types::{lenient_seconds, JsonError, RequestError, TransportError};
+
+/// Google's tokeninfo endpoint.
+pub const GOOGLE_TOKENINFO_URL: &str = "https://oauth2.googleapis.com/tokeninfo";
+
+/// What an access token grants, as returned by `tokeninfo()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessTokenInfo {
+    /// The client ID of the application the token was issued to.
+    pub audience: String,
+    /// The scopes the token grants.
+    pub scopes: Vec<String>,
+    /// The ID of the user who authorized the token, unless it was issued to a service account
+    /// without the `openid` scope.
+    pub user_id: Option<String>,
+    /// The email address of the user, if the token grants the `email` scope.
+    pub email: Option<String>,
+    expires_at: Option<i64>,
+}
+
+impl AccessTokenInfo {
+    /// When the token expires.
+    pub fn expires_at(&self) -> Option<Timestamp> {
+        self.expires_at.map(time::from_secs)
+    }
+}
+
+/// The response of the tokeninfo endpoint. Its first version named some fields differently,
+/// and only had the relative expiry.
+#[derive(Deserialize)]
+struct TokenInfoResponse {
+    #[serde(alias = "audience")]
+    aud: String,
+    #[serde(default)]
+    scope: String,
+    #[serde(alias = "user_id")]
+    sub: Option<String>,
+    email: Option<String>,
+    #[serde(default, deserialize_with = "lenient_seconds")]
+    exp: Option<i64>,
+    #[serde(default, deserialize_with = "lenient_seconds")]
+    expires_in: Option<i64>,
+}
+
+impl From<TokenInfoResponse> for AccessTokenInfo {
+    fn from(r: TokenInfoResponse) -> AccessTokenInfo {
+        let expires_in = r.expires_in;
+        AccessTokenInfo {
+            audience: r.aud,
+            scopes: r.scope.split_whitespace().map(String::from).collect(),
+            user_id: r.sub,
+            email: r.email,
+            expires_at: r
+                .exp
+                .or_else(|| expires_in.map(|secs| time::now().saturating_add(secs))),
+        }
+    }
+}
+
+/// Posts `access_token` to the tokeninfo endpoint at `url`, rather than passing it in the query
+/// string, so that it doesn't end up in access logs.
+pub(crate) fn post<C>(
+    client: hyper::Client<C>,
+    url: &str,
+    access_token: &str,
+) -> impl Future<Item = hyper::Response<hyper::Body>, Error = TransportError> + Send
+where
+    C: 'static + hyper::client::connect::Connect,
+{
+    let body = TokenRequest::new()
+        .param("access_token", access_token)
+        .body();
+    transport::post_form(client, vec![url.to_string()], body, None)
+}
+
+/// Looks up `access_token` at `GOOGLE_TOKENINFO_URL`, e.g. to debug which scopes a token
+/// grants, or to check a token presented to a server.
+///
+/// Tokens which expired or were revoked fail with a `RequestError::NegativeServerResponse` with
+/// the error `invalid_token`.
+pub fn tokeninfo<C>(
+    client: hyper::Client<C>,
+    access_token: &str,
+) -> impl Future<Item = AccessTokenInfo, Error = RequestError> + Send
+where
+    C: 'static + hyper::client::connect::Connect,
+{
+    post(client, GOOGLE_TOKENINFO_URL, access_token)
+        .map_err(RequestError::ClientError)
+        .and_then(|response| {
+            let status = response.status();
+            transport::read_body(response).map(move |body| (status, body))
+        })
+        .and_then(|(status, body)| {
+            if status.is_success() {
+                return serde_json::from_str::<TokenInfoResponse>(&body)
+                    .map(AccessTokenInfo::from)
+                    .map_err(RequestError::JSONError);
+            }
+            match JsonError::from_response(&body) {
+                Some(e) => Err(RequestError::from(e)),
+                None => Err(RequestError::BadServerResponse(format!(
+                    "looking up the token failed with {}: {}",
+                    status, body
+                ))),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::{FakeConnector, FakeReply};
+
+    fn lookup(reply: FakeReply) -> Result<AccessTokenInfo, RequestError> {
+        let connector = FakeConnector::new(vec![reply]);
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(tokeninfo(connector.client(), "token"))
+    }
+
+    #[test]
+    fn test_tokeninfo() {
+        let info = lookup(FakeReply::Json(
+            200,
+            r#"{"azp": "client", "aud": "client", "sub": "1234",
+                "scope": "openid https://www.googleapis.com/auth/userinfo.email",
+                "exp": "1572000000", "expires_in": "3599",
+                "email": "user@example.com", "email_verified": "true"}"#,
+        ))
+        .unwrap();
+        assert_eq!("client", info.audience);
+        assert_eq!(
+            vec!["openid", "https://www.googleapis.com/auth/userinfo.email"],
+            info.scopes
+        );
+        assert_eq!(Some("1234"), info.user_id.as_deref());
+        assert_eq!(Some("user@example.com"), info.email.as_deref());
+        assert_eq!(Some(time::from_secs(1572000000)), info.expires_at());
+
+        // The first version of the endpoint.
+        let info = lookup(FakeReply::Json(
+            200,
+            r#"{"issued_to": "client", "audience": "client", "user_id": "1234",
+                "scope": "email", "expires_in": 3599}"#,
+        ))
+        .unwrap();
+        assert_eq!("client", info.audience);
+        assert_eq!(Some("1234"), info.user_id.as_deref());
+        let expires_at = time::to_secs(&info.expires_at().unwrap());
+        assert!((time::now() + 3598..=time::now() + 3599).contains(&expires_at));
+        // A huge expiry saturates rather than overflowing.
+        let info = lookup(FakeReply::Json(
+            200,
+            r#"{"aud": "client", "expires_in": "9223372036854775807"}"#,
+        ))
+        .unwrap();
+        assert!(info.expires_at().unwrap() > time::from_secs(time::now()));
+
+        match lookup(FakeReply::Json(
+            400,
+            r#"{"error": "invalid_token", "error_description": "Invalid Value"}"#,
+        )) {
+            Err(RequestError::NegativeServerResponse(e)) => assert_eq!("invalid_token", e.error),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(lookup(FakeReply::Json(503, "unavailable")).is_err());
+    }
+}
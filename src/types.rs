@@ -1,31 +1,249 @@
-use chrono::{DateTime, TimeZone, Utc};
+use crate::authenticator::ScopedAuthenticator;
+use crate::config_check::ConfigReport;
+use crate::helper::{sanitize_credential, sanitize_refresh_token};
+use crate::stats::{AuthenticatorStats, SignInOutcome, TokenInfo, TokenSource};
+use crate::storage::RefreshFailure;
+use crate::time::{self, Timestamp};
 use hyper;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
 use std::io;
 use std::str::FromStr;
+use std::sync::Arc;
 
-use futures::prelude::*;
+use serde::Deserialize;
+
+use futures::{future, prelude::*};
 
 /// A marker trait for all Flows
+#[cfg(feature = "device")]
 pub trait Flow {
     fn type_id() -> FlowType;
 }
 
-#[derive(Deserialize, Debug)]
+/// An error response of the provider, as described in
+/// [RFC 6749, section 5.2](https://tools.ietf.org/html/rfc6749#section-5.2).
+///
+/// Besides the standard fields, any additional fields and the raw response body are kept
+/// for debugging.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct JsonError {
+    /// The error code, e.g. `invalid_grant`.
     pub error: String,
+    /// A human-readable description of the error.
     pub error_description: Option<String>,
+    /// A URI identifying a human-readable web page with information about the error.
     pub error_uri: Option<String>,
+    /// Google-specific refinement of `error`, e.g. `invalid_rapt` for reauth failures.
+    pub error_subtype: Option<String>,
+    /// Non-standard fields contained in the response.
+    #[serde(flatten)]
+    pub additional_fields: HashMap<String, serde_json::Value>,
+    /// The response body, if the error was received from a server.
+    #[serde(skip)]
+    pub raw: String,
+}
+
+impl JsonError {
+    /// Create an error with the given error code and description.
+    pub fn new<S: Into<String>>(error: S, error_description: Option<String>) -> JsonError {
+        JsonError {
+            error: error.into(),
+            error_description,
+            error_uri: None,
+            error_subtype: None,
+            additional_fields: HashMap::new(),
+            raw: String::new(),
+        }
+    }
+
+    /// Parse a response body. Returns `None` if `body` is not an error response.
+    pub fn from_response(body: &str) -> Option<JsonError> {
+        serde_json::from_str::<JsonError>(body)
+            .ok()
+            .map(|e| JsonError {
+                raw: body.to_string(),
+                ..e
+            })
+    }
+
+    /// Returns true if the provider demands that the user reauthenticates before new tokens
+    /// are issued. Google signals this with `invalid_rapt`, e.g. when a session control policy
+    /// of a G Suite domain limits the session length.
+    pub fn is_reauth_required(&self) -> bool {
+        self.error_subtype
+            .as_ref()
+            .map(|s| s == "invalid_rapt")
+            .unwrap_or(false)
+            || self
+                .error_description
+                .as_ref()
+                .map(|d| d.contains("invalid_rapt"))
+                .unwrap_or(false)
+    }
+
+    /// A hint how to resolve the error, for well-known error codes, to show to the user or
+    /// put into the log. Many of them are caused by the configuration of the OAuth client or
+    /// of the user's domain, rather than by the application.
+    pub fn help(&self) -> Option<&'static str> {
+        remediation(&self.error)
+    }
+}
+
+/// The remediation of the error code `error`, see `JsonError::help()`.
+fn remediation(error: &str) -> Option<&'static str> {
+    Some(match error {
+        "unauthorized_client" => {
+            "The OAuth client may not use this flow. Check its application type in the \
+             provider's console: the device flow needs a \"TVs and Limited Input devices\" \
+             client, the installed flow a \"Desktop app\" client. For domain-wide delegation, \
+             authorize the client ID and scopes in the Google Workspace Admin console."
+        }
+        "admin_policy_enforced" => {
+            "The administrator of the user's Google Workspace domain doesn't allow this \
+             application or these scopes. Ask them to trust the application in the Admin \
+             console, under Security > Access and data control > API controls."
+        }
+        "org_internal" => {
+            "The OAuth client is restricted to the users of its Google Cloud organization. Sign \
+             in with an account of the organization, or set the user type of the project's \
+             OAuth consent screen to External."
+        }
+        "access_denied" => {
+            "The user didn't grant access. If they didn't decline, the project's OAuth consent \
+             screen may be in testing mode without the user listed as a test user."
+        }
+        "invalid_client" => {
+            "The client ID or the client secret is wrong, or the OAuth client was deleted. \
+             Download the client secret from the provider's console again."
+        }
+        "invalid_grant" => {
+            "The refresh token was revoked or expired, e.g. because the user changed their \
+             password, or after seven days for projects whose OAuth consent screen is in \
+             testing mode. Sign in again."
+        }
+        _ => return None,
+    })
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.error.fmt(f)?;
+        if let Some(ref desc) = self.error_description {
+            write!(f, ": {}", desc)?;
+        }
+        Ok(())
+    }
+}
+
+/// A failure of the HTTP client, e.g. while connecting to the server or reading its response.
+///
+/// Which HTTP client is used is an implementation detail of this crate; the error it returned
+/// is available as `source()`.
+#[derive(Debug)]
+pub enum TransportError {
+    /// The server's host name could not be resolved, e.g. as DNS or egress is blocked.
+    Dns(Box<dyn Error + Send + Sync>),
+    /// The server could not be reached, e.g. as the connection was refused or timed out.
+    Connect(Box<dyn Error + Send + Sync>),
+    /// The TLS handshake or connection failed, e.g. as the server's certificate isn't trusted.
+    Tls(Box<dyn Error + Send + Sync>),
+    /// The connection was closed before the response was complete.
+    Closed(Box<dyn Error + Send + Sync>),
+    /// Reading the response failed, e.g. as the connection was reset.
+    Read(Box<dyn Error + Send + Sync>),
+    /// The server's response was not valid HTTP.
+    Protocol(Box<dyn Error + Send + Sync>),
+    /// Any other failure of the HTTP client.
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl TransportError {
+    pub(crate) fn from_hyper(error: hyper::Error) -> TransportError {
+        let io_errors = || causes(&error).filter_map(|e| e.downcast_ref::<io::Error>());
+        if causes(&error).any(|e| e.is::<rustls::TLSError>()) {
+            TransportError::Tls(Box::new(error))
+        } else if error.is_connect() && io_errors().any(is_lookup_failure) {
+            TransportError::Dns(Box::new(error))
+        } else if error.is_connect() {
+            TransportError::Connect(Box::new(error))
+        } else if error.is_canceled()
+            || error.is_closed()
+            || error.is_incomplete_message()
+            || error.is_body_write_aborted()
+            // hyper reports a connection closed while reading a response body as an I/O error
+            // rather than an incomplete message.
+            || io_errors().any(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+        {
+            TransportError::Closed(Box::new(error))
+        } else if error.is_parse() {
+            TransportError::Protocol(Box::new(error))
+        } else if io_errors().next().is_some() {
+            TransportError::Read(Box::new(error))
+        } else {
+            TransportError::Other(Box::new(error))
+        }
+    }
+
+    fn inner(&self) -> &(dyn Error + Send + Sync + 'static) {
+        match *self {
+            TransportError::Dns(ref e)
+            | TransportError::Connect(ref e)
+            | TransportError::Tls(ref e)
+            | TransportError::Closed(ref e)
+            | TransportError::Read(ref e)
+            | TransportError::Protocol(ref e)
+            | TransportError::Other(ref e) => &**e,
+        }
+    }
+}
+
+/// The chain of errors which caused `error`, starting with `error` itself. Unlike `source()`,
+/// which skips the error wrapped by an `io::Error`, it includes all of them.
+fn causes<'a>(error: &'a (dyn Error + 'static)) -> impl Iterator<Item = &'a (dyn Error + 'static)> {
+    std::iter::successors(Some(error), |&e: &&'a (dyn Error + 'static)| match e
+        .downcast_ref::<io::Error>()
+    {
+        Some(e) => e.get_ref().map(|e| e as &(dyn Error + 'static)),
+        None => e.source(),
+    })
+}
+
+/// Whether `error` is the failure to resolve a host name, as reported by the standard library.
+fn is_lookup_failure(error: &io::Error) -> bool {
+    // WSAHOST_NOT_FOUND, WSATRY_AGAIN, WSANO_RECOVERY and WSANO_DATA.
+    let windows = cfg!(windows) && matches!(error.raw_os_error(), Some(11001..=11004));
+    windows
+        || error
+            .to_string()
+            .starts_with("failed to lookup address information")
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt::Display::fmt(self.inner(), f)
+    }
+}
+
+impl Error for TransportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.inner())
+    }
 }
 
 /// All possible outcomes of the refresh flow
 #[derive(Debug)]
 pub enum RefreshResult {
     /// Indicates connection failure
-    Error(hyper::Error),
+    Error(TransportError),
     /// The server did not answer with a new token, providing the server message
-    RefreshError(String, Option<String>),
+    RefreshError(Box<JsonError>),
+    /// The refresh token is still valid, but the provider requires the user to reauthenticate
+    /// (obtain a new reauth proof token) first. Contains the URL describing the reason, if the
+    /// server provided one.
+    ReauthRequired(Option<String>),
     /// The refresh operation finished successfully, providing a new `Token`
     Success(Token),
 }
@@ -34,9 +252,9 @@ pub enum RefreshResult {
 #[derive(Debug)]
 pub enum PollError {
     /// Connection failure - retry if you think it's worth it
-    HttpError(hyper::Error),
+    HttpError(TransportError),
     /// Indicates we are expired, including the expiration date
-    Expired(DateTime<Utc>),
+    Expired(Timestamp),
     /// Indicates that the user declined access. String is server response
     AccessDenied,
     /// Indicates that too many attempts failed.
@@ -49,7 +267,7 @@ pub enum PollError {
 #[derive(Debug)]
 pub enum RequestError {
     /// Indicates connection failure
-    ClientError(hyper::Error),
+    ClientError(TransportError),
     /// The OAuth client was not found
     InvalidClient,
     /// Some requested scopes were invalid. String contains the scopes as part of
@@ -57,7 +275,20 @@ pub enum RequestError {
     InvalidScope(String),
     /// A 'catch-all' variant containing the server error and description
     /// First string is the error code, the second may be a more detailed description
-    NegativeServerResponse(String, Option<String>),
+    NegativeServerResponse(Box<JsonError>),
+    /// The OAuth client may not use the grant type, e.g. a web client used for the device
+    /// flow, or a service account without domain-wide delegation impersonating a user
+    /// (`unauthorized_client`). See `help()`.
+    UnauthorizedClient(Box<JsonError>),
+    /// A policy of the user's Google Workspace domain blocks the application or the scopes
+    /// (`admin_policy_enforced`). See `help()`.
+    AdminPolicyEnforced(Box<JsonError>),
+    /// The OAuth client is internal to an organization the user doesn't belong to
+    /// (`org_internal`). See `help()`.
+    OrgInternal(Box<JsonError>),
+    /// The user, or the provider on their behalf, didn't grant access (`access_denied`).
+    /// See `help()`.
+    AccessDenied(Box<JsonError>),
     /// A malformed server response.
     BadServerResponse(String),
     /// Error while decoding a JSON response.
@@ -72,11 +303,46 @@ pub enum RequestError {
     Refresh(RefreshResult),
     /// Error in token cache layer
     Cache(Box<dyn Error + Send + Sync>),
+    /// The request is refused by a policy: the requested scopes by the authenticator's
+    /// `ScopePolicy`, or the configuration of a flow by its OAuth 2.1 mode.
+    PolicyViolation(String),
+    /// The stored token expired and can't be refreshed, as it has no refresh token. Use
+    /// `GetToken::force_refresh()` to obtain a new one from the flow, which may involve the
+    /// user.
+    NoRefreshTokenAvailable,
+    /// The provider wasn't contacted, as previous requests failed repeatedly, see
+    /// `RetryBudget`. Contains the time until the next request is let through.
+    CircuitOpen(std::time::Duration),
+    /// The application secret couldn't be loaded from the authenticator's `SecretStorage`.
+    SecretStorage(Box<dyn Error + Send + Sync>),
+    /// No authorization was begun, as the configured maximum of authorizations is pending, see
+    /// `WebFlow::max_pending_authorizations()`.
+    TooManyPendingAuthorizations,
+    /// The flow didn't finish within the deadline set with `DeviceFlow::deadline()` or
+    /// `InstalledFlow::deadline()`, which it contains.
+    TimedOut(std::time::Duration),
 }
 
-impl From<hyper::Error> for RequestError {
-    fn from(error: hyper::Error) -> RequestError {
-        RequestError::ClientError(error)
+impl RequestError {
+    pub(crate) fn client_error(error: hyper::Error) -> RequestError {
+        RequestError::ClientError(TransportError::from_hyper(error))
+    }
+
+    /// A hint how to resolve the error, if the provider refused the request with a well-known
+    /// error code, see `JsonError::help()`. The hint is meant for users and operators, e.g. to
+    /// show below the error message, so that they can fix the configuration themselves.
+    pub fn help(&self) -> Option<&'static str> {
+        match *self {
+            RequestError::InvalidClient => remediation("invalid_client"),
+            RequestError::NegativeServerResponse(ref e)
+            | RequestError::UnauthorizedClient(ref e)
+            | RequestError::AdminPolicyEnforced(ref e)
+            | RequestError::OrgInternal(ref e)
+            | RequestError::AccessDenied(ref e) => e.help(),
+            RequestError::Poll(PollError::AccessDenied) => remediation("access_denied"),
+            RequestError::Refresh(RefreshResult::RefreshError(ref e)) => e.help(),
+            _ => None,
+        }
     }
 }
 
@@ -89,7 +355,11 @@ impl From<JsonError> for RequestError {
                     .error_description
                     .unwrap_or("no description provided".to_string()),
             ),
-            _ => RequestError::NegativeServerResponse(value.error, value.error_description),
+            "unauthorized_client" => RequestError::UnauthorizedClient(Box::new(value)),
+            "admin_policy_enforced" => RequestError::AdminPolicyEnforced(Box::new(value)),
+            "org_internal" => RequestError::OrgInternal(Box::new(value)),
+            "access_denied" => RequestError::AccessDenied(Box::new(value)),
+            _ => RequestError::NegativeServerResponse(Box::new(value)),
         }
     }
 }
@@ -100,13 +370,11 @@ impl fmt::Display for RequestError {
             RequestError::ClientError(ref err) => err.fmt(f),
             RequestError::InvalidClient => "Invalid Client".fmt(f),
             RequestError::InvalidScope(ref scope) => writeln!(f, "Invalid Scope: '{}'", scope),
-            RequestError::NegativeServerResponse(ref error, ref desc) => {
-                error.fmt(f)?;
-                if let &Some(ref desc) = desc {
-                    write!(f, ": {}", desc)?;
-                }
-                "\n".fmt(f)
-            }
+            RequestError::NegativeServerResponse(ref error) => writeln!(f, "{}", error),
+            RequestError::UnauthorizedClient(ref error)
+            | RequestError::AdminPolicyEnforced(ref error)
+            | RequestError::OrgInternal(ref error)
+            | RequestError::AccessDenied(ref error) => error.fmt(f),
             RequestError::BadServerResponse(ref s) => s.fmt(f),
             RequestError::JSONError(ref e) => format!(
                 "JSON Error; this might be a bug with unexpected server responses! {}",
@@ -118,6 +386,24 @@ impl fmt::Display for RequestError {
             RequestError::Poll(ref pe) => pe.fmt(f),
             RequestError::Refresh(ref rr) => format!("{:?}", rr).fmt(f),
             RequestError::Cache(ref e) => e.fmt(f),
+            RequestError::PolicyViolation(ref s) => s.fmt(f),
+            RequestError::NoRefreshTokenAvailable => {
+                "The stored token expired and has no refresh token".fmt(f)
+            }
+            RequestError::CircuitOpen(retry_after) => write!(
+                f,
+                "Token requests are suspended after repeated failures; retry in {}s",
+                retry_after.as_secs()
+            ),
+            RequestError::SecretStorage(ref e) => {
+                write!(f, "Failed to load the application secret: {}", e)
+            }
+            RequestError::TooManyPendingAuthorizations => {
+                "Too many authorizations are pending; try again later".fmt(f)
+            }
+            RequestError::TimedOut(deadline) => {
+                write!(f, "The flow didn't finish within {}s", deadline.as_secs())
+            }
         }
     }
 }
@@ -133,47 +419,6 @@ impl Error for RequestError {
     }
 }
 
-#[derive(Debug)]
-pub struct StringError {
-    error: String,
-}
-
-impl fmt::Display for StringError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        self.description().fmt(f)
-    }
-}
-
-impl StringError {
-    pub fn new<S: AsRef<str>>(error: S, desc: Option<S>) -> StringError {
-        let mut error = error.as_ref().to_string();
-        if let Some(d) = desc {
-            error.push_str(": ");
-            error.push_str(d.as_ref());
-        }
-
-        StringError { error: error }
-    }
-}
-
-impl<'a> From<&'a dyn Error> for StringError {
-    fn from(err: &'a dyn Error) -> StringError {
-        StringError::new(err.description().to_string(), None)
-    }
-}
-
-impl From<String> for StringError {
-    fn from(value: String) -> StringError {
-        StringError::new(value, None)
-    }
-}
-
-impl Error for StringError {
-    fn description(&self) -> &str {
-        &self.error
-    }
-}
-
 /// Represents all implemented token types
 #[derive(Clone, PartialEq, Debug)]
 pub enum TokenType {
@@ -208,14 +453,30 @@ pub struct Scheme {
     pub access_token: String,
 }
 
-impl std::convert::Into<hyper::header::HeaderValue> for Scheme {
-    fn into(self) -> hyper::header::HeaderValue {
+impl Scheme {
+    /// The value of an `Authorization` header sending the token. Fails with
+    /// `RequestError::BadServerResponse` if the access token contains characters which aren't
+    /// allowed in headers.
+    pub fn header_value(&self) -> Result<hyper::header::HeaderValue, RequestError> {
         hyper::header::HeaderValue::from_str(&format!(
             "{} {}",
             self.token_type.as_ref(),
             self.access_token
         ))
-        .expect("Invalid Scheme header value")
+        .map_err(|_| {
+            RequestError::BadServerResponse(
+                "The access token is not a valid header value".to_string(),
+            )
+        })
+    }
+}
+
+/// # Panics
+/// * if the access token contains characters which aren't allowed in headers; use
+///   `Scheme::header_value()` for tokens received from a provider.
+impl From<Scheme> for hyper::header::HeaderValue {
+    fn from(scheme: Scheme) -> hyper::header::HeaderValue {
+        scheme.header_value().expect("Invalid Scheme header value")
     }
 }
 
@@ -239,20 +500,611 @@ impl FromStr for Scheme {
 /// A provider for authorization tokens, yielding tokens valid for a given scope.
 /// The `api_key()` method is an alternative in case there are no scopes or
 /// if no user is involved.
+///
+/// All methods take `&self`, so that implementations which are `Send + Sync` (like the ones
+/// returned by `Authenticator::build()`) can be shared between threads, e.g. using an `Arc`.
 pub trait GetToken {
-    fn token<I, T>(
-        &mut self,
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>;
+
+    /// Returns a new token for `scopes`, even if the cached one hasn't expired yet, e.g. after a
+    /// resource server rejected it with 401 because it was revoked, lacks a scope, or the clocks
+    /// disagree. Implementations without a cache return the same as `token()`.
+    fn force_refresh<I, T>(
+        &self,
         scopes: I,
     ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
     where
         T: Into<String>,
-        I: IntoIterator<Item = T>;
+        I: IntoIterator<Item = T>,
+    {
+        self.token(scopes)
+    }
+
+    /// Returns a token for `scopes` which remains valid for at least `duration`, e.g. for a
+    /// resumable upload or a long streaming call which can't obtain a new token halfway. The
+    /// authenticator refreshes its stored token if necessary; other implementations return the
+    /// result of `token()`. Fails with `RequestError::UserError` if the provider issues tokens
+    /// with a shorter lifetime.
+    fn token_valid_for<I, T>(
+        &self,
+        duration: std::time::Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        Box::new(
+            self.token(scopes)
+                .and_then(move |token| check_valid_for(token, duration)),
+        )
+    }
+
+    /// Stops handing out the cached token whose access token is `access_token`, e.g. after a
+    /// resource server rejected it with 401, so that the next call to `token()` refreshes it.
+    /// Returns whether a cached token matched. Implementations without a cache return `false`.
+    fn invalidate(&self, _access_token: &str) -> Result<bool, RequestError> {
+        Ok(false)
+    }
+
+    /// Returns health information about the tokens handed out so far. Only the authenticator
+    /// keeps track of it; other implementations return empty stats.
+    fn stats(&self) -> AuthenticatorStats {
+        AuthenticatorStats::default()
+    }
+
+    /// Returns the outcome of the most recent interactive sign-in, i.e. the last token obtained
+    /// from the flow rather than the storage, for wrapper scripts and tests to inspect. Only the
+    /// authenticator keeps track of it; other implementations return `None`.
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        None
+    }
+
+    /// Like `token()`, but also tells where the token came from: the token storage, a refresh,
+    /// or the flow. Only the `Authenticator` knows; other token sources return the token of
+    /// `token()` from `TokenSource::Unknown`.
+    fn token_info<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        Box::new(self.token(scopes).map(|token| TokenInfo {
+            token,
+            obtained_via: TokenSource::Unknown,
+            obtained_at: None,
+            storage: None,
+        }))
+    }
 
-    fn api_key(&mut self) -> Option<String>;
+    fn api_key(&self) -> Option<String>;
 
     /// Return an application secret with at least token_uri, client_secret, and client_id filled
     /// in. This is used for refreshing tokens without interaction from the flow.
     fn application_secret(&self) -> ApplicationSecret;
+
+    /// Returns an OpenID Connect ID token identifying the caller to `audience`, e.g. the URL of
+    /// a Cloud Run service, as opposed to an access token authorizing scopes. The ID token is
+    /// the returned `Token`'s `access_token`, to be sent as bearer token. Service accounts and
+    /// the metadata server issue ID tokens; other implementations fail with
+    /// `RequestError::UserError`.
+    fn id_token(
+        &self,
+        _audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        Box::new(future::err(RequestError::UserError(
+            "this token source doesn't issue ID tokens".to_string(),
+        )))
+    }
+
+    /// Returns the failed attempts to refresh the token for `scopes` since it was obtained,
+    /// oldest first. Only the authenticator records them, in its token storage.
+    fn refresh_failures<I, T>(&self, _scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        Ok(Vec::new())
+    }
+
+    /// Checks the configuration without obtaining a token: parses the credentials, verifies
+    /// that keys can sign, and that the endpoints respond, so that a deployment can fail at
+    /// startup rather than on the first request. Problems are collected in the report, rather
+    /// than failing the future. The default implementation reports a failed check, as the token
+    /// source wasn't validated.
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        let mut report = ConfigReport::default();
+        report.check(
+            "token source",
+            Err("not validated: the token source has no checks".to_string()),
+        );
+        Box::new(future::ok(report))
+    }
+
+    /// Returns a handle which only hands out tokens for `scopes`, or subsets thereof, so that
+    /// components of an application can be given access to the scopes they need only. To share
+    /// one authenticator, and thus its storage and client, between several handles, call this
+    /// on a reference or an `Arc`, e.g. `(&auth).scoped(vec!["scope-a"])`.
+    fn scoped<I, T>(self, scopes: I) -> ScopedAuthenticator<Self>
+    where
+        Self: Sized,
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        ScopedAuthenticator::new(self, scopes)
+    }
+}
+
+impl<G: GetToken + ?Sized> GetToken for &G {
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (*self).token(scopes)
+    }
+
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (*self).force_refresh(scopes)
+    }
+
+    fn token_valid_for<I, T>(
+        &self,
+        duration: std::time::Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (*self).token_valid_for(duration, scopes)
+    }
+
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        (*self).invalidate(access_token)
+    }
+
+    fn stats(&self) -> AuthenticatorStats {
+        (*self).stats()
+    }
+
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        (*self).last_sign_in()
+    }
+
+    fn token_info<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (*self).token_info(scopes)
+    }
+
+    fn api_key(&self) -> Option<String> {
+        (*self).api_key()
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        (*self).application_secret()
+    }
+
+    fn id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        (*self).id_token(audience)
+    }
+
+    fn refresh_failures<I, T>(&self, scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (*self).refresh_failures(scopes)
+    }
+
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        (*self).validate()
+    }
+}
+
+impl<G: GetToken + ?Sized> GetToken for Arc<G> {
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).token(scopes)
+    }
+
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).force_refresh(scopes)
+    }
+
+    fn token_valid_for<I, T>(
+        &self,
+        duration: std::time::Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).token_valid_for(duration, scopes)
+    }
+
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        (**self).invalidate(access_token)
+    }
+
+    fn stats(&self) -> AuthenticatorStats {
+        (**self).stats()
+    }
+
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        (**self).last_sign_in()
+    }
+
+    fn token_info<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).token_info(scopes)
+    }
+
+    fn api_key(&self) -> Option<String> {
+        (**self).api_key()
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        (**self).application_secret()
+    }
+
+    fn id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        (**self).id_token(audience)
+    }
+
+    fn refresh_failures<I, T>(&self, scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).refresh_failures(scopes)
+    }
+
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        (**self).validate()
+    }
+}
+
+impl<G: GetToken + ?Sized> GetToken for Box<G> {
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).token(scopes)
+    }
+
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).force_refresh(scopes)
+    }
+
+    fn token_valid_for<I, T>(
+        &self,
+        duration: std::time::Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).token_valid_for(duration, scopes)
+    }
+
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        (**self).invalidate(access_token)
+    }
+
+    fn stats(&self) -> AuthenticatorStats {
+        (**self).stats()
+    }
+
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        (**self).last_sign_in()
+    }
+
+    fn token_info<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).token_info(scopes)
+    }
+
+    fn api_key(&self) -> Option<String> {
+        (**self).api_key()
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        (**self).application_secret()
+    }
+
+    fn id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        (**self).id_token(audience)
+    }
+
+    fn refresh_failures<I, T>(&self, scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        (**self).refresh_failures(scopes)
+    }
+
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        (**self).validate()
+    }
+}
+
+/// An object safe version of `GetToken`, which is generic over the scopes and thus can't be
+/// made into a trait object. Application code can depend on a `Box<dyn TokenProvider>` or an
+/// `Arc<dyn TokenProvider>`, and be handed a real authenticator in production and e.g. a
+/// `StaticTokenSource` in tests, chosen at runtime.
+///
+/// All `GetToken` implementations which are `Send + Sync` implement it, and
+/// `dyn TokenProvider` implements `GetToken` in turn, so that a boxed token provider can be
+/// passed wherever a `GetToken` is expected. The methods are named differently than those of
+/// `GetToken`, so that calls stay unambiguous when both traits are in scope.
+pub trait TokenProvider: Send + Sync {
+    /// `GetToken::token()`.
+    fn get_token(
+        &self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>;
+
+    /// `GetToken::force_refresh()`.
+    fn get_fresh_token(
+        &self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>;
+
+    /// `GetToken::token_valid_for()`.
+    fn get_token_valid_for(
+        &self,
+        duration: std::time::Duration,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>;
+
+    /// `GetToken::id_token()`.
+    fn get_id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>;
+
+    /// `GetToken::token_info()`.
+    fn get_token_info(
+        &self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>;
+
+    /// `GetToken::refresh_failures()`.
+    fn get_refresh_failures(
+        &self,
+        scopes: Vec<String>,
+    ) -> Result<Vec<RefreshFailure>, RequestError>;
+
+    /// `GetToken::invalidate()`.
+    fn invalidate_token(&self, access_token: &str) -> Result<bool, RequestError>;
+
+    /// `GetToken::validate()`.
+    fn validate_config(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send>;
+
+    /// `GetToken::stats()`.
+    fn get_stats(&self) -> AuthenticatorStats;
+
+    /// `GetToken::last_sign_in()`.
+    fn get_last_sign_in(&self) -> Option<SignInOutcome>;
+
+    /// `GetToken::api_key()`.
+    fn get_api_key(&self) -> Option<String>;
+
+    /// `GetToken::application_secret()`.
+    fn get_application_secret(&self) -> ApplicationSecret;
+}
+
+impl<G: GetToken + Send + Sync> TokenProvider for G {
+    fn get_token(
+        &self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.token(scopes)
+    }
+
+    fn get_fresh_token(
+        &self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.force_refresh(scopes)
+    }
+
+    fn get_token_valid_for(
+        &self,
+        duration: std::time::Duration,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.token_valid_for(duration, scopes)
+    }
+
+    fn get_id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.id_token(audience)
+    }
+
+    fn get_token_info(
+        &self,
+        scopes: Vec<String>,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send> {
+        self.token_info(scopes)
+    }
+
+    fn get_refresh_failures(
+        &self,
+        scopes: Vec<String>,
+    ) -> Result<Vec<RefreshFailure>, RequestError> {
+        self.refresh_failures(scopes)
+    }
+
+    fn invalidate_token(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.invalidate(access_token)
+    }
+
+    fn validate_config(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        self.validate()
+    }
+
+    fn get_stats(&self) -> AuthenticatorStats {
+        self.stats()
+    }
+
+    fn get_last_sign_in(&self) -> Option<SignInOutcome> {
+        self.last_sign_in()
+    }
+
+    fn get_api_key(&self) -> Option<String> {
+        self.api_key()
+    }
+
+    fn get_application_secret(&self) -> ApplicationSecret {
+        self.application_secret()
+    }
+}
+
+impl GetToken for dyn TokenProvider {
+    fn token<I, T>(&self, scopes: I) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.get_token(scopes.into_iter().map(Into::into).collect())
+    }
+
+    fn force_refresh<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.get_fresh_token(scopes.into_iter().map(Into::into).collect())
+    }
+
+    fn token_valid_for<I, T>(
+        &self,
+        duration: std::time::Duration,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.get_token_valid_for(duration, scopes.into_iter().map(Into::into).collect())
+    }
+
+    fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.invalidate_token(access_token)
+    }
+
+    fn stats(&self) -> AuthenticatorStats {
+        self.get_stats()
+    }
+
+    fn last_sign_in(&self) -> Option<SignInOutcome> {
+        self.get_last_sign_in()
+    }
+
+    fn token_info<I, T>(
+        &self,
+        scopes: I,
+    ) -> Box<dyn Future<Item = TokenInfo, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.get_token_info(scopes.into_iter().map(Into::into).collect())
+    }
+
+    fn refresh_failures<I, T>(&self, scopes: I) -> Result<Vec<RefreshFailure>, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        self.get_refresh_failures(scopes.into_iter().map(Into::into).collect())
+    }
+
+    fn api_key(&self) -> Option<String> {
+        self.get_api_key()
+    }
+
+    fn application_secret(&self) -> ApplicationSecret {
+        self.get_application_secret()
+    }
+
+    fn id_token(
+        &self,
+        audience: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        self.get_id_token(audience)
+    }
+
+    fn validate(&self) -> Box<dyn Future<Item = ConfigReport, Error = RequestError> + Send> {
+        self.validate_config()
+    }
 }
 
 /// Represents a token as returned by OAuth2 servers.
@@ -262,12 +1114,41 @@ pub trait GetToken {
 /// it reached it's expiry date.
 ///
 /// The type is tuned to be suitable for direct de-serialization from server
-/// replies, as well as for serialization for later reuse. This is the reason
-/// for the two fields dealing with expiry - once in relative in and once in
-/// absolute terms.
+/// replies, as well as for serialization for later reuse. The expiry is stored in
+/// absolute terms: a relative `expires_in` field found in server replies is converted
+/// upon deserialization, and tokens serialized by previous versions of this crate
+/// can still be read.
 ///
+/// Returns the claims of `jwt` without verifying its signature, or `None` if it isn't a JWT.
+pub(crate) fn jwt_claims(jwt: &str) -> Option<serde_json::Value> {
+    jwt.split('.')
+        .nth(1)
+        .and_then(|payload| base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok())
+        .and_then(|payload| serde_json::from_slice(&payload).ok())
+}
+
+/// Returns `token` if it remains valid for at least `duration`.
+pub(crate) fn check_valid_for(
+    token: Token,
+    duration: std::time::Duration,
+) -> Result<Token, RequestError> {
+    if token.expires_within(duration) {
+        return Err(RequestError::UserError(format!(
+            "the token expires in {}s, before the requested {}s",
+            token.expires_in().map(|d| d.as_secs()).unwrap_or(0),
+            duration.as_secs()
+        )));
+    }
+    Ok(token)
+}
+
+/// How long before their expiry tokens are treated as expired by default, so that they don't
+/// expire while a request is in flight.
+pub const DEFAULT_EXPIRY_MARGIN: std::time::Duration = std::time::Duration::from_secs(60);
+
 /// Utility methods make common queries easier, see `expired()`.
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(from = "SerializedToken", into = "SerializedToken")]
 pub struct Token {
     /// used when authenticating calls to oauth2 enabled services.
     pub access_token: String,
@@ -275,50 +1156,369 @@ pub struct Token {
     pub refresh_token: Option<String>,
     /// The token type as string - usually 'Bearer'.
     pub token_type: String,
-    /// access_token will expire after this amount of time.
-    /// Prefer using expiry_date()
-    pub expires_in: Option<i64>,
-    /// timestamp is seconds since epoch indicating when the token will expire in absolute terms.
-    /// use expiry_date() to convert to DateTime.
-    pub expires_in_timestamp: Option<i64>,
+    /// seconds since epoch indicating when the token will expire.
+    expires_at: Option<i64>,
+}
+
+/// The serialized form of a `Token`, as stored by previous versions of this crate and as
+/// returned by OAuth2 servers. Like `DefaultTokenResponseParser`, it tolerates a missing
+/// `token_type` and an `expires_in` sent as string.
+#[derive(Deserialize, Serialize)]
+struct SerializedToken {
+    access_token: String,
+    refresh_token: Option<String>,
+    #[serde(default = "bearer")]
+    token_type: String,
+    #[serde(
+        default,
+        deserialize_with = "lenient_seconds",
+        skip_serializing_if = "Option::is_none"
+    )]
+    expires_in: Option<i64>,
+    expires_in_timestamp: Option<i64>,
+}
+
+fn bearer() -> String {
+    "Bearer".to_string()
+}
+
+/// Deserializes an optional number of seconds, which some providers send as a string like
+/// `"3600"`, or as a fraction.
+pub(crate) fn lenient_seconds<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seconds {
+        Integer(i64),
+        Fraction(f64),
+        Text(String),
+    }
+    match Option::<Seconds>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Seconds::Integer(secs)) => Ok(Some(secs)),
+        Some(Seconds::Fraction(secs)) => Ok(Some(secs as i64)),
+        Some(Seconds::Text(ref text)) if text.trim().is_empty() => Ok(None),
+        Some(Seconds::Text(text)) => match text.trim().parse::<f64>() {
+            Ok(secs) if secs.is_finite() => Ok(Some(secs as i64)),
+            _ => Err(serde::de::Error::custom(format!(
+                "invalid number of seconds: {}",
+                text
+            ))),
+        },
+    }
+}
+
+impl From<SerializedToken> for Token {
+    fn from(t: SerializedToken) -> Token {
+        let expires_in = t.expires_in;
+        Token {
+            access_token: t.access_token,
+            refresh_token: t.refresh_token,
+            token_type: t.token_type,
+            expires_at: t
+                .expires_in_timestamp
+                .or_else(|| expires_in.map(|e| time::now().saturating_add(e))),
+        }
+    }
+}
+
+impl From<Token> for SerializedToken {
+    fn from(t: Token) -> SerializedToken {
+        SerializedToken {
+            access_token: t.access_token,
+            refresh_token: t.refresh_token,
+            token_type: t.token_type,
+            expires_in: None,
+            expires_in_timestamp: t.expires_at,
+        }
+    }
 }
 
 impl Token {
-    /// Returns true if we are expired.
+    /// Create a new token. `expires_in` is the lifetime of the token as reported by the server,
+    /// counting from now; `None` means the token doesn't expire.
+    pub fn new(
+        access_token: String,
+        token_type: String,
+        refresh_token: Option<String>,
+        expires_in: Option<i64>,
+    ) -> Token {
+        Token {
+            access_token,
+            refresh_token,
+            token_type,
+            expires_at: expires_in.map(|e| time::now().saturating_add(e)),
+        }
+    }
+
+    /// Creates a bearer token obtained outside of this crate, e.g. from a metadata service or a
+    /// sidecar, expiring at `expires_at`, or never if `None`. The token can then be stored in the
+    /// `TokenStorage` of an `Authenticator`, or sent with `Scheme::header_value()`.
     ///
-    /// # Panics
-    /// * if our access_token is unset
+    /// Surrounding whitespace is removed. Fails with `RequestError::UserError` if the access
+    /// token is empty or contains whitespace or control characters, or if it expired already.
+    pub fn new_bearer<S: Into<String>>(
+        access_token: S,
+        expires_at: Option<Timestamp>,
+    ) -> Result<Token, RequestError> {
+        Token::from_parts(access_token, "Bearer", None, expires_at)
+    }
+
+    /// Like `new_bearer()`, for a token valid for `expires_in` from now, as reported by e.g.
+    /// the `expires_in` of a metadata service.
+    pub fn new_bearer_expiring_in<S: Into<String>>(
+        access_token: S,
+        expires_in: std::time::Duration,
+    ) -> Result<Token, RequestError> {
+        let expires_at = time::now().saturating_add(expires_in.as_secs() as i64);
+        Token::new_bearer(access_token, Some(time::from_secs(expires_at)))
+    }
+
+    /// Like `new_bearer()`, with any `token_type` and a refresh token, which is checked with
+    /// `sanitize_refresh_token()`.
+    pub fn from_parts<S, T>(
+        access_token: S,
+        token_type: T,
+        refresh_token: Option<String>,
+        expires_at: Option<Timestamp>,
+    ) -> Result<Token, RequestError>
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        let invalid = |e: io::Error| RequestError::UserError(e.to_string());
+        let access_token =
+            sanitize_credential("access token", &access_token.into()).map_err(invalid)?;
+        let token_type = sanitize_credential("token type", &token_type.into()).map_err(invalid)?;
+        let refresh_token = match refresh_token {
+            Some(refresh_token) => Some(sanitize_refresh_token(&refresh_token).map_err(invalid)?),
+            None => None,
+        };
+        let expires_at = expires_at.map(|t| time::to_secs(&t));
+        if let Some(expires_at) = expires_at {
+            if expires_at <= time::now() {
+                return Err(RequestError::UserError(format!(
+                    "Bad access token: it expired at {}",
+                    time::display(&time::from_secs(expires_at))
+                )));
+            }
+        }
+        Ok(Token {
+            access_token,
+            refresh_token,
+            token_type,
+            expires_at,
+        })
+    }
+
+    /// Returns true if we are expired, or expire within `DEFAULT_EXPIRY_MARGIN`. Tokens with an
+    /// empty access token, which can't be used, are always expired.
     pub fn expired(&self) -> bool {
-        if self.access_token.len() == 0 {
-            panic!("called expired() on unset token");
+        self.access_token.is_empty() || self.expires_within(DEFAULT_EXPIRY_MARGIN)
+    }
+
+    /// Returns true if we are expired, or expire within `margin`.
+    pub fn expires_within(&self, margin: std::time::Duration) -> bool {
+        match self.expires_at {
+            Some(expires_at) => {
+                let margin = i64::try_from(margin.as_secs()).unwrap_or(i64::MAX);
+                expires_at.saturating_sub(margin) <= time::now()
+            }
+            None => false,
         }
-        if let Some(expiry_date) = self.expiry_date() {
-            expiry_date - chrono::Duration::minutes(1) <= Utc::now()
-        } else {
-            false
+    }
+
+    /// Creates a bearer token from a JWT, like an ID token, expiring as stated by its `exp`
+    /// claim. Tokens which aren't JWTs or lack the claim don't expire.
+    pub(crate) fn from_jwt(jwt: String) -> Token {
+        let exp =
+            jwt_claims(&jwt).and_then(|claims| claims.get("exp").and_then(|exp| exp.as_i64()));
+        Token {
+            access_token: jwt,
+            refresh_token: None,
+            token_type: "Bearer".to_string(),
+            expires_at: exp,
+        }
+    }
+
+    /// Marks the token as expired, e.g. because a resource server refused it.
+    pub(crate) fn expire(&mut self) {
+        self.expires_at = Some(time::now());
+    }
+
+    /// Returns a timestamp representing our expiry date, or `None` if the token doesn't
+    /// expire.
+    pub fn expires_at(&self) -> Option<Timestamp> {
+        Some(time::from_secs(self.expires_at?))
+    }
+
+    /// Returns the time left until the token expires. This is zero for tokens that already
+    /// expired, and `None` for tokens that don't expire.
+    pub fn expires_in(&self) -> Option<std::time::Duration> {
+        let left = self.expires_at?.saturating_sub(time::now());
+        Some(std::time::Duration::from_secs(left.max(0) as u64))
+    }
+
+    /// Set the expiry date. `None` means the token doesn't expire.
+    pub fn set_expires_at(&mut self, expires_at: Option<Timestamp>) {
+        self.expires_at = expires_at.map(|d| time::to_secs(&d));
+    }
+
+    /// Returns a timestamp representing our expiry date.
+    #[deprecated(note = "use expires_at()")]
+    pub fn expiry_date(&self) -> Option<Timestamp> {
+        self.expires_at()
+    }
+
+    /// Serializes the token, including its refresh token, to move it to another machine or to
+    /// keep it in a secret manager, see `from_json_string()`. The JSON object carries a
+    /// `version`, and the absolute expiry as `expires_at` in seconds since the epoch, like
+    /// `{"access_token":"ya29...","expires_at":1572003600,"refresh_token":"1/...","token_type":"Bearer","version":1}`.
+    ///
+    /// Treat the output like a password: whoever has it can obtain tokens for the user.
+    pub fn to_json_string(&self) -> String {
+        serde_json::json!({
+            "version": TOKEN_EXPORT_VERSION,
+            "access_token": self.access_token,
+            "refresh_token": self.refresh_token,
+            "token_type": self.token_type,
+            "expires_at": self.expires_at,
+        })
+        .to_string()
+    }
+
+    /// Deserializes a token exported by `to_json_string()`, possibly by a different version of
+    /// this crate. Fails for versions of the format this crate doesn't know.
+    pub fn from_json_string(json: &str) -> Result<Token, serde_json::Error> {
+        #[derive(Deserialize)]
+        struct ExportedToken {
+            version: u32,
+            access_token: String,
+            refresh_token: Option<String>,
+            token_type: String,
+            expires_at: Option<i64>,
+        }
+        let exported: ExportedToken = serde_json::from_str(json)?;
+        if exported.version != TOKEN_EXPORT_VERSION {
+            return Err(serde::de::Error::custom(format!(
+                "unsupported token format version {}",
+                exported.version
+            )));
+        }
+        Ok(Token {
+            access_token: exported.access_token,
+            refresh_token: exported.refresh_token,
+            token_type: exported.token_type,
+            expires_at: exported.expires_at,
+        })
+    }
+}
+
+/// The version of the format written by `Token::to_json_string()`.
+const TOKEN_EXPORT_VERSION: u32 = 1;
+
+/// Turns the body of a successful token endpoint response into a `Token`.
+///
+/// The default implementation, `DefaultTokenResponseParser`, expects the fields defined in
+/// [RFC 6749, section 5.1](https://tools.ietf.org/html/rfc6749#section-5.1), tolerating common
+/// deviations; `StrictTokenResponseParser` refuses those. Implement this trait
+/// to adapt providers using different field names or formats. Error responses are detected
+/// before `parse_token_response()` is invoked.
+pub trait TokenResponseParser {
+    fn parse_token_response(&self, body: &str) -> Result<Token, RequestError>;
+
+    /// Parses a JSON token response while it is read from the received chunks, which saves
+    /// copying it when refreshing tokens. It may be given an error response, which only needs to
+    /// fail: the body is then checked for errors and handed to `parse_token_response()`.
+    ///
+    /// By default, the body is read into a string, and handed to `parse_token_response()` unless
+    /// it is an error response.
+    fn read_token_response(&self, body: &mut dyn io::Read) -> Result<Token, RequestError> {
+        let mut text = String::new();
+        body.read_to_string(&mut text)
+            .map_err(RequestError::LowLevelError)?;
+        match JsonError::from_response(&text) {
+            Some(e) => Err(RequestError::from(e)),
+            None => self.parse_token_response(&text),
         }
     }
+}
+
+impl<P: TokenResponseParser + ?Sized> TokenResponseParser for std::sync::Arc<P> {
+    fn parse_token_response(&self, body: &str) -> Result<Token, RequestError> {
+        (**self).parse_token_response(body)
+    }
 
-    /// Returns a DateTime object representing our expiry date.
-    pub fn expiry_date(&self) -> Option<DateTime<Utc>> {
-        let expires_in_timestamp = self.expires_in_timestamp?;
+    fn read_token_response(&self, body: &mut dyn io::Read) -> Result<Token, RequestError> {
+        (**self).read_token_response(body)
+    }
+}
 
-        Utc.timestamp(expires_in_timestamp, 0).into()
+/// A `TokenResponseParser` for RFC 6749 token responses, tolerating common deviations: unknown
+/// fields are ignored, a missing `token_type` is taken to be `Bearer`, and `expires_in` may be a
+/// string like `"3600"`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultTokenResponseParser;
+
+impl TokenResponseParser for DefaultTokenResponseParser {
+    fn parse_token_response(&self, body: &str) -> Result<Token, RequestError> {
+        self.read_token_response(&mut body.as_bytes())
     }
 
-    /// Adjust our stored expiry format to be absolute, using the current time.
-    pub fn set_expiry_absolute(&mut self) -> &mut Token {
-        if self.expires_in_timestamp.is_some() {
-            assert!(self.expires_in.is_none());
-            return self;
+    fn read_token_response(&self, body: &mut dyn io::Read) -> Result<Token, RequestError> {
+        #[derive(Deserialize)]
+        struct JsonToken {
+            access_token: String,
+            #[serde(default = "bearer")]
+            token_type: String,
+            refresh_token: Option<String>,
+            #[serde(default, deserialize_with = "lenient_seconds")]
+            expires_in: Option<i64>,
         }
 
-        if let Some(expires_in) = self.expires_in {
-            self.expires_in_timestamp = Some(Utc::now().timestamp() + expires_in);
-            self.expires_in = None;
+        let t: JsonToken = serde_json::from_reader(body).map_err(RequestError::JSONError)?;
+        Ok(Token::new(
+            t.access_token,
+            t.token_type,
+            t.refresh_token,
+            t.expires_in,
+        ))
+    }
+}
+
+/// A `TokenResponseParser` accepting only token responses as specified by RFC 6749: fields other
+/// than `access_token`, `token_type`, `refresh_token`, `expires_in` and `scope` are refused, as
+/// is an `expires_in` which isn't a number. Use it with `Authenticator::token_response_parser()`
+/// to notice deviations of a provider early.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StrictTokenResponseParser;
+
+impl TokenResponseParser for StrictTokenResponseParser {
+    fn parse_token_response(&self, body: &str) -> Result<Token, RequestError> {
+        self.read_token_response(&mut body.as_bytes())
+    }
+
+    fn read_token_response(&self, body: &mut dyn io::Read) -> Result<Token, RequestError> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        #[allow(dead_code)]
+        struct JsonToken {
+            access_token: String,
+            token_type: String,
+            refresh_token: Option<String>,
+            expires_in: Option<i64>,
+            scope: Option<String>,
         }
 
-        self
+        let t: JsonToken = serde_json::from_reader(body).map_err(RequestError::JSONError)?;
+        Ok(Token::new(
+            t.access_token,
+            t.token_type,
+            t.refresh_token,
+            t.expires_in,
+        ))
     }
 }
 
@@ -346,12 +1546,27 @@ pub enum FlowType {
 pub struct ApplicationSecret {
     /// The client ID.
     pub client_id: String,
-    /// The client secret.
+    /// The client secret. Public clients, like native apps using PKCE, have none; it is empty
+    /// then, and omitted from token requests.
+    #[serde(default)]
     pub client_secret: String,
     /// The token server endpoint URI.
     pub token_uri: String,
+    /// Alternative token server endpoint URIs, tried in order if connecting to `token_uri`
+    /// fails. For Google, `https://oauth2.googleapis.com/token` and
+    /// `https://accounts.google.com/o/oauth2/token` are interchangeable.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub token_uri_fallbacks: Vec<String>,
+    /// How the client authenticates at the token endpoint.
+    #[serde(default, skip_serializing_if = "ClientAuthMethod::is_default")]
+    pub token_endpoint_auth_method: ClientAuthMethod,
+    /// How scopes are joined in authorization and device code requests.
+    #[serde(default, skip_serializing_if = "ScopeSeparator::is_default")]
+    pub scope_separator: ScopeSeparator,
     /// The authorization server endpoint URI.
     pub auth_uri: String,
+    /// The registered redirect URIs. Flows pick the one of the kind they need, see
+    /// `redirect_uri()`.
     pub redirect_uris: Vec<String>,
 
     /// Name of the google project the credentials are associated with
@@ -365,6 +1580,150 @@ pub struct ApplicationSecret {
     pub client_x509_cert_url: Option<String>,
 }
 
+impl ApplicationSecret {
+    /// Returns `token_uri` followed by `token_uri_fallbacks`.
+    pub(crate) fn token_uris(&self) -> Vec<String> {
+        std::iter::once(&self.token_uri)
+            .chain(self.token_uri_fallbacks.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the first of the `redirect_uris` of `kind`.
+    pub fn redirect_uri(&self, kind: RedirectUriKind) -> Option<&str> {
+        self.redirect_uris
+            .iter()
+            .map(String::as_str)
+            .find(|uri| RedirectUriKind::of(uri) == kind)
+    }
+}
+
+/// The kinds of redirect URIs, telling how the authorization code reaches the application.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedirectUriKind {
+    /// `http://localhost`, `http://127.0.0.1` or `http://[::1]`, with any port and path: a
+    /// local server receives the code.
+    Loopback,
+    /// A private-use URI scheme like `com.example.app:/oauth2redirect`: the handler the
+    /// application registered with the operating system receives the code.
+    CustomScheme,
+    /// `urn:ietf:wg:oauth:2.0:oob` or `oob`: the provider shows the code, for the user to copy.
+    OutOfBand,
+    /// Any other URI, like the `https` redirect URIs of web applications.
+    Web,
+}
+
+impl RedirectUriKind {
+    /// The kind of `uri`.
+    pub fn of(uri: &str) -> RedirectUriKind {
+        if uri == "oob" || uri.starts_with("urn:ietf:wg:oauth:2.0:oob") {
+            return RedirectUriKind::OutOfBand;
+        }
+        match url::Url::parse(uri) {
+            Ok(ref url) if url.scheme() == "http" => match url.host_str() {
+                Some("localhost") | Some("127.0.0.1") | Some("[::1]") => RedirectUriKind::Loopback,
+                _ => RedirectUriKind::Web,
+            },
+            Ok(ref url) if url.scheme() == "https" => RedirectUriKind::Web,
+            Ok(_) => RedirectUriKind::CustomScheme,
+            Err(_) => RedirectUriKind::Web,
+        }
+    }
+}
+
+/// The client authentication methods of [RFC 6749, section 2.3.1][rfc6749] and [OpenID Connect
+/// Core, section 9][oidc], named as in the OpenID Connect `token_endpoint_auth_method` client
+/// metadata.
+///
+/// [rfc6749]: https://tools.ietf.org/html/rfc6749#section-2.3.1
+/// [oidc]: https://openid.net/specs/openid-connect-core-1_0.html#ClientAuthentication
+///
+/// `private_key_jwt` isn't supported, as the `ApplicationSecret` has no private key; use a
+/// service account for key-based authentication with Google.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum ClientAuthMethod {
+    /// Send `client_id` and `client_secret` as part of the request body. This is what Google
+    /// expects. (default)
+    #[serde(rename = "client_secret_post")]
+    RequestBody,
+    /// Send `client_id` and `client_secret` using HTTP Basic authentication. Servers are required
+    /// to support this method, and some, like AWS Cognito, don't support any other.
+    #[serde(rename = "client_secret_basic")]
+    HttpBasic,
+    /// Send a JWT assertion signed with the `client_secret` using HMAC SHA-256, as in
+    /// [RFC 7523, section 2.2](https://tools.ietf.org/html/rfc7523#section-2.2). The secret itself
+    /// isn't sent.
+    #[serde(rename = "client_secret_jwt")]
+    SecretJwt,
+    /// Don't authenticate, only send the `client_id`, as public clients do. This is also what is
+    /// done for an empty `client_secret`, whatever the method.
+    #[serde(rename = "none")]
+    Public,
+}
+
+// Not derived, as `#[default]` needs Rust 1.62.
+#[allow(clippy::derivable_impls)]
+impl Default for ClientAuthMethod {
+    fn default() -> ClientAuthMethod {
+        ClientAuthMethod::RequestBody
+    }
+}
+
+impl ClientAuthMethod {
+    fn is_default(&self) -> bool {
+        *self == ClientAuthMethod::default()
+    }
+}
+
+/// How a list of scopes is sent to the provider.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScopeSeparator {
+    /// Separated by spaces, as RFC 6749, section 3.3 requires. (default)
+    #[serde(rename = "space")]
+    Space,
+    /// Separated by commas, as some providers, like Facebook and Strava, expect.
+    #[serde(rename = "comma")]
+    Comma,
+}
+
+// Not derived, as `#[default]` needs Rust 1.62.
+#[allow(clippy::derivable_impls)]
+impl Default for ScopeSeparator {
+    fn default() -> ScopeSeparator {
+        ScopeSeparator::Space
+    }
+}
+
+impl ScopeSeparator {
+    /// The separating character.
+    pub fn as_char(self) -> char {
+        match self {
+            ScopeSeparator::Space => ' ',
+            ScopeSeparator::Comma => ',',
+        }
+    }
+
+    /// Joins `scopes`.
+    pub fn join<I, T>(self, scopes: I) -> String
+    where
+        T: AsRef<str>,
+        I: IntoIterator<Item = T>,
+    {
+        let mut joined = String::new();
+        for scope in scopes {
+            if !joined.is_empty() {
+                joined.push(self.as_char());
+            }
+            joined.push_str(scope.as_ref());
+        }
+        joined
+    }
+
+    fn is_default(&self) -> bool {
+        *self == ScopeSeparator::default()
+    }
+}
+
 /// A type to facilitate reading and writing the json secret file
 /// as returned by the [google developer console](https://code.google.com/apis/console)
 #[derive(Deserialize, Serialize, Default)]
@@ -386,6 +1745,39 @@ pub mod tests {
          \"14070749909-vgip2f1okm7bkvajhi9jugan6126io9v.apps.googleusercontent.com\",\
          \"auth_provider_x509_cert_url\":\"https://www.googleapis.com/oauth2/v1/certs\"}}";
 
+    #[test]
+    fn test_redirect_uri_kinds() {
+        for (uri, kind) in &[
+            ("http://localhost", RedirectUriKind::Loopback),
+            ("http://127.0.0.1:8080/callback", RedirectUriKind::Loopback),
+            ("http://[::1]/", RedirectUriKind::Loopback),
+            (
+                "com.example.app:/oauth2redirect",
+                RedirectUriKind::CustomScheme,
+            ),
+            ("urn:ietf:wg:oauth:2.0:oob", RedirectUriKind::OutOfBand),
+            ("urn:ietf:wg:oauth:2.0:oob:auto", RedirectUriKind::OutOfBand),
+            ("oob", RedirectUriKind::OutOfBand),
+            ("https://localhost/callback", RedirectUriKind::Web),
+            ("http://app.example.com/callback", RedirectUriKind::Web),
+        ] {
+            assert_eq!(*kind, RedirectUriKind::of(uri), "{}", uri);
+        }
+        let secret = ApplicationSecret {
+            redirect_uris: vec![
+                "https://app.example.com/callback".to_string(),
+                "http://127.0.0.1/callback".to_string(),
+                "http://localhost".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("http://127.0.0.1/callback"),
+            secret.redirect_uri(RedirectUriKind::Loopback)
+        );
+        assert_eq!(None, secret.redirect_uri(RedirectUriKind::OutOfBand));
+    }
+
     #[test]
     fn console_secret() {
         use serde_json as json;
@@ -409,10 +1801,269 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn token_serialization() {
+        use serde_json as json;
+
+        // Stored by previous versions of this crate.
+        let stored = r#"{"access_token":"ya29.token","refresh_token":"1/refresh","token_type":"Bearer","expires_in":null,"expires_in_timestamp":1572000000}"#;
+        let token: Token = json::from_str(stored).unwrap();
+        assert_eq!(1572000000, time::to_secs(&token.expires_at().unwrap()));
+        assert_eq!(Some(std::time::Duration::from_secs(0)), token.expires_in());
+        assert!(token.expired());
+
+        // As returned by a server.
+        let response = r#"{"access_token":"ya29.token","token_type":"Bearer","expires_in":3600}"#;
+        let token: Token = json::from_str(response).unwrap();
+        assert!(token.expires_in().unwrap() > std::time::Duration::from_secs(3590));
+        assert!(!token.expired());
+        assert!(token.expires_within(std::time::Duration::from_secs(3600)));
+        assert!(token.expires_within(std::time::Duration::from_secs(u64::MAX)));
+
+        let roundtrip: Token = json::from_str(&json::to_string(&token).unwrap()).unwrap();
+        assert_eq!(token, roundtrip);
+        assert!(!json::to_string(&token).unwrap().contains("\"expires_in\""));
+
+        let token = Token::new("ya29.token".to_string(), "Bearer".to_string(), None, None);
+        assert_eq!(None, token.expires_in());
+        assert!(!token.expired());
+    }
+
+    #[test]
+    fn token_export() {
+        let token = Token::new(
+            "ya29.token".to_string(),
+            "Bearer".to_string(),
+            Some("1/refresh".to_string()),
+            Some(3600),
+        );
+        let exported = token.to_json_string();
+        assert_eq!(token, Token::from_json_string(&exported).unwrap());
+        let without_expiry = Token::new("at".to_string(), "Bearer".to_string(), None, None);
+        assert_eq!(
+            r#"{"access_token":"at","expires_at":null,"refresh_token":null,"token_type":"Bearer","version":1}"#,
+            without_expiry.to_json_string()
+        );
+
+        let future = exported.replace(r#""version":1"#, r#""version":2"#);
+        let e = Token::from_json_string(&future).unwrap_err();
+        assert!(e.to_string().contains("version 2"), "{}", e);
+        // The storage format isn't accepted, as it lacks the version.
+        let stored = serde_json::to_string(&token).unwrap();
+        assert!(Token::from_json_string(&stored).is_err());
+    }
+
+    #[test]
+    fn external_tokens() {
+        let token =
+            Token::new_bearer_expiring_in("ya29.metadata\n", std::time::Duration::from_secs(3600))
+                .unwrap();
+        assert_eq!("ya29.metadata", token.access_token);
+        assert_eq!("Bearer", token.token_type);
+        assert!(!token.expired());
+        let scheme = Scheme {
+            token_type: TokenType::Bearer,
+            access_token: token.access_token,
+        };
+        assert_eq!("Bearer ya29.metadata", scheme.header_value().unwrap());
+        assert_eq!(None, Token::new_bearer("at", None).unwrap().expires_at());
+
+        let token = Token::from_parts(
+            "at",
+            "MAC",
+            Some("1%2F%2F0gLVUBwKy7Ws5CgYIARAAGBASNwF-L9Ir3FQR9xbpBJ4weXkSxZyJwVSjnBuU".into()),
+            None,
+        )
+        .unwrap();
+        assert_eq!("MAC", token.token_type);
+        assert!(token.refresh_token.unwrap().starts_with("1//0g"));
+
+        let expired = time::from_secs(time::now() - 10);
+        for bad in [
+            Token::new_bearer("", None),
+            Token::new_bearer("ya29.a\nb", None),
+            Token::new_bearer("at", Some(expired)),
+            Token::from_parts("at", " ", None, None),
+            Token::from_parts("at", "Bearer", Some("1//cut".into()), None),
+        ]
+        .iter()
+        {
+            match bad {
+                Err(RequestError::UserError(ref e)) => assert!(e.starts_with("Bad "), "{}", e),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn token_provider_objects() {
+        use crate::static_token::StaticTokenSource;
+
+        fn first_token<G: GetToken>(auth: G) -> Result<Token, RequestError> {
+            auth.token(vec!["scope"]).wait()
+        }
+
+        let real: Box<dyn TokenProvider> =
+            Box::new(StaticTokenSource::bearer("ya29.real").unwrap());
+        let fake: Arc<dyn TokenProvider> = Arc::new(StaticTokenSource::bearer("fake").unwrap());
+        assert_eq!("ya29.real", first_token(&real).unwrap().access_token);
+        assert_eq!("fake", first_token(fake.clone()).unwrap().access_token);
+        assert_eq!("fake", first_token(&*fake).unwrap().access_token);
+        assert!(!fake.invalidate_token("fake").unwrap());
+        assert!(fake.validate_config().wait().unwrap().is_ok());
+        assert_eq!(None, fake.get_api_key());
+
+        // Boxed providers are token providers themselves.
+        let providers: Vec<Box<dyn TokenProvider>> = vec![real, Box::new(fake)];
+        let tokens: Vec<_> = providers
+            .iter()
+            .map(|p| {
+                p.get_token(vec!["scope".to_string()])
+                    .wait()
+                    .unwrap()
+                    .access_token
+            })
+            .collect();
+        assert_eq!(vec!["ya29.real", "fake"], tokens);
+    }
+
+    #[test]
+    fn provider_error_help() {
+        let error = |code: &str| RequestError::from(JsonError::new(code, None));
+        match error("admin_policy_enforced") {
+            e @ RequestError::AdminPolicyEnforced(_) => {
+                assert!(e.help().unwrap().contains("Admin console"));
+                assert_eq!("admin_policy_enforced", e.to_string());
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        match error("org_internal") {
+            e @ RequestError::OrgInternal(_) => assert!(e.help().unwrap().contains("External")),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match error("unauthorized_client") {
+            RequestError::UnauthorizedClient(e) => assert!(e.help().is_some()),
+            e => panic!("unexpected error {:?}", e),
+        }
+        match error("access_denied") {
+            RequestError::AccessDenied(_) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(error("invalid_client").help().is_some());
+        assert!(error("invalid_grant")
+            .help()
+            .unwrap()
+            .contains("Sign in again"));
+        assert_eq!(None, error("slow_down").help());
+
+        let refresh = RequestError::Refresh(RefreshResult::RefreshError(Box::new(JsonError::new(
+            "invalid_grant",
+            Some("Token has been expired or revoked.".into()),
+        ))));
+        assert!(refresh.help().is_some());
+        assert_eq!(
+            error("access_denied").help(),
+            RequestError::Poll(PollError::AccessDenied).help()
+        );
+        assert_eq!(None, RequestError::NoRefreshTokenAvailable.help());
+    }
+
+    #[test]
+    fn token_response_tolerance() {
+        let deviating =
+            r#"{"access_token": "ya29.token", "expires_in": "3600", "id_token": "eyJ"}"#;
+        let token = DefaultTokenResponseParser
+            .parse_token_response(deviating)
+            .unwrap();
+        assert_eq!("Bearer", token.token_type);
+        assert!(token.expires_in().unwrap() > std::time::Duration::from_secs(3590));
+        let token: Token = serde_json::from_str(deviating).unwrap();
+        assert!(token.expires_in().unwrap() > std::time::Duration::from_secs(3590));
+        let token = DefaultTokenResponseParser
+            .parse_token_response(r#"{"access_token": "a", "expires_in": 59.9}"#)
+            .unwrap();
+        assert!(token.expires_in().unwrap() <= std::time::Duration::from_secs(59));
+        assert!(DefaultTokenResponseParser
+            .parse_token_response(r#"{"access_token": "a", "expires_in": "soon"}"#)
+            .is_err());
+
+        match StrictTokenResponseParser.parse_token_response(deviating) {
+            Err(RequestError::JSONError(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let token = StrictTokenResponseParser
+            .parse_token_response(
+                r#"{"access_token": "a", "token_type": "bearer", "expires_in": 60, "scope": "email"}"#,
+            )
+            .unwrap();
+        assert_eq!("bearer", token.token_type);
+    }
+
     #[test]
     fn parse_schema() {
         let auth = Scheme::from_str("Bearer foo").unwrap();
         assert_eq!(auth.token_type, TokenType::Bearer);
         assert_eq!(auth.access_token, "foo".to_string());
     }
+
+    #[test]
+    fn transport_error() {
+        use futures::Future;
+        use std::net::TcpListener;
+
+        // A port nobody listens on.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let uri = format!("http://127.0.0.1:{}/token", port);
+        let fut = hyper::Client::new()
+            .get(uri.parse().unwrap())
+            .map_err(RequestError::client_error);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        match rt.block_on(fut) {
+            Err(RequestError::ClientError(e @ TransportError::Connect(_))) => {
+                assert!(e.source().is_some())
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_transport_error_categories() {
+        use crate::transport::tests::{FakeConnector, FakeReply};
+
+        let connector = FakeConnector::new(vec![
+            FakeReply::Fail(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "failed to lookup address information: Name or service not known",
+                )
+            }),
+            FakeReply::Fail(|| {
+                let tls = io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    rustls::TLSError::General("the certificate issuer is unknown".to_string()),
+                );
+                io::Error::new(io::ErrorKind::Other, tls)
+            }),
+            FakeReply::Refused,
+        ]);
+        let client = connector.client();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut get = || {
+            rt.block_on(
+                client
+                    .get("http://oauth2.example.com/".parse().unwrap())
+                    .map_err(TransportError::from_hyper),
+            )
+        };
+        assert!(matches!(get(), Err(TransportError::Dns(_))));
+        match get() {
+            Err(e @ TransportError::Tls(_)) => assert!(e.to_string().contains("issuer is unknown")),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(matches!(get(), Err(TransportError::Connect(_))));
+    }
 }
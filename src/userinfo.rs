@@ -0,0 +1,233 @@
+//! Fetches the profile of the signed-in user from an OpenID Connect userinfo endpoint
+//! (OpenID Connect Core 1.0, section 5.3).
+use futures::{future, prelude::*};
+use hyper::header;
+use serde::Deserialize;
+
+use crate::transport;
+use crate::types::{JsonError, RequestError, Scheme, Token, TokenType};
+
+/// Google's userinfo endpoint.
+pub const GOOGLE_USERINFO_URL: &str = "https://openidconnect.googleapis.com/v1/userinfo";
+
+/// The claims about the user returned by `userinfo()`. Which ones are present depends on the
+/// scopes of the token: `profile` for the name and picture, `email` for the email address.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct UserInfo {
+    /// The ID of the user at the provider.
+    #[serde(rename = "sub")]
+    pub subject: String,
+    pub name: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub picture: Option<String>,
+    pub email: Option<String>,
+    /// Whether the provider verified that the email address belongs to the user.
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub email_verified: bool,
+    pub locale: Option<String>,
+    /// The Google Workspace domain of the user, if any.
+    #[serde(rename = "hd")]
+    pub hosted_domain: Option<String>,
+    /// All claims, including the ones above.
+    #[serde(skip)]
+    pub claims: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Deserializes a boolean, which some providers send as a string like `"true"`.
+fn lenient_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bool {
+        Bool(bool),
+        Text(String),
+    }
+    match Option::<Bool>::deserialize(deserializer)? {
+        None => Ok(false),
+        Some(Bool::Bool(b)) => Ok(b),
+        Some(Bool::Text(text)) => Ok(text.eq_ignore_ascii_case("true")),
+    }
+}
+
+/// Fetches the claims about the user who authorized `token` from `userinfo_endpoint`, e.g.
+/// `GOOGLE_USERINFO_URL`, or the `userinfo_endpoint` of the provider's discovery document. The
+/// token needs the `openid` scope.
+pub fn userinfo<C>(
+    client: hyper::Client<C>,
+    userinfo_endpoint: &str,
+    token: &Token,
+) -> impl Future<Item = UserInfo, Error = RequestError> + Send
+where
+    C: 'static + hyper::client::connect::Connect,
+{
+    let scheme = Scheme {
+        token_type: TokenType::Bearer,
+        access_token: token.access_token.clone(),
+    };
+    let mut request = hyper::Request::get(userinfo_endpoint);
+    request.header(header::ACCEPT, "application/json");
+    let request = scheme.header_value().and_then(|authorization| {
+        request
+            .header(header::AUTHORIZATION, authorization)
+            .body(hyper::Body::empty())
+            .map_err(|e| {
+                RequestError::UserError(format!(
+                    "invalid userinfo endpoint {}: {}",
+                    userinfo_endpoint, e
+                ))
+            })
+    });
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => return future::Either::A(future::err(e)),
+    };
+    future::Either::B(
+        client
+            .request(request)
+            .map_err(RequestError::client_error)
+            .and_then(|response| {
+                let status = response.status();
+                let challenge = response
+                    .headers()
+                    .get(header::WWW_AUTHENTICATE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(bearer_error);
+                transport::read_body(response).map(move |body| (status, challenge, body))
+            })
+            .and_then(|(status, challenge, body)| {
+                if status.is_success() {
+                    return parse_userinfo(&body);
+                }
+                match JsonError::from_response(&body).or(challenge) {
+                    Some(e) => Err(RequestError::from(JsonError { raw: body, ..e })),
+                    None => Err(RequestError::BadServerResponse(format!(
+                        "fetching the user info failed with {}: {}",
+                        status, body
+                    ))),
+                }
+            }),
+    )
+}
+
+/// Parses the error of a `WWW-Authenticate` challenge of the `Bearer` scheme (RFC 6750, section
+/// 3), like `Bearer error="invalid_token", error_description="The token expired"`. Returns
+/// `None` for other schemes, and for challenges without an error.
+fn bearer_error(challenge: &str) -> Option<JsonError> {
+    let challenge = challenge.trim_start();
+    let scheme_len = challenge.find(' ').unwrap_or(challenge.len());
+    if !challenge[..scheme_len].eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let mut params = Vec::new();
+    let mut rest = challenge[scheme_len..].trim_start();
+    while !rest.is_empty() {
+        let eq = rest.find('=')?;
+        let name = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let mut value = String::new();
+        if rest.starts_with('"') {
+            let mut chars = rest[1..].char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        end = Some(i + 2);
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            rest = &rest[end?..];
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            value.push_str(rest[..end].trim());
+            rest = &rest[end..];
+        }
+        params.push((name, value));
+        rest = rest.trim_start().trim_start_matches(',').trim_start();
+    }
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+    };
+    let mut error = JsonError::new(param("error")?, param("error_description"));
+    error.error_uri = param("error_uri");
+    Some(error)
+}
+
+fn parse_userinfo(body: &str) -> Result<UserInfo, RequestError> {
+    let claims: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(body).map_err(RequestError::JSONError)?;
+    let info = serde_json::from_value(serde_json::Value::Object(claims.clone()))
+        .map_err(RequestError::JSONError)?;
+    Ok(UserInfo { claims, ..info })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_userinfo() {
+        let endpoint = format!("{}/userinfo/v1/userinfo", mockito::server_url());
+        let client = hyper::Client::builder()
+            .keep_alive(false)
+            .build_http::<hyper::Body>();
+        let token = Token::new("at".to_string(), "Bearer".to_string(), None, Some(3600));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let _userinfo = mockito::mock("GET", "/userinfo/v1/userinfo")
+            .match_header("authorization", "Bearer at")
+            .with_body(
+                r#"{"sub": "1234", "name": "Jane Doe", "given_name": "Jane",
+                    "email": "jane@example.com", "email_verified": "true", "hd": "example.com"}"#,
+            )
+            .expect(1)
+            .create();
+        let info = rt
+            .block_on(userinfo(client.clone(), &endpoint, &token))
+            .unwrap();
+        assert_eq!("1234", info.subject);
+        assert_eq!(Some("Jane Doe"), info.name.as_deref());
+        assert_eq!(None, info.family_name);
+        assert_eq!(Some("jane@example.com"), info.email.as_deref());
+        assert!(info.email_verified);
+        assert_eq!(Some("example.com"), info.hosted_domain.as_deref());
+        assert_eq!(6, info.claims.len());
+        _userinfo.assert();
+
+        let _refused = mockito::mock("GET", "/userinfo/v1/userinfo")
+            .with_status(401)
+            .with_header("www-authenticate", "Bearer error=\"invalid_token\"")
+            .create();
+        let revoked = Token::new("revoked".to_string(), "Bearer".to_string(), None, None);
+        match rt.block_on(userinfo(client, &endpoint, &revoked)) {
+            Err(RequestError::NegativeServerResponse(e)) => assert_eq!("invalid_token", e.error),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_bearer_error() {
+        let error = bearer_error(
+            r#"Bearer realm="example", error="invalid_token", error_description="The token, \"at\", expired""#,
+        )
+        .unwrap();
+        assert_eq!("invalid_token", error.error);
+        assert_eq!(
+            Some("The token, \"at\", expired"),
+            error.error_description.as_deref()
+        );
+        let error = bearer_error("bearer error=insufficient_scope,scope=\"email\"").unwrap();
+        assert_eq!("insufficient_scope", error.error);
+        assert!(bearer_error(r#"Bearer realm="example""#).is_none());
+        assert!(bearer_error(r#"Basic realm="example""#).is_none());
+        assert!(bearer_error(r#"Bearer error="unterminated"#).is_none());
+    }
+}
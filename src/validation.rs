@@ -0,0 +1,493 @@
+//! Validation of JWT access tokens received by resource servers.
+//!
+//! Some providers issue access tokens which are JWTs signed using a key from their JSON Web Key
+//! Set, e.g. Google's ID tokens and the access tokens of Azure AD, Auth0 or Okta. APIs receiving
+//! them as bearer tokens can check them offline using `validate_access_token_claims()`, with the
+//! key set obtained from a `DocumentCache`.
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use ring::signature;
+
+use crate::oidc::{Jwk, Jwks};
+use crate::time;
+
+/// What a token has to satisfy to be accepted by `validate_access_token_claims()`.
+#[derive(Clone, Debug)]
+pub struct TokenValidation {
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    required_scopes: Vec<String>,
+    leeway: Duration,
+}
+
+impl TokenValidation {
+    /// Accept tokens issued by `issuer` for `audience`, which usually is the API's client ID or
+    /// URL.
+    pub fn new<S: Into<String>, T: Into<String>>(issuer: S, audience: T) -> TokenValidation {
+        TokenValidation {
+            issuers: vec![issuer.into()],
+            audiences: vec![audience.into()],
+            required_scopes: Vec::new(),
+            leeway: Duration::from_secs(60),
+        }
+    }
+
+    /// Also accept tokens issued by `issuer`, e.g. `accounts.google.com` besides
+    /// `https://accounts.google.com`.
+    pub fn issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuers.push(issuer.into());
+        self
+    }
+
+    /// Also accept tokens for `audience`.
+    pub fn audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audiences.push(audience.into());
+        self
+    }
+
+    /// Only accept tokens granting `scope`. Scopes are read from the `scope` claim (RFC 9068)
+    /// or the `scp` claim.
+    pub fn required_scope<S: Into<String>>(mut self, scope: S) -> Self {
+        self.required_scopes.push(scope.into());
+        self
+    }
+
+    /// The clock skew tolerated when checking `exp` and `nbf`. (default: 60 seconds)
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+}
+
+/// The claims of a token accepted by `validate_access_token_claims()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessTokenClaims {
+    pub issuer: String,
+    pub subject: Option<String>,
+    pub audiences: Vec<String>,
+    pub scopes: Vec<String>,
+    expires_at: i64,
+    /// All claims, including the ones above.
+    pub claims: serde_json::Map<String, serde_json::Value>,
+}
+
+impl AccessTokenClaims {
+    /// When the token expires.
+    pub fn expires_at(&self) -> time::Timestamp {
+        time::from_secs(self.expires_at)
+    }
+}
+
+/// Why a token was refused by `validate_access_token_claims()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationError {
+    /// The token isn't a well-formed JWT.
+    Malformed(String),
+    /// The token is signed using an algorithm which isn't supported, or `none`.
+    UnsupportedAlgorithm(String),
+    /// The key set contains no key usable for the token's key ID, if any.
+    UnknownKey(Option<String>),
+    /// The signature doesn't match.
+    InvalidSignature,
+    /// The token expired at the contained time, in seconds since the epoch.
+    Expired(i64),
+    /// The token may not be used before the contained time, in seconds since the epoch.
+    NotYetValid(i64),
+    /// The token was issued by a different issuer.
+    InvalidIssuer(String),
+    /// The token was issued for a different audience.
+    InvalidAudience(Vec<String>),
+    /// The token lacks a required scope.
+    MissingScope(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::Malformed(ref e) => write!(f, "malformed token: {}", e),
+            ValidationError::UnsupportedAlgorithm(ref alg) => {
+                write!(f, "unsupported signature algorithm {}", alg)
+            }
+            ValidationError::UnknownKey(Some(ref kid)) => write!(f, "unknown key {}", kid),
+            ValidationError::UnknownKey(None) => "no key to verify the token with".fmt(f),
+            ValidationError::InvalidSignature => "invalid signature".fmt(f),
+            ValidationError::Expired(exp) => {
+                write!(
+                    f,
+                    "token expired at {}",
+                    time::display(&time::from_secs(exp))
+                )
+            }
+            ValidationError::NotYetValid(nbf) => write!(
+                f,
+                "token not valid before {}",
+                time::display(&time::from_secs(nbf))
+            ),
+            ValidationError::InvalidIssuer(ref iss) => write!(f, "unexpected issuer {}", iss),
+            ValidationError::InvalidAudience(ref aud) => {
+                write!(f, "unexpected audience {}", aud.join(", "))
+            }
+            ValidationError::MissingScope(ref scope) => write!(f, "scope {} not granted", scope),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+fn decode_part(part: &str) -> Result<Vec<u8>, ValidationError> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| ValidationError::Malformed(e.to_string()))
+}
+
+fn decode_param(param: &Option<String>) -> Result<Vec<u8>, ValidationError> {
+    match *param {
+        Some(ref param) => decode_part(param),
+        None => Err(ValidationError::Malformed("incomplete key".to_string())),
+    }
+}
+
+/// Verifies `signature` over `message` using `key`, for the JWS algorithm `alg`.
+fn verify(alg: &str, key: &Jwk, message: &[u8], signature: &[u8]) -> Result<(), ValidationError> {
+    let verified = match (alg, key.kty.as_str()) {
+        ("RS256", "RSA") => signature::RsaPublicKeyComponents {
+            n: decode_param(&key.n)?,
+            e: decode_param(&key.e)?,
+        }
+        .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature),
+        ("ES256", "EC") if key.crv.as_deref() == Some("P-256") => {
+            let mut point = vec![0x04];
+            point.extend(decode_param(&key.x)?);
+            point.extend(decode_param(&key.y)?);
+            signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, signature)
+        }
+        ("RS256", _) | ("ES256", _) => return Err(ValidationError::UnknownKey(key.kid.clone())),
+        _ => return Err(ValidationError::UnsupportedAlgorithm(alg.to_string())),
+    };
+    verified.map_err(|_| ValidationError::InvalidSignature)
+}
+
+/// Returns the strings of `value`, which is either a string of space-separated values, or an
+/// array of strings.
+fn strings(value: Option<&serde_json::Value>, separated: bool) -> Vec<String> {
+    match value {
+        Some(serde_json::Value::String(s)) if separated => {
+            s.split_whitespace().map(str::to_string).collect()
+        }
+        Some(serde_json::Value::String(s)) => vec![s.clone()],
+        Some(serde_json::Value::Array(values)) => values
+            .iter()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Checks the signature of the JWT `token` using the key set `jwks`, and its claims according
+/// to `validation`: the issuer (`iss`), audience (`aud`), expiry (`exp`, which is required) and
+/// `nbf`, and its scopes. RS256 and ES256 signatures are supported.
+///
+/// Keys are selected by the `kid` of the token. If no key matches, the provider may have
+/// rotated its keys; fetch the key set again once the cached one is stale.
+pub fn validate_access_token_claims(
+    token: &str,
+    jwks: &Jwks,
+    validation: &TokenValidation,
+) -> Result<AccessTokenClaims, ValidationError> {
+    let (signed, signature) = token
+        .rsplit_once('.')
+        .ok_or_else(|| ValidationError::Malformed("not a JWT".to_string()))?;
+    let (header, payload) = signed
+        .split_once('.')
+        .ok_or_else(|| ValidationError::Malformed("not a JWT".to_string()))?;
+    let header: Header = serde_json::from_slice(&decode_part(header)?)
+        .map_err(|e| ValidationError::Malformed(e.to_string()))?;
+
+    let key = match header.kid {
+        Some(ref kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    };
+    let key = match key {
+        Some(key) if key.use_.as_deref().unwrap_or("sig") == "sig" => key,
+        _ => return Err(ValidationError::UnknownKey(header.kid)),
+    };
+    if key
+        .alg
+        .as_ref()
+        .map(|alg| *alg != header.alg)
+        .unwrap_or(false)
+    {
+        return Err(ValidationError::UnsupportedAlgorithm(header.alg));
+    }
+    verify(
+        &header.alg,
+        key,
+        signed.as_bytes(),
+        &decode_part(signature)?,
+    )?;
+
+    let claims: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&decode_part(payload)?)
+            .map_err(|e| ValidationError::Malformed(e.to_string()))?;
+    let now = time::now();
+    let leeway = validation.leeway.as_secs() as i64;
+    let expires_at = match claims.get("exp").and_then(|exp| exp.as_i64()) {
+        Some(exp) if exp.saturating_add(leeway) <= now => {
+            return Err(ValidationError::Expired(exp))
+        }
+        Some(exp) => exp,
+        None => return Err(ValidationError::Malformed("exp missing".to_string())),
+    };
+    if let Some(nbf) = claims.get("nbf").and_then(|nbf| nbf.as_i64()) {
+        if nbf.saturating_sub(leeway) > now {
+            return Err(ValidationError::NotYetValid(nbf));
+        }
+    }
+    let issuer = claims
+        .get("iss")
+        .and_then(|iss| iss.as_str())
+        .unwrap_or("")
+        .to_string();
+    if !validation.issuers.contains(&issuer) {
+        return Err(ValidationError::InvalidIssuer(issuer));
+    }
+    let audiences = strings(claims.get("aud"), false);
+    if !audiences
+        .iter()
+        .any(|aud| validation.audiences.contains(aud))
+    {
+        return Err(ValidationError::InvalidAudience(audiences));
+    }
+    let mut scopes = strings(claims.get("scope"), true);
+    scopes.extend(strings(claims.get("scp"), true));
+    if let Some(scope) = validation
+        .required_scopes
+        .iter()
+        .find(|scope| !scopes.contains(scope))
+    {
+        return Err(ValidationError::MissingScope(scope.clone()));
+    }
+
+    Ok(AccessTokenClaims {
+        issuer,
+        subject: claims
+            .get("sub")
+            .and_then(|sub| sub.as_str())
+            .map(str::to_string),
+        audiences,
+        scopes,
+        expires_at,
+        claims,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::KeyPair;
+
+    const ISSUER: &str = "https://issuer.example.com";
+
+    fn encode<T: AsRef<[u8]>>(data: T) -> String {
+        base64::encode_config(data.as_ref(), base64::URL_SAFE_NO_PAD)
+    }
+
+    fn jwk(kty: &str, kid: &str) -> Jwk {
+        Jwk {
+            kty: kty.to_string(),
+            kid: Some(kid.to_string()),
+            alg: None,
+            use_: Some("sig".to_string()),
+            n: None,
+            e: None,
+            crv: None,
+            x: None,
+            y: None,
+        }
+    }
+
+    fn payload(exp: i64, scope: &str) -> serde_json::Value {
+        serde_json::json!({
+            "iss": ISSUER,
+            "sub": "user",
+            "aud": ["https://api.example.com", "other"],
+            "exp": exp,
+            "scope": scope,
+        })
+    }
+
+    /// Returns an ES256-signed token with `claims`, and the key set to verify it.
+    fn es256_token(claims: &serde_json::Value) -> (String, Jwks) {
+        let rng = SystemRandom::new();
+        let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        let key_pair = signature::EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref()).unwrap();
+        let signed = format!(
+            "{}.{}",
+            encode(r#"{"alg":"ES256","kid":"ec"}"#),
+            encode(claims.to_string())
+        );
+        let signature = key_pair.sign(&rng, signed.as_bytes()).unwrap();
+        let point = key_pair.public_key().as_ref();
+        let mut key = jwk("EC", "ec");
+        key.crv = Some("P-256".to_string());
+        key.x = Some(encode(&point[1..33]));
+        key.y = Some(encode(&point[33..]));
+        let token = format!("{}.{}", signed, encode(signature));
+        (token, Jwks { keys: vec![key] })
+    }
+
+    #[test]
+    fn test_validate_es256() {
+        let validation = TokenValidation::new(ISSUER, "https://api.example.com")
+            .required_scope("read")
+            .leeway(Duration::from_secs(0));
+        let (token, jwks) = es256_token(&payload(time::now() + 60, "read write"));
+        let claims = validate_access_token_claims(&token, &jwks, &validation).unwrap();
+        assert_eq!(Some("user".to_string()), claims.subject);
+        assert_eq!(vec!["read", "write"], claims.scopes);
+        assert_eq!("other", claims.audiences[1]);
+
+        let tampered = token.replacen('.', ".e", 1);
+        assert!(validate_access_token_claims(&tampered, &jwks, &validation).is_err());
+        let mut other_key = jwks.clone();
+        other_key.keys[0].kid = Some("other".to_string());
+        assert_eq!(
+            Err(ValidationError::UnknownKey(Some("ec".to_string()))),
+            validate_access_token_claims(&token, &other_key, &validation)
+        );
+
+        let cases = vec![
+            (payload(time::now() - 1, "read"), "expired"),
+            (payload(time::now() + 60, "write"), "scope read"),
+        ];
+        for (claims, error) in cases {
+            let (token, jwks) = es256_token(&claims);
+            let e = validate_access_token_claims(&token, &jwks, &validation).unwrap_err();
+            assert!(e.to_string().contains(error), "{}", e);
+        }
+        let (token, jwks) = es256_token(&payload(time::now() + 60, "read"));
+        let wrong_audience = TokenValidation::new(ISSUER, "https://other.example.com");
+        match validate_access_token_claims(&token, &jwks, &wrong_audience) {
+            Err(ValidationError::InvalidAudience(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let wrong_issuer = TokenValidation::new("https://other.example.com", "other");
+        match validate_access_token_claims(&token, &jwks, &wrong_issuer) {
+            Err(ValidationError::InvalidIssuer(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_validate_odd_claims() {
+        // Signed tokens with claims of unexpected types and extreme values fail validation
+        // cleanly, or pass it.
+        let validation = TokenValidation::new(ISSUER, "https://api.example.com");
+        let times = vec![
+            serde_json::json!(i64::MIN),
+            serde_json::json!(i64::MAX),
+            serde_json::json!(u64::MAX),
+            serde_json::json!(1e300),
+            serde_json::json!("1572000000"),
+            serde_json::Value::Null,
+        ];
+        let audiences = vec![
+            serde_json::json!("https://api.example.com"),
+            serde_json::json!([1, null, "https://api.example.com"]),
+            serde_json::json!({}),
+        ];
+        for exp in &times {
+            for nbf in &times {
+                for aud in &audiences {
+                    let mut claims = payload(0, "read");
+                    claims["exp"] = exp.clone();
+                    claims["nbf"] = nbf.clone();
+                    claims["aud"] = aud.clone();
+                    claims["scope"] = aud.clone();
+                    let (token, jwks) = es256_token(&claims);
+                    if let Err(e) = validate_access_token_claims(&token, &jwks, &validation) {
+                        let _ = e.to_string();
+                    }
+                }
+            }
+        }
+        let mut claims = payload(i64::MAX, "read");
+        claims["nbf"] = serde_json::json!(i64::MAX);
+        let (token, jwks) = es256_token(&claims);
+        match validate_access_token_claims(&token, &jwks, &validation) {
+            Err(ValidationError::NotYetValid(i64::MAX)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_validate_rs256() {
+        let key = std::fs::read_to_string("examples/Sanguine-69411a0c0eea.json").unwrap();
+        let key: serde_json::Value = serde_json::from_str(&key).unwrap();
+        let pem = key["private_key"].as_str().unwrap().replace("\\n", "\n");
+        let der: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+        let key_pair = signature::RsaKeyPair::from_pkcs8(&base64::decode(&der).unwrap()).unwrap();
+
+        let signed = format!(
+            "{}.{}",
+            encode(r#"{"alg":"RS256","kid":"rsa"}"#),
+            encode(payload(time::now() + 60, "read").to_string())
+        );
+        let mut signature = vec![0; key_pair.public_modulus_len()];
+        key_pair
+            .sign(
+                &signature::RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                signed.as_bytes(),
+                &mut signature,
+            )
+            .unwrap();
+        let mut key = jwk("RSA", "rsa");
+        key.n = Some(encode(
+            key_pair
+                .public_key()
+                .modulus()
+                .big_endian_without_leading_zero(),
+        ));
+        key.e = Some(encode(
+            key_pair
+                .public_key()
+                .exponent()
+                .big_endian_without_leading_zero(),
+        ));
+        let mut jwks = Jwks { keys: vec![key] };
+        let token = format!("{}.{}", signed, encode(signature));
+
+        let validation = TokenValidation::new(ISSUER, "other");
+        assert!(validate_access_token_claims(&token, &jwks, &validation).is_ok());
+        jwks.keys[0].alg = Some("RS512".to_string());
+        assert_eq!(
+            Err(ValidationError::UnsupportedAlgorithm("RS256".to_string())),
+            validate_access_token_claims(&token, &jwks, &validation)
+        );
+        let unsigned = format!(
+            "{}.",
+            signed.replacen(
+                &encode(r#"{"alg":"RS256","kid":"rsa"}"#),
+                &encode(r#"{"alg":"none","kid":"rsa"}"#),
+                1
+            )
+        );
+        jwks.keys[0].alg = None;
+        assert_eq!(
+            Err(ValidationError::UnsupportedAlgorithm("none".to_string())),
+            validate_access_token_claims(&unsigned, &jwks, &validation)
+        );
+    }
+}
@@ -0,0 +1,546 @@
+//! The authorization code flow for web applications, authorizing many users.
+//!
+//! Unlike the `InstalledFlow`, the `WebFlow` doesn't wait for a single user: it creates an
+//! authorization URL per user session, keeps the `state` and PKCE code verifier in a
+//! `SessionStore` until the provider redirects the user back to the application, and keeps the
+//! tokens of every user in a `TokenStorage`, keyed by a user ID chosen by the application.
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, prelude::*};
+
+use crate::authenticator::{DefaultHyperClient, HyperClientBuilder};
+use crate::installed::{
+    check_oauth21_redirect_uri, exchange_code, start_authorization, PendingAuthorization,
+};
+use crate::random::{RandomSource, Rng};
+use crate::refresh::RefreshFlow;
+use crate::storage::{MemoryStorage, NullError, TokenKey, TokenStorage};
+use crate::types::{
+    ApplicationSecret, DefaultTokenResponseParser, RefreshResult, RequestError, Token,
+};
+
+/// Tokens of a user are stored for the user's scopes plus this prefix followed by the user ID,
+/// so that tokens of different users never match each other's scopes in a `TokenStorage`.
+const USER_SCOPE_PREFIX: &str = "yup-oauth2-user:";
+
+/// How long authorizations may remain pending by default. Google's authorization codes are valid
+/// for ten minutes, too.
+const DEFAULT_PENDING_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// Keeps the authorizations begun by `WebAuthenticator::authorization_url()` until they are
+/// completed, e.g. in the session storage of a web framework.
+pub trait SessionStore {
+    type Error: 'static + Error + Send + Sync;
+
+    /// Saves the pending authorization of the session `session_id`, replacing any previous one.
+    fn put(&mut self, session_id: &str, pending: PendingAuthorization) -> Result<(), Self::Error>;
+    /// Removes and returns the pending authorization of the session `session_id`, so that it
+    /// can only be completed once.
+    fn take(&mut self, session_id: &str) -> Result<Option<PendingAuthorization>, Self::Error>;
+}
+
+/// A `SessionStore` keeping pending authorizations in memory, for single-process applications.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    pending: HashMap<String, PendingAuthorization>,
+}
+
+impl SessionStore for MemorySessionStore {
+    type Error = NullError;
+
+    fn put(&mut self, session_id: &str, pending: PendingAuthorization) -> Result<(), NullError> {
+        self.pending.insert(session_id.to_string(), pending);
+        Ok(())
+    }
+
+    fn take(&mut self, session_id: &str) -> Result<Option<PendingAuthorization>, NullError> {
+        Ok(self.pending.remove(session_id))
+    }
+}
+
+/// Builds a `WebAuthenticator`, which obtains tokens for the users of a web application.
+///
+/// ```no_run
+/// use futures::prelude::*;
+/// use yup_oauth2::{ApplicationSecret, WebFlow};
+///
+/// let secret = ApplicationSecret::default();
+/// let web = WebFlow::new(secret, "https://app.example.com/oauth2callback").build();
+///
+/// // In the handler starting the authorization, redirect the user to:
+/// let url = web.authorization_url("session-id", vec!["email"]).unwrap();
+///
+/// // In the handler of /oauth2callback?code=...&state=..., once the user came back:
+/// let token = web.finish("session-id", "user-id", "code", "state").wait();
+///
+/// // Later, in any handler acting on behalf of the user:
+/// match web.token("user-id", vec!["email"]).wait() {
+///     Ok(Some(token)) => { /* call the API */ }
+///     Ok(None) => { /* send the user to a new authorization URL */ }
+///     Err(e) => { /* e.g. the authorization was revoked */ }
+/// }
+/// ```
+pub struct WebFlow<SS, TS, C> {
+    appsecret: ApplicationSecret,
+    redirect_uri: String,
+    sessions: SS,
+    tokens: TS,
+    client: C,
+    rng: Rng,
+    oauth21: bool,
+    max_pending: Option<usize>,
+    pending_lifetime: Duration,
+}
+
+impl WebFlow<MemorySessionStore, MemoryStorage, DefaultHyperClient> {
+    /// Create a new WebFlow for the application `secret`, whose redirect handler is reachable
+    /// at `redirect_uri`. By default, pending authorizations and tokens are kept in memory.
+    pub fn new<S: Into<String>>(
+        secret: ApplicationSecret,
+        redirect_uri: S,
+    ) -> WebFlow<MemorySessionStore, MemoryStorage, DefaultHyperClient> {
+        WebFlow {
+            appsecret: secret,
+            redirect_uri: redirect_uri.into(),
+            sessions: MemorySessionStore::default(),
+            tokens: MemoryStorage::new(),
+            client: DefaultHyperClient::default(),
+            rng: Rng::default(),
+            oauth21: false,
+            max_pending: None,
+            pending_lifetime: DEFAULT_PENDING_LIFETIME,
+        }
+    }
+}
+
+impl<SS, TS, C> WebFlow<SS, TS, C>
+where
+    SS: SessionStore,
+    TS: TokenStorage,
+    C: HyperClientBuilder,
+{
+    /// Use the provided session store.
+    pub fn session_store<NewSS: SessionStore>(self, sessions: NewSS) -> WebFlow<NewSS, TS, C> {
+        WebFlow {
+            appsecret: self.appsecret,
+            redirect_uri: self.redirect_uri,
+            sessions,
+            tokens: self.tokens,
+            client: self.client,
+            rng: self.rng,
+            oauth21: self.oauth21,
+            max_pending: self.max_pending,
+            pending_lifetime: self.pending_lifetime,
+        }
+    }
+
+    /// Use the provided token storage for the tokens of all users.
+    pub fn token_storage<NewTS: TokenStorage>(self, tokens: NewTS) -> WebFlow<SS, NewTS, C> {
+        WebFlow {
+            appsecret: self.appsecret,
+            redirect_uri: self.redirect_uri,
+            sessions: self.sessions,
+            tokens,
+            client: self.client,
+            rng: self.rng,
+            oauth21: self.oauth21,
+            max_pending: self.max_pending,
+            pending_lifetime: self.pending_lifetime,
+        }
+    }
+
+    /// Use the provided hyper client.
+    pub fn hyper_client<NewC: HyperClientBuilder>(self, client: NewC) -> WebFlow<SS, TS, NewC> {
+        WebFlow {
+            appsecret: self.appsecret,
+            redirect_uri: self.redirect_uri,
+            sessions: self.sessions,
+            tokens: self.tokens,
+            client,
+            rng: self.rng,
+            oauth21: self.oauth21,
+            max_pending: self.max_pending,
+            pending_lifetime: self.pending_lifetime,
+        }
+    }
+
+    /// Use `source` to generate the `state` and PKCE code verifier of authorizations.
+    /// (default: `OsRandom`)
+    pub fn random_source<R: 'static + RandomSource>(mut self, source: R) -> Self {
+        self.rng = Rng::new(source);
+        self
+    }
+
+    /// Enforce OAuth 2.1, requiring the redirect URI to be one of the application secret's
+    /// `redirect_uris`. Otherwise, `WebAuthenticator::authorization_url()` fails with
+    /// `RequestError::PolicyViolation`. Authorizations always use PKCE. (default: false)
+    pub fn oauth21(mut self, strict: bool) -> Self {
+        self.oauth21 = strict;
+        self
+    }
+
+    /// Allow at most `max` authorizations to be pending at the same time. Beyond that,
+    /// `WebAuthenticator::authorization_url()` fails with
+    /// `RequestError::TooManyPendingAuthorizations` until authorizations complete or expire, so
+    /// that requests to the login route can't fill up the session store. Restarting the
+    /// authorization of a session is always possible. (default: unlimited)
+    pub fn max_pending_authorizations(mut self, max: usize) -> Self {
+        self.max_pending = Some(max);
+        self
+    }
+
+    /// Expire authorizations which weren't completed within `lifetime`, removing them from the
+    /// session store. (default: 10 minutes)
+    pub fn pending_authorization_lifetime(mut self, lifetime: Duration) -> Self {
+        self.pending_lifetime = lifetime;
+        self
+    }
+
+    /// Build the configured WebAuthenticator. It can be shared between threads.
+    pub fn build(self) -> WebAuthenticator<SS, TS, C::Connector> {
+        WebAuthenticator {
+            appsecret: self.appsecret,
+            redirect_uri: self.redirect_uri,
+            sessions: Arc::new(Mutex::new(self.sessions)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            tokens: Arc::new(Mutex::new(self.tokens)),
+            client: self.client.build_hyper_client(),
+            rng: self.rng,
+            oauth21: self.oauth21,
+            max_pending: self.max_pending,
+            pending_lifetime: self.pending_lifetime,
+        }
+    }
+}
+
+/// Obtains and refreshes tokens for the users of a web application. See `WebFlow`.
+pub struct WebAuthenticator<SS, TS, C> {
+    appsecret: ApplicationSecret,
+    redirect_uri: String,
+    sessions: Arc<Mutex<SS>>,
+    /// When the authorization of each session with a pending authorization began.
+    pending: Arc<Mutex<HashMap<String, Instant>>>,
+    tokens: Arc<Mutex<TS>>,
+    client: hyper::Client<C>,
+    rng: Rng,
+    oauth21: bool,
+    max_pending: Option<usize>,
+    pending_lifetime: Duration,
+}
+
+/// Returns the storage key of the tokens of `user_id` for `scopes`, and the scopes to store them
+/// with, which include the user so that they don't match the scopes of other users.
+fn user_scopes<I, T>(client_id: &str, user_id: &str, scopes: I) -> (u64, Vec<String>)
+where
+    T: Into<String>,
+    I: IntoIterator<Item = T>,
+{
+    let key = TokenKey::new(client_id, scopes).with_account(user_id);
+    let user = format!("{}{}", USER_SCOPE_PREFIX, user_id);
+    let mut scopes = key.scopes().to_vec();
+    scopes.push(user);
+    scopes.sort();
+    (key.scope_hash(), scopes)
+}
+
+fn store_token<TS: TokenStorage>(
+    tokens: &Mutex<TS>,
+    scope_hash: u64,
+    scopes: &[String],
+    token: Token,
+) -> Result<Token, RequestError> {
+    tokens
+        .lock()
+        .unwrap()
+        .set(
+            scope_hash,
+            &scopes.iter().map(|s| s.as_str()).collect(),
+            Some(token.clone()),
+        )
+        .map_err(|e| RequestError::Cache(Box::new(e)))?;
+    Ok(token)
+}
+
+impl<SS, TS, C> WebAuthenticator<SS, TS, C>
+where
+    SS: SessionStore,
+    TS: 'static + TokenStorage + Send,
+    C: 'static + hyper::client::connect::Connect,
+{
+    /// Removes the authorizations pending for longer than the lifetime from `pending` and the
+    /// session store.
+    fn expire_pending(&self, pending: &mut HashMap<String, Instant>) -> Result<(), RequestError> {
+        let lifetime = self.pending_lifetime;
+        let expired: Vec<String> = pending
+            .iter()
+            .filter(|(_, started)| started.elapsed() >= lifetime)
+            .map(|(session_id, _)| session_id.clone())
+            .collect();
+        let mut sessions = self.sessions.lock().unwrap();
+        for session_id in expired {
+            sessions
+                .take(&session_id)
+                .map_err(|e| RequestError::Cache(Box::new(e)))?;
+            pending.remove(&session_id);
+        }
+        Ok(())
+    }
+
+    /// Begins an authorization of `scopes` for the session `session_id` and returns the URL the
+    /// user has to be redirected to.
+    pub fn authorization_url<I, T>(
+        &self,
+        session_id: &str,
+        scopes: I,
+    ) -> Result<String, RequestError>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        if self.oauth21 {
+            check_oauth21_redirect_uri(&self.appsecret, &self.redirect_uri)?;
+        }
+        let mut pending_sessions = self.pending.lock().unwrap();
+        self.expire_pending(&mut pending_sessions)?;
+        if let Some(max) = self.max_pending {
+            if pending_sessions.len() >= max && !pending_sessions.contains_key(session_id) {
+                return Err(RequestError::TooManyPendingAuthorizations);
+            }
+        }
+        let pending = start_authorization(
+            &self.rng,
+            &self.appsecret,
+            self.redirect_uri.clone(),
+            scopes.into_iter().map(Into::into).collect(),
+        )?;
+        let url = pending.url.clone();
+        self.sessions
+            .lock()
+            .unwrap()
+            .put(session_id, pending)
+            .map_err(|e| RequestError::Cache(Box::new(e)))?;
+        pending_sessions.insert(session_id.to_string(), Instant::now());
+        Ok(url)
+    }
+
+    /// Completes the authorization begun for `session_id`, using the `code` and `state` query
+    /// parameters the provider redirected the user with, and stores the token for `user_id`.
+    pub fn finish(
+        &self,
+        session_id: &str,
+        user_id: &str,
+        code: &str,
+        state: &str,
+    ) -> Box<dyn Future<Item = Token, Error = RequestError> + Send> {
+        {
+            let mut pending_sessions = self.pending.lock().unwrap();
+            if let Err(e) = self.expire_pending(&mut pending_sessions) {
+                return Box::new(future::err(e));
+            }
+            pending_sessions.remove(session_id);
+        }
+        let pending = match self.sessions.lock().unwrap().take(session_id) {
+            Ok(Some(pending)) => pending,
+            Ok(None) => {
+                return Box::new(future::err(RequestError::UserError(
+                    "no authorization is pending for this session".to_string(),
+                )))
+            }
+            Err(e) => return Box::new(future::err(RequestError::Cache(Box::new(e)))),
+        };
+        if pending.state != state {
+            return Box::new(future::err(RequestError::UserError(
+                "state of authorization response doesn't match".to_string(),
+            )));
+        }
+        let (scope_hash, scopes) = user_scopes(
+            &self.appsecret.client_id,
+            user_id,
+            pending.scopes.iter().cloned(),
+        );
+        let tokens = self.tokens.clone();
+        Box::new(
+            exchange_code(
+                self.client.clone(),
+                &self.appsecret,
+                code,
+                &pending.redirect_uri,
+                Some(&pending.pkce_verifier),
+            )
+            .and_then(move |token| store_token(&tokens, scope_hash, &scopes, token)),
+        )
+    }
+
+    /// Returns the token of `user_id` for `scopes`, refreshing it if it expired, or `None` if the
+    /// user hasn't authorized them yet, in which case the user has to be sent to a new
+    /// authorization URL.
+    pub fn token<I, T>(
+        &self,
+        user_id: &str,
+        scopes: I,
+    ) -> Box<dyn Future<Item = Option<Token>, Error = RequestError> + Send>
+    where
+        T: Into<String>,
+        I: IntoIterator<Item = T>,
+    {
+        let (scope_hash, scopes) = user_scopes(&self.appsecret.client_id, user_id, scopes);
+        let stored = self
+            .tokens
+            .lock()
+            .unwrap()
+            .get(scope_hash, &scopes.iter().map(|s| s.as_str()).collect());
+        let token = match stored {
+            Ok(Some(token)) => token,
+            Ok(None) => return Box::new(future::ok(None)),
+            Err(e) => return Box::new(future::err(RequestError::Cache(Box::new(e)))),
+        };
+        let refresh_token = match token.refresh_token {
+            _ if !token.expired() => return Box::new(future::ok(Some(token))),
+            Some(ref refresh_token) => refresh_token.clone(),
+            None => return Box::new(future::ok(None)),
+        };
+        let tokens = self.tokens.clone();
+        Box::new(
+            RefreshFlow::refresh_token_with_parser(
+                self.client.clone(),
+                self.appsecret.clone(),
+                refresh_token,
+                DefaultTokenResponseParser,
+            )
+            .and_then(move |rr| match rr {
+                RefreshResult::Success(token) => {
+                    store_token(&tokens, scope_hash, &scopes, token).map(Some)
+                }
+                rr => Err(RequestError::Refresh(rr)),
+            }),
+        )
+    }
+
+    /// Expires the stored token with `access_token`, e.g. after a resource server rejected it,
+    /// so that `token()` refreshes it next. Returns whether a stored token matched.
+    pub fn invalidate(&self, access_token: &str) -> Result<bool, RequestError> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .invalidate(access_token)
+            .map_err(|e| RequestError::Cache(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper_rustls::HttpsConnector;
+    use mockito::{self, mock, Matcher};
+    use url::form_urlencoded;
+
+    #[test]
+    fn test_web_flow() {
+        let secret = ApplicationSecret {
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            auth_uri: "https://example.com/auth".to_string(),
+            token_uri: format!("{}/web/token", mockito::server_url()),
+            ..Default::default()
+        };
+        let client = hyper::Client::builder().build::<_, hyper::Body>(HttpsConnector::new(1));
+        let web = WebFlow::new(secret, "https://app.example.com/callback")
+            .hyper_client(client)
+            .build();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+        let url = web.authorization_url("session", vec!["email"]).unwrap();
+        assert!(url.contains("redirect_uri=https://app.example.com/callback"));
+        // The redirect URI isn't among the secret's redirect_uris.
+        let strict = WebFlow::new(web.appsecret.clone(), "https://app.example.com/callback")
+            .oauth21(true)
+            .build();
+        match strict.authorization_url("session", vec!["email"]) {
+            Err(RequestError::PolicyViolation(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(url.contains("code_challenge_method=S256"));
+        let state = form_urlencoded::parse(url.split_once('?').unwrap().1.as_bytes())
+            .find(|(k, _)| k == "state")
+            .map(|(_, v)| v.into_owned())
+            .unwrap();
+
+        // A wrong state is refused, and the pending authorization can't be completed twice.
+        web.authorization_url("other-session", vec!["email"])
+            .unwrap();
+        assert!(rt
+            .block_on(web.finish("other-session", "bob", "code", "wrong"))
+            .is_err());
+        assert!(rt
+            .block_on(web.finish("other-session", "bob", "code", "wrong"))
+            .is_err());
+
+        let _exchange = mock("POST", "/web/token")
+            .match_body(Matcher::Regex("code=authcode.*code_verifier=".to_string()))
+            .with_body(r#"{"access_token":"at1","refresh_token":"rt","token_type":"Bearer","expires_in":0}"#)
+            .expect(1)
+            .create();
+        let _refresh = mock("POST", "/web/token")
+            .match_body(Matcher::Regex("grant_type=refresh_token".to_string()))
+            .with_body(r#"{"access_token":"at2","token_type":"Bearer","expires_in":3600}"#)
+            .expect(1)
+            .create();
+
+        let token = rt
+            .block_on(web.finish("session", "alice", "authcode", &state))
+            .unwrap();
+        assert_eq!("at1", token.access_token);
+        assert!(rt
+            .block_on(web.token("bob", vec!["email"]))
+            .unwrap()
+            .is_none());
+        // The first token expired immediately, so it is refreshed once.
+        for _ in 0..2 {
+            let token = rt
+                .block_on(web.token("alice", vec!["email"]))
+                .unwrap()
+                .unwrap();
+            assert_eq!("at2", token.access_token);
+        }
+        _exchange.assert();
+        _refresh.assert();
+    }
+
+    #[test]
+    fn test_pending_authorization_limits() {
+        let web = WebFlow::new(
+            ApplicationSecret::default(),
+            "https://app.example.com/callback",
+        )
+        .max_pending_authorizations(2)
+        .pending_authorization_lifetime(Duration::from_millis(50))
+        .build();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        web.authorization_url("first", vec!["email"]).unwrap();
+        web.authorization_url("second", vec!["email"]).unwrap();
+        match web.authorization_url("third", vec!["email"]) {
+            Err(RequestError::TooManyPendingAuthorizations) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        // A session may begin its authorization again.
+        web.authorization_url("second", vec!["email"]).unwrap();
+        // Completing an authorization, even unsuccessfully, makes room for another one.
+        assert!(rt
+            .block_on(web.finish("second", "bob", "code", "wrong"))
+            .is_err());
+        web.authorization_url("third", vec!["email"]).unwrap();
+
+        // Abandoned authorizations expire, and are removed from the session store.
+        std::thread::sleep(Duration::from_millis(60));
+        web.authorization_url("fourth", vec!["email"]).unwrap();
+        web.authorization_url("fifth", vec!["email"]).unwrap();
+        assert_eq!(2, web.sessions.lock().unwrap().pending.len());
+        match rt.block_on(web.finish("first", "alice", "code", "state")) {
+            Err(RequestError::UserError(e)) => assert!(e.contains("no authorization")),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,468 @@
+//! A minimal OAuth 2.0 authorization server for integration tests, implementing the device
+//! authorization (RFC 8628 and Google's variant), authorization code with PKCE (RFC 7636),
+//! refresh and revocation (RFC 7009) endpoints. Faults can be injected to exercise error paths.
+//!
+//! The server checks requests as strictly as a real provider would: clients must authenticate
+//! using the method they were registered with, codes are single-use, and PKCE verifiers and
+//! redirect URIs must match.
+
+// Each test crate uses a part of the server.
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::{future, stream, Future, Stream};
+use hyper::{Body, Request, Response, StatusCode};
+use ring::{digest, hmac};
+use url::form_urlencoded;
+
+use yup_oauth2::{ApplicationSecret, ClientAuthMethod};
+
+pub const CLIENT_ID: &str = "conformance-client";
+pub const CLIENT_SECRET: &str = "conformance-secret";
+pub const REDIRECT_URI: &str = "com.example.app:/oauth2redirect";
+
+/// A response returned instead of handling the next request.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Respond with this status and body.
+    Status(u16, &'static str),
+    /// Respond with this status and body, which needn't be UTF-8, let alone JSON.
+    Body(u16, Vec<u8>),
+    /// Respond only after this many milliseconds, then handle the request.
+    Delay(u64),
+    /// Break off the response, which the client sees as a transport error.
+    Reset,
+}
+
+struct Device {
+    user_code: String,
+    scopes: Vec<String>,
+    approved: bool,
+    denied: bool,
+}
+
+struct Grant {
+    redirect_uri: String,
+    code_challenge: Option<String>,
+    scopes: Vec<String>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    expires_in: i64,
+    /// The `token_endpoint_auth_method` the client is registered with.
+    auth_method: ClientAuthMethod,
+    devices: HashMap<String, Device>,
+    codes: HashMap<String, Grant>,
+    /// Scopes by refresh token.
+    refresh_tokens: HashMap<String, Vec<String>>,
+    faults: VecDeque<Fault>,
+    /// Paths of the requests received, in order.
+    requests: Vec<String>,
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn issue_tokens(&mut self, scopes: Vec<String>, with_refresh_token: bool) -> String {
+        let id = self.next_id();
+        let mut response = serde_json::json!({
+            "access_token": format!("access-{}", id),
+            "token_type": "Bearer",
+            "expires_in": self.expires_in,
+            "scope": scopes.join(" "),
+        });
+        if with_refresh_token {
+            let refresh_token = format!("refresh-{}", id);
+            response["refresh_token"] = refresh_token.clone().into();
+            self.refresh_tokens.insert(refresh_token, scopes);
+        }
+        response.to_string()
+    }
+}
+
+pub struct ConformanceServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+type Reply = (StatusCode, String);
+
+fn error(status: u16, error: &str) -> Reply {
+    (
+        StatusCode::from_u16(status).unwrap(),
+        serde_json::json!({ "error": error }).to_string(),
+    )
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    base64::encode_config(
+        digest::digest(&digest::SHA256, verifier.as_bytes()).as_ref(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Whether `assertion` is a valid `client_secret_jwt` assertion of the client.
+fn valid_client_assertion(assertion: &str) -> bool {
+    let parts: Vec<&str> = assertion.split('.').collect();
+    if parts.len() != 3 {
+        return false;
+    }
+    let (signed, signature) = assertion.split_at(parts[0].len() + 1 + parts[1].len());
+    let signature = &signature[1..];
+    let key = hmac::Key::new(hmac::HMAC_SHA256, CLIENT_SECRET.as_bytes());
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap_or_default();
+    if hmac::verify(&key, signed.as_bytes(), &signature).is_err() {
+        return false;
+    }
+    let claims: serde_json::Value = base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|claims| serde_json::from_slice(&claims).ok())
+        .unwrap_or_default();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    claims["iss"] == CLIENT_ID
+        && claims["sub"] == CLIENT_ID
+        && claims["aud"]
+            .as_str()
+            .map(|aud| aud.ends_with("/token"))
+            .unwrap_or(false)
+        && claims["jti"].is_string()
+        && claims["exp"].as_i64().map(|exp| exp > now).unwrap_or(false)
+}
+
+/// Decodes a form-encoded part of HTTP Basic credentials.
+fn form_decode(part: &str) -> String {
+    form_urlencoded::parse(part.as_bytes())
+        .map(|(decoded, _)| decoded.into_owned())
+        .next()
+        .unwrap_or_default()
+}
+
+/// How the client authenticated the request, taking the client ID of HTTP Basic authentication
+/// into `params`. `None` if the credentials presented are wrong.
+fn client_auth(
+    authorization: Option<&str>,
+    params: &mut HashMap<String, String>,
+) -> Option<ClientAuthMethod> {
+    if let Some(authorization) = authorization.filter(|a| a.starts_with("Basic ")) {
+        let credentials = base64::decode(&authorization["Basic ".len()..]).unwrap_or_default();
+        let credentials = String::from_utf8(credentials).unwrap_or_default();
+        let mut parts = credentials.splitn(2, ':').map(form_decode);
+        let (client_id, client_secret) = (parts.next(), parts.next());
+        if client_secret.as_deref() != Some(CLIENT_SECRET) {
+            return None;
+        }
+        params.insert("client_id".to_string(), client_id.unwrap_or_default());
+        return Some(ClientAuthMethod::HttpBasic);
+    }
+    match (params.get("client_secret"), params.get("client_assertion")) {
+        (Some(secret), None) if secret == CLIENT_SECRET => Some(ClientAuthMethod::RequestBody),
+        (None, Some(assertion))
+            if params.get("client_assertion_type").map(String::as_str)
+                == Some("urn:ietf:params:oauth:client-assertion-type:jwt-bearer")
+                && valid_client_assertion(assertion) =>
+        {
+            Some(ClientAuthMethod::SecretJwt)
+        }
+        (None, None) => Some(ClientAuthMethod::Public),
+        _ => None,
+    }
+}
+
+fn handle(
+    state: &mut State,
+    path: &str,
+    params: &HashMap<String, String>,
+    auth: Option<ClientAuthMethod>,
+) -> Reply {
+    let param = |name: &str| params.get(name).map(String::as_str).unwrap_or("");
+    let scopes = || -> Vec<String> { param("scope").split(' ').map(String::from).collect() };
+    // Like Google, the device authorization endpoint also takes just the client ID.
+    if param("client_id") != CLIENT_ID || auth.is_none() {
+        return error(401, "invalid_client");
+    }
+    match path {
+        "/device/code" => {
+            let id = state.next_id();
+            let (device_code, user_code) = (format!("device-{}", id), format!("USER-{}", id));
+            state.devices.insert(
+                device_code.clone(),
+                Device {
+                    user_code: user_code.clone(),
+                    scopes: scopes(),
+                    approved: false,
+                    denied: false,
+                },
+            );
+            let body = serde_json::json!({
+                "device_code": device_code,
+                "user_code": user_code,
+                "verification_url": "https://example.com/device",
+                "expires_in": 600,
+                "interval": 0,
+            });
+            (StatusCode::OK, body.to_string())
+        }
+        "/token" if auth != Some(state.auth_method) => error(401, "invalid_client"),
+        "/token" => match param("grant_type") {
+            grant @ "http://oauth.net/grant_type/device/1.0"
+            | grant @ "urn:ietf:params:oauth:grant-type:device_code" => {
+                let code_param = if grant.starts_with("urn:") {
+                    "device_code"
+                } else {
+                    "code"
+                };
+                match state.devices.get(param(code_param)) {
+                    None => error(400, "expired_token"),
+                    Some(device) if device.denied => error(400, "access_denied"),
+                    Some(device) if !device.approved => error(400, "authorization_pending"),
+                    Some(_) => {
+                        let device = state.devices.remove(param(code_param)).unwrap();
+                        (StatusCode::OK, state.issue_tokens(device.scopes, true))
+                    }
+                }
+            }
+            "authorization_code" => match state.codes.remove(param("code")) {
+                Some(ref grant) if grant.redirect_uri != param("redirect_uri") => {
+                    error(400, "invalid_grant")
+                }
+                Some(ref grant)
+                    if grant
+                        .code_challenge
+                        .as_ref()
+                        .map(|c| *c != pkce_challenge(param("code_verifier")))
+                        .unwrap_or(false) =>
+                {
+                    error(400, "invalid_grant")
+                }
+                Some(grant) => (StatusCode::OK, state.issue_tokens(grant.scopes, true)),
+                None => error(400, "invalid_grant"),
+            },
+            "refresh_token" => match state.refresh_tokens.get(param("refresh_token")) {
+                Some(scopes) => {
+                    let scopes = scopes.clone();
+                    (StatusCode::OK, state.issue_tokens(scopes, false))
+                }
+                None => error(400, "invalid_grant"),
+            },
+            _ => error(400, "unsupported_grant_type"),
+        },
+        "/revoke" => {
+            state.refresh_tokens.remove(param("token"));
+            (StatusCode::OK, String::new())
+        }
+        _ => error(404, "not_found"),
+    }
+}
+
+impl ConformanceServer {
+    /// Starts the server on an ephemeral port. Access tokens expire after an hour.
+    pub fn start() -> ConformanceServer {
+        let state = Arc::new(Mutex::new(State {
+            expires_in: 3600,
+            ..State::default()
+        }));
+        let service_state = state.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let state = service_state.clone();
+            hyper::service::service_fn(move |req: Request<Body>| {
+                let state = state.clone();
+                let path = req.uri().path().to_string();
+                let authorization = req
+                    .headers()
+                    .get(hyper::header::AUTHORIZATION)
+                    .and_then(|a| a.to_str().ok())
+                    .map(String::from);
+                req.into_body().concat2().and_then(move |body| {
+                    let mut params: HashMap<String, String> =
+                        form_urlencoded::parse(&body).into_owned().collect();
+                    let auth = client_auth(authorization.as_deref(), &mut params);
+                    let fault = {
+                        let mut state = state.lock().unwrap();
+                        state.requests.push(path.clone());
+                        state.faults.pop_front()
+                    };
+                    let delay = match fault {
+                        Some(Fault::Status(status, body)) => {
+                            let response = Response::builder()
+                                .status(status)
+                                .body(Body::from(body))
+                                .unwrap();
+                            return future::Either::A(future::ok(response));
+                        }
+                        Some(Fault::Body(status, body)) => {
+                            let response = Response::builder()
+                                .status(status)
+                                .header(hyper::header::CONTENT_TYPE, "application/json")
+                                .body(Body::from(body))
+                                .unwrap();
+                            return future::Either::A(future::ok(response));
+                        }
+                        Some(Fault::Reset) => {
+                            let broken = stream::once::<hyper::Chunk, _>(Err(io::Error::new(
+                                io::ErrorKind::ConnectionReset,
+                                "fault injected",
+                            )));
+                            let response = Response::new(Body::wrap_stream(broken));
+                            return future::Either::A(future::ok(response));
+                        }
+                        Some(Fault::Delay(ms)) => ms,
+                        None => 0,
+                    };
+                    let delay = tokio_timer::sleep(std::time::Duration::from_millis(delay));
+                    future::Either::B(delay.then(move |_| {
+                        let (status, body) =
+                            handle(&mut state.lock().unwrap(), &path, &params, auth);
+                        Ok(Response::builder()
+                            .status(status)
+                            .header(hyper::header::CONTENT_TYPE, "application/json")
+                            .body(Body::from(body))
+                            .unwrap())
+                    }))
+                })
+            })
+        });
+        let addr = server.local_addr();
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(
+            server
+                .with_graceful_shutdown(shutdown_rx)
+                .map_err(|e| panic!("conformance server failed: {}", e)),
+        );
+        ConformanceServer {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+            runtime: Some(runtime),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// The secret of the server's only client.
+    pub fn secret(&self) -> ApplicationSecret {
+        ApplicationSecret {
+            client_id: CLIENT_ID.to_string(),
+            client_secret: CLIENT_SECRET.to_string(),
+            token_uri: self.url("/token"),
+            auth_uri: self.url("/auth"),
+            redirect_uris: vec![REDIRECT_URI.to_string()],
+            ..Default::default()
+        }
+    }
+
+    /// Register the client with `method`, which it must authenticate with at the token endpoint
+    /// from now on.
+    pub fn set_auth_method(&self, method: ClientAuthMethod) {
+        self.state.lock().unwrap().auth_method = method;
+    }
+
+    /// Let access tokens issued from now on expire after `expires_in` seconds.
+    pub fn set_expires_in(&self, expires_in: i64) {
+        self.state.lock().unwrap().expires_in = expires_in;
+    }
+
+    /// Handles the next requests by injecting `faults`, in order.
+    pub fn inject<I: IntoIterator<Item = Fault>>(&self, faults: I) {
+        self.state.lock().unwrap().faults.extend(faults);
+    }
+
+    /// The user grants (or denies) the device authorization with `user_code`.
+    pub fn approve_device(&self, user_code: &str, approve: bool) {
+        let mut state = self.state.lock().unwrap();
+        let device = state
+            .devices
+            .values_mut()
+            .find(|d| d.user_code == user_code)
+            .expect("unknown user code");
+        device.approved = approve;
+        device.denied = !approve;
+    }
+
+    /// The user visits the authorization `url` and grants access. Returns the URI the browser is
+    /// redirected to.
+    pub fn authorize(&self, url: &str) -> String {
+        let url = url::Url::parse(url).unwrap();
+        assert_eq!(Some(self.addr.port()), url.port());
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(
+            Some("code"),
+            params.get("response_type").map(String::as_str)
+        );
+        assert_eq!(Some(CLIENT_ID), params.get("client_id").map(String::as_str));
+        if let Some(method) = params.get("code_challenge_method") {
+            assert_eq!("S256", method);
+        }
+        let mut state = self.state.lock().unwrap();
+        let code = format!("code-{}", state.next_id());
+        let redirect_uri = params["redirect_uri"].clone();
+        state.codes.insert(
+            code.clone(),
+            Grant {
+                redirect_uri: redirect_uri.clone(),
+                code_challenge: params.get("code_challenge").cloned(),
+                scopes: params["scope"].split(' ').map(String::from).collect(),
+            },
+        );
+        let mut redirect = url::Url::parse(&redirect_uri).unwrap();
+        redirect.query_pairs_mut().append_pair("code", &code);
+        if let Some(state) = params.get("state") {
+            redirect.query_pairs_mut().append_pair("state", state);
+        }
+        redirect.to_string()
+    }
+
+    /// Revokes `refresh_token` using the revocation endpoint.
+    pub fn revoke(&self, refresh_token: &str) {
+        let client = hyper::Client::new();
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("client_id", CLIENT_ID)
+            .append_pair("token", refresh_token)
+            .finish();
+        let request = Request::post(self.url("/revoke"))
+            .header(
+                hyper::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let response = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(client.request(request))
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    /// The paths of the requests received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for ConformanceServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(runtime) = self.runtime.take() {
+            let _ = runtime.shutdown_on_idle().wait();
+        }
+    }
+}
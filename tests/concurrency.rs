@@ -0,0 +1,189 @@
+//! Requests for tokens from many threads at once, against the local authorization server in
+//! `common`: expired tokens are refreshed once, however many requests need them, stored tokens
+//! aren't lost to concurrent writers, and nothing deadlocks.
+#![cfg(all(feature = "device", feature = "disk-storage"))]
+
+mod common;
+
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::{ConformanceServer, Fault};
+use yup_oauth2::{
+    Authenticator, DeviceFlow, FixedBackoff, FlowDelegate, GetToken, PollInformation, RequestError,
+    Retry,
+};
+
+/// How long the requests of a test may take before they are considered deadlocked.
+const DEADLOCK_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Plays the user, who approves the device authorization while the flow polls.
+#[derive(Clone)]
+struct User(Arc<ConformanceServer>);
+
+impl FlowDelegate for User {
+    fn present_user_code(&mut self, _: &PollInformation) {}
+
+    fn pending(&mut self, pi: &PollInformation) -> Retry {
+        self.0.approve_device(&pi.user_code, true);
+        Retry::After(Duration::from_millis(10))
+    }
+}
+
+fn device_flow(server: &Arc<ConformanceServer>) -> DeviceFlow<User> {
+    DeviceFlow::new(server.secret())
+        .device_code_url(server.url("/device/code"))
+        .delegate(User(server.clone()))
+}
+
+/// Runs `request` on `threads` threads at once, each with its own runtime, and returns the
+/// results. Panics if they don't finish within `DEADLOCK_TIMEOUT`.
+fn concurrently<F, R>(threads: usize, request: F) -> Vec<R>
+where
+    F: 'static + Fn(usize) -> R + Send + Sync,
+    R: 'static + Send,
+{
+    let request = Arc::new(request);
+    let (results, received) = mpsc::channel();
+    for i in 0..threads {
+        let (request, results) = (request.clone(), results.clone());
+        thread::spawn(move || results.send(request(i)).unwrap());
+    }
+    (0..threads)
+        .map(|_| {
+            received
+                .recv_timeout(DEADLOCK_TIMEOUT)
+                .expect("the requests deadlocked")
+        })
+        .collect()
+}
+
+fn token<G: GetToken>(auth: &G, scopes: Vec<&str>) -> Result<String, RequestError> {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(auth.token(scopes)).map(|t| t.access_token)
+}
+
+fn count(requests: &[String], path: &str) -> usize {
+    requests.iter().filter(|r| *r == path).count()
+}
+
+#[test]
+fn test_concurrent_refresh() {
+    let server = Arc::new(ConformanceServer::start());
+    // The first token is due for a refresh right away.
+    server.set_expires_in(120);
+    let auth = Arc::new(
+        Authenticator::new(device_flow(&server))
+            .expiry_margin(Duration::from_secs(600))
+            .build()
+            .unwrap(),
+    );
+    let first = token(&*auth, vec!["drive"]).unwrap();
+
+    // A slow refresh, which all requests wait for.
+    server.set_expires_in(3600);
+    server.inject(vec![Fault::Delay(200)]);
+    let requests = server.requests().len();
+    let shared = auth.clone();
+    let tokens = concurrently(16, move |_| token(&*shared, vec!["drive"]).unwrap());
+    assert!(tokens.iter().all(|t| *t == tokens[0]));
+    assert_ne!(first, tokens[0]);
+    assert_eq!(vec!["/token"], server.requests()[requests..].to_vec());
+}
+
+#[test]
+fn test_concurrent_refresh_retries() {
+    let server = Arc::new(ConformanceServer::start());
+    server.set_expires_in(120);
+    let auth = Arc::new(
+        Authenticator::new(device_flow(&server))
+            .expiry_margin(Duration::from_secs(600))
+            .refresh_retries(2, FixedBackoff(Duration::from_millis(50)))
+            .build()
+            .unwrap(),
+    );
+    let first = token(&*auth, vec!["drive"]).unwrap();
+
+    // The requests waiting for the refresh keep waiting while it is retried.
+    server.set_expires_in(3600);
+    server.inject(vec![Fault::Reset, Fault::Reset]);
+    let requests = server.requests().len();
+    let shared = auth.clone();
+    let tokens = concurrently(16, move |_| token(&*shared, vec!["drive"]).unwrap());
+    assert!(tokens.iter().all(|t| *t == tokens[0]));
+    assert_ne!(first, tokens[0]);
+    assert_eq!(
+        vec!["/token", "/token", "/token"],
+        server.requests()[requests..].to_vec()
+    );
+}
+
+#[test]
+fn test_concurrent_flows() {
+    let server = Arc::new(ConformanceServer::start());
+    let auth = Arc::new(Authenticator::new(device_flow(&server)).build().unwrap());
+
+    // The user is asked once per set of scopes, not once per request.
+    let shared = auth.clone();
+    let tokens = concurrently(12, move |i| {
+        let scopes = if i % 2 == 0 {
+            vec!["drive"]
+        } else {
+            vec!["email"]
+        };
+        (i % 2, token(&*shared, scopes).unwrap())
+    });
+    for parity in 0..2 {
+        let mut tokens = tokens.iter().filter(|(p, _)| *p == parity).map(|(_, t)| t);
+        let first = tokens.next().unwrap();
+        assert!(tokens.all(|t| t == first));
+    }
+    assert_eq!(2, count(&server.requests(), "/device/code"));
+}
+
+#[test]
+fn test_shared_storage() {
+    let server = Arc::new(ConformanceServer::start());
+    let path = std::env::temp_dir().join(format!(
+        "yup-oauth2-concurrency-{}.json",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let authenticator = |server: &Arc<ConformanceServer>| {
+        Authenticator::new(device_flow(server))
+            .expiry_margin(Duration::from_secs(600))
+            .persist_tokens_to_disk(&path)
+            .build()
+            .unwrap()
+    };
+
+    // Several processes, each refreshing its own tokens in the same file, repeatedly.
+    server.set_expires_in(120);
+    let processes: Vec<_> = (0..4).map(|_| Arc::new(authenticator(&server))).collect();
+    let latest = concurrently(4, move |i| {
+        let scopes = vec!["scope", ["a", "b", "c", "d"][i]];
+        let tokens: Vec<_> = (0..5)
+            .map(|_| token(&*processes[i], scopes.clone()).unwrap())
+            .collect();
+        (scopes, tokens.last().unwrap().clone())
+    });
+
+    // No process lost the tokens of another one.
+    let requests = server.requests().len();
+    let auth = Authenticator::new(device_flow(&server))
+        .persist_tokens_to_disk(&path)
+        .build()
+        .unwrap();
+    for (scopes, stored) in latest {
+        assert_eq!(
+            stored,
+            token(&auth, scopes.clone()).unwrap(),
+            "{:?}",
+            scopes
+        );
+    }
+    assert_eq!(requests, server.requests().len());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,345 @@
+//! End-to-end tests of the flows against the local authorization server in `common`.
+#![cfg(all(feature = "device", feature = "installed"))]
+
+mod common;
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+
+use hyper_rustls::HttpsConnector;
+
+use common::{ConformanceServer, Fault};
+use yup_oauth2::{
+    Authenticator, Cli, ClientAuthMethod, DeviceFlow, DeviceFlowProtocol, FlowDelegate, GetToken,
+    InstalledFlow, InstalledFlowReturnMethod, LoginMethod, PollError, PollInformation,
+    RefreshResult, RequestError, Retry,
+};
+
+/// Plays the user, who approves (or denies) the device authorization while the flow polls.
+#[derive(Clone)]
+struct User {
+    server: Arc<ConformanceServer>,
+    approve: bool,
+}
+
+impl FlowDelegate for User {
+    fn present_user_code(&mut self, _: &PollInformation) {}
+
+    fn pending(&mut self, pi: &PollInformation) -> Retry {
+        self.server.approve_device(&pi.user_code, self.approve);
+        Retry::After(Duration::from_millis(10))
+    }
+}
+
+fn device_flow(server: &Arc<ConformanceServer>, approve: bool) -> DeviceFlow<User> {
+    DeviceFlow::new(server.secret())
+        .device_code_url(server.url("/device/code"))
+        .delegate(User {
+            server: server.clone(),
+            approve,
+        })
+}
+
+#[test]
+fn test_device_flow_and_refresh() {
+    let server = Arc::new(ConformanceServer::start());
+    // Tokens expire right away, so that each request refreshes the token.
+    server.set_expires_in(0);
+    let auth = Authenticator::new(device_flow(&server, true))
+        .build()
+        .unwrap();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    let token = rt.block_on(auth.token(vec!["drive"])).unwrap();
+    let refresh_token = token.refresh_token.clone().unwrap();
+    let refreshed = rt.block_on(auth.token(vec!["drive"])).unwrap();
+    assert_ne!(token.access_token, refreshed.access_token);
+    assert_eq!(Some(refresh_token.clone()), refreshed.refresh_token);
+
+    // An outage of the token endpoint fails a single request.
+    server.inject(vec![Fault::Status(503, "<html>Service Unavailable</html>")]);
+    assert!(rt.block_on(auth.token(vec!["drive"])).is_err());
+    rt.block_on(auth.token(vec!["drive"])).unwrap();
+
+    // Once the user revoked the authorization, the refresh token is refused.
+    server.revoke(&refresh_token);
+    match rt.block_on(auth.token(vec!["drive"])) {
+        Err(RequestError::Refresh(RefreshResult::RefreshError(e))) => {
+            assert_eq!("invalid_grant", e.error)
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    // The device flow polls twice, as the user approves after the first poll.
+    assert_eq!(
+        vec![
+            "/device/code",
+            "/token",
+            "/token",
+            "/token",
+            "/token",
+            "/token",
+            "/revoke",
+            "/token"
+        ],
+        server.requests()
+    );
+}
+
+#[test]
+fn test_refresh_latency_budget() {
+    let server = Arc::new(ConformanceServer::start());
+    server.set_expires_in(120);
+    let auth = Authenticator::new(device_flow(&server, true))
+        .expiry_margin(Duration::from_secs(600))
+        .refresh_latency_budget(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let token = rt.block_on(auth.token(vec!["drive"])).unwrap();
+
+    // The stored token is due for a refresh, which is slow; the token is returned meanwhile.
+    server.set_expires_in(3600);
+    server.inject(vec![Fault::Delay(300)]);
+    let started = Instant::now();
+    let stale = rt.block_on(auth.token(vec!["drive"])).unwrap();
+    assert!(started.elapsed() < Duration::from_millis(300));
+    assert_eq!(token.access_token, stale.access_token);
+
+    // Once the refresh completed in the background, its token is returned.
+    std::thread::sleep(Duration::from_millis(500));
+    let refreshed = rt.block_on(auth.token(vec!["drive"])).unwrap();
+    assert_ne!(token.access_token, refreshed.access_token);
+    assert_eq!(
+        vec!["/device/code", "/token", "/token", "/token"],
+        server.requests()
+    );
+}
+
+#[test]
+fn test_cli() {
+    let server = Arc::new(ConformanceServer::start());
+    let path = std::env::temp_dir().join(format!("yup-oauth2-cli-{}.json", std::process::id()));
+    let cli = Cli::new(server.secret(), &path)
+        .method(LoginMethod::Device)
+        .device_code_url(server.url("/device/code"))
+        .revocation_url(server.url("/revoke"))
+        .delegate(User {
+            server: server.clone(),
+            approve: true,
+        });
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    assert!(cli.status().unwrap().is_empty());
+
+    let token = rt.block_on(cli.login(vec!["profile", "email"])).unwrap();
+    let status = cli.status().unwrap();
+    assert_eq!(1, status.len());
+    assert_eq!(vec!["email", "profile"], status[0].scopes);
+    assert!(status[0].signed_in() && status[0].refreshable);
+
+    // Logging in again signs in anew, which the tool's authenticator picks up.
+    let relogin = rt.block_on(cli.login(vec!["email", "profile"])).unwrap();
+    assert_ne!(token.refresh_token, relogin.refresh_token);
+    assert_eq!(1, cli.status().unwrap().len());
+    let auth = Authenticator::new(device_flow(&server, true))
+        .persist_tokens_to_disk(&path)
+        .build()
+        .unwrap();
+    let stored = rt.block_on(auth.token(vec!["email", "profile"])).unwrap();
+    assert_eq!(relogin.access_token, stored.access_token);
+
+    assert!(rt.block_on(cli.logout()).unwrap());
+    assert!(cli.status().unwrap().is_empty());
+    assert!(!rt.block_on(cli.logout()).unwrap());
+    assert_eq!(
+        vec![
+            "/device/code",
+            "/token",
+            "/token",
+            "/device/code",
+            "/token",
+            "/token",
+            "/revoke"
+        ],
+        server.requests()
+    );
+}
+
+#[test]
+fn test_device_flow_denied() {
+    let server = Arc::new(ConformanceServer::start());
+    let auth =
+        Authenticator::new(device_flow(&server, false).protocol(DeviceFlowProtocol::Rfc8628))
+            .build()
+            .unwrap();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    match rt.block_on(auth.token(vec!["drive"])) {
+        Err(RequestError::Poll(PollError::AccessDenied)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_client_authentication() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let methods = vec![
+        ClientAuthMethod::RequestBody,
+        ClientAuthMethod::HttpBasic,
+        ClientAuthMethod::SecretJwt,
+        ClientAuthMethod::Public,
+    ];
+    for method in methods {
+        let server = Arc::new(ConformanceServer::start());
+        server.set_auth_method(method);
+        server.set_expires_in(0);
+        let mut secret = server.secret();
+        secret.token_endpoint_auth_method = method;
+        let flow = DeviceFlow::new(secret)
+            .protocol(DeviceFlowProtocol::Rfc8628)
+            .device_code_url(server.url("/device/code"))
+            .delegate(User {
+                server: server.clone(),
+                approve: true,
+            });
+        let auth = Authenticator::new(flow).build().unwrap();
+        let token = rt.block_on(auth.token(vec!["drive"])).unwrap();
+        let refreshed = rt.block_on(auth.token(vec!["drive"])).unwrap();
+        assert_ne!(token.access_token, refreshed.access_token, "{:?}", method);
+    }
+
+    // Once the client is registered with another method, its requests are refused.
+    let server = Arc::new(ConformanceServer::start());
+    server.set_expires_in(0);
+    let auth = Authenticator::new(device_flow(&server, true))
+        .build()
+        .unwrap();
+    rt.block_on(auth.token(vec!["drive"])).unwrap();
+    server.set_auth_method(ClientAuthMethod::HttpBasic);
+    match rt.block_on(auth.token(vec!["drive"])) {
+        Err(RequestError::Refresh(RefreshResult::RefreshError(e))) => {
+            assert_eq!("invalid_client", e.error)
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+/// Plays the user of an application on a remote host, who authorizes it in a local browser and
+/// pastes the address the browser failed to load.
+#[derive(Clone)]
+struct RemoteUser(Arc<ConformanceServer>);
+
+impl FlowDelegate for RemoteUser {
+    fn present_user_url<S: AsRef<str> + fmt::Display>(
+        &mut self,
+        url: S,
+        need_code: bool,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Box<dyn Error + Send>> + Send> {
+        assert!(need_code);
+        let redirect = self.0.authorize(url.as_ref());
+        assert!(redirect.starts_with("http://localhost/?code="));
+        Box::new(futures::future::ok(Some(redirect)))
+    }
+}
+
+#[test]
+fn test_remote_browser() {
+    let server = Arc::new(ConformanceServer::start());
+    let mut secret = server.secret();
+    secret.redirect_uris.push("http://localhost".to_string());
+    let flow = InstalledFlow::new(secret, InstalledFlowReturnMethod::Remote)
+        .delegate(RemoteUser(server.clone()));
+    let auth = Authenticator::new(flow).build().unwrap();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let token = rt.block_on(auth.token(vec!["email"])).unwrap();
+    assert!(token.access_token.starts_with("access-"));
+    assert_eq!(vec!["/token"], server.requests());
+}
+
+/// Plays a user who never shows up.
+#[derive(Clone)]
+struct AbsentUser;
+
+impl FlowDelegate for AbsentUser {
+    fn present_user_code(&mut self, _: &PollInformation) {}
+
+    fn pending(&mut self, _: &PollInformation) -> Retry {
+        Retry::After(Duration::from_millis(10))
+    }
+
+    fn present_user_url<S: AsRef<str> + fmt::Display>(
+        &mut self,
+        _: S,
+        _: bool,
+    ) -> Box<dyn Future<Item = Option<String>, Error = Box<dyn Error + Send>> + Send> {
+        Box::new(futures::future::ok(None))
+    }
+}
+
+#[test]
+fn test_flow_deadline() {
+    let server = Arc::new(ConformanceServer::start());
+    let deadline = Duration::from_millis(200);
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    let flow = DeviceFlow::new(server.secret())
+        .device_code_url(server.url("/device/code"))
+        .delegate(AbsentUser)
+        .deadline(deadline);
+    let auth = Authenticator::new(flow).build().unwrap();
+    match rt.block_on(auth.token(vec!["drive"])) {
+        Err(RequestError::TimedOut(d)) => assert_eq!(deadline, d),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // The redirect listener is shut down rather than waited for.
+    let flow = InstalledFlow::new(
+        server.secret(),
+        InstalledFlowReturnMethod::HTTPRedirectEphemeral,
+    )
+    .delegate(AbsentUser)
+    .deadline(deadline);
+    let auth = Authenticator::new(flow).build().unwrap();
+    match rt.block_on(auth.token(vec!["email"])) {
+        Err(RequestError::TimedOut(d)) => assert_eq!(deadline, d),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_authorization_code_flow() {
+    let server = ConformanceServer::start();
+    let flow = InstalledFlow::new(server.secret(), InstalledFlowReturnMethod::Interactive);
+    let client = hyper::Client::builder()
+        .keep_alive(false)
+        .build::<_, hyper::Body>(HttpsConnector::new(1));
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    let pending = flow.start(vec!["email", "profile"]).unwrap();
+    // Google's `access_type` isn't sent to other providers.
+    assert!(!pending.url.contains("access_type"));
+    let redirect = server.authorize(&pending.url);
+    // A slow token endpoint doesn't matter.
+    server.inject(vec![Fault::Delay(50)]);
+    let token = rt
+        .block_on(flow.finish_redirect(client.clone(), &pending, &redirect))
+        .unwrap();
+    assert!(token.access_token.starts_with("access-"));
+    assert!(token.refresh_token.is_some());
+
+    // Authorization codes are single-use.
+    assert!(rt
+        .block_on(flow.finish_redirect(client.clone(), &pending, &redirect))
+        .is_err());
+
+    // The PKCE code verifier must match the challenge of the authorization.
+    let stolen = flow.start(vec!["email"]).unwrap();
+    let redirect = server.authorize(&stolen.url);
+    let mut forged = flow.start(vec!["email"]).unwrap();
+    forged.state = stolen.state.clone();
+    assert!(rt
+        .block_on(flow.finish_redirect(client, &forged, &redirect))
+        .is_err());
+}
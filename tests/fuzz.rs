@@ -0,0 +1,206 @@
+//! Feeds malformed responses to the parsers and flows, which must fail with an error rather than
+//! panic. The inputs are mutations of valid responses, derived with a fixed seed so that failures
+//! reproduce; the targets in `fuzz/` explore the parsers further, guided by coverage.
+#![cfg(all(feature = "device", feature = "installed"))]
+
+mod common;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use common::{ConformanceServer, Fault};
+use yup_oauth2::{
+    AuthorizationResponse, DefaultTokenResponseParser, DeviceFlow, DiscoveryDocument, ForeignToken,
+    InstalledFlow, InstalledFlowReturnMethod, JsonError, Jwks, PendingDeviceAuthorization,
+    RefreshFlow, Scheme, StrictTokenResponseParser, Token, TokenResponseParser, TokenValidation,
+};
+
+/// Valid responses, as sent by providers, to start the mutations from.
+const CORPUS: &[&str] = &[
+    r#"{"access_token":"ya29.token","token_type":"Bearer","expires_in":3599,"refresh_token":"1/refresh","scope":"email"}"#,
+    r#"{"access_token":"ya29.token","expires_in":"3600","id_token":"eyJhbGciOiJSUzI1NiJ9.eyJleHAiOjE1NzIwMDAwMDB9.c2ln"}"#,
+    r#"{"error":"invalid_grant","error_description":"Token has been expired or revoked.","error_uri":"https://example.com"}"#,
+    "access_token=gho_token&scope=repo&token_type=bearer&expires_in=28800",
+    r#"{"device_code":"dc","user_code":"ABCD-EFGH","verification_url":"https://example.com/device","expires_in":1800,"interval":5}"#,
+    "device_code=dc&expires_in=899&interval=5&user_code=WDJB-MJHT&verification_uri=https%3A%2F%2Fgithub.com%2Flogin%2Fdevice",
+    "com.example.app:/oauth2redirect?code=4/code&state=xyz#fragment",
+    "Bearer ya29.token",
+    r#"{"keys":[{"kty":"RSA","kid":"k1","use":"sig","alg":"RS256","n":"AQAB","e":"AQAB"}]}"#,
+    r#"{"issuer":"https://accounts.google.com","jwks_uri":"https://www.googleapis.com/oauth2/v3/certs"}"#,
+    r#"{"token":"ya29.token","refresh_token":"1/refresh","client_id":"id","expiry":"2019-10-25T10:40:00.123456Z"}"#,
+    "eyJhbGciOiJSUzI1NiIsImtpZCI6ImsxIn0.eyJpc3MiOiJodHRwczovL2FjY291bnRzLmdvb2dsZS5jb20iLCJhdWQiOiJjbGllbnQiLCJleHAiOjk5OTk5OTk5OTk5OSwic2NvcGUiOiJyZWFkIn0.c2ln",
+    "eyJhbGciOiJSUzI1NiIsImtpZCI6ImsxIn0.eyJpc3MiOiJodHRwczovL2FjY291bnRzLmdvb2dsZS5jb20iLCJhdWQiOlsiY2xpZW50Il0sImV4cCI6LTkyMjMzNzIwMzY4NTQ3NzU4MDgsIm5iZiI6OTIyMzM3MjAzNjg1NDc3NTgwN30.c2ln",
+];
+
+/// Fragments which parsers tend to trip over.
+const FRAGMENTS: &[&str] = &[
+    "\"",
+    "{",
+    "}",
+    "[",
+    "]",
+    ",",
+    ":",
+    "null",
+    "-1",
+    "0",
+    "9223372036854775807",
+    "-9223372036854775808",
+    "18446744073709551616",
+    "1e400",
+    "\"\\u0000\\n\"",
+    "\u{feff}",
+    "=",
+    "&",
+    "%ff",
+    "?",
+    "#",
+    ".",
+    "\"expires_in\":",
+    "\"interval\":",
+    "\"access_token\":",
+    "\"error\":",
+    "\"expiry\":\"9999-99-99T99:99:99+99:99\"",
+];
+
+/// Mutates inputs using a xorshift generator; reproducibility matters here, not randomness.
+struct Mutator(u64);
+
+impl Mutator {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n.max(1) as u64) as usize
+    }
+
+    /// Returns a mutation of one of the `CORPUS` entries.
+    fn next(&mut self) -> Vec<u8> {
+        let mut input = CORPUS[self.below(CORPUS.len())].as_bytes().to_vec();
+        for _ in 0..=self.below(4) {
+            let at = self.below(input.len() + 1);
+            match self.below(5) {
+                0 => input.truncate(at),
+                1 if at < input.len() => input[at] = self.below(256) as u8,
+                2 if at < input.len() => {
+                    input.remove(at);
+                }
+                3 => {
+                    let end = at + self.below(input.len() - at + 1);
+                    let copy = input[at..end].to_vec();
+                    input.splice(at..at, copy);
+                }
+                _ => {
+                    let fragment = FRAGMENTS[self.below(FRAGMENTS.len())].bytes();
+                    input.splice(at..at, fragment);
+                }
+            }
+        }
+        input
+    }
+}
+
+/// Uses `token` the way applications do.
+fn use_token(token: &Token) {
+    let _ = token.expired();
+    let _ = token.expires_in();
+    let _ = token.expires_at();
+    let _ = serde_json::to_string(token);
+}
+
+#[test]
+fn test_parsers_never_panic() {
+    let mut mutator = Mutator(0x5eed);
+    let validation = TokenValidation::new("https://accounts.google.com", "client");
+    let jwks: Jwks = serde_json::from_str(CORPUS[8]).unwrap();
+    for _ in 0..20_000 {
+        let input = mutator.next();
+        let input = String::from_utf8_lossy(&input);
+        if let Ok(token) = DefaultTokenResponseParser.parse_token_response(&input) {
+            use_token(&token);
+        }
+        if let Ok(token) = StrictTokenResponseParser.parse_token_response(&input) {
+            use_token(&token);
+        }
+        if let Ok(token) = serde_json::from_str::<Token>(&input) {
+            use_token(&token);
+        }
+        let _ = JsonError::from_response(&input);
+        let _ = AuthorizationResponse::from_uri(&input).into_code();
+        let _ = AuthorizationResponse::from_query(&input).into_code();
+        if let Ok(scheme) = Scheme::from_str(&input) {
+            let _ = scheme.header_value();
+        }
+        let _ = serde_json::from_str::<Jwks>(&input);
+        let _ = serde_json::from_str::<DiscoveryDocument>(&input);
+        let _ = yup_oauth2::validate_access_token_claims(&input, &jwks, &validation);
+        if let Ok(foreign) = ForeignToken::parse(&input) {
+            let _ = foreign.key("client");
+        }
+    }
+}
+
+#[test]
+fn test_flows_never_panic() {
+    let server = ConformanceServer::start();
+    let client = hyper::Client::builder()
+        .keep_alive(false)
+        .build_http::<hyper::Body>();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let device = DeviceFlow::new(server.secret())
+        .device_code_url(server.url("/device/code"))
+        .wait_duration(Duration::from_secs(1));
+    let installed = InstalledFlow::new(server.secret(), InstalledFlowReturnMethod::Interactive);
+
+    let mut mutator = Mutator(0xfa17);
+    for i in 0..200 {
+        let status = [200, 400, 401, 500][mutator.below(4)];
+        server.inject(vec![Fault::Body(status, mutator.next())]);
+        match i % 4 {
+            0 => {
+                if let Ok(result) = rt.block_on(RefreshFlow::refresh_token_with_parser(
+                    client.clone(),
+                    server.secret(),
+                    "refresh-token".to_string(),
+                    DefaultTokenResponseParser,
+                )) {
+                    let _ = format!("{:?}", result);
+                }
+            }
+            1 => {
+                if let Ok(pending) = rt.block_on(device.start(client.clone(), vec!["drive"])) {
+                    let _ = pending.expired();
+                    let _ = pending.poll_information();
+                }
+            }
+            2 => {
+                // The first poll is answered by the fault, the next ones by the server, which
+                // doesn't know the device code.
+                let pending = PendingDeviceAuthorization {
+                    device_code: "unknown".to_string(),
+                    user_code: "USER".to_string(),
+                    verification_url: "https://example.com/device".to_string(),
+                    verification_url_complete: None,
+                    expires_at: i64::MAX,
+                    interval: 0,
+                    scopes: vec!["drive".to_string()],
+                };
+                if let Ok(token) = rt.block_on(device.resume(client.clone(), pending)) {
+                    use_token(&token);
+                }
+            }
+            _ => {
+                let pending = installed.start(vec!["email"]).unwrap();
+                let redirect = format!(
+                    "com.example.app:/oauth2redirect?code=code&state={}",
+                    pending.state
+                );
+                if let Ok(token) =
+                    rt.block_on(installed.finish_redirect(client.clone(), &pending, &redirect))
+                {
+                    use_token(&token);
+                }
+            }
+        }
+    }
+}